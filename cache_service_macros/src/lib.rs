@@ -0,0 +1,147 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, Expr, FnArg, GenericParam, ItemFn, Pat, ReturnType};
+
+/// Memoizes a function through a `CacheService`.
+///
+/// The first parameter must be the `&mut CacheService` to cache through. The remaining
+/// parameters make up the key together with the function path, so they must implement `Debug`.
+/// The return type must implement `ToString` and `FromStr`, and the wrapped function returns
+/// `Result<T, CacheServiceError>`. `ttl = <seconds>` overrides the service TTLs.
+///
+/// ```ignore
+/// #[cached(ttl = 60)]
+/// fn price(cache: &mut CacheService, sku: &str) -> u64 {
+///     load_price(sku)
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn cached(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut ttl: Option<Expr> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("ttl") {
+            ttl = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("Unsupported #[cached] argument, expected `ttl`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    expand(function, ttl)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(function: ItemFn, ttl: Option<Expr>) -> syn::Result<TokenStream2> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+    let name = &sig.ident;
+    let (impl_generics, _, where_clause) = sig.generics.split_for_impl();
+
+    let ReturnType::Type(_, output) = &sig.output else {
+        return Err(Error::new(
+            sig.span(),
+            "#[cached] functions must return a value",
+        ));
+    };
+
+    let mut inputs = sig.inputs.iter();
+    let cache = match inputs.next() {
+        Some(FnArg::Typed(cache)) => cache,
+        Some(FnArg::Receiver(receiver)) => {
+            return Err(Error::new(
+                receiver.span(),
+                "#[cached] is not supported on methods",
+            ))
+        }
+        None => {
+            return Err(Error::new(
+                sig.span(),
+                "#[cached] functions take the CacheService as their first parameter",
+            ))
+        }
+    };
+    let cache_ident = binding(&cache.pat)?;
+    let args: Vec<FnArg> = inputs.cloned().collect();
+    // `mut` bindings only matter inside the original body.
+    let outer_args: Vec<FnArg> = args
+        .iter()
+        .cloned()
+        .map(|mut arg| {
+            if let FnArg::Typed(arg) = &mut arg {
+                if let Pat::Ident(pat) = &mut *arg.pat {
+                    pat.mutability = None;
+                }
+            }
+            arg
+        })
+        .collect();
+    let arg_idents = args
+        .iter()
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => binding(&arg.pat),
+            FnArg::Receiver(receiver) => Err(Error::new(receiver.span(), "Unexpected receiver")),
+        })
+        .collect::<syn::Result<Vec<Ident>>>()?;
+
+    let type_args: Vec<&Ident> = sig
+        .generics
+        .params
+        .iter()
+        .filter_map(|param| match param {
+            GenericParam::Type(param) => Some(&param.ident),
+            GenericParam::Const(param) => Some(&param.ident),
+            GenericParam::Lifetime(_) => None,
+        })
+        .collect();
+    let turbofish = (!type_args.is_empty()).then(|| quote!(::<#(#type_args),*>));
+
+    let inner = format_ident!("__cached_{}", name);
+    let ttl = match ttl {
+        Some(ttl) => quote!(::std::option::Option::Some(::std::time::Duration::from_secs(#ttl))),
+        None => quote!(::std::option::Option::None),
+    };
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #name #impl_generics (#cache, #(#outer_args),*)
+            -> ::std::result::Result<#output, ::cache_service::CacheServiceError>
+            #where_clause
+        {
+            fn #inner #impl_generics (#(#args),*) -> #output #where_clause #block
+
+            let __cache_key = ::std::format!(
+                "{}::{}({})",
+                ::std::module_path!(),
+                ::std::stringify!(#name),
+                <[::std::string::String]>::join(
+                    &[#(::std::format!("{:?}", &#arg_idents)),*],
+                    ", "
+                )
+            );
+            #cache_ident.resolve_parsed::<#output, _>(
+                &__cache_key,
+                #ttl,
+                move || #inner #turbofish (#(#arg_idents),*),
+            )
+        }
+    })
+}
+
+fn binding(pat: &Pat) -> syn::Result<Ident> {
+    match pat {
+        Pat::Ident(pat) => Ok(pat.ident.clone()),
+        _ => Err(Error::new(
+            pat.span(),
+            "#[cached] parameters must be plain identifiers",
+        )),
+    }
+}
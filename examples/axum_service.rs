@@ -0,0 +1,221 @@
+//! A product price service showing how the pieces of `cache_service` fit together behind
+//! axum:
+//!
+//! - `GET /prices/:sku` reads a typed price through both cache tiers. Prices read in the last
+//!   fifth of their TTL are reloaded in the background, so hot prices never expire under load
+//!   (stale-while-revalidate).
+//! - `PUT /prices/:sku` writes the database and invalidates the cached price, here and on every
+//!   other instance listening on the invalidation channel.
+//! - `GET /shipping/:zone` memoizes a pure computation with `#[cached]`.
+//! - `GET /catalogue` caches the whole response with `CacheLayer`.
+//! - `GET /metrics` reports the cache counters in the Prometheus text format.
+//!
+//! Start Redis on `127.0.0.1:6379`, then run `cargo run --example axum_service --features tower`
+//! and try `curl localhost:3000/prices/apple`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use bytes::Bytes;
+use cache_service::http_layer::{BoxError, CacheLayer};
+use cache_service::{cached, CacheService, CacheServiceError};
+use tower::ServiceBuilder;
+
+/// Stands in for the real database, with lookups slow enough to be worth caching.
+#[derive(Default)]
+struct Catalogue {
+    prices: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Catalogue {
+    fn price(&self, sku: &str) -> Price {
+        thread::sleep(Duration::from_millis(50));
+        Price(self.prices.lock().unwrap().get(sku).copied())
+    }
+
+    fn set_price(&self, sku: &str, cents: u64) {
+        self.prices.lock().unwrap().insert(sku.to_string(), cents);
+    }
+
+    fn list(&self) -> String {
+        thread::sleep(Duration::from_millis(200));
+        let prices = self.prices.lock().unwrap();
+        prices
+            .iter()
+            .map(|(sku, cents)| format!("{} {}\n", sku, cents))
+            .collect()
+    }
+}
+
+/// Price of a SKU in cents. Unknown SKUs are cached as well, so requests for them don't reach
+/// the database either.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Price(Option<u64>);
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(cents) => write!(f, "{}", cents),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+impl FromStr for Price {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unknown" => Ok(Price(None)),
+            cents => cents.parse().map(|cents| Price(Some(cents))),
+        }
+    }
+}
+
+fn price_key(sku: &str) -> String {
+    format!("price:{}", sku)
+}
+
+/// Pure and costly, so safe to keep for a long time.
+#[cached(ttl = 300)]
+fn shipping_cost(cache: &mut CacheService, zone: &str) -> u64 {
+    thread::sleep(Duration::from_millis(100));
+    zone.bytes().map(u64::from).sum::<u64>() % 20 * 100 + 500
+}
+
+#[derive(Clone)]
+struct AppState {
+    cache: Arc<Mutex<CacheService>>,
+    catalogue: Arc<Catalogue>,
+}
+
+/// Runs cache calls, which block on Redis, off the async workers.
+async fn blocking<T, F>(f: F) -> Result<T, StatusCode>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, CacheServiceError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn get_price(
+    State(state): State<AppState>,
+    Path(sku): Path<String>,
+) -> Result<String, StatusCode> {
+    let price = blocking(move || {
+        let catalogue = &state.catalogue;
+        let mut cache = state.cache.lock().unwrap();
+        cache.resolve_parsed(&price_key(&sku), None, || catalogue.price(&sku))
+    })
+    .await?;
+    price
+        .0
+        .map(|cents| cents.to_string())
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn put_price(
+    State(state): State<AppState>,
+    Path(sku): Path<String>,
+    body: String,
+) -> Result<StatusCode, StatusCode> {
+    let cents = body.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    blocking(move || {
+        state.catalogue.set_price(&sku, cents);
+        state.cache.lock().unwrap().invalidate(&price_key(&sku))
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn shipping(
+    State(state): State<AppState>,
+    Path(zone): Path<String>,
+) -> Result<String, StatusCode> {
+    let cents = blocking(move || shipping_cost(&mut state.cache.lock().unwrap(), &zone)).await?;
+    Ok(cents.to_string())
+}
+
+async fn catalogue_page(State(state): State<AppState>) -> String {
+    tokio::task::spawn_blocking(move || state.catalogue.list())
+        .await
+        .unwrap_or_default()
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    let stats = state.cache.lock().unwrap().stats();
+    let counters = [
+        ("memory_hits", stats.memory_hits),
+        ("memory_misses", stats.memory_misses),
+        ("kv_hits", stats.kv_hits),
+        ("kv_misses", stats.kv_misses),
+        ("resolver_calls", stats.resolver_calls),
+        ("evictions", stats.evictions),
+        ("errors", stats.errors),
+    ];
+    let mut text: String = counters
+        .iter()
+        .map(|(name, value)| format!("rcache_{}_total {}\n", name, value))
+        .collect();
+    text.push_str(&format!("rcache_entries {}\n", stats.entries));
+    text
+}
+
+#[tokio::main]
+async fn main() {
+    let catalogue = Arc::new(Catalogue::default());
+    catalogue.set_price("apple", 120);
+    catalogue.set_price("pear", 95);
+
+    let reload = Arc::clone(&catalogue);
+    let cache = CacheService::builder("redis://127.0.0.1:6379")
+        .ttl(Duration::from_secs(60))
+        .namespace("shop")
+        .invalidation_channel("shop-invalidation")
+        .refresh_ahead(0.2, move |key| {
+            let sku = key.strip_prefix("price:")?;
+            Some(Bytes::from(reload.price(sku).to_string()))
+        })
+        .build()
+        .expect("cannot set up the cache, is Redis running on 127.0.0.1:6379?");
+    let state = AppState {
+        cache: Arc::new(Mutex::new(cache)),
+        catalogue,
+    };
+
+    let cached_pages = Router::new()
+        .route("/catalogue", get(catalogue_page))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|err: BoxError| async move {
+                    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                }))
+                .layer(CacheLayer::new(
+                    Arc::clone(&state.cache),
+                    Duration::from_secs(5),
+                )),
+        );
+    let app = Router::new()
+        .route("/prices/:sku", get(get_price).put(put_price))
+        .route("/shipping/:zone", get(shipping))
+        .route("/metrics", get(metrics))
+        .merge(cached_pages)
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+        .await
+        .expect("cannot listen on 127.0.0.1:3000");
+    axum::serve(listener, app).await.unwrap();
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::in_memory_cache::InMemoryCacheError;
+use crate::kv_cache::KvError;
+use crate::Tier;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectionReason {
+    EmptyKey,
+    WriteFailed,
+    QuotaExceeded,
+    TooLarge,
+    /// `EvictionPolicy::TinyLfu` dropped the entry as it left the window, as the frequency
+    /// sketch counted fewer requests for it than for the entry it would have displaced.
+    AdmissionDenied,
+    /// Resolved while the service or its namespace was passing through.
+    PassThrough,
+    /// Resolved directly as the key is outside the rollout percentage.
+    OutsideRollout,
+}
+
+/// Errors that can stop a tier from storing a value.
+pub trait RejectionCause {
+    fn rejection_reason(&self) -> RejectionReason;
+}
+
+impl RejectionCause for InMemoryCacheError {
+    fn rejection_reason(&self) -> RejectionReason {
+        match self {
+            InMemoryCacheError::EmptyKey => RejectionReason::EmptyKey,
+            InMemoryCacheError::NotACounter | InMemoryCacheError::CounterOverflow => {
+                RejectionReason::WriteFailed
+            }
+        }
+    }
+}
+
+impl RejectionCause for KvError {
+    fn rejection_reason(&self) -> RejectionReason {
+        RejectionReason::WriteFailed
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub key: String,
+    pub tier: Tier,
+    pub reason: RejectionReason,
+    pub timestamp: u64,
+}
+
+/// Bounded log of values that were resolved but not stored in a tier. The newest `capacity`
+/// rejections are kept; counts per reason cover every rejection since creation.
+pub struct AdmissionLog {
+    capacity: usize,
+    entries: VecDeque<Rejection>,
+    counts: HashMap<RejectionReason, u64>,
+}
+
+impl AdmissionLog {
+    pub fn new(capacity: usize) -> AdmissionLog {
+        AdmissionLog {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, rejection: Rejection) {
+        *self.counts.entry(rejection.reason).or_insert(0) += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(rejection);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Rejection> {
+        self.entries.iter()
+    }
+
+    pub fn count(&self, reason: RejectionReason) -> u64 {
+        self.counts.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.counts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection(key: &str) -> Rejection {
+        Rejection {
+            key: key.to_string(),
+            tier: Tier::Memory,
+            reason: RejectionReason::EmptyKey,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn it_should_keep_newest_entries_only() {
+        let mut log = AdmissionLog::new(2);
+        log.record(rejection("a"));
+        log.record(rejection("b"));
+        log.record(rejection("c"));
+        let keys: Vec<&str> = log.entries().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["b", "c"]);
+        assert_eq!(log.count(RejectionReason::EmptyKey), 3);
+        assert_eq!(log.count(RejectionReason::WriteFailed), 0);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// The first `depth` `:`-separated segments of `key`, or all of it if it has fewer.
+pub fn key_prefix(key: &str, depth: usize) -> &str {
+    match depth.checked_sub(1) {
+        Some(separators) => key
+            .match_indices(':')
+            .nth(separators)
+            .map_or(key, |(end, _)| &key[..end]),
+        None => "",
+    }
+}
+
+/// Usage of the keys sharing a prefix. Entries, bytes and TTLs describe the memory tier at
+/// the time of the report; hits and misses count lookups since the service was built or last
+/// reset, with a hit in either tier counting as a hit.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PrefixUsage {
+    pub entries: u64,
+    /// Size of the cached values.
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    ttl_total: Duration,
+}
+
+impl PrefixUsage {
+    /// `None` until a key with this prefix was looked up.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+
+    /// Mean TTL the entries were stored with, `None` without entries.
+    pub fn average_ttl(&self) -> Option<Duration> {
+        (self.entries > 0).then(|| self.ttl_total.div_f64(self.entries as f64))
+    }
+}
+
+/// Key space usage grouped by key prefix, without the namespace.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeySpaceReport {
+    /// Number of key segments making up a prefix.
+    pub depth: usize,
+    pub prefixes: BTreeMap<String, PrefixUsage>,
+}
+
+impl KeySpaceReport {
+    /// The `count` prefixes holding the most bytes, largest first.
+    pub fn largest(&self, count: usize) -> Vec<(&str, &PrefixUsage)> {
+        let mut prefixes: Vec<(&str, &PrefixUsage)> = self
+            .prefixes
+            .iter()
+            .map(|(prefix, usage)| (prefix.as_str(), usage))
+            .collect();
+        prefixes.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.bytes));
+        prefixes.truncate(count);
+        prefixes
+    }
+}
+
+/// Hits and misses per key prefix. Holds one counter pair per distinct prefix, so `depth`
+/// should stop before segments holding ids.
+#[derive(Debug)]
+pub(crate) struct KeySpaceAnalytics {
+    depth: usize,
+    lookups: HashMap<String, (u64, u64)>,
+}
+
+impl KeySpaceAnalytics {
+    pub(crate) fn new(depth: usize) -> KeySpaceAnalytics {
+        KeySpaceAnalytics {
+            depth,
+            lookups: HashMap::new(),
+        }
+    }
+
+    /// Counts a lookup of `key`, given without the namespace.
+    pub(crate) fn record(&mut self, key: &str, hit: bool) {
+        let prefix = key_prefix(key, self.depth);
+        let (hits, misses) = match self.lookups.get_mut(prefix) {
+            Some(counts) => counts,
+            None => self.lookups.entry(prefix.to_string()).or_default(),
+        };
+        if hit {
+            *hits += 1;
+        } else {
+            *misses += 1;
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.lookups.clear();
+    }
+
+    /// Combines the lookup counts with `entries`, given as key without the namespace, value
+    /// size and TTL.
+    pub(crate) fn report<'a>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a str, usize, Duration)>,
+    ) -> KeySpaceReport {
+        let mut prefixes: BTreeMap<String, PrefixUsage> = BTreeMap::new();
+        for (key, bytes, ttl) in entries {
+            let usage = prefixes
+                .entry(key_prefix(key, self.depth).to_string())
+                .or_default();
+            usage.entries += 1;
+            usage.bytes += bytes as u64;
+            usage.ttl_total = usage.ttl_total.saturating_add(ttl);
+        }
+        for (prefix, (hits, misses)) in &self.lookups {
+            let usage = prefixes.entry(prefix.clone()).or_default();
+            usage.hits = *hits;
+            usage.misses = *misses;
+        }
+        KeySpaceReport {
+            depth: self.depth,
+            prefixes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_aggregate_usage_by_prefix() {
+        assert_eq!(key_prefix("user:42:profile", 2), "user:42");
+        assert_eq!(key_prefix("user", 2), "user");
+
+        let mut analytics = KeySpaceAnalytics::new(1);
+        analytics.record("user:1", true);
+        analytics.record("user:2", false);
+        analytics.record("session:1", false);
+        let report = analytics.report([
+            ("user:1", 10, Duration::from_secs(60)),
+            ("user:3", 30, Duration::from_secs(120)),
+        ]);
+
+        let user = &report.prefixes["user"];
+        assert_eq!((user.entries, user.bytes), (2, 40));
+        assert_eq!(user.hit_ratio(), Some(0.5));
+        assert_eq!(user.average_ttl(), Some(Duration::from_secs(90)));
+        let session = &report.prefixes["session"];
+        assert_eq!((session.entries, session.hit_ratio()), (0, Some(0.0)));
+        assert_eq!(report.largest(1)[0].0, "user");
+    }
+}
@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cache_service::server_config::ServerConfig;
+use cache_service::CacheService;
+use clap::Args;
+use rand::Rng;
+
+use crate::{build_cache, describe, CliResult};
+
+/// Load to generate with `rcache bench`.
+#[derive(Args)]
+pub struct BenchArgs {
+    /// Operations to run, spread over the workers.
+    #[arg(long, default_value_t = 100_000)]
+    requests: u64,
+    /// Distinct keys the operations go to.
+    #[arg(long, default_value_t = 10_000)]
+    keys: u64,
+    /// Size in bytes of the values written.
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+    /// Share of operations that are reads; the rest are writes.
+    #[arg(long, default_value_t = 0.8)]
+    read_ratio: f64,
+    /// Share of reads that go to keys written beforehand; the rest miss.
+    #[arg(long, default_value_t = 0.9)]
+    hit_ratio: f64,
+    /// Worker threads, each with its own connection, or its own cache with `--direct`.
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+}
+
+/// Where the load goes: the server at `bind`, or the library itself.
+enum Target {
+    Server(redis::Connection),
+    Direct(Box<CacheService>),
+}
+
+impl Target {
+    fn open(config: &ServerConfig, direct: bool) -> Result<Target, String> {
+        if direct {
+            return build_cache(config).map(|cache| Target::Direct(Box::new(cache)));
+        }
+        redis::Client::open(format!("redis://{}", config.bind))
+            .and_then(|client| client.get_connection())
+            .map(Target::Server)
+            .map_err(|err| format!("cannot connect to {}: {}", config.bind, err))
+    }
+
+    fn get(&mut self, key: &str) -> Result<(), String> {
+        match self {
+            Target::Server(con) => redis::cmd("GET")
+                .arg(key)
+                .query::<Option<Vec<u8>>>(con)
+                .map(drop)
+                .map_err(|err| err.to_string()),
+            Target::Direct(cache) => cache.get_bytes(key).map(drop).map_err(|err| describe(&err)),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &[u8], ttl: Duration) -> Result<(), String> {
+        match self {
+            Target::Server(con) => redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query::<()>(con)
+                .map_err(|err| err.to_string()),
+            Target::Direct(cache) => cache
+                .set_bytes(key, value, ttl)
+                .map_err(|err| describe(&err)),
+        }
+    }
+}
+
+/// Latencies and failures seen by one worker, or by all of them.
+#[derive(Default)]
+struct WorkerReport {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+/// Writes every key once, then runs the requested mix of reads and writes and prints the
+/// throughput and latency percentiles.
+pub fn run(args: &BenchArgs, config: &ServerConfig, direct: bool) -> CliResult {
+    let (WorkerReport { latencies, errors }, elapsed) = measure(args, config, direct)?;
+    println!(
+        "{} requests in {:.2?} ({:.0} ops/s), {} errors",
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        errors
+    );
+    if let Some(max) = latencies.last() {
+        let percentile =
+            |quantile: f64| latencies[((latencies.len() - 1) as f64 * quantile).round() as usize];
+        println!(
+            "latency p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, p99.9 {:.2?}, max {:.2?}",
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            percentile(0.999),
+            max
+        );
+    }
+    Ok(())
+}
+
+/// Runs the load and returns what the workers saw, latencies sorted, and how long it took.
+fn measure(
+    args: &BenchArgs,
+    config: &ServerConfig,
+    direct: bool,
+) -> Result<(WorkerReport, Duration), Box<dyn Error>> {
+    if !(0.0..=1.0).contains(&args.read_ratio) || !(0.0..=1.0).contains(&args.hit_ratio) {
+        return Err("--read-ratio and --hit-ratio must be between 0 and 1".into());
+    }
+    let keys = args.keys.max(1);
+    let value = vec![b'x'; args.value_size];
+    let ttl = Duration::from_secs(config.ttl);
+    let mut target = Target::open(config, direct)?;
+    for key in 0..keys {
+        target.set(&format!("bench:{}", key), &value, ttl)?;
+    }
+
+    let concurrency = args.concurrency.max(1);
+    let started = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|worker| {
+            let requests = args.requests / concurrency as u64
+                + u64::from((worker as u64) < args.requests % concurrency as u64);
+            let mut target = Target::open(config, direct)?;
+            let (read_ratio, hit_ratio) = (args.read_ratio, args.hit_ratio);
+            let value = value.clone();
+            Ok(thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut report = WorkerReport::default();
+                for _ in 0..requests {
+                    let key = rng.gen_range(0..keys);
+                    let op_started = Instant::now();
+                    let result = if !rng.gen_bool(read_ratio) {
+                        target.set(&format!("bench:{}", key), &value, ttl)
+                    } else if rng.gen_bool(hit_ratio) {
+                        target.get(&format!("bench:{}", key))
+                    } else {
+                        target.get(&format!("bench:missing:{}", key))
+                    };
+                    report.latencies.push(op_started.elapsed());
+                    report.errors += u64::from(result.is_err());
+                }
+                report
+            }))
+        })
+        .collect::<Result<_, String>>()?;
+
+    let mut latencies = Vec::with_capacity(args.requests as usize);
+    let mut errors = 0;
+    for worker in workers {
+        let report = worker.join().map_err(|_| "a worker panicked")?;
+        latencies.extend(report.latencies);
+        errors += report.errors;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+    Ok((WorkerReport { latencies, errors }, elapsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use cache_service::resp_server::RespServer;
+    use clap::Parser;
+
+    use super::*;
+    use crate::{Cli, Command};
+
+    fn tiny() -> BenchArgs {
+        BenchArgs {
+            requests: 20,
+            keys: 5,
+            value_size: 10,
+            read_ratio: 0.5,
+            hit_ratio: 0.5,
+            concurrency: 2,
+        }
+    }
+
+    #[test]
+    fn it_should_run_a_tiny_load_directly_and_on_the_server() {
+        let mut config = ServerConfig {
+            bind: "127.0.0.1:0".to_string(),
+            ..ServerConfig::default()
+        };
+        let cache = Arc::new(Mutex::new(build_cache(&config).unwrap()));
+        let server = RespServer::bind(&config.bind, cache, Duration::from_secs(60)).unwrap();
+        config.bind = server.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || server.serve());
+
+        let (direct, _) = measure(&tiny(), &config, true).unwrap();
+        let (served, _) = measure(&tiny(), &config, false).unwrap();
+        let invalid = measure(
+            &BenchArgs {
+                read_ratio: 1.5,
+                ..tiny()
+            },
+            &config,
+            true,
+        );
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+
+        for report in [direct, served] {
+            assert_eq!((report.latencies.len(), report.errors), (20, 0));
+            assert!(report.latencies.is_sorted());
+        }
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn it_should_parse_bench_arguments() {
+        let cli = Cli::try_parse_from(["rcache", "bench", "--requests", "10", "--read-ratio", "1"])
+            .unwrap();
+        let Command::Bench(args) = cli.command else {
+            panic!("not a bench command");
+        };
+        assert_eq!(
+            (args.requests, args.keys, args.read_ratio),
+            (10, 10_000, 1.0)
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cache_service::resp_server::{RespServer, ShutdownHandle};
+use cache_service::server_config::ServerConfig;
+use cache_service::{CacheService, CacheServiceError};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn, LevelFilter, Log, Metadata, Record};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+mod bench;
+
+/// Writes log records to stderr.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Two-tier cache over Redis: runs the server, or talks to a running one.
+#[derive(Parser)]
+#[command(name = "rcache", version)]
+struct Cli {
+    /// TOML config file; defaults to `RCACHE_CONFIG`. `RCACHE_*` variables override it.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Read and write Redis directly instead of going through the server at `bind`.
+    #[arg(long, global = true)]
+    direct: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serves the cache over the Redis protocol on `bind` until SIGINT or SIGTERM.
+    Serve,
+    /// Prints the value of KEY, or `(nil)`.
+    Get { key: String },
+    /// Stores VALUE under KEY.
+    Set {
+        key: String,
+        value: String,
+        /// Seconds to keep the value; defaults to the configured `ttl`.
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// Removes the keys and prints how many existed.
+    Del {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Prints the server's counters, or those of Redis with `--direct`.
+    Stats,
+    /// Prints where KEY is cached and when and by which instance it was written, or `(nil)`.
+    Entry { key: String },
+    /// Runs a synthetic load against the server, or the library with `--direct`, and prints
+    /// the throughput and latency percentiles.
+    Bench(bench::BenchArgs),
+    /// Copies the Redis keys matching PATTERN, written without rcache, into NAMESPACE with
+    /// their TTLs. The source keys are kept.
+    Import {
+        pattern: String,
+        #[arg(long)]
+        namespace: String,
+        /// Removed from the start of each key before it goes into the namespace.
+        #[arg(long, default_value = "")]
+        strip_prefix: String,
+    },
+}
+
+pub(crate) type CliResult = Result<(), Box<dyn Error>>;
+
+fn main() -> ExitCode {
+    let _ = log::set_logger(&StderrLogger);
+    log::set_max_level(LevelFilter::Info);
+    let cli = Cli::parse();
+    let path = cli
+        .config
+        .clone()
+        .or_else(|| std::env::var_os("RCACHE_CONFIG").map(PathBuf::from));
+    let config = match ServerConfig::load(path.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    log::set_max_level(config.log_level);
+
+    let mut stdout = io::stdout();
+    let result = match (&cli.command, cli.direct) {
+        (Command::Serve, _) => serve(&config),
+        (Command::Bench(args), direct) => bench::run(args, &config, direct),
+        (
+            Command::Import {
+                pattern,
+                namespace,
+                strip_prefix,
+            },
+            _,
+        ) => import(pattern, namespace, strip_prefix, &config),
+        (command, false) => run_on_server(command, &config, &mut stdout),
+        (command, true) => run_direct(command, &config, &mut stdout),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `err` followed by its sources, separated by colons.
+pub(crate) fn describe(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+pub(crate) fn build_cache(config: &ServerConfig) -> Result<CacheService, String> {
+    config
+        .cache_builder()
+        .build()
+        .map_err(|err| format!("cannot build the cache: {}", describe(&err)))
+}
+
+fn serve(config: &ServerConfig) -> CliResult {
+    let mut cache = build_cache(config)?;
+    if let Some(path) = config.snapshot_path.as_deref().filter(|path| path.exists()) {
+        match cache.load_memory_snapshot(path) {
+            Ok(loaded) => info!("loaded {} entries from {}", loaded, path.display()),
+            Err(err) => warn!("cannot load {}: {}", path.display(), err),
+        }
+    }
+    let cache = Arc::new(Mutex::new(cache));
+    let server = RespServer::bind(
+        &config.bind,
+        Arc::clone(&cache),
+        Duration::from_secs(config.ttl),
+    )
+    .map_err(|err| format!("cannot listen on {}: {}", config.bind, err))?;
+    stop_on_signal(server.shutdown_handle()?)?;
+    info!("listening on {}, caching {}", config.bind, config.redis_url);
+    server
+        .serve()
+        .map_err(|err| format!("stopped serving: {}", err))?;
+
+    info!("connections drained, flushing writes");
+    let mut cache = cache.lock().unwrap();
+    cache.shutdown();
+    if let Some(path) = &config.snapshot_path {
+        let saved = cache
+            .save_memory_snapshot(path)
+            .map_err(|err| format!("cannot save {}: {}", path.display(), err))?;
+        info!("saved {} entries to {}", saved, path.display());
+    }
+    Ok(())
+}
+
+fn import(pattern: &str, namespace: &str, strip_prefix: &str, config: &ServerConfig) -> CliResult {
+    let mut cache = config
+        .cache_builder()
+        .namespace(namespace)
+        .build()
+        .map_err(|err| format!("cannot build the cache: {}", describe(&err)))?;
+    let stats = cache
+        .import_keys(pattern, strip_prefix, |stats| {
+            info!(
+                "{} keys scanned, {} imported",
+                stats.scanned, stats.imported
+            )
+        })
+        .map_err(|err| format!("import failed: {}", describe(&err)))?;
+    println!(
+        "{} imported, {} already in {}, {} skipped",
+        stats.imported, stats.existing, namespace, stats.skipped
+    );
+    Ok(())
+}
+
+/// Shuts the server down gracefully on the first SIGINT or SIGTERM, and exits at once on
+/// the second.
+fn stop_on_signal(shutdown: ShutdownHandle) -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        let mut signals = signals.forever();
+        if signals.next().is_some() {
+            info!("shutting down");
+            shutdown.shutdown();
+        }
+        if signals.next().is_some() {
+            warn!("exiting without draining");
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+/// Runs `command` against the server at `bind` and writes what it prints to `out`.
+fn run_on_server(command: &Command, config: &ServerConfig, out: &mut dyn Write) -> CliResult {
+    let mut con = redis::Client::open(format!("redis://{}", config.bind))?.get_connection()?;
+    match command {
+        Command::Serve | Command::Bench(_) | Command::Import { .. } => {
+            unreachable!("handled by main")
+        }
+        Command::Get { key } => {
+            let value: Option<Vec<u8>> = redis::cmd("GET").arg(key).query(&mut con)?;
+            print_value(out, value.as_deref())
+        }
+        Command::Entry { key } => {
+            let info: Option<String> = redis::cmd("ENTRY").arg(key).query(&mut con)?;
+            print_entry(out, info)
+        }
+        Command::Set { key, value, ttl } => {
+            let mut set = redis::cmd("SET");
+            set.arg(key).arg(value);
+            if let Some(ttl) = ttl {
+                set.arg("EX").arg(ttl);
+            }
+            set.query::<()>(&mut con)?;
+            writeln!(out, "OK")?;
+            Ok(())
+        }
+        Command::Del { keys } => {
+            let deleted: u64 = redis::cmd("DEL").arg(keys).query(&mut con)?;
+            writeln!(out, "{}", deleted)?;
+            Ok(())
+        }
+        Command::Stats => {
+            let info: String = redis::cmd("INFO").query(&mut con)?;
+            write!(out, "{}", info)?;
+            Ok(())
+        }
+    }
+}
+
+/// Runs `command` on Redis through the library and writes what it prints to `out`.
+fn run_direct(command: &Command, config: &ServerConfig, out: &mut dyn Write) -> CliResult {
+    let cache_error = |err: CacheServiceError| describe(&err);
+    match command {
+        Command::Serve | Command::Bench(_) | Command::Import { .. } => {
+            unreachable!("handled by main")
+        }
+        Command::Get { key } => {
+            let value = build_cache(config)?.get_bytes(key).map_err(cache_error)?;
+            print_value(out, value.as_deref())
+        }
+        Command::Entry { key } => {
+            let info = build_cache(config)?.entry_info(key).map_err(cache_error)?;
+            print_entry(out, info.map(|info| info.to_string()))
+        }
+        Command::Set { key, value, ttl } => {
+            build_cache(config)?
+                .set_bytes(
+                    key,
+                    value.as_bytes(),
+                    Duration::from_secs(ttl.unwrap_or(config.ttl)),
+                )
+                .map_err(cache_error)?;
+            writeln!(out, "OK")?;
+            Ok(())
+        }
+        Command::Del { keys } => {
+            let mut cache = build_cache(config)?;
+            let mut deleted = 0;
+            for key in keys {
+                if cache.contains(key).map_err(cache_error)? {
+                    deleted += 1;
+                }
+                cache.invalidate(key).map_err(cache_error)?;
+            }
+            writeln!(out, "{}", deleted)?;
+            Ok(())
+        }
+        Command::Stats => {
+            let mut con = redis::Client::open(config.redis_url.as_str())?.get_connection()?;
+            let info: String = redis::cmd("INFO").arg("stats").query(&mut con)?;
+            write!(out, "{}", info)?;
+            Ok(())
+        }
+    }
+}
+
+fn print_entry(out: &mut dyn Write, info: Option<String>) -> CliResult {
+    match info {
+        Some(info) => write!(out, "{}", info)?,
+        None => writeln!(out, "(nil)")?,
+    }
+    Ok(())
+}
+
+fn print_value(out: &mut dyn Write, value: Option<&[u8]>) -> CliResult {
+    match value {
+        Some(value) => {
+            out.write_all(value)?;
+            out.write_all(b"\n")?;
+        }
+        None => writeln!(out, "(nil)")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["rcache"], args].concat())
+    }
+
+    #[test]
+    fn it_should_parse_subcommands() {
+        Cli::command().debug_assert();
+
+        let set = parse(&["set", "key", "value", "--ttl", "5", "--direct"]).unwrap();
+        assert!(set.direct);
+        assert!(matches!(
+            set.command,
+            Command::Set { key, value, ttl: Some(5) } if key == "key" && value == "value"
+        ));
+        let del = parse(&["--config", "rcache.toml", "del", "a", "b"]).unwrap();
+        assert_eq!(del.config, Some(PathBuf::from("rcache.toml")));
+        assert!(matches!(del.command, Command::Del { keys } if keys == ["a", "b"]));
+        let import = parse(&["import", "legacy:*", "--namespace", "app"]).unwrap();
+        assert!(matches!(
+            import.command,
+            Command::Import { pattern, namespace, strip_prefix }
+                if pattern == "legacy:*" && namespace == "app" && strip_prefix.is_empty()
+        ));
+
+        assert!(parse(&["del"]).is_err());
+        assert!(parse(&["set", "key"]).is_err());
+        assert!(parse(&["set", "key", "value", "--ttl", "soon"]).is_err());
+        assert!(parse(&["import", "legacy:*"]).is_err());
+        assert!(parse(&["flush"]).is_err());
+    }
+
+    #[test]
+    fn it_should_get_and_set_through_the_server_and_directly() {
+        let mut config = ServerConfig {
+            bind: "127.0.0.1:0".to_string(),
+            ..ServerConfig::default()
+        };
+        let cache = Arc::new(Mutex::new(build_cache(&config).unwrap()));
+        let server = RespServer::bind(&config.bind, cache, Duration::from_secs(60)).unwrap();
+        config.bind = server.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = std::thread::spawn(move || server.serve());
+        let run = |command: Command, direct: bool| {
+            let mut out = Vec::new();
+            if direct {
+                run_direct(&command, &config, &mut out).unwrap();
+            } else {
+                run_on_server(&command, &config, &mut out).unwrap();
+            }
+            String::from_utf8(out).unwrap()
+        };
+        let key = || "rcache_cli:key".to_string();
+
+        run(Command::Del { keys: vec![key()] }, false);
+        let set = run(
+            Command::Set {
+                key: key(),
+                value: "value".to_string(),
+                ttl: Some(10),
+            },
+            false,
+        );
+        let got = run(Command::Get { key: key() }, false);
+        let got_directly = run(Command::Get { key: key() }, true);
+        let deleted = run(Command::Del { keys: vec![key()] }, true);
+        let missing = run(Command::Get { key: key() }, true);
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+
+        assert_eq!(set, "OK\n");
+        assert_eq!(got, "value\n");
+        assert_eq!(got_directly, "value\n");
+        assert_eq!(deleted, "1\n");
+        assert_eq!(missing, "(nil)\n");
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Keys the filter is sized for beyond those found by a rebuild, so writes until the next
+/// rebuild don't push the false-positive rate far above the configured one.
+const MIN_HEADROOM: usize = 1024;
+
+/// Bloom filter over the keys stored in the KV tier, rebuilt from a scan every `rebuild`
+/// and following this instance's own writes in between. A key the filter has not seen was
+/// never written, so looking it up in Redis can be skipped; a key written by another
+/// instance since the last rebuild reads as a miss. Deleted keys stay in the filter until
+/// the next rebuild and only cost the lookups it would have saved.
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    false_positive_rate: f64,
+    rebuild: Duration,
+    bits: Vec<u64>,
+    hashes: u32,
+    built_at: Option<Instant>,
+}
+
+impl BloomFilter {
+    pub(crate) fn new(false_positive_rate: f64, rebuild: Duration) -> BloomFilter {
+        BloomFilter {
+            false_positive_rate,
+            rebuild,
+            bits: Vec::new(),
+            hashes: 0,
+            built_at: None,
+        }
+    }
+
+    pub(crate) fn needs_rebuild(&self, now: Instant) -> bool {
+        self.built_at
+            .is_none_or(|built_at| now.duration_since(built_at) >= self.rebuild)
+    }
+
+    /// Replaces the filter with one holding `keys`, sized for twice as many.
+    pub(crate) fn rebuild(&mut self, keys: &[String], now: Instant) {
+        let capacity = (keys.len() * 2).max(MIN_HEADROOM) as f64;
+        let bits = (-capacity * self.false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        self.bits = vec![0; bits.div_ceil(64)];
+        self.hashes = ((bits as f64 / capacity * LN_2).round() as u32).max(1);
+        self.built_at = Some(now);
+        for key in keys {
+            self.insert(key);
+        }
+    }
+
+    /// Bit positions of `key`, by double hashing.
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (first, second) = (hash & u32::MAX as u64, (hash >> 32) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+
+    /// False only for keys known to be absent. Everything may exist before the first build.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.built_at.is_none()
+            || self
+                .positions(key)
+                .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        if self.built_at.is_none() {
+            return;
+        }
+        let positions: Vec<usize> = self.positions(key).collect();
+        for bit in positions {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_rule_out_unwritten_keys_at_about_the_configured_rate() {
+        let mut filter = BloomFilter::new(0.01, Duration::from_secs(5));
+        let now = Instant::now();
+        assert!(filter.needs_rebuild(now));
+        assert!(filter.may_contain("a"));
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("key:{}", i)).collect();
+        filter.rebuild(&keys, now);
+        assert!(!filter.needs_rebuild(now + Duration::from_secs(4)));
+        assert!(filter.needs_rebuild(now + Duration::from_secs(5)));
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(&format!("other:{}", i)))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        filter.insert("new");
+        assert!(filter.may_contain("new"));
+    }
+}
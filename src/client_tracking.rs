@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use redis::Connection;
+
+use crate::in_memory_cache::InMemoryCache;
+use crate::kv_cache::KvError;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Channel Redis publishes client tracking invalidations on for connections using RESP2.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Receives the invalidation messages of Redis client tracking on a background thread and
+/// evicts the memory-tier copies of the keys they name; a flush of the database clears the
+/// memory tier. The KV connection turns tracking on with `KvCache::set_client_tracking`,
+/// redirecting the messages to this listener's `client_id`. The redirect takes the place
+/// of RESP3 push messages, which the Redis client in use does not support.
+pub struct TrackingListener {
+    client_id: i64,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TrackingListener {
+    /// Takes over `con` as a dedicated pub/sub connection.
+    pub fn new(
+        mut con: Connection,
+        in_memory_cache: InMemoryCache,
+    ) -> Result<TrackingListener, KvError> {
+        let client_id: i64 = redis::cmd("CLIENT")
+            .arg("ID")
+            .query(&mut con)
+            .map_err(KvError::CommandFailed)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let (ready_sender, ready_receiver) = mpsc::sync_channel(1);
+
+        let handle = thread::spawn(move || {
+            let mut cache = in_memory_cache;
+            let mut pubsub = con.as_pubsub();
+            let subscribed = pubsub
+                .subscribe(INVALIDATE_CHANNEL)
+                .and_then(|_| pubsub.set_read_timeout(Some(POLL_INTERVAL)));
+            let _ = ready_sender.send(subscribed.map_err(KvError::CommandFailed));
+
+            while !thread_stop.load(Ordering::Relaxed) {
+                let message = match pubsub.get_message() {
+                    Ok(message) => message,
+                    Err(err) if err.is_timeout() => continue,
+                    Err(_) => break,
+                };
+                match message.get_payload::<Option<Vec<String>>>() {
+                    Ok(Some(keys)) => {
+                        for key in keys {
+                            cache.remove(&key);
+                        }
+                    }
+                    Ok(None) => {
+                        cache.remove_prefix("");
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+
+        ready_receiver
+            .recv()
+            .map_err(|_| KvError::ConnectionNotEstablished)??;
+
+        Ok(TrackingListener {
+            client_id,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// The client ID of the listening connection, to redirect invalidations to.
+    pub fn client_id(&self) -> i64 {
+        self.client_id
+    }
+}
+
+impl Drop for TrackingListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
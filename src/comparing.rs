@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use redis::{ConnectionLike, RedisResult, Value};
+
+/// What a backend answered: its reply, or the error message.
+pub type Outcome = Result<Value, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Name of the command, or "PIPELINE" for a batch.
+    pub command: String,
+    pub primary: Outcome,
+    pub secondary: Outcome,
+    pub primary_latency: Duration,
+    pub secondary_latency: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ComparisonStats {
+    pub commands: u64,
+    pub divergences: u64,
+    pub primary_latency: Duration,
+    pub secondary_latency: Duration,
+}
+
+#[derive(Default)]
+struct ComparisonLog {
+    capacity: usize,
+    divergences: VecDeque<Divergence>,
+    stats: ComparisonStats,
+}
+
+/// Read access to what a `ComparingBackend` recorded, usable after the backend was moved into
+/// a `KvCache` or `CacheService`.
+#[derive(Clone)]
+pub struct ComparisonReport {
+    log: Arc<Mutex<ComparisonLog>>,
+}
+
+impl ComparisonReport {
+    pub fn stats(&self) -> ComparisonStats {
+        self.log.lock().unwrap().stats
+    }
+
+    /// The newest divergences, oldest first.
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.log
+            .lock()
+            .unwrap()
+            .divergences
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Sends every command to both `primary` and `secondary` and answers from the primary. Replies
+/// and latencies are compared, and the newest `capacity` divergences are kept, so a new backend
+/// can shadow the current one before a migration.
+pub struct ComparingBackend<P: ConnectionLike, S: ConnectionLike> {
+    primary: P,
+    secondary: S,
+    log: Arc<Mutex<ComparisonLog>>,
+}
+
+impl<P: ConnectionLike, S: ConnectionLike> ComparingBackend<P, S> {
+    pub fn new(primary: P, secondary: S, capacity: usize) -> ComparingBackend<P, S> {
+        ComparingBackend {
+            primary,
+            secondary,
+            log: Arc::new(Mutex::new(ComparisonLog {
+                capacity,
+                ..ComparisonLog::default()
+            })),
+        }
+    }
+
+    pub fn report(&self) -> ComparisonReport {
+        ComparisonReport {
+            log: Arc::clone(&self.log),
+        }
+    }
+
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+
+    fn record(&self, command: impl FnOnce() -> String, primary: Timed, secondary: Timed) {
+        let mut log = self.log.lock().unwrap();
+        log.stats.commands += 1;
+        log.stats.primary_latency += primary.1;
+        log.stats.secondary_latency += secondary.1;
+        if primary.0 == secondary.0 {
+            return;
+        }
+        log.stats.divergences += 1;
+        if log.capacity == 0 {
+            return;
+        }
+        if log.divergences.len() == log.capacity {
+            log.divergences.pop_front();
+        }
+        log.divergences.push_back(Divergence {
+            command: command(),
+            primary: primary.0,
+            secondary: secondary.0,
+            primary_latency: primary.1,
+            secondary_latency: secondary.1,
+        });
+    }
+}
+
+type Timed = (Outcome, Duration);
+
+fn timed<T>(request: impl FnOnce() -> RedisResult<T>) -> (RedisResult<T>, Duration) {
+    let started = Instant::now();
+    let result = request();
+    (result, started.elapsed())
+}
+
+fn outcome<T>(result: &RedisResult<T>, reply: impl FnOnce(&T) -> Value) -> Outcome {
+    result.as_ref().map(reply).map_err(|err| err.to_string())
+}
+
+/// Reads the command name out of a RESP-encoded command.
+fn command_name(packed: &[u8]) -> String {
+    packed
+        .split(|&byte| byte == b'\n')
+        .nth(2)
+        .map(|name| String::from_utf8_lossy(name.trim_ascii_end()).to_uppercase())
+        .unwrap_or_default()
+}
+
+impl<P: ConnectionLike, S: ConnectionLike> ConnectionLike for ComparingBackend<P, S> {
+    /// The primary's reply is returned unchanged, including its error.
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let (primary, primary_latency) = timed(|| self.primary.req_packed_command(cmd));
+        let (secondary, secondary_latency) = timed(|| self.secondary.req_packed_command(cmd));
+        self.record(
+            || command_name(cmd),
+            (outcome(&primary, Value::clone), primary_latency),
+            (outcome(&secondary, Value::clone), secondary_latency),
+        );
+        primary
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let (primary, primary_latency) =
+            timed(|| self.primary.req_packed_commands(cmd, offset, count));
+        let (secondary, secondary_latency) =
+            timed(|| self.secondary.req_packed_commands(cmd, offset, count));
+        let bulk = |values: &Vec<Value>| Value::Bulk(values.clone());
+        self.record(
+            || "PIPELINE".to_string(),
+            (outcome(&primary, bulk), primary_latency),
+            (outcome(&secondary, bulk), secondary_latency),
+        );
+        primary
+    }
+
+    fn get_db(&self) -> i64 {
+        self.primary.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.primary.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.primary.is_open()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    use super::*;
+
+    #[test]
+    fn it_should_serve_from_primary_and_log_divergence() {
+        let primary = MockRedisConnection::new(vec![
+            MockCmd::new(redis::cmd("GET").arg("key"), Ok("old")),
+            MockCmd::new(redis::cmd("GET").arg("same"), Ok("value")),
+        ]);
+        let secondary = MockRedisConnection::new(vec![
+            MockCmd::new(redis::cmd("GET").arg("key"), Ok("new")),
+            MockCmd::new(redis::cmd("GET").arg("same"), Ok("value")),
+        ]);
+        let mut backend = ComparingBackend::new(primary, secondary, 10);
+        let report = backend.report();
+
+        let value: String = redis::cmd("GET").arg("key").query(&mut backend).unwrap();
+        assert_eq!(value, "old");
+        let value: String = redis::cmd("GET").arg("same").query(&mut backend).unwrap();
+        assert_eq!(value, "value");
+
+        let stats = report.stats();
+        assert_eq!(stats.commands, 2);
+        assert_eq!(stats.divergences, 1);
+        let divergences = report.divergences();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].command, "GET");
+        assert_eq!(divergences[0].primary, Ok(Value::Data(b"old".to_vec())));
+        assert_eq!(divergences[0].secondary, Ok(Value::Data(b"new".to_vec())));
+    }
+}
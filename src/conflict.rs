@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+/// Combines the value already stored under a key with a newly computed one.
+pub type MergeFn = Arc<dyn Fn(&[u8], &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Decides what is kept when a value is written over an existing one, e.g. when two instances
+/// recompute the same key at the same time.
+#[derive(Clone, Default)]
+pub enum ConflictPolicy {
+    /// Last write wins.
+    #[default]
+    KeepNewest,
+    /// The first stored value wins until it expires.
+    KeepOldest,
+    /// `merge(existing, incoming)` is stored. On Redis the read-modify-write is retried until
+    /// no other writer changed the value in between.
+    Merge(MergeFn),
+}
+
+impl ConflictPolicy {
+    pub fn merge<F>(merge: F) -> ConflictPolicy
+    where
+        F: Fn(&[u8], &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        ConflictPolicy::Merge(Arc::new(merge))
+    }
+
+    pub fn apply(&self, existing: Option<&[u8]>, incoming: &[u8]) -> Vec<u8> {
+        match (self, existing) {
+            (ConflictPolicy::KeepOldest, Some(existing)) => existing.to_vec(),
+            (ConflictPolicy::Merge(merge), Some(existing)) => merge(existing, incoming),
+            _ => incoming.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_apply_policy_to_existing_value() {
+        let merge = ConflictPolicy::merge(|existing, incoming| [existing, b",", incoming].concat());
+        assert_eq!(
+            ConflictPolicy::KeepNewest.apply(Some(b"old"), b"new"),
+            b"new"
+        );
+        assert_eq!(
+            ConflictPolicy::KeepOldest.apply(Some(b"old"), b"new"),
+            b"old"
+        );
+        assert_eq!(merge.apply(Some(b"old"), b"new"), b"old,new");
+        assert_eq!(merge.apply(None, b"new"), b"new");
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Local copy of the Redis set listing the keys stored in the KV tier by any instance. A key
+/// missing from the copy is known to be absent from Redis, so looking it up there can be
+/// skipped. The copy is reloaded every `refresh` and follows this instance's own writes in
+/// between; a key written by another instance since the last reload reads as a miss.
+#[derive(Debug)]
+pub(crate) struct KeyDirectory {
+    refresh: Duration,
+    members: HashSet<String>,
+    loaded_at: Option<Instant>,
+}
+
+impl KeyDirectory {
+    pub(crate) fn new(refresh: Duration) -> KeyDirectory {
+        KeyDirectory {
+            refresh,
+            members: HashSet::new(),
+            loaded_at: None,
+        }
+    }
+
+    pub(crate) fn needs_reload(&self, now: Instant) -> bool {
+        self.loaded_at
+            .is_none_or(|loaded_at| now.duration_since(loaded_at) >= self.refresh)
+    }
+
+    pub(crate) fn load(&mut self, members: HashSet<String>, now: Instant) {
+        self.members = members;
+        self.loaded_at = Some(now);
+    }
+
+    /// False only for keys known to be absent. Everything may exist before the first load.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.loaded_at.is_none() || self.members.contains(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        if self.loaded_at.is_some() && !self.members.contains(key) {
+            self.members.insert(key.to_string());
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.members.remove(key);
+    }
+
+    pub(crate) fn remove_prefix(&mut self, prefix: &str) {
+        self.members.retain(|key| !key.starts_with(prefix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_rule_out_only_unlisted_keys_once_loaded() {
+        let mut directory = KeyDirectory::new(Duration::from_secs(5));
+        let now = Instant::now();
+        assert!(directory.needs_reload(now));
+        assert!(directory.may_contain("a"));
+
+        directory.load(HashSet::from(["a".to_string()]), now);
+        assert!(!directory.needs_reload(now + Duration::from_secs(4)));
+        assert!(directory.needs_reload(now + Duration::from_secs(5)));
+        assert!(directory.may_contain("a"));
+        assert!(!directory.may_contain("b"));
+
+        directory.insert("b");
+        directory.remove("a");
+        assert!(directory.may_contain("b"));
+        assert!(!directory.may_contain("a"));
+    }
+}
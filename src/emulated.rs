@@ -0,0 +1,90 @@
+//! Runs packed Redis commands against backends that only emulate Redis, so `KvCache` can talk
+//! to them through `ConnectionLike`.
+
+use redis::{ErrorKind, RedisError, RedisResult, Value};
+
+/// Runs the single command packed in `cmd`.
+pub(crate) fn run_command(
+    cmd: &[u8],
+    mut execute: impl FnMut(&[Vec<u8>]) -> RedisResult<Value>,
+) -> RedisResult<Value> {
+    match unpack(cmd)?.as_slice() {
+        [command] => execute(command),
+        _ => Err(malformed()),
+    }
+}
+
+/// Runs a packed pipeline and returns `count` replies from `offset`. Commands between MULTI
+/// and EXEC run one by one as they come; the emulated backends have no transactions.
+pub(crate) fn run_pipeline(
+    cmd: &[u8],
+    offset: usize,
+    count: usize,
+    mut execute: impl FnMut(&[Vec<u8>]) -> RedisResult<Value>,
+) -> RedisResult<Vec<Value>> {
+    let mut replies = Vec::new();
+    let mut transaction: Option<Vec<Value>> = None;
+    for command in unpack(cmd)? {
+        let name = command.first().map(|name| name.to_ascii_uppercase());
+        match (name.as_deref(), transaction.as_mut()) {
+            (Some(b"MULTI"), _) => {
+                transaction = Some(Vec::new());
+                replies.push(Value::Okay);
+            }
+            (Some(b"EXEC"), _) => {
+                replies.push(Value::Bulk(transaction.take().unwrap_or_default()));
+            }
+            (_, Some(queued)) => {
+                queued.push(execute(&command)?);
+                replies.push(Value::Status("QUEUED".to_string()));
+            }
+            (_, None) => replies.push(execute(&command)?),
+        }
+    }
+    Ok(replies.into_iter().skip(offset).take(count).collect())
+}
+
+pub(crate) fn number(arg: &[u8]) -> RedisResult<u64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(malformed)
+}
+
+pub(crate) fn malformed() -> RedisError {
+    RedisError::from((ErrorKind::ClientError, "Malformed command"))
+}
+
+pub(crate) fn unsupported(backend: &'static str, command: String) -> RedisError {
+    RedisError::from((ErrorKind::ClientError, backend, command))
+}
+
+/// Splits packed RESP commands into their arguments.
+fn unpack(mut packed: &[u8]) -> RedisResult<Vec<Vec<Vec<u8>>>> {
+    let mut commands = Vec::new();
+    while !packed.is_empty() {
+        let (count, rest) = header(packed, b'*')?;
+        packed = rest;
+        let mut command = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (len, rest) = header(packed, b'$')?;
+            command.push(rest.get(..len).ok_or_else(malformed)?.to_vec());
+            packed = rest.get(len + 2..).ok_or_else(malformed)?;
+        }
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+/// Parses a `<prefix><number>\r\n` line, returning the number and what follows.
+fn header(packed: &[u8], prefix: u8) -> RedisResult<(usize, &[u8])> {
+    let end = packed
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .ok_or_else(malformed)?;
+    if packed.first() != Some(&prefix) {
+        return Err(malformed());
+    }
+    let number = number(&packed[1..end])? as usize;
+    Ok((number, &packed[end + 2..]))
+}
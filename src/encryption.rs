@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use bytes::Bytes;
+
+const MAGIC: u8 = 0xcf;
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+/// An AES-256 key.
+pub type KeyBytes = [u8; 32];
+
+/// Hands out the keys values in the KV tier are encrypted with. New values use the current
+/// key; older ones name theirs by ID, so a rotated out key stays readable as long as the
+/// provider still knows it.
+pub trait KeyProvider: Send + Sync {
+    /// The ID and key new values are encrypted with. IDs are 1 to 255 bytes long.
+    fn current(&self) -> (String, KeyBytes);
+
+    fn key(&self, id: &str) -> Option<KeyBytes>;
+}
+
+/// Keys held in memory: one current key and any number of retired ones kept for reading.
+#[derive(Clone)]
+pub struct StaticKeys {
+    current: String,
+    keys: HashMap<String, KeyBytes>,
+}
+
+impl StaticKeys {
+    pub fn new(id: &str, key: KeyBytes) -> StaticKeys {
+        StaticKeys {
+            current: id.to_string(),
+            keys: HashMap::from([(id.to_string(), key)]),
+        }
+    }
+
+    /// Keeps values encrypted with the retired key `id` readable.
+    pub fn with_retired(mut self, id: &str, key: KeyBytes) -> StaticKeys {
+        self.keys.entry(id.to_string()).or_insert(key);
+        self
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current(&self) -> (String, KeyBytes) {
+        (self.current.clone(), self.keys[&self.current])
+    }
+
+    fn key(&self, id: &str) -> Option<KeyBytes> {
+        self.keys.get(id).copied()
+    }
+}
+
+/// Encrypts sealed envelopes with AES-256-GCM before they are written to the KV tier. An
+/// encrypted value is a header with the key ID and a random nonce, followed by the
+/// ciphertext. The header is authenticated along with it, and so is a context that is not
+/// stored: the KV tier passes the Redis key, so a value copied to another key can't be
+/// decrypted there.
+#[derive(Clone)]
+pub struct Encryption {
+    keys: Arc<dyn KeyProvider>,
+}
+
+impl Encryption {
+    pub fn new(keys: impl KeyProvider + 'static) -> Encryption {
+        Encryption {
+            keys: Arc::new(keys),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let (id, _) = self.keys.current();
+        !id.is_empty() && id.len() <= u8::MAX as usize
+    }
+
+    pub fn encrypt(&self, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let (id, key) = self.keys.current();
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut raw = Vec::with_capacity(3 + id.len() + NONCE_LEN + plaintext.len() + 16);
+        raw.extend_from_slice(&[MAGIC, VERSION, id.len() as u8]);
+        raw.extend_from_slice(id.as_bytes());
+        raw.extend_from_slice(&nonce);
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &[&raw[..], context].concat(),
+                },
+            )
+            .expect("AES-GCM encrypts any payload that fits in memory");
+        raw.extend_from_slice(&ciphertext);
+        raw
+    }
+
+    /// The plaintext of a value encrypted with `context`, or `None` if its key is unknown,
+    /// the context differs or it was tampered with.
+    pub fn decrypt(&self, context: &[u8], raw: &[u8]) -> Option<Bytes> {
+        let (&id_len, rest) = raw.get(2..)?.split_first()?;
+        let id = std::str::from_utf8(rest.get(..usize::from(id_len))?).ok()?;
+        let header_len = 3 + usize::from(id_len) + NONCE_LEN;
+        let nonce = raw.get(header_len - NONCE_LEN..header_len)?;
+        let key = self.keys.key(id)?;
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: &raw[header_len..],
+                    aad: &[&raw[..header_len], context].concat(),
+                },
+            )
+            .ok()?;
+        Some(Bytes::from(plaintext))
+    }
+}
+
+/// Whether `raw` was written by `Encryption::encrypt`, and so can't be read without a key.
+pub fn is_encrypted(raw: &[u8]) -> bool {
+    raw.starts_with(&[MAGIC, VERSION])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_decrypt_with_rotated_keys_and_reject_tampering() {
+        let old = Encryption::new(StaticKeys::new("k1", [1; 32]));
+        let rotated = Encryption::new(StaticKeys::new("k2", [2; 32]).with_retired("k1", [1; 32]));
+        let written = old.encrypt(b"key", b"secret");
+
+        assert!(is_encrypted(&written));
+        assert!(!written.windows(6).any(|window| window == b"secret"));
+        assert_eq!(
+            rotated.decrypt(b"key", &written).as_deref(),
+            Some(&b"secret"[..])
+        );
+        assert_eq!(
+            old.decrypt(b"key", &rotated.encrypt(b"key", b"secret")),
+            None,
+            "k2 is unknown to the old keys"
+        );
+        assert_eq!(rotated.decrypt(b"other", &written), None);
+
+        let mut tampered = written.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(rotated.decrypt(b"key", &tampered), None);
+        let mut relabeled = written;
+        relabeled[4] = b'2';
+        assert_eq!(rotated.decrypt(b"key", &relabeled), None);
+    }
+}
@@ -0,0 +1,343 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+const MAGIC: u8 = 0xce;
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 11;
+/// Version 3 adds the writer after the creation time, as a length-prefixed instance ID and
+/// label. Values without a writer are still written as version 2.
+const V3: u8 = 3;
+/// Version 1 had no codec byte.
+const V1: u8 = 1;
+const V1_HEADER_LEN: usize = 10;
+const UNCOMPRESSED: u8 = 0;
+
+/// A value as stored in Redis: a header with the codec, the time the value was created and
+/// optionally who wrote it, followed by the payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// Unix seconds when the value was first stored.
+    pub created: u64,
+    pub writer: Option<Provenance>,
+    pub payload: Bytes,
+}
+
+/// Identifies the writer of a value: the instance, and optionally the code path on it. Both
+/// are at most 255 bytes long.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub instance: String,
+    pub label: Option<String>,
+}
+
+impl Provenance {
+    pub fn is_valid(&self) -> bool {
+        let fits = |text: &str| !text.is_empty() && text.len() <= u8::MAX as usize;
+        fits(&self.instance) && self.label.as_deref().is_none_or(fits)
+    }
+}
+
+/// What a raw Redis value turned out to be.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoded {
+    Current(Envelope),
+    /// A plain value written before envelopes existed. The whole value is the payload.
+    Legacy(Bytes),
+    /// An envelope of a version or codec this build cannot read, or a corrupt payload.
+    Unreadable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Zstd => 1,
+            Codec::Gzip => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Gzip),
+            _ => None,
+        }
+    }
+
+    fn compress(self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::encode_all(payload, 0),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decompress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::decode_all(body),
+            Codec::Gzip => {
+                let mut payload = Vec::new();
+                GzDecoder::new(body).read_to_end(&mut payload)?;
+                Ok(payload)
+            }
+        }
+    }
+}
+
+/// Compresses payloads of at least `threshold` bytes, keeping the result only when it is
+/// actually smaller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub threshold: usize,
+}
+
+impl Envelope {
+    pub fn encode(created: u64, payload: &[u8]) -> Vec<u8> {
+        Envelope::encode_with(created, payload, None)
+    }
+
+    pub fn encode_with(created: u64, payload: &[u8], compression: Option<Compression>) -> Vec<u8> {
+        Envelope::encode_by(created, payload, compression, None)
+    }
+
+    /// Same as `encode_with`, recording `writer` in the header. `writer` must be valid.
+    pub fn encode_by(
+        created: u64,
+        payload: &[u8],
+        compression: Option<Compression>,
+        writer: Option<&Provenance>,
+    ) -> Vec<u8> {
+        let compressed = compression
+            .filter(|compression| payload.len() >= compression.threshold)
+            .and_then(|compression| {
+                let body = compression.codec.compress(payload).ok()?;
+                (body.len() < payload.len()).then_some((compression.codec.id(), body))
+            });
+        let (codec, body) = match &compressed {
+            Some((codec, body)) => (*codec, &body[..]),
+            None => (UNCOMPRESSED, payload),
+        };
+
+        let mut raw = Vec::with_capacity(HEADER_LEN + body.len());
+        raw.push(MAGIC);
+        raw.push(if writer.is_some() { V3 } else { VERSION });
+        raw.push(codec);
+        raw.extend_from_slice(&created.to_be_bytes());
+        if let Some(writer) = writer {
+            for field in [&writer.instance[..], writer.label.as_deref().unwrap_or("")] {
+                raw.push(field.len() as u8);
+                raw.extend_from_slice(field.as_bytes());
+            }
+        }
+        raw.extend_from_slice(body);
+        raw
+    }
+
+    pub fn decode(raw: Bytes) -> Decoded {
+        let header_len = match raw.get(1) {
+            Some(&V1) => V1_HEADER_LEN,
+            _ => HEADER_LEN,
+        };
+        if raw.len() < header_len || raw[0] != MAGIC {
+            return Decoded::Legacy(raw);
+        }
+        let created_at = header_len - 8;
+        let mut created = [0; 8];
+        created.copy_from_slice(&raw[created_at..header_len]);
+        let created = u64::from_be_bytes(created);
+        let (writer, header_len) = match raw[1] {
+            V3 => match read_writer(&raw, header_len) {
+                Some(read) => read,
+                None => return Decoded::Unreadable,
+            },
+            _ => (None, header_len),
+        };
+        let body = raw.slice(header_len..);
+
+        let payload = match (raw[1], raw[2]) {
+            (V1, _) | (VERSION | V3, UNCOMPRESSED) => body,
+            (VERSION | V3, codec) => {
+                match Codec::from_id(codec).and_then(|codec| codec.decompress(&body).ok()) {
+                    Some(payload) => Bytes::from(payload),
+                    None => return Decoded::Unreadable,
+                }
+            }
+            _ => return Decoded::Unreadable,
+        };
+        Decoded::Current(Envelope {
+            created,
+            writer,
+            payload,
+        })
+    }
+}
+
+/// Reads the writer starting at `at`, returning it and where the body starts.
+fn read_writer(raw: &[u8], at: usize) -> Option<(Option<Provenance>, usize)> {
+    let mut fields = Vec::with_capacity(2);
+    let mut at = at;
+    for _ in 0..2 {
+        let len = usize::from(*raw.get(at)?);
+        let field = raw.get(at + 1..at + 1 + len)?;
+        fields.push(String::from_utf8(field.to_vec()).ok()?);
+        at += 1 + len;
+    }
+    let label = fields.pop().filter(|label| !label.is_empty());
+    let instance = fields.pop()?;
+    Some((Some(Provenance { instance, label }), at))
+}
+
+/// What to do with plain values left over from before envelopes. Unreadable envelopes are
+/// always discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LegacyValuePolicy {
+    /// Rewrite the value inside an envelope, keeping its TTL. Its age starts at conversion.
+    #[default]
+    Convert,
+    Discard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MigrationStats {
+    pub converted: u64,
+    pub discarded: u64,
+}
+
+/// Progress of copying keys stored under ad-hoc names into a namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ImportStats {
+    /// Source keys matched by the pattern.
+    pub scanned: u64,
+    pub imported: u64,
+    /// Source keys whose target key already existed and was left alone.
+    pub existing: u64,
+    /// Source keys that held something other than a string or expired before being read.
+    pub skipped: u64,
+}
+
+/// Counts migrated entries. Shared by every connection of a `CacheService`, including the
+/// background scan.
+#[derive(Debug, Default)]
+pub struct MigrationCounters {
+    converted: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl MigrationCounters {
+    pub fn record(&self, stats: MigrationStats) {
+        self.converted.fetch_add(stats.converted, Ordering::Relaxed);
+        self.discarded.fetch_add(stats.discarded, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> MigrationStats {
+        MigrationStats {
+            converted: self.converted.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_round_trip_envelope() {
+        let raw = Envelope::encode(1700000000, b"value");
+        let Decoded::Current(envelope) = Envelope::decode(Bytes::from(raw)) else {
+            panic!("Should decode current envelope");
+        };
+        assert_eq!(envelope.created, 1700000000);
+        assert_eq!(envelope.payload, "value");
+    }
+
+    #[test]
+    fn it_should_compress_large_payloads() {
+        let payload = "{\"key\":\"value\"}".repeat(100);
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let compression = Compression {
+                codec,
+                threshold: 64,
+            };
+            let raw = Envelope::encode_with(1700000000, payload.as_bytes(), Some(compression));
+            assert!(raw.len() < payload.len());
+            let Decoded::Current(envelope) = Envelope::decode(Bytes::from(raw)) else {
+                panic!("Should decode compressed envelope");
+            };
+            assert_eq!(envelope.payload, payload.as_bytes());
+
+            let raw = Envelope::encode_with(1700000000, b"small", Some(compression));
+            assert_eq!(raw, Envelope::encode(1700000000, b"small"));
+        }
+    }
+
+    #[test]
+    fn it_should_record_writer() {
+        let writer = Provenance {
+            instance: "web-1".to_string(),
+            label: Some("checkout".to_string()),
+        };
+        let compression = Compression {
+            codec: Codec::Zstd,
+            threshold: 0,
+        };
+        let payload = "value".repeat(100);
+        let raw = Envelope::encode_by(
+            1700000000,
+            payload.as_bytes(),
+            Some(compression),
+            Some(&writer),
+        );
+        let Decoded::Current(envelope) = Envelope::decode(Bytes::from(raw)) else {
+            panic!("Should decode envelope with writer");
+        };
+        assert_eq!(envelope.writer, Some(writer));
+        assert_eq!(envelope.payload, payload.as_bytes());
+
+        let mut truncated = Envelope::encode_by(1700000000, b"", None, envelope.writer.as_ref());
+        truncated.truncate(HEADER_LEN + 3);
+        assert_eq!(
+            Envelope::decode(Bytes::from(truncated)),
+            Decoded::Unreadable
+        );
+    }
+
+    #[test]
+    fn it_should_read_version_one_envelope() {
+        let mut raw = vec![MAGIC, V1];
+        raw.extend_from_slice(&1700000000u64.to_be_bytes());
+        raw.extend_from_slice(b"value");
+        assert_eq!(
+            Envelope::decode(Bytes::from(raw)),
+            Decoded::Current(Envelope {
+                created: 1700000000,
+                writer: None,
+                payload: Bytes::from("value"),
+            })
+        );
+    }
+
+    #[test]
+    fn it_should_detect_old_formats() {
+        assert_eq!(
+            Envelope::decode(Bytes::from("value")),
+            Decoded::Legacy(Bytes::from("value"))
+        );
+        let mut raw = Envelope::encode(1700000000, b"value");
+        raw[1] = V3 + 1;
+        assert_eq!(Envelope::decode(Bytes::from(raw)), Decoded::Unreadable);
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::{Arc, RwLock};
+
+use crate::Tier;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheEventKind {
+    /// A value was written to the tier.
+    Insert,
+    Hit,
+    Miss,
+    /// An entry past its TTL or max age was found and dropped.
+    Expiration,
+    /// A live entry was dropped to stay within capacity.
+    Eviction,
+}
+
+/// Something that happened to a key in one tier, as reported to `on_event` listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheEvent<'a> {
+    /// The key as stored, namespace included.
+    pub key: &'a str,
+    pub kind: CacheEventKind,
+    pub tier: Tier,
+}
+
+pub type EventListener = Arc<dyn Fn(&CacheEvent) + Send + Sync>;
+
+/// Listeners registered with `on_event`, shared by a cache and its clones so events from
+/// background threads reach them too.
+#[derive(Clone, Default)]
+pub(crate) struct Listeners(Arc<RwLock<Vec<EventListener>>>);
+
+impl Listeners {
+    pub(crate) fn add(&self, listener: EventListener) {
+        self.0.write().unwrap().push(listener);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    /// Calls every listener without holding the lock, so listeners may register others.
+    pub(crate) fn emit(&self, key: &str, kind: CacheEventKind, tier: Tier) {
+        let listeners = self.0.read().unwrap().clone();
+        let event = CacheEvent { key, kind, tier };
+        for listener in &listeners {
+            listener(&event);
+        }
+    }
+}
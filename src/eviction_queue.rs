@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+
+const NIL: usize = usize::MAX;
+
+/// Which part of the memory tier an entry is in, for the eviction policies that split it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Segment {
+    /// The most recently used entries under `EvictionPolicy::TinyLfu`.
+    Window,
+    /// Every entry under the other policies, the ones past the window under `TinyLfu` and
+    /// the ones not read since they were stored under `Slru`.
+    Probation,
+    /// The entries read since they were stored under `EvictionPolicy::Slru`.
+    Protected,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct List {
+    head: usize,
+    tail: usize,
+}
+
+const EMPTY: List = List {
+    head: NIL,
+    tail: NIL,
+};
+
+#[derive(Debug)]
+struct Node {
+    key: String,
+    segment: Segment,
+    /// Clock tick the entry was last put at the back of its segment.
+    stamp: u64,
+    prev: usize,
+    next: usize,
+    rank: Option<(u64, u64)>,
+}
+
+/// The entries of one memory shard in eviction order: a doubly linked list per segment,
+/// least recently queued first, and for LFU a set ordered by rank. Nodes live in a slab and
+/// each entry keeps its slot, so queueing, moving and removing an entry are O(1), or
+/// O(log n) for ranks, and finding the next victim never scans the shard.
+#[derive(Debug)]
+pub(crate) struct EvictionQueue {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    lists: [List; 3],
+    ranks: BTreeSet<((u64, u64), usize)>,
+}
+
+impl Default for EvictionQueue {
+    fn default() -> EvictionQueue {
+        EvictionQueue {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            lists: [EMPTY; 3],
+            ranks: BTreeSet::new(),
+        }
+    }
+}
+
+impl EvictionQueue {
+    /// Queues `key` at the back of `segment` and returns its slot.
+    pub(crate) fn push(&mut self, key: &str, segment: Segment, stamp: u64) -> usize {
+        let node = Node {
+            key: key.to_owned(),
+            segment,
+            stamp,
+            prev: NIL,
+            next: NIL,
+            rank: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.link(slot);
+        slot
+    }
+
+    /// Removes the entry in `slot`, returning the segment it was in.
+    pub(crate) fn remove(&mut self, slot: usize) -> Segment {
+        self.unlink(slot);
+        if let Some(rank) = self.nodes[slot].rank.take() {
+            self.ranks.remove(&(rank, slot));
+        }
+        self.nodes[slot].key = String::new();
+        self.free.push(slot);
+        self.nodes[slot].segment
+    }
+
+    pub(crate) fn segment(&self, slot: usize) -> Segment {
+        self.nodes[slot].segment
+    }
+
+    /// Moves the entry in `slot` to the back of its segment.
+    pub(crate) fn touch(&mut self, slot: usize, stamp: u64) {
+        self.move_to(slot, self.segment(slot), stamp);
+    }
+
+    /// Moves the entry in `slot` to the back of `segment`.
+    pub(crate) fn move_to(&mut self, slot: usize, segment: Segment, stamp: u64) {
+        self.unlink(slot);
+        self.nodes[slot].segment = segment;
+        self.nodes[slot].stamp = stamp;
+        self.link(slot);
+    }
+
+    /// The key at the front of `segment` with the tick it was queued at, so the fronts of
+    /// several queues fed from one clock can be compared.
+    pub(crate) fn oldest(&self, segment: Segment) -> Option<(u64, &str)> {
+        self.nodes
+            .get(self.lists[segment as usize].head)
+            .map(|node| (node.stamp, node.key.as_str()))
+    }
+
+    pub(crate) fn set_rank(&mut self, slot: usize, rank: (u64, u64)) {
+        if let Some(previous) = self.nodes[slot].rank.replace(rank) {
+            self.ranks.remove(&(previous, slot));
+        }
+        self.ranks.insert((rank, slot));
+    }
+
+    /// The key with the lowest rank, with that rank.
+    pub(crate) fn lowest_rank(&self) -> Option<((u64, u64), &str)> {
+        self.ranks
+            .first()
+            .map(|&(rank, slot)| (rank, self.nodes[slot].key.as_str()))
+    }
+
+    fn link(&mut self, slot: usize) {
+        let list = &mut self.lists[self.nodes[slot].segment as usize];
+        self.nodes[slot].prev = list.tail;
+        self.nodes[slot].next = NIL;
+        match self.nodes.get_mut(list.tail) {
+            Some(tail) => tail.next = slot,
+            None => list.head = slot,
+        }
+        list.tail = slot;
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let Node {
+            prev,
+            next,
+            segment,
+            ..
+        } = self.nodes[slot];
+        let list = &mut self.lists[segment as usize];
+        match self.nodes.get_mut(prev) {
+            Some(node) => node.next = next,
+            None => list.head = next,
+        }
+        match self.nodes.get_mut(next) {
+            Some(node) => node.prev = prev,
+            None => list.tail = prev,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_hand_out_the_least_recently_queued_key() {
+        let mut queue = EvictionQueue::default();
+        let a = queue.push("a", Segment::Probation, 1);
+        let b = queue.push("b", Segment::Probation, 2);
+        let c = queue.push("c", Segment::Probation, 3);
+        queue.touch(a, 4);
+        assert_eq!(queue.oldest(Segment::Probation), Some((2, "b")));
+
+        queue.remove(b);
+        let d = queue.push("d", Segment::Window, 5);
+        assert_eq!(d, b, "the freed slot is reused");
+        assert_eq!(queue.oldest(Segment::Probation), Some((3, "c")));
+        queue.move_to(c, Segment::Window, 6);
+        assert_eq!(queue.oldest(Segment::Window), Some((5, "d")));
+        assert_eq!(queue.oldest(Segment::Probation), Some((4, "a")));
+        queue.move_to(a, Segment::Protected, 7);
+        assert_eq!(queue.oldest(Segment::Probation), None);
+        assert_eq!(queue.oldest(Segment::Protected), Some((7, "a")));
+
+        queue.set_rank(a, (2, 4));
+        queue.set_rank(d, (1, 5));
+        assert_eq!(queue.lowest_rank(), Some(((1, 5), "d")));
+        queue.set_rank(d, (3, 6));
+        assert_eq!(queue.lowest_rank(), Some(((2, 4), "a")));
+    }
+}
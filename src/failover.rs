@@ -0,0 +1,264 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use redis::{Connection, ConnectionLike, RedisError, RedisResult};
+
+use crate::kv_cache::{is_failover_error, KvTimeouts, RedisAuth};
+
+/// How long connecting to a URL may take before the next one is tried.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailoverEvent {
+    /// The connection to `from` failed and commands now go to `to`.
+    FailedOver { from: String, to: String },
+    /// `to`, listed before `from`, answers again and commands go back to it.
+    FailedBack { from: String, to: String },
+}
+
+pub type FailoverObserver = Arc<dyn Fn(&FailoverEvent) + Send + Sync>;
+
+/// A Redis connection over an ordered list of URLs, e.g. a primary and its standbys. When
+/// a command fails because the server is gone, the next reachable URL in the list takes over
+/// and the command is retried once. While not on the first URL, the earlier ones are probed
+/// every `probe_interval` and the first to answer a PING is switched back to.
+pub struct FailoverConnection {
+    urls: Vec<String>,
+    current: usize,
+    con: Connection,
+    probe_interval: Duration,
+    last_probe: Instant,
+    observer: Option<FailoverObserver>,
+    timeouts: KvTimeouts,
+    /// Boxed to keep `KvConnection` small.
+    auth: Box<RedisAuth>,
+}
+
+impl FailoverConnection {
+    /// Connects to the first reachable URL, failing with the last error if none is.
+    pub fn open(urls: &[&str], probe_interval: Duration) -> RedisResult<FailoverConnection> {
+        FailoverConnection::open_with_timeouts(urls, probe_interval, KvTimeouts::default())
+    }
+
+    /// Same as `open`, with `timeouts` on every connection. The connect timeout replaces the
+    /// default of a second per URL.
+    pub fn open_with_timeouts(
+        urls: &[&str],
+        probe_interval: Duration,
+        timeouts: KvTimeouts,
+    ) -> RedisResult<FailoverConnection> {
+        FailoverConnection::open_with(urls, probe_interval, timeouts, RedisAuth::default())
+    }
+
+    /// Same as `open_with_timeouts`, logging in to every URL with `auth`.
+    pub fn open_with(
+        urls: &[&str],
+        probe_interval: Duration,
+        timeouts: KvTimeouts,
+        auth: RedisAuth,
+    ) -> RedisResult<FailoverConnection> {
+        let mut last_err = None;
+        for (index, url) in urls.iter().enumerate() {
+            match connect(url, &timeouts, &auth) {
+                Ok(con) => {
+                    return Ok(FailoverConnection {
+                        urls: urls.iter().map(|url| url.to_string()).collect(),
+                        current: index,
+                        con,
+                        probe_interval,
+                        last_probe: Instant::now(),
+                        observer: None,
+                        timeouts,
+                        auth: Box::new(auth),
+                    })
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            RedisError::from((redis::ErrorKind::InvalidClientConfig, "No failover URLs"))
+        }))
+    }
+
+    /// Calls `observer` on every switch between URLs.
+    pub fn set_observer(&mut self, observer: FailoverObserver) {
+        self.observer = Some(observer);
+    }
+
+    pub fn current_url(&self) -> &str {
+        &self.urls[self.current]
+    }
+
+    /// Gives up the connection to the current URL, e.g. to turn it into a pub/sub listener.
+    pub fn into_current(self) -> Connection {
+        self.con
+    }
+
+    fn switch(&mut self, index: usize, con: Connection) {
+        let from = self.urls[self.current].clone();
+        let to = self.urls[index].clone();
+        let event = if index < self.current {
+            FailoverEvent::FailedBack { from, to }
+        } else {
+            FailoverEvent::FailedOver { from, to }
+        };
+        self.current = index;
+        self.con = con;
+        self.last_probe = Instant::now();
+        if let Some(observer) = &self.observer {
+            observer(&event);
+        }
+    }
+
+    /// Switches back to the earliest URL that answers, if a probe is due.
+    fn probe(&mut self) {
+        if self.current == 0 || self.last_probe.elapsed() < self.probe_interval {
+            return;
+        }
+        self.last_probe = Instant::now();
+        for index in 0..self.current {
+            if let Ok(mut con) = connect(&self.urls[index], &self.timeouts, &self.auth) {
+                if redis::cmd("PING").query::<()>(&mut con).is_ok() {
+                    self.switch(index, con);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Switches to the next reachable URL after the current one, wrapping around. Returns
+    /// false if none is reachable.
+    fn fail_over(&mut self) -> bool {
+        for offset in 1..self.urls.len() {
+            let index = (self.current + offset) % self.urls.len();
+            if let Ok(con) = connect(&self.urls[index], &self.timeouts, &self.auth) {
+                self.switch(index, con);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn run<R, F>(&mut self, command: F) -> RedisResult<R>
+    where
+        F: Fn(&mut Connection) -> RedisResult<R>,
+    {
+        self.probe();
+        match command(&mut self.con) {
+            Err(err) if is_failover_error(&err) && self.fail_over() => command(&mut self.con),
+            Err(err) if err.is_timeout() => {
+                // A late answer would be read as the next command's, so start over.
+                if let Ok(con) = connect(&self.urls[self.current], &self.timeouts, &self.auth) {
+                    self.con = con;
+                }
+                Err(err)
+            }
+            res => res,
+        }
+    }
+}
+
+fn connect(url: &str, timeouts: &KvTimeouts, auth: &RedisAuth) -> RedisResult<Connection> {
+    let mut con = auth
+        .client(url)?
+        .get_connection_with_timeout(timeouts.connect.unwrap_or(CONNECT_TIMEOUT))?;
+    timeouts.apply(&con)?;
+    auth.name(&mut con)?;
+    Ok(con)
+}
+
+impl ConnectionLike for FailoverConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<redis::Value> {
+        self.run(|con| con.req_packed_command(cmd))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<redis::Value>> {
+        self.run(|con| con.req_packed_commands(cmd, offset, count))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.con.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.con.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.con.is_open()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use redis::Commands;
+
+    use super::*;
+    use crate::resp_server::{RespServer, ShutdownHandle};
+    use crate::CacheService;
+
+    /// A RESP server on `addr` standing in for a primary that can be taken down.
+    fn primary(addr: SocketAddr) -> (SocketAddr, ShutdownHandle) {
+        let cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("failover-primary")
+            .build()
+            .unwrap();
+        let server =
+            RespServer::bind(addr, Arc::new(Mutex::new(cache)), Duration::from_secs(100)).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        thread::spawn(move || server.serve());
+        (addr, shutdown)
+    }
+
+    #[test]
+    fn it_should_fail_over_and_back() {
+        let (addr, shutdown) = primary("127.0.0.1:0".parse().unwrap());
+        let primary_url = format!("redis://{}", addr);
+        let urls = [
+            "redis://127.0.0.1:1",
+            &primary_url,
+            "redis://127.0.0.1:6379",
+        ];
+        let mut con = FailoverConnection::open(&urls, Duration::ZERO).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        con.set_observer(Arc::new(move |event| {
+            recorded.lock().unwrap().push(event.clone())
+        }));
+        assert_eq!(con.current_url(), primary_url);
+
+        shutdown.shutdown();
+        thread::sleep(Duration::from_millis(100));
+        con.set::<_, _, ()>("failover", "standby").unwrap();
+        assert_eq!(con.current_url(), "redis://127.0.0.1:6379");
+
+        primary(addr);
+        let value: Option<String> = con.get("failover").unwrap();
+        assert_eq!(con.current_url(), primary_url);
+        assert_eq!(value, None);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                FailoverEvent::FailedOver {
+                    from: primary_url.clone(),
+                    to: "redis://127.0.0.1:6379".to_string(),
+                },
+                FailoverEvent::FailedBack {
+                    from: "redis://127.0.0.1:6379".to_string(),
+                    to: primary_url.clone(),
+                },
+            ]
+        );
+    }
+}
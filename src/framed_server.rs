@@ -0,0 +1,224 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use prost::Message;
+
+use crate::proto::response::Result as Reply;
+use crate::proto::{self, Request, Response, COMPRESSION};
+use crate::CacheService;
+
+/// Largest frame accepted from a client.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Serves a `CacheService` over plain TCP, for clients that want the protobuf messages of
+/// `proto/cache.proto` without HTTP/2. Each `Request` and `Response` is sent as a 4-byte
+/// big-endian length followed by the encoded message; responses come in request order. A
+/// connection may start with a `Hello` to agree on a version and capabilities.
+pub struct FramedServer {
+    listener: TcpListener,
+    cache: Arc<Mutex<CacheService>>,
+    ttl: Duration,
+}
+
+impl FramedServer {
+    /// Listens on `addr`. Set requests without a TTL store values for `ttl`.
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        cache: Arc<Mutex<CacheService>>,
+        ttl: Duration,
+    ) -> io::Result<FramedServer> {
+        Ok(FramedServer {
+            listener: TcpListener::bind(addr)?,
+            cache,
+            ttl,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn serve(&self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let cache = Arc::clone(&self.cache);
+            let ttl = self.ttl;
+            thread::spawn(move || {
+                let _ = serve_connection(stream, &cache, ttl);
+            });
+        }
+    }
+}
+
+fn serve_connection(
+    stream: TcpStream,
+    cache: &Mutex<CacheService>,
+    ttl: Duration,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut compressed = false;
+    while let Some(request) = read_frame::<Request>(&mut reader, compressed)? {
+        let response: Response = proto::execute(&mut cache.lock().unwrap(), request, ttl);
+        write_frame(&mut writer, &response, compressed)?;
+        if let Some(Reply::Hello(hello)) = &response.result {
+            compressed = hello.capabilities.iter().any(|name| name == COMPRESSION);
+        }
+        // Pipelined requests are answered together.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+/// Reads one length-prefixed message, zstd-compressed if `compressed`. `None` if the peer
+/// hung up between frames.
+pub fn read_frame<M: Message + Default>(
+    reader: &mut impl Read,
+    compressed: bool,
+) -> io::Result<Option<M>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(protocol_error("frame too long"));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    if compressed {
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::new(&frame[..])?
+            .take(MAX_FRAME_LEN as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > MAX_FRAME_LEN {
+            return Err(protocol_error("frame too long"));
+        }
+        frame = decompressed;
+    }
+    M::decode(&frame[..])
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Writes `message` with its length prefix, zstd-compressed if `compressed`.
+pub fn write_frame(
+    writer: &mut impl Write,
+    message: &impl Message,
+    compressed: bool,
+) -> io::Result<()> {
+    let mut frame = message.encode_to_vec();
+    if compressed {
+        frame = zstd::encode_all(&frame[..], 0)?;
+    }
+    let len = u32::try_from(frame.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&frame)
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::request::Command;
+    use crate::proto::{
+        DeleteRequest, ErrorCode, GetRequest, GetResponse, HelloRequest, SetRequest,
+        PROTOCOL_VERSION,
+    };
+
+    fn connect(namespace: &str) -> TcpStream {
+        let cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace(namespace)
+            .build()
+            .unwrap();
+        let server = FramedServer::bind(
+            "127.0.0.1:0",
+            Arc::new(Mutex::new(cache)),
+            Duration::from_secs(100),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+        TcpStream::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn it_should_serve_framed_protobuf_requests() {
+        let mut stream = connect("framed");
+
+        let requests = [
+            Command::Set(SetRequest {
+                key: "key".into(),
+                value: b"value".to_vec(),
+                ttl: 0,
+            }),
+            Command::Get(GetRequest { key: "key".into() }),
+            Command::Delete(DeleteRequest { key: "key".into() }),
+        ];
+        for command in requests {
+            let request = Request {
+                command: Some(command),
+            };
+            write_frame(&mut stream, &request, false).unwrap();
+        }
+        write_frame(&mut stream, &Request { command: None }, false).unwrap();
+
+        let mut replies = Vec::new();
+        for _ in 0..4 {
+            let response: Response = read_frame(&mut stream, false).unwrap().unwrap();
+            replies.push(response.result.unwrap());
+        }
+        assert!(matches!(replies[0], Reply::Set(_)));
+        assert_eq!(
+            replies[1],
+            Reply::Get(GetResponse {
+                value: Some(b"value".to_vec())
+            })
+        );
+        assert!(matches!(&replies[2], Reply::Delete(delete) if delete.deleted));
+        assert!(
+            matches!(&replies[3], Reply::Error(err) if err.code == ErrorCode::UnknownCommand as i32)
+        );
+    }
+
+    #[test]
+    fn it_should_negotiate_version_and_compression() {
+        let mut stream = connect("framed-hello");
+        let hello = Request {
+            command: Some(Command::Hello(HelloRequest {
+                version: PROTOCOL_VERSION + 1,
+                capabilities: vec!["watch".into(), COMPRESSION.into()],
+            })),
+        };
+        write_frame(&mut stream, &hello, false).unwrap();
+        let response: Response = read_frame(&mut stream, false).unwrap().unwrap();
+        let Some(Reply::Hello(hello)) = response.result else {
+            panic!("expected a hello, got {:?}", response.result);
+        };
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        assert_eq!(hello.capabilities, [COMPRESSION]);
+
+        let get = Request {
+            command: Some(Command::Get(GetRequest { key: "key".into() })),
+        };
+        write_frame(&mut stream, &get, true).unwrap();
+        let response: Response = read_frame(&mut stream, true).unwrap().unwrap();
+        assert_eq!(
+            response.result,
+            Some(Reply::Get(GetResponse { value: None }))
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+const DEPTH: usize = 4;
+const MAX_COUNT: u8 = 15;
+const SEEDS: [u64; DEPTH] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xc2b2_ae3d_27d4_eb4f,
+    0x1656_67b1_9e37_79f9,
+    0x85eb_ca77_c2b2_ae63,
+];
+
+/// Count-min sketch estimating how often keys were accessed recently, in little memory and
+/// without locks. Counts stop at 15 and are halved once `10 * capacity` accesses have been
+/// recorded, so keys that were popular long ago fade out.
+#[derive(Debug)]
+pub(crate) struct FrequencySketch {
+    counters: Vec<AtomicU8>,
+    mask: usize,
+    additions: AtomicUsize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    pub(crate) fn new(capacity: usize) -> FrequencySketch {
+        let width = capacity.max(16).next_power_of_two();
+        FrequencySketch {
+            counters: (0..width * DEPTH).map(|_| AtomicU8::new(0)).collect(),
+            mask: width - 1,
+            additions: AtomicUsize::new(0),
+            sample_size: capacity.max(16).saturating_mul(10),
+        }
+    }
+
+    fn slots(&self, key: &str) -> [usize; DEPTH] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let width = self.mask + 1;
+        let mut slots = [0; DEPTH];
+        for (row, seed) in SEEDS.iter().enumerate() {
+            let index = (hash.wrapping_mul(*seed) >> 32) as usize & self.mask;
+            slots[row] = row * width + index;
+        }
+        slots
+    }
+
+    pub(crate) fn increment(&self, key: &str) {
+        for slot in self.slots(key) {
+            let _ =
+                self.counters[slot].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    (count < MAX_COUNT).then_some(count + 1)
+                });
+        }
+        if self.additions.fetch_add(1, Ordering::Relaxed) + 1 >= self.sample_size {
+            self.age();
+        }
+    }
+
+    pub(crate) fn frequency(&self, key: &str) -> u8 {
+        self.slots(key)
+            .iter()
+            .map(|slot| self.counters[*slot].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Halves every count. Racing increments may be lost, which only makes the estimate a
+    /// little lower.
+    fn age(&self) {
+        self.additions.store(0, Ordering::Relaxed);
+        for counter in &self.counters {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                Some(count / 2)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_estimate_frequencies_and_age_them() {
+        let sketch = FrequencySketch::new(64);
+        for _ in 0..20 {
+            sketch.increment("hot");
+        }
+        sketch.increment("cold");
+        assert_eq!(sketch.frequency("hot"), MAX_COUNT);
+        assert!(sketch.frequency("cold") >= 1);
+        assert_eq!(sketch.frequency("unseen"), 0);
+
+        sketch.age();
+        assert_eq!(sketch.frequency("hot"), MAX_COUNT / 2);
+    }
+}
@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fmt;
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const MAX_PRECISION: usize = 12;
+
+/// Inclusive `(min, max)` span of a tile along one axis.
+pub type Span = (f64, f64);
+
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum GeoKeyError {
+    InvalidPrecision,
+    InvalidCoordinates,
+    InvalidGeohash,
+}
+
+impl fmt::Display for GeoKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoKeyError::InvalidPrecision => {
+                write!(
+                    f,
+                    "geohash precision must be between 1 and {}",
+                    MAX_PRECISION
+                )
+            }
+            GeoKeyError::InvalidCoordinates => {
+                f.write_str("latitude must be in [-90, 90] and longitude in [-180, 180]")
+            }
+            GeoKeyError::InvalidGeohash => write!(
+                f,
+                "not a geohash of 1 to {} base32 characters",
+                MAX_PRECISION
+            ),
+        }
+    }
+}
+
+impl Error for GeoKeyError {}
+
+/// Derives cache keys from the geohash tile containing a coordinate, so lookups for nearby
+/// points share one cache entry. Higher precision means smaller tiles.
+pub struct GeoKey {
+    prefix: String,
+    precision: usize,
+}
+
+impl GeoKey {
+    pub fn new(prefix: &str, precision: usize) -> Result<GeoKey, GeoKeyError> {
+        if precision == 0 || precision > MAX_PRECISION {
+            return Err(GeoKeyError::InvalidPrecision);
+        }
+        Ok(GeoKey {
+            prefix: prefix.to_string(),
+            precision,
+        })
+    }
+
+    pub fn tile(&self, lat: f64, lon: f64) -> Result<String, GeoKeyError> {
+        encode(lat, lon, self.precision)
+    }
+
+    pub fn key(&self, lat: f64, lon: f64) -> Result<String, GeoKeyError> {
+        Ok(self.key_for_tile(&self.tile(lat, lon)?))
+    }
+
+    pub fn key_for_tile(&self, tile: &str) -> String {
+        format!("{}:geo:{}", self.prefix, tile)
+    }
+
+    /// Returns the tile containing the coordinate followed by its (up to) eight neighbours.
+    pub fn neighborhood(&self, lat: f64, lon: f64) -> Result<Vec<String>, GeoKeyError> {
+        let center = self.tile(lat, lon)?;
+        neighborhood(&center)
+    }
+}
+
+pub fn encode(lat: f64, lon: f64, precision: usize) -> Result<String, GeoKeyError> {
+    if precision == 0 || precision > MAX_PRECISION {
+        return Err(GeoKeyError::InvalidPrecision);
+    }
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(GeoKeyError::InvalidCoordinates);
+    }
+
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let mut bits = 0;
+    let mut index = 0;
+
+    while hash.len() < precision {
+        let (range, value) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        index <<= 1;
+        if value >= mid {
+            index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Returns the latitude and longitude spans of the tile.
+pub fn bounds(hash: &str) -> Result<(Span, Span), GeoKeyError> {
+    if hash.is_empty() || hash.len() > MAX_PRECISION {
+        return Err(GeoKeyError::InvalidGeohash);
+    }
+
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut even = true;
+    for c in hash.bytes() {
+        let index = BASE32
+            .iter()
+            .position(|&b| b == c)
+            .ok_or(GeoKeyError::InvalidGeohash)?;
+        for shift in (0..5).rev() {
+            let range = if even { &mut lon_range } else { &mut lat_range };
+            let mid = (range.0 + range.1) / 2.0;
+            if (index >> shift) & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+
+    Ok((lat_range, lon_range))
+}
+
+pub fn neighborhood(hash: &str) -> Result<Vec<String>, GeoKeyError> {
+    let ((min_lat, max_lat), (min_lon, max_lon)) = bounds(hash)?;
+    let (height, width) = (max_lat - min_lat, max_lon - min_lon);
+    let (center_lat, center_lon) = ((min_lat + max_lat) / 2.0, (min_lon + max_lon) / 2.0);
+
+    let mut tiles = vec![hash.to_string()];
+    for d_lat in [-1.0, 0.0, 1.0] {
+        for d_lon in [-1.0, 0.0, 1.0] {
+            let lat = center_lat + d_lat * height;
+            if !(-90.0..=90.0).contains(&lat) {
+                continue;
+            }
+            let mut lon = center_lon + d_lon * width;
+            if lon > 180.0 {
+                lon -= 360.0;
+            } else if lon < -180.0 {
+                lon += 360.0;
+            }
+            let tile = encode(lat, lon, hash.len())?;
+            if !tiles.contains(&tile) {
+                tiles.push(tile);
+            }
+        }
+    }
+
+    Ok(tiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_encode_known_geohash() {
+        let hash = encode(57.64911, 10.40744, 11).unwrap();
+        assert_eq!(hash, "u4pruydqqvj");
+    }
+
+    #[test]
+    fn it_should_decode_bounds_containing_point() {
+        let ((min_lat, max_lat), (min_lon, max_lon)) = bounds("u4pruyd").unwrap();
+        assert!(min_lat <= 57.64911 && 57.64911 <= max_lat);
+        assert!(min_lon <= 10.40744 && 10.40744 <= max_lon);
+    }
+
+    #[test]
+    fn it_should_return_center_and_eight_neighbors() {
+        let tiles = neighborhood("u4pruyd").unwrap();
+        assert_eq!(tiles.len(), 9);
+        assert_eq!(tiles[0], "u4pruyd");
+        assert!(tiles.iter().all(|tile| tile.starts_with("u4pru")));
+    }
+
+    #[test]
+    fn it_should_wrap_neighbors_around_antimeridian() {
+        let tiles = neighborhood(&encode(0.0, 179.99, 3).unwrap()).unwrap();
+        assert_eq!(tiles.len(), 9);
+        assert!(tiles.iter().any(|tile| bounds(tile).unwrap().1 .0 < 0.0));
+    }
+
+    #[test]
+    fn it_should_build_cache_key_from_coordinates() {
+        let geo_key = GeoKey::new("restaurants", 5).unwrap();
+        assert_eq!(
+            geo_key.key(57.64911, 10.40744).unwrap(),
+            "restaurants:geo:u4pru"
+        );
+    }
+
+    #[test]
+    fn it_should_reject_invalid_input() {
+        assert!(matches!(
+            GeoKey::new("restaurants", 0),
+            Err(GeoKeyError::InvalidPrecision)
+        ));
+        assert_eq!(encode(91.0, 0.0, 5), Err(GeoKeyError::InvalidCoordinates));
+        assert_eq!(bounds("u4a"), Err(GeoKeyError::InvalidGeohash));
+    }
+}
@@ -0,0 +1,379 @@
+//! A gRPC server over a shared `CacheService`, so services in any language can use it as a
+//! sidecar cache. The schema is `proto/cache.proto`; its messages are in `proto`.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, Body, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+
+use crate::proto::{
+    self, DeleteRequest, DeleteResponse, Entry, ErrorCode, GetRequest, GetResponse,
+    ResolveStreamRequest, SetRequest, SetResponse, StatsRequest, StatsResponse,
+};
+use crate::{error_chain, CacheService, CacheServiceError};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, Status>> + Send>>;
+
+/// Entries a `ResolveStream` call may have looked up ahead of the client reading them.
+const STREAM_BUFFER: usize = 16;
+
+/// The `rcache.v1.Cache` service. Calls run on Tokio's blocking pool, since `CacheService`
+/// blocks on Redis.
+#[derive(Clone)]
+pub struct GrpcCache {
+    cache: Arc<Mutex<CacheService>>,
+    ttl: Duration,
+}
+
+impl GrpcCache {
+    /// `Set` calls without a TTL store values for `ttl`.
+    pub fn new(cache: Arc<Mutex<CacheService>>, ttl: Duration) -> GrpcCache {
+        GrpcCache { cache, ttl }
+    }
+
+    /// Serves the service on `addr` until the server fails.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+        Server::builder().add_service(self).serve(addr).await
+    }
+
+    fn blocking<T, F>(&self, f: F) -> BoxFuture<Response<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut CacheService) -> Result<T, CacheServiceError> + Send + 'static,
+    {
+        let cache = Arc::clone(&self.cache);
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || f(&mut cache.lock().unwrap()))
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?
+                .map(Response::new)
+                .map_err(to_status)
+        })
+    }
+
+    fn get(&self, request: Request<GetRequest>) -> BoxFuture<Response<GetResponse>> {
+        let request = request.into_inner();
+        self.blocking(move |cache| proto::get(cache, request))
+    }
+
+    fn set(&self, request: Request<SetRequest>) -> BoxFuture<Response<SetResponse>> {
+        let request = request.into_inner();
+        let ttl = self.ttl;
+        self.blocking(move |cache| proto::set(cache, request, ttl))
+    }
+
+    fn delete(&self, request: Request<DeleteRequest>) -> BoxFuture<Response<DeleteResponse>> {
+        let request = request.into_inner();
+        self.blocking(move |cache| proto::delete(cache, request))
+    }
+
+    fn stats(&self, _: Request<StatsRequest>) -> BoxFuture<Response<StatsResponse>> {
+        self.blocking(|cache| Ok(proto::stats(cache)))
+    }
+
+    /// Looks the keys up one by one, so the first entries arrive before the last are found.
+    /// A failed lookup ends the stream with its error.
+    fn resolve_stream(
+        &self,
+        request: Request<ResolveStreamRequest>,
+    ) -> BoxFuture<Response<ReceiverStream<Result<Entry, Status>>>> {
+        let ResolveStreamRequest { keys } = request.into_inner();
+        let cache = Arc::clone(&self.cache);
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            for key in keys {
+                let entry = cache
+                    .lock()
+                    .unwrap()
+                    .get_bytes(&key)
+                    .map(|value| Entry {
+                        key,
+                        value: value.map(|value| value.to_vec()),
+                    })
+                    .map_err(to_status);
+                let failed = entry.is_err();
+                if sender.blocking_send(entry).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Box::pin(async move { Ok(Response::new(ReceiverStream::new(receiver))) })
+    }
+}
+
+fn to_status(err: CacheServiceError) -> Status {
+    let code = match ErrorCode::from(&err) {
+        ErrorCode::ResourceExhausted => Code::ResourceExhausted,
+        ErrorCode::InvalidArgument => Code::InvalidArgument,
+        ErrorCode::Unavailable => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    Status::new(code, error_chain(&err))
+}
+
+type Method<Req, Res> = fn(&GrpcCache, Request<Req>) -> BoxFuture<Response<Res>>;
+
+/// Adapts a method of `GrpcCache` to tonic's unary service.
+struct Unary<Req, Res>(GrpcCache, Method<Req, Res>);
+
+impl<Req, Res> UnaryService<Req> for Unary<Req, Res> {
+    type Response = Res;
+    type Future = BoxFuture<Response<Res>>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        (self.1)(&self.0, request)
+    }
+}
+
+/// Adapts a method of `GrpcCache` to tonic's server streaming service.
+struct Streaming<Req, Res>(GrpcCache, Method<Req, ReceiverStream<Result<Res, Status>>>);
+
+impl<Req, Res> ServerStreamingService<Req> for Streaming<Req, Res> {
+    type Response = Res;
+    type ResponseStream = ReceiverStream<Result<Res, Status>>;
+    type Future = BoxFuture<Response<Self::ResponseStream>>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        (self.1)(&self.0, request)
+    }
+}
+
+impl NamedService for GrpcCache {
+    const NAME: &'static str = "rcache.v1.Cache";
+}
+
+impl<B> Service<http::Request<B>> for GrpcCache
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            let response = match req.uri().path() {
+                "/rcache.v1.Cache/Get" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(Unary(service, GrpcCache::get), req)
+                        .await
+                }
+                "/rcache.v1.Cache/Set" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(Unary(service, GrpcCache::set), req)
+                        .await
+                }
+                "/rcache.v1.Cache/Delete" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(Unary(service, GrpcCache::delete), req)
+                        .await
+                }
+                "/rcache.v1.Cache/Stats" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(Unary(service, GrpcCache::stats), req)
+                        .await
+                }
+                "/rcache.v1.Cache/ResolveStream" => {
+                    Grpc::new(ProstCodec::default())
+                        .server_streaming(Streaming(service, GrpcCache::resolve_stream), req)
+                        .await
+                }
+                _ => Status::unimplemented("Unknown method").into_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tokio_stream::StreamExt;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::transport::{Channel, Endpoint};
+
+    use super::*;
+    use crate::limits::SizeLimits;
+    use crate::KvError;
+
+    async fn call<Req, Res>(
+        channel: &Channel,
+        path: &'static str,
+        request: Req,
+    ) -> Result<Res, Status>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut client = tonic::client::Grpc::new(channel.clone());
+        client.ready().await.unwrap();
+        client
+            .unary(
+                Request::new(request),
+                PathAndQuery::from_static(path),
+                ProstCodec::default(),
+            )
+            .await
+            .map(Response::into_inner)
+    }
+
+    async fn unary<Req, Res>(channel: &Channel, path: &'static str, request: Req) -> Res
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        call(channel, path, request).await.unwrap()
+    }
+
+    async fn serve(cache: CacheService) -> Channel {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = GrpcCache::new(Arc::new(Mutex::new(cache)), Duration::from_secs(100));
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_should_serve_cache_over_grpc() {
+        let cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("grpc")
+            .build()
+            .unwrap();
+        let channel = serve(cache).await;
+
+        let _: DeleteResponse = unary(
+            &channel,
+            "/rcache.v1.Cache/Delete",
+            DeleteRequest { key: "b".into() },
+        )
+        .await;
+        let _: SetResponse = unary(
+            &channel,
+            "/rcache.v1.Cache/Set",
+            SetRequest {
+                key: "a".into(),
+                value: b"value".to_vec(),
+                ttl: 0,
+            },
+        )
+        .await;
+        let got: GetResponse = unary(
+            &channel,
+            "/rcache.v1.Cache/Get",
+            GetRequest { key: "a".into() },
+        )
+        .await;
+        assert_eq!(got.value.as_deref(), Some(&b"value"[..]));
+
+        let mut client = tonic::client::Grpc::new(channel.clone());
+        client.ready().await.unwrap();
+        let entries: Vec<Entry> = client
+            .server_streaming(
+                Request::new(ResolveStreamRequest {
+                    keys: vec!["a".into(), "b".into()],
+                }),
+                PathAndQuery::from_static("/rcache.v1.Cache/ResolveStream"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(entries[0].value.as_deref(), Some(&b"value"[..]));
+        assert_eq!((entries[1].key.as_str(), &entries[1].value), ("b", &None));
+
+        let deleted: DeleteResponse = unary(
+            &channel,
+            "/rcache.v1.Cache/Delete",
+            DeleteRequest { key: "a".into() },
+        )
+        .await;
+        assert!(deleted.deleted);
+        let stats: StatsResponse = unary(&channel, "/rcache.v1.Cache/Stats", StatsRequest {}).await;
+        assert_eq!(stats.memory_hits, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_should_answer_failed_calls_with_status() {
+        let cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("grpc_status")
+            .size_limits(SizeLimits {
+                max_key_len: Some(16),
+                ..SizeLimits::default()
+            })
+            .build()
+            .unwrap();
+        let channel = serve(cache).await;
+
+        let rejected = call::<_, SetResponse>(
+            &channel,
+            "/rcache.v1.Cache/Set",
+            SetRequest {
+                key: "a-key-over-the-limit".into(),
+                value: b"value".to_vec(),
+                ttl: 0,
+            },
+        )
+        .await
+        .unwrap_err();
+        let unknown = call::<_, GetResponse>(
+            &channel,
+            "/rcache.v1.Cache/Unknown",
+            GetRequest { key: "a".into() },
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(rejected.code(), Code::InvalidArgument);
+        assert!(rejected.message().contains("over the limit of 16"));
+        assert_eq!(unknown.code(), Code::Unimplemented);
+    }
+
+    #[test]
+    fn it_should_map_errors_to_status_codes() {
+        let unavailable = CacheServiceError::kv("GET", "key")(KvError::ConnectionNotEstablished);
+        let cases = [
+            (
+                CacheServiceError::QuotaExceeded { max_keys: 10 },
+                Code::ResourceExhausted,
+            ),
+            (unavailable, Code::Unavailable),
+            (CacheServiceError::IdempotencyInProgress, Code::Internal),
+        ];
+        for (err, code) in cases {
+            let message = error_chain(&err);
+            let status = to_status(err);
+            assert_eq!((status.code(), status.message()), (code, message.as_str()));
+        }
+    }
+}
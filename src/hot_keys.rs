@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use crate::Tier;
+
+/// Lookups of one of the most requested keys, from `CacheService::top_keys`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HotKey {
+    /// The key without the namespace.
+    pub key: String,
+    /// Estimated lookups, at most `error` more than the actual number.
+    pub lookups: u64,
+    /// Lookups counted for keys this one replaced in the tracker, before it was first seen.
+    pub error: u64,
+    /// Lookups since the key was tracked that the memory tier answered.
+    pub memory_hits: u64,
+    /// Lookups since the key was tracked that Redis answered.
+    pub kv_hits: u64,
+    /// Lookups since the key was tracked that neither tier answered.
+    pub misses: u64,
+}
+
+/// Space-saving tracker of the most looked up keys. It keeps counters for `capacity` keys.
+/// When it is full, a new key takes over the counter of the least looked up one, so a key
+/// requested more often than `1 / capacity` of all lookups is never missed.
+#[derive(Debug)]
+pub(crate) struct HotKeys {
+    capacity: usize,
+    counters: HashMap<String, HotKey>,
+}
+
+impl HotKeys {
+    pub(crate) fn new(capacity: usize) -> HotKeys {
+        HotKeys {
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Counts a lookup of `key`, given without the namespace, and the tier that answered it.
+    pub(crate) fn record(&mut self, key: &str, served: Option<Tier>) {
+        let counter = match self.counters.get_mut(key) {
+            Some(counter) => counter,
+            None => {
+                let error = self.evict_coldest();
+                self.counters
+                    .entry(key.to_string())
+                    .or_insert_with(|| HotKey {
+                        key: key.to_string(),
+                        lookups: error,
+                        error,
+                        ..HotKey::default()
+                    })
+            }
+        };
+        counter.lookups += 1;
+        match served {
+            Some(Tier::Memory) => counter.memory_hits += 1,
+            Some(Tier::Kv) => counter.kv_hits += 1,
+            None => counter.misses += 1,
+        }
+    }
+
+    /// Makes room for a new key when full, returning the lookups of the key it dropped.
+    fn evict_coldest(&mut self) -> u64 {
+        if self.counters.len() < self.capacity {
+            return 0;
+        }
+        let coldest = self
+            .counters
+            .values()
+            .min_by_key(|counter| counter.lookups)
+            .map(|counter| counter.key.clone());
+        coldest
+            .and_then(|key| self.counters.remove(&key))
+            .map_or(0, |counter| counter.lookups)
+    }
+
+    /// The `count` keys with the most lookups, most looked up first.
+    pub(crate) fn top(&self, count: usize) -> Vec<HotKey> {
+        let mut keys: Vec<HotKey> = self.counters.values().cloned().collect();
+        keys.sort_by(|a, b| b.lookups.cmp(&a.lookups).then_with(|| a.key.cmp(&b.key)));
+        keys.truncate(count);
+        keys
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.counters.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_keep_the_most_looked_up_keys() {
+        let mut hot_keys = HotKeys::new(2);
+        for _ in 0..5 {
+            hot_keys.record("hot", Some(Tier::Memory));
+        }
+        hot_keys.record("hot", Some(Tier::Kv));
+        hot_keys.record("cold", None);
+        hot_keys.record("new", None);
+
+        let top = hot_keys.top(2);
+        assert_eq!(top[0].key, "hot");
+        assert_eq!(
+            (top[0].lookups, top[0].memory_hits, top[0].kv_hits),
+            (6, 5, 1)
+        );
+        assert_eq!(
+            (top[1].key.as_str(), top[1].lookups, top[1].error),
+            ("new", 2, 1)
+        );
+        assert_eq!(hot_keys.top(1).len(), 1);
+    }
+}
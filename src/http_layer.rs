@@ -0,0 +1,259 @@
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::request::Parts;
+use http::{HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body::Body;
+use http_body_util::{BodyExt, Either, Full};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::CacheService;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Derives the cache key of a request, or `None` to pass it through uncached.
+pub type KeyFn = Arc<dyn Fn(&Parts) -> Option<String> + Send + Sync>;
+
+/// Responses served by `CacheMiddleware`: buffered when they went through the cache, the inner
+/// service's own body otherwise.
+pub type CachedBody<B> = Either<Full<Bytes>, B>;
+
+/// Caches successful responses in a `CacheService`. By default GET and HEAD requests are keyed
+/// by method and path, and every other request passes through.
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Arc<Mutex<CacheService>>,
+    ttl: Duration,
+    key: KeyFn,
+}
+
+impl CacheLayer {
+    pub fn new(cache: Arc<Mutex<CacheService>>, ttl: Duration) -> CacheLayer {
+        CacheLayer {
+            cache,
+            ttl,
+            key: Arc::new(method_and_path),
+        }
+    }
+
+    pub fn key_fn<F>(mut self, key: F) -> CacheLayer
+    where
+        F: Fn(&Parts) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Arc::new(key);
+        self
+    }
+}
+
+fn method_and_path(parts: &Parts) -> Option<String> {
+    matches!(parts.method, Method::GET | Method::HEAD)
+        .then(|| format!("http:{}:{}", parts.method, parts.uri.path()))
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = CacheMiddleware<S>;
+
+    fn layer(&self, inner: S) -> CacheMiddleware<S> {
+        CacheMiddleware {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheMiddleware<S> {
+    inner: S,
+    layer: CacheLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for CacheMiddleware<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    ReqBody: Send + 'static,
+    ResBody: Body + Send + 'static,
+    ResBody::Data: Send,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<CachedBody<ResBody>>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    /// Cache failures never fail the request; the inner service answers instead.
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (parts, body) = req.into_parts();
+        let key = (self.layer.key)(&parts);
+        let req = Request::from_parts(parts, body);
+        // The clone that was driven to readiness handles this request.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let cache = Arc::clone(&self.layer.cache);
+        let ttl = self.layer.ttl;
+
+        Box::pin(async move {
+            let Some(key) = key else {
+                let response = inner.call(req).await.map_err(Into::into)?;
+                return Ok(response.map(Either::Right));
+            };
+
+            let cached = cache.lock().unwrap().get_bytes(&key);
+            if let Some(response) = cached.ok().flatten().and_then(decode_response) {
+                return Ok(response.map(Either::Left));
+            }
+
+            let response = inner.call(req).await.map_err(Into::into)?;
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await.map_err(Into::into)?.to_bytes();
+            let response = Response::from_parts(parts, body);
+            if response.status().is_success() {
+                let _ = cache
+                    .lock()
+                    .unwrap()
+                    .set_bytes(&key, &encode_response(&response), ttl);
+            }
+            Ok(response.map(|body| Either::Left(Full::new(body))))
+        })
+    }
+}
+
+/// Status, then each header as length-prefixed name and value, then the body.
+fn encode_response(response: &Response<Bytes>) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(response.body().len() + 64);
+    raw.extend_from_slice(&response.status().as_u16().to_be_bytes());
+    raw.extend_from_slice(&(response.headers().len() as u32).to_be_bytes());
+    for (name, value) in response.headers() {
+        for part in [name.as_str().as_bytes(), value.as_bytes()] {
+            raw.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            raw.extend_from_slice(part);
+        }
+    }
+    raw.extend_from_slice(response.body());
+    raw
+}
+
+fn decode_response(raw: Bytes) -> Option<Response<Full<Bytes>>> {
+    let mut raw = Cursor { raw, at: 0 };
+    let status = StatusCode::from_u16(u16::from_be_bytes(raw.take(2)?.try_into().ok()?)).ok()?;
+    let mut response = Response::builder().status(status);
+    for _ in 0..raw.length()? {
+        let len = raw.length()?;
+        let name = HeaderName::from_bytes(raw.take(len)?).ok()?;
+        let len = raw.length()?;
+        let value = HeaderValue::from_bytes(raw.take(len)?).ok()?;
+        response = response.header(name, value);
+    }
+    response.body(Full::new(raw.rest())).ok()
+}
+
+struct Cursor {
+    raw: Bytes,
+    at: usize,
+}
+
+impl Cursor {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let part = self.raw.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        Some(part)
+    }
+
+    fn length(&mut self) -> Option<usize> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+
+    fn rest(&self) -> Bytes {
+        self.raw.slice(self.at..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::{ready, Ready};
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::task::Waker;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct Hello {
+        calls: Arc<AtomicU32>,
+    }
+
+    impl Service<Request<()>> for Hello {
+        type Response = Response<Full<Bytes>>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<()>) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let response = Response::builder()
+                .header("content-type", "text/plain")
+                .body(Full::new(Bytes::from("hello")))
+                .unwrap();
+            ready(Ok(response))
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    fn get(service: &mut CacheMiddleware<Hello>, path: &str) -> Response<Bytes> {
+        let request = Request::get(path).body(()).unwrap();
+        block_on(async {
+            let response = service.call(request).await.unwrap();
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await.unwrap().to_bytes();
+            Response::from_parts(parts, body)
+        })
+    }
+
+    #[test]
+    fn it_should_serve_repeated_requests_from_cache() {
+        let cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("http_layer")
+            .build()
+            .unwrap();
+        let cache = Arc::new(Mutex::new(cache));
+        cache.lock().unwrap().invalidate("http:GET:/hello").unwrap();
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut service =
+            CacheLayer::new(Arc::clone(&cache), Duration::from_secs(10)).layer(Hello {
+                calls: Arc::clone(&calls),
+            });
+
+        let first = get(&mut service, "/hello");
+        let second = get(&mut service, "/hello");
+
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers()["content-type"], "text/plain");
+        assert_eq!(first.body(), second.body());
+        assert_eq!(second.body(), "hello");
+    }
+}
@@ -1,24 +1,140 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter};
 use std::marker::PhantomData;
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::SetPayload;
+use bytes::{Bytes, BytesMut};
+
+use crate::events::{CacheEvent, CacheEventKind, Listeners};
+use crate::eviction_queue::{EvictionQueue, Segment};
+use crate::frequency_sketch::FrequencySketch;
+use crate::snapshot::{self, SnapshotEntry};
+use crate::stale::StaleCopies;
+use crate::timing_wheel::TimingWheel;
+use crate::tombstones::Tombstones;
+use crate::{SetPayload, Tier};
 
 #[derive(Debug)]
 struct CacheValue {
-    value: String,
+    value: Bytes,
+    /// What the weigher made of the value when it was stored.
+    weight: u64,
+    /// Unix time in milliseconds the value was stored at.
     timestamp: u64,
-    ttl: u64,
+    ttl: Duration,
+    delta: f64,
+    created: u64,
+    /// Reads since `created`.
+    reads: AtomicU64,
+    /// Unix time in milliseconds of the last read, 0 if none was recorded.
+    last_read: AtomicU64,
+    pinned: bool,
+    /// Read again since it was stored, which moves it to the protected segment of
+    /// `EvictionPolicy::Slru`.
+    protected: AtomicBool,
+    /// Reads and writes so far, halved once per elapsed LFU decay interval.
+    frequency: AtomicU32,
+    /// Decay interval in which `frequency` was last brought up to date.
+    frequency_epoch: AtomicU64,
+    /// Where the entry is in its shard's eviction queue, `None` while pinned.
+    slot: Option<usize>,
+}
+
+impl CacheValue {
+    fn is_protected(&self) -> bool {
+        self.protected.load(Ordering::Relaxed)
+    }
+}
+
+/// Which entry to drop when the cache is at capacity and none have expired.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(
+    feature = "server",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum EvictionPolicy {
+    /// Least recently read or written.
+    #[default]
+    Lru,
+    /// Oldest inserted.
+    Fifo,
+    /// Least frequently read or written, the least recently used among equals. Counts never
+    /// shrink unless a decay interval is set with `set_lfu_decay`.
+    Lfu,
+    /// Least recently used, with W-TinyLFU admission: the most recently used 1% of entries,
+    /// the window, are always kept, and an entry leaving the window only displaces the least
+    /// recently used of the others if a frequency sketch has seen it requested more often,
+    /// so one-hit wonders don't push out hot entries.
+    TinyLfu,
+    /// Segmented LRU: new entries go to a probation segment and move to a protected one when
+    /// read, so a scan over many keys read once only evicts other probation entries. The
+    /// least recently used protected entries go back to probation when the protected segment
+    /// outgrows its share of the capacity, set with `set_slru_protected_share`.
+    Slru,
 }
 
 #[derive(Debug, PartialEq)]
+pub struct EntryMeta {
+    /// Unix time in milliseconds the key was first stored at; replacing its value keeps it.
+    pub created: u64,
+    /// Unix time in milliseconds, `u64::MAX` if the entry never expires.
+    pub expires_at: u64,
+    /// TTL the current value was stored with.
+    pub ttl: Duration,
+    /// Seconds it took to compute the value, used by early expiration.
+    pub delta: f64,
+    /// Pinned entries outlive `expires_at` until unpinned.
+    pub pinned: bool,
+    pub size: usize,
+    /// Reads since `created`.
+    pub reads: u64,
+    /// Unix time in milliseconds of the last read, `None` if there was none since `created`
+    /// or read times are not tracked.
+    pub last_read: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum InMemoryCacheError {
     EmptyKey,
+    /// `increment` found a value that is not a decimal `i64`.
+    NotACounter,
+    /// `increment` would take the counter out of the range of `i64`.
+    CounterOverflow,
 }
 
+impl fmt::Display for InMemoryCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InMemoryCacheError::EmptyKey => f.write_str("keys must not be empty"),
+            InMemoryCacheError::NotACounter => f.write_str("the value is not an integer"),
+            InMemoryCacheError::CounterOverflow => {
+                f.write_str("increment or decrement would overflow")
+            }
+        }
+    }
+}
+
+impl Error for InMemoryCacheError {}
+
 pub trait TimeSource {
+    /// Unix time in seconds.
     fn now(&self) -> u64;
+
+    /// Unix time in milliseconds.
+    fn now_millis(&self) -> u64 {
+        self.now().saturating_mul(1000)
+    }
 }
 
 pub struct SystemTimeSource;
@@ -30,72 +146,1394 @@ impl TimeSource for SystemTimeSource {
             .expect("Time went backwards")
             .as_secs()
     }
-}
 
-impl Default for SystemTimeSource {
-    fn default() -> Self {
-        SystemTimeSource
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64
+    }
+}
+
+impl Default for SystemTimeSource {
+    fn default() -> Self {
+        SystemTimeSource
+    }
+}
+
+const SHARD_COUNT: usize = 16;
+
+const UNBOUNDED: usize = 0;
+
+/// Sizes the `TinyLfu` sketch of a cache bounded by weight only.
+const DEFAULT_SKETCH_CAPACITY: usize = 4096;
+
+const DEFAULT_SLRU_PROTECTED_SHARE: f64 = 0.8;
+
+/// Tells how much an entry counts against `max_weight`, given its key and value.
+pub type Weigher = Arc<dyn Fn(&str, &[u8]) -> u64 + Send + Sync>;
+
+fn value_len(_key: &str, value: &[u8]) -> u64 {
+    value.len() as u64
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: RwLock<HashMap<String, CacheValue>>,
+    /// Locked on its own or while holding `entries`, never the other way around.
+    queue: Mutex<EvictionQueue>,
+}
+
+impl Shard {
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, CacheValue>> {
+        self.entries.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, CacheValue>> {
+        self.entries.write().unwrap()
+    }
+
+    fn queue(&self) -> MutexGuard<'_, EvictionQueue> {
+        self.queue.lock().unwrap()
+    }
+}
+
+pub struct InMemoryCache<T: TimeSource = SystemTimeSource> {
+    /// Entries are spread over lock-striped shards by key hash, so operations on keys in
+    /// different shards don't contend.
+    shards: Arc<Vec<Shard>>,
+    len: Arc<AtomicUsize>,
+    #[cfg(test)]
+    time_source: T,
+    #[cfg(not(test))]
+    time_source: SystemTimeSource,
+    _marker: PhantomData<T>,
+    /// Expiry times of the entries, so expired ones can be dropped without a full sweep.
+    wheel: Arc<Mutex<TimingWheel>>,
+    /// Time the wheel was last advanced to, so writes within the same millisecond skip it.
+    reclaimed_until: Arc<AtomicU64>,
+    clock: Arc<AtomicU64>,
+    evictions: Arc<AtomicU64>,
+    expired_removals: Arc<AtomicU64>,
+    /// Shared with clones so a new capacity applies everywhere. `UNBOUNDED` means no limit.
+    capacity: Arc<AtomicUsize>,
+    /// Shared like `capacity`; zero means no limit.
+    max_weight: Arc<AtomicU64>,
+    weight: Arc<AtomicU64>,
+    weigher: Weigher,
+    eviction_policy: EvictionPolicy,
+    /// Access counts for `EvictionPolicy::TinyLfu`.
+    sketch: Option<Arc<FrequencySketch>>,
+    /// Entries in the `TinyLfu` window.
+    window_len: Arc<AtomicUsize>,
+    /// The entry that last left the `TinyLfu` window, up for admission at the next eviction.
+    admission_candidate: Arc<Mutex<Option<String>>>,
+    protected_len: Arc<AtomicUsize>,
+    protected_share: f64,
+    track_last_read: bool,
+    /// Set with `set_tombstone_lifetime`.
+    tombstones: Option<Arc<Mutex<Tombstones>>>,
+    /// Set with `set_stale_grace`.
+    stale: Option<Arc<Mutex<StaleCopies>>>,
+    /// In milliseconds, like the decay interval.
+    max_age: Option<u64>,
+    lfu_decay: Option<u64>,
+    /// Keys `EvictionPolicy::TinyLfu` refused to admit, kept once `record_denied_admissions`
+    /// was called and until `take_denied_admissions`.
+    denied_admissions: Option<Arc<Mutex<Vec<String>>>>,
+    listeners: Listeners,
+}
+
+/// Scales `ttl` by a random factor in `1 - factor..=1 + factor`, never going below a
+/// millisecond. A TTL without expiry is returned as is.
+pub(crate) fn jitter_ttl(ttl: Duration, factor: f64) -> Duration {
+    if expiry_millis(ttl).is_none() {
+        return ttl;
+    }
+    let scale = 1.0 + factor * (2.0 * rand::random::<f64>() - 1.0);
+    Duration::from_millis(((millis(ttl) as f64 * scale).round() as u64).max(1))
+}
+
+/// `duration` in whole milliseconds, saturating at `u64::MAX`.
+pub(crate) fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// TTLs from this long on are kept without expiry, as Redis can't set them.
+const MAX_EXPIRY_MILLIS: u64 = i64::MAX as u64 / 2;
+
+/// `ttl` in milliseconds, at least one, or `None` if it means no expiry: a zero TTL, or one
+/// too long to expire, like `Duration::MAX`.
+pub(crate) fn expiry_millis(ttl: Duration) -> Option<u64> {
+    let ttl_millis = millis(ttl);
+    if ttl.is_zero() || ttl_millis >= MAX_EXPIRY_MILLIS {
+        None
+    } else {
+        Some(ttl_millis.max(1))
+    }
+}
+
+/// Unix time in milliseconds a value stored at `stored_at` with `ttl` expires at, `u64::MAX`
+/// if never.
+pub(crate) fn expiry(stored_at: u64, ttl: Duration) -> u64 {
+    expiry_millis(ttl).map_or(u64::MAX, |ttl| stored_at.saturating_add(ttl))
+}
+
+/// The shorter of two TTLs, counting a TTL without expiry as longer than any other.
+pub(crate) fn shorter_ttl(a: Duration, b: Duration) -> Duration {
+    match (expiry_millis(a), expiry_millis(b)) {
+        (None, _) => b,
+        (_, None) => a,
+        _ => a.min(b),
+    }
+}
+
+fn new_shards() -> Arc<Vec<Shard>> {
+    Arc::new((0..SHARD_COUNT).map(|_| Shard::default()).collect())
+}
+
+/// Adds `delta` to the counter in `value`, leaving it as it is if it is not a counter or the
+/// sum overflows.
+fn add_to_counter(value: &mut BytesMut, delta: i64) -> Result<i64, InMemoryCacheError> {
+    let count = std::str::from_utf8(value)
+        .ok()
+        .and_then(|count| count.parse::<i64>().ok())
+        .ok_or(InMemoryCacheError::NotACounter)?
+        .checked_add(delta)
+        .ok_or(InMemoryCacheError::CounterOverflow)?;
+    value.clear();
+    value.extend_from_slice(count.to_string().as_bytes());
+    Ok(count)
+}
+
+impl<T: TimeSource> InMemoryCache<T> {
+    /// Entries are dropped `max_age` after the key was first stored, even if `replace` kept
+    /// writing newer values over it in the meantime.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(millis(max_age));
+    }
+
+    /// Halves the LFU access counts every `interval`, so keys that were popular once but are
+    /// no longer read eventually become eviction candidates. Counts are decayed when an entry
+    /// is next read, written or considered for eviction.
+    pub fn set_lfu_decay(&mut self, interval: Duration) {
+        self.lfu_decay = Some(millis(interval).max(1));
+    }
+
+    fn frequency_epoch(&self, now: u64) -> u64 {
+        self.lfu_decay.map_or(0, |interval| now / interval)
+    }
+
+    fn frequency(&self, value: &CacheValue, now: u64) -> u32 {
+        let halvings = self
+            .frequency_epoch(now)
+            .saturating_sub(value.frequency_epoch.load(Ordering::Relaxed));
+        value
+            .frequency
+            .load(Ordering::Relaxed)
+            .checked_shr(halvings as u32)
+            .unwrap_or(0)
+    }
+
+    /// Concurrent readers may lose a count, which only matters for exact LFU ties.
+    fn record_use(&self, value: &CacheValue, now: u64) {
+        let frequency = self.frequency(value, now).saturating_add(1);
+        value.frequency.store(frequency, Ordering::Relaxed);
+        value
+            .frequency_epoch
+            .store(self.frequency_epoch(now), Ordering::Relaxed);
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn expires_at(&self, value: &CacheValue) -> u64 {
+        let ttl_expiry = expiry(value.timestamp, value.ttl);
+        match self.max_age {
+            Some(max_age) => ttl_expiry.min(value.created.saturating_add(max_age)),
+            None => ttl_expiry,
+        }
+    }
+
+    fn is_expired(&self, value: &CacheValue, now: u64) -> bool {
+        !value.pinned && now >= self.expires_at(value)
+    }
+
+    /// Calls `listener` for every insert, hit, miss, expiration and eviction in this cache and
+    /// its clones, on the thread that caused it and after the entry's lock is released.
+    pub fn on_event<F>(&self, listener: F)
+    where
+        F: Fn(&CacheEvent) + Send + Sync + 'static,
+    {
+        self.listeners.add(Arc::new(listener));
+    }
+
+    fn emit(&self, key: &str, kind: CacheEventKind) {
+        self.listeners.emit(key, kind, Tier::Memory);
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Bytes> {
+        self.record_access(key);
+        let now = self.time_source.now_millis();
+        let shard = self.shard(key);
+        let entries = shard.read();
+        let Some(cached_value) = entries.get(key) else {
+            drop(entries);
+            self.emit(key, CacheEventKind::Miss);
+            return None;
+        };
+        if self.is_expired(cached_value, now) {
+            drop(entries);
+            self.remove_if_expired(key, now);
+            self.emit(key, CacheEventKind::Miss);
+            return None;
+        }
+        let tick = self.tick();
+        cached_value.reads.fetch_add(1, Ordering::Relaxed);
+        if self.track_last_read {
+            cached_value.last_read.store(now, Ordering::Relaxed);
+        }
+        self.record_use(cached_value, now);
+        let promoted = self.requeue(shard, cached_value, tick);
+        let value = cached_value.value.clone();
+        drop(entries);
+        if promoted {
+            self.balance_segments();
+        }
+        self.emit(key, CacheEventKind::Hit);
+        Some(value)
+    }
+
+    /// Same as `get`, with the time the entry has left, `None` if it never expires or is
+    /// pinned.
+    pub fn get_with_ttl(&mut self, key: &str) -> Option<(Bytes, Option<Duration>)> {
+        let value = self.get(key)?;
+        let meta = self.meta(key)?;
+        let remaining = (!meta.pinned && meta.expires_at != u64::MAX).then(|| {
+            Duration::from_millis(
+                meta.expires_at
+                    .saturating_sub(self.time_source.now_millis()),
+            )
+        });
+        Some((value, remaining))
+    }
+
+    /// Restarts the TTL of a live entry from now, as if it had just been stored. `max_age`
+    /// still applies. Returns false if the key is missing or expired.
+    pub fn touch(&self, key: &str) -> bool {
+        self.restart(key, None)
+    }
+
+    /// Same as `touch`, replacing the TTL of the entry with `ttl`.
+    pub fn touch_with_ttl(&self, key: &str, ttl: Duration) -> bool {
+        self.restart(key, Some(ttl))
+    }
+
+    fn restart(&self, key: &str, ttl: Option<Duration>) -> bool {
+        let now = self.time_source.now_millis();
+        let shard = self.shard(key);
+        let mut entries = shard.write();
+        match entries.get_mut(key) {
+            Some(value) if !self.is_expired(value, now) => {
+                value.timestamp = now;
+                value.ttl = ttl.unwrap_or(value.ttl);
+                self.schedule_expiry(key, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total weight of the entries, as measured by the weigher.
+    pub fn weight(&self) -> u64 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    fn weigh(&self, key: &str, value: &[u8]) -> u64 {
+        (self.weigher)(key, value)
+    }
+
+    /// Accounts for the entry about to be stored under `key` in `shard`, queues it for
+    /// eviction and schedules its expiry.
+    fn added(&self, shard: &Shard, key: &str, value: &mut CacheValue, segment: Segment) {
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.weight.fetch_add(value.weight, Ordering::Relaxed);
+        self.enqueue(shard, key, value, segment);
+        self.schedule_expiry(key, value);
+    }
+
+    /// The segment new entries start in.
+    fn entry_segment(&self) -> Segment {
+        match self.eviction_policy {
+            EvictionPolicy::TinyLfu => Segment::Window,
+            _ => Segment::Probation,
+        }
+    }
+
+    /// Puts the entry at the back of `segment` in its shard's eviction queue, unless it is
+    /// pinned.
+    fn enqueue(&self, shard: &Shard, key: &str, value: &mut CacheValue, segment: Segment) {
+        if value.pinned {
+            return;
+        }
+        let tick = self.tick();
+        let mut queue = shard.queue();
+        let slot = queue.push(key, segment, tick);
+        if self.eviction_policy == EvictionPolicy::Lfu {
+            queue.set_rank(slot, self.lfu_rank(value, tick));
+        }
+        if segment == Segment::Window {
+            self.window_len.fetch_add(1, Ordering::Relaxed);
+        }
+        value.slot = Some(slot);
+    }
+
+    fn dequeue(&self, shard: &Shard, value: &CacheValue) {
+        if let Some(slot) = value.slot {
+            if shard.queue().remove(slot) == Segment::Window {
+                self.window_len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The segment the entry under `key` is queued in, `None` if it is missing or pinned.
+    fn segment_of(&self, key: &str) -> Option<Segment> {
+        let shard = self.shard(key);
+        let entries = shard.read();
+        let slot = entries.get(key)?.slot?;
+        let segment = shard.queue().segment(slot);
+        Some(segment)
+    }
+
+    /// Moves an entry just read to where the eviction policy now ranks it. Returns true if
+    /// that promoted it to the protected segment of `EvictionPolicy::Slru`.
+    fn requeue(&self, shard: &Shard, value: &CacheValue, tick: u64) -> bool {
+        let Some(slot) = value.slot else {
+            return false;
+        };
+        match self.eviction_policy {
+            EvictionPolicy::Fifo => {}
+            EvictionPolicy::Lfu => shard.queue().set_rank(slot, self.lfu_rank(value, tick)),
+            EvictionPolicy::Slru => {
+                let mut queue = shard.queue();
+                queue.move_to(slot, Segment::Protected, tick);
+                if !value.protected.swap(true, Ordering::Relaxed) {
+                    self.protected_len.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+            }
+            _ => shard.queue().touch(slot, tick),
+        }
+        false
+    }
+
+    /// Orders entries for `EvictionPolicy::Lfu` by their access counts decayed to a common
+    /// point in time, then by when they were last used. A count halved once per elapsed
+    /// epoch compares as `count * 2^epoch`, kept in range as its logarithm.
+    fn lfu_rank(&self, value: &CacheValue, tick: u64) -> (u64, u64) {
+        let frequency = f64::from(value.frequency.load(Ordering::Relaxed).max(1));
+        let epoch = value.frequency_epoch.load(Ordering::Relaxed) as f64;
+        ((epoch + frequency.log2()).to_bits(), tick)
+    }
+
+    fn schedule_expiry(&self, key: &str, value: &CacheValue) {
+        let expires_at = self.expires_at(value);
+        if expires_at != u64::MAX {
+            self.wheel
+                .lock()
+                .unwrap()
+                .schedule(key.to_owned(), expires_at);
+        }
+    }
+
+    /// Drops the entries the timing wheel has seen expire by `now`. Keys it hands back that
+    /// were since removed, overwritten or touched are left alone.
+    fn reclaim_expired(&self, now: u64) {
+        if self.reclaimed_until.fetch_max(now, Ordering::Relaxed) >= now {
+            return;
+        }
+        let due = self.wheel.lock().unwrap().advance(now);
+        for key in due {
+            self.remove_if_expired(&key, now);
+        }
+    }
+
+    /// Removes the entry under `key` if it is still expired once the shard is locked for
+    /// writing, reporting the expiration.
+    fn remove_if_expired(&self, key: &str, now: u64) {
+        let shard = self.shard(key);
+        let mut entries = shard.write();
+        if !entries
+            .get(key)
+            .is_some_and(|value| self.is_expired(value, now))
+        {
+            return;
+        }
+        if let Some(expired) = entries.remove(key) {
+            self.removed(shard, &expired);
+            self.keep_stale(key, &expired, now);
+        }
+        self.expired_removals.fetch_add(1, Ordering::Relaxed);
+        drop(entries);
+        self.emit(key, CacheEventKind::Expiration);
+    }
+
+    fn removed(&self, shard: &Shard, value: &CacheValue) {
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.weight.fetch_sub(value.weight, Ordering::Relaxed);
+        if value.is_protected() {
+            self.protected_len.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.dequeue(shard, value);
+    }
+
+    /// Live entries dropped to stay within capacity.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn expired_removals(&self) -> u64 {
+        self.expired_removals.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_stats(&self) {
+        self.evictions.store(0, Ordering::Relaxed);
+        self.expired_removals.store(0, Ordering::Relaxed);
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn remove_expired(&self, now: u64) {
+        let listening = !self.listeners.is_empty();
+        let mut expired = Vec::new();
+        for shard in self.shards.iter() {
+            let mut entries = shard.write();
+            let before = entries.len();
+            let mut weight = 0;
+            let mut protected = 0;
+            entries.retain(|key, value| {
+                let live = !self.is_expired(value, now);
+                if !live {
+                    weight += value.weight;
+                    protected += value.is_protected() as usize;
+                    self.dequeue(shard, value);
+                    self.keep_stale(key, value, now);
+                    if listening {
+                        expired.push(key.clone());
+                    }
+                }
+                live
+            });
+            let removed = before - entries.len();
+            self.len.fetch_sub(removed, Ordering::Relaxed);
+            self.weight.fetch_sub(weight, Ordering::Relaxed);
+            self.protected_len.fetch_sub(protected, Ordering::Relaxed);
+            self.expired_removals
+                .fetch_add(removed as u64, Ordering::Relaxed);
+        }
+        for key in &expired {
+            self.emit(key, CacheEventKind::Expiration);
+        }
+    }
+
+    /// Frees a slot for a new key when the cache is at capacity, and enough weight for a
+    /// value of `weight` under `key` when it would go past `max_weight`, evicting entries
+    /// chosen by the eviction policy. Expired entries were already dropped by the timing
+    /// wheel. Shards are locked one at a time, so concurrent inserts can briefly overshoot
+    /// either limit.
+    fn make_room(&self, key: &str, weight: u64) {
+        let at_capacity = || {
+            self.capacity()
+                .is_some_and(|capacity| self.len() >= capacity)
+                && !self.shard(key).read().contains_key(key)
+        };
+        let overweight = || {
+            self.max_weight().is_some_and(|max_weight| {
+                let replaced = self
+                    .shard(key)
+                    .read()
+                    .get(key)
+                    .map_or(0, |value| value.weight);
+                self.weight().saturating_sub(replaced) + weight > max_weight
+            })
+        };
+        if self.too_heavy(weight) || !(at_capacity() || overweight()) {
+            return;
+        }
+
+        if at_capacity() {
+            self.evict_one();
+        }
+        while overweight() && self.evict_one() {}
+    }
+
+    fn max_weight(&self) -> Option<u64> {
+        match self.max_weight.load(Ordering::Relaxed) {
+            0 => None,
+            max_weight => Some(max_weight),
+        }
+    }
+
+    /// Whether a value of `weight` could never fit, and so is not stored at all.
+    fn too_heavy(&self, weight: u64) -> bool {
+        self.max_weight()
+            .is_some_and(|max_weight| weight > max_weight)
+    }
+
+    /// Drops the entry under `key` in place of storing a newer value too heavy to keep, so
+    /// reads don't return the outdated one.
+    fn drop_outweighed(&self, key: &str) {
+        self.evict(self.shard(key), key);
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        match self.capacity.load(Ordering::Relaxed) {
+            UNBOUNDED => None,
+            capacity => Some(capacity),
+        }
+    }
+
+    /// Changes the capacity of this cache and all its clones, evicting entries right away
+    /// if it shrank below the current size.
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.capacity
+            .store(capacity.unwrap_or(UNBOUNDED), Ordering::Relaxed);
+        let Some(capacity) = capacity else {
+            return;
+        };
+        if self.len() > capacity {
+            self.remove_expired(self.time_source.now_millis());
+        }
+        while self.len() > capacity && self.evict_one() {}
+        self.balance_segments();
+    }
+
+    /// Changes the maximum total weight of this cache and all its clones, evicting entries
+    /// right away if they weigh more. `None` means no limit.
+    pub fn set_max_weight(&self, max_weight: Option<u64>) {
+        self.max_weight
+            .store(max_weight.unwrap_or(0), Ordering::Relaxed);
+        let Some(max_weight) = max_weight else {
+            return;
+        };
+        if self.weight() > max_weight {
+            self.remove_expired(self.time_source.now_millis());
+        }
+        while self.weight() > max_weight && self.evict_one() {}
+    }
+
+    /// Measures entries stored from now on with `weigher` instead of by value length.
+    pub fn set_weigher(&mut self, weigher: Weigher) {
+        self.weigher = weigher;
+    }
+
+    pub fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+        self.sketch = (eviction_policy == EvictionPolicy::TinyLfu).then(|| {
+            let capacity = self.capacity().unwrap_or(DEFAULT_SKETCH_CAPACITY);
+            Arc::new(FrequencySketch::new(capacity))
+        });
+    }
+
+    /// Share of the capacity the protected segment of `EvictionPolicy::Slru` may take up,
+    /// 0.8 unless set.
+    pub fn set_slru_protected_share(&mut self, share: f64) {
+        self.protected_share = share.clamp(0.0, 1.0);
+    }
+
+    /// Records when each entry was last read, for `EntryMeta::last_read`. Off by default to
+    /// spare reads the extra store.
+    pub fn set_track_last_read(&mut self, enabled: bool) {
+        self.track_last_read = enabled;
+    }
+
+    /// Moves the least recently used protected entries back to probation, as if just
+    /// stored, until the protected segment fits its share of the capacity.
+    fn balance_segments(&self) {
+        let Some(capacity) = self.capacity() else {
+            return;
+        };
+        let max_protected = (capacity as f64 * self.protected_share) as usize;
+        while self.protected_len.load(Ordering::Relaxed) > max_protected {
+            let Some((shard, key)) = self.oldest(Segment::Protected) else {
+                return;
+            };
+            let entries = shard.read();
+            let Some(value) = entries.get(&key) else {
+                continue;
+            };
+            let Some(slot) = value.slot else {
+                continue;
+            };
+            let mut queue = shard.queue();
+            if queue.segment(slot) == Segment::Protected
+                && value.protected.swap(false, Ordering::Relaxed)
+            {
+                queue.move_to(slot, Segment::Probation, self.tick());
+                self.protected_len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Keeps the keys of entries `EvictionPolicy::TinyLfu` drops as they leave the window,
+    /// for `take_denied_admissions`. Clones made after the call share them.
+    pub fn record_denied_admissions(&mut self) {
+        self.denied_admissions = Some(Arc::default());
+    }
+
+    /// The keys denied admission since the last call, oldest first.
+    pub fn take_denied_admissions(&self) -> Vec<String> {
+        self.denied_admissions
+            .as_ref()
+            .map_or_else(Vec::new, |denied| mem::take(&mut *denied.lock().unwrap()))
+    }
+
+    fn record_access(&self, key: &str) {
+        if let Some(sketch) = &self.sketch {
+            sketch.increment(key);
+        }
+    }
+
+    /// Drops the entry chosen by the eviction policy. Returns false if every entry is pinned.
+    fn evict_one(&self) -> bool {
+        if let Some(sketch) = &self.sketch {
+            return self.evict_admitting(sketch);
+        }
+        let victim = match self.eviction_policy {
+            EvictionPolicy::Lfu => self.least_frequent(),
+            EvictionPolicy::Slru => self
+                .oldest(Segment::Probation)
+                .or_else(|| self.oldest(Segment::Protected)),
+            _ => self.oldest(Segment::Probation),
+        };
+        let Some((shard, key)) = victim else {
+            return false;
+        };
+        self.evict(shard, &key);
+        true
+    }
+
+    /// The entry queued in `segment` longest ago, comparing the fronts of the shards' queues.
+    fn oldest(&self, segment: Segment) -> Option<(&Shard, String)> {
+        let mut oldest: Option<(u64, &Shard, String)> = None;
+        for shard in self.shards.iter() {
+            if let Some((stamp, key)) = shard.queue().oldest(segment) {
+                if oldest.as_ref().is_none_or(|(first, _, _)| stamp < *first) {
+                    oldest = Some((stamp, shard, key.to_owned()));
+                }
+            }
+        }
+        oldest.map(|(_, shard, key)| (shard, key))
+    }
+
+    /// The entry with the lowest LFU rank, comparing the lowest of every shard.
+    fn least_frequent(&self) -> Option<(&Shard, String)> {
+        let mut least: Option<((u64, u64), &Shard, String)> = None;
+        for shard in self.shards.iter() {
+            if let Some((rank, key)) = shard.queue().lowest_rank() {
+                if least.as_ref().is_none_or(|(lowest, _, _)| rank < *lowest) {
+                    least = Some((rank, shard, key.to_owned()));
+                }
+            }
+        }
+        least.map(|(_, shard, key)| (shard, key))
+    }
+
+    /// Drops one entry the W-TinyLFU way. The entry that most recently left the window is
+    /// the candidate for admission: it stays and the least recently used probation entry
+    /// goes if the sketch counts more requests for it, otherwise it goes itself.
+    fn evict_admitting(&self, sketch: &FrequencySketch) -> bool {
+        let candidate = self
+            .admission_candidate
+            .lock()
+            .unwrap()
+            .take()
+            .filter(|key| self.segment_of(key) == Some(Segment::Probation));
+        let victim = self
+            .oldest(Segment::Probation)
+            .or_else(|| self.oldest(Segment::Window));
+        let Some((victim_shard, victim)) = victim else {
+            return false;
+        };
+        let (shard, key) = match candidate {
+            Some(candidate) if candidate != victim => {
+                if sketch.frequency(&candidate) > sketch.frequency(&victim) {
+                    (victim_shard, victim)
+                } else {
+                    if let Some(denied) = &self.denied_admissions {
+                        denied.lock().unwrap().push(candidate.clone());
+                    }
+                    (self.shard(&candidate), candidate)
+                }
+            }
+            _ => (victim_shard, victim),
+        };
+        self.evict(shard, &key);
+        true
+    }
+
+    /// Moves the least recently used entries of the `EvictionPolicy::TinyLfu` window to the
+    /// probation segment while the window holds more than 1% of the capacity. The last one
+    /// moved becomes the candidate for admission.
+    fn shrink_window(&self) {
+        if self.sketch.is_none() {
+            return;
+        }
+        let window = (self.capacity().unwrap_or_else(|| self.len()) / 100).max(1);
+        while self.window_len.load(Ordering::Relaxed) > window {
+            let Some((shard, key)) = self.oldest(Segment::Window) else {
+                return;
+            };
+            let entries = shard.read();
+            let Some(slot) = entries.get(&key).and_then(|value| value.slot) else {
+                continue;
+            };
+            let mut queue = shard.queue();
+            if queue.segment(slot) == Segment::Window {
+                queue.move_to(slot, Segment::Probation, self.tick());
+                self.window_len.fetch_sub(1, Ordering::Relaxed);
+                *self.admission_candidate.lock().unwrap() = Some(key);
+            }
+        }
+    }
+
+    fn evict(&self, shard: &Shard, key: &str) {
+        if self.remove_from(shard, key) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            self.emit(key, CacheEventKind::Eviction);
+        }
+    }
+
+    fn remove_from(&self, shard: &Shard, key: &str) -> bool {
+        let mut entries = shard.write();
+        let removed = entries.remove(key);
+        if let Some(value) = &removed {
+            self.removed(shard, value);
+        }
+        removed.is_some()
+    }
+
+    pub fn set(&mut self, payload: SetPayload) -> Result<Bytes, InMemoryCacheError> {
+        self.set_computed(payload, 0.0)
+    }
+
+    /// Same as `set`, recording that the value took `delta` seconds to compute.
+    pub fn set_computed(
+        &mut self,
+        payload: SetPayload,
+        delta: f64,
+    ) -> Result<Bytes, InMemoryCacheError> {
+        if payload.key.is_empty() {
+            return Err(InMemoryCacheError::EmptyKey);
+        }
+
+        let now = self.time_source.now_millis();
+        self.reclaim_expired(now);
+
+        let weight = self.weigh(payload.key, payload.value);
+        self.record_access(payload.key);
+        self.make_room(payload.key, weight);
+
+        let shard = self.shard(payload.key);
+        let mut entries = shard.write();
+        match entries.get(payload.key) {
+            Some(cached_value) if !self.is_expired(cached_value, now) => {
+                return Ok(cached_value.value.clone())
+            }
+            _ => {}
+        }
+        let value = Bytes::copy_from_slice(payload.value);
+        if self.too_heavy(weight) {
+            return Ok(value);
+        }
+        let mut cached_value = CacheValue {
+            value: value.clone(),
+            weight,
+            timestamp: now,
+            ttl: payload.ttl,
+            delta,
+            created: now,
+            reads: AtomicU64::new(0),
+            last_read: AtomicU64::new(0),
+            pinned: false,
+            protected: AtomicBool::new(false),
+            frequency: AtomicU32::new(1),
+            frequency_epoch: AtomicU64::new(self.frequency_epoch(now)),
+            slot: None,
+        };
+        self.added(shard, payload.key, &mut cached_value, self.entry_segment());
+        let expired = entries.insert(payload.key.to_owned(), cached_value);
+        if let Some(expired) = &expired {
+            self.removed(shard, expired);
+            self.expired_removals.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(entries);
+        self.shrink_window();
+        if expired.is_some() {
+            self.emit(payload.key, CacheEventKind::Expiration);
+        }
+        self.emit(payload.key, CacheEventKind::Insert);
+        Ok(value)
+    }
+
+    /// Stores the value only if there is no live entry under the key, checking and inserting
+    /// under one lock. Returns whether it was stored.
+    pub fn set_if_absent(&mut self, payload: SetPayload) -> Result<bool, InMemoryCacheError> {
+        if payload.key.is_empty() {
+            return Err(InMemoryCacheError::EmptyKey);
+        }
+
+        let now = self.time_source.now_millis();
+        self.reclaim_expired(now);
+        let weight = self.weigh(payload.key, payload.value);
+        self.record_access(payload.key);
+        self.make_room(payload.key, weight);
+
+        let shard = self.shard(payload.key);
+        let mut entries = shard.write();
+        if let Some(value) = entries.get(payload.key) {
+            if !self.is_expired(value, now) {
+                return Ok(false);
+            }
+        }
+        if self.too_heavy(weight) {
+            return Ok(false);
+        }
+        let mut cached_value = CacheValue {
+            value: Bytes::copy_from_slice(payload.value),
+            weight,
+            timestamp: now,
+            ttl: payload.ttl,
+            delta: 0.0,
+            created: now,
+            reads: AtomicU64::new(0),
+            last_read: AtomicU64::new(0),
+            pinned: false,
+            protected: AtomicBool::new(false),
+            frequency: AtomicU32::new(1),
+            frequency_epoch: AtomicU64::new(self.frequency_epoch(now)),
+            slot: None,
+        };
+        self.added(shard, payload.key, &mut cached_value, self.entry_segment());
+        let expired = entries.insert(payload.key.to_owned(), cached_value);
+        if let Some(expired) = &expired {
+            self.removed(shard, expired);
+            self.expired_removals.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(entries);
+        self.shrink_window();
+        if expired.is_some() {
+            self.emit(payload.key, CacheEventKind::Expiration);
+        }
+        self.emit(payload.key, CacheEventKind::Insert);
+        Ok(true)
+    }
+
+    /// Stores the value even if a live entry exists, recording how long it took to compute.
+    pub fn replace(&mut self, payload: SetPayload, delta: f64) -> Result<(), InMemoryCacheError> {
+        if payload.key.is_empty() {
+            return Err(InMemoryCacheError::EmptyKey);
+        }
+
+        let now = self.time_source.now_millis();
+        self.reclaim_expired(now);
+        let weight = self.weigh(payload.key, payload.value);
+        if self.too_heavy(weight) {
+            self.drop_outweighed(payload.key);
+            return Ok(());
+        }
+        self.record_access(payload.key);
+        self.make_room(payload.key, weight);
+
+        let shard = self.shard(payload.key);
+        let mut entries = shard.write();
+        let (created, reads, last_read, pinned, protected, frequency, segment) = entries
+            .get(payload.key)
+            .filter(|value| !self.is_expired(value, now))
+            .map_or(
+                (now, 0, 0, false, false, 0, self.entry_segment()),
+                |value| {
+                    (
+                        value.created,
+                        value.reads.load(Ordering::Relaxed),
+                        value.last_read.load(Ordering::Relaxed),
+                        value.pinned,
+                        value.is_protected(),
+                        self.frequency(value, now),
+                        value
+                            .slot
+                            .map_or(self.entry_segment(), |slot| shard.queue().segment(slot)),
+                    )
+                },
+            );
+        let mut cached_value = CacheValue {
+            value: Bytes::copy_from_slice(payload.value),
+            weight,
+            timestamp: now,
+            ttl: payload.ttl,
+            delta,
+            created,
+            reads: AtomicU64::new(reads),
+            last_read: AtomicU64::new(last_read),
+            pinned,
+            protected: AtomicBool::new(protected),
+            frequency: AtomicU32::new(frequency.saturating_add(1)),
+            frequency_epoch: AtomicU64::new(self.frequency_epoch(now)),
+            slot: None,
+        };
+        self.added(shard, payload.key, &mut cached_value, segment);
+        let previous = entries.insert(payload.key.to_owned(), cached_value);
+        if let Some(previous) = &previous {
+            self.removed(shard, previous);
+        }
+        if protected {
+            self.protected_len.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(entries);
+        self.shrink_window();
+        self.emit(payload.key, CacheEventKind::Insert);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.bury(key);
+        self.discard_stale(key);
+        self.remove_from(self.shard(key), key)
+    }
+
+    /// Remembers when keys were removed for `lifetime`, for `removed_since`. Clones made
+    /// after the call share the tombstones, so removals by an invalidation listener count.
+    pub fn set_tombstone_lifetime(&mut self, lifetime: Duration) {
+        self.tombstones = Some(Arc::new(Mutex::new(Tombstones::new(millis(lifetime)))));
+    }
+
+    fn bury(&self, key: &str) {
+        if let Some(tombstones) = &self.tombstones {
+            let now = self.time_source.now_millis();
+            tombstones.lock().unwrap().bury(key, now);
+        }
+    }
+
+    /// Whether `key` was removed at or after `since`, Unix time in milliseconds, within the
+    /// tombstone lifetime. A value read elsewhere before then may be outdated. Always false
+    /// without a tombstone lifetime.
+    pub fn removed_since(&self, key: &str, since: u64) -> bool {
+        self.tombstones.as_ref().is_some_and(|tombstones| {
+            let now = self.time_source.now_millis();
+            tombstones.lock().unwrap().removed_since(key, since, now)
+        })
+    }
+
+    /// Keeps the values of entries for `grace` after they expire, for `get_stale`. Removed
+    /// entries are not kept. Clones made after the call share the copies.
+    pub fn set_stale_grace(&mut self, grace: Duration) {
+        self.stale = Some(Arc::new(Mutex::new(StaleCopies::new(millis(grace)))));
+    }
+
+    fn keep_stale(&self, key: &str, value: &CacheValue, now: u64) {
+        if let Some(stale) = &self.stale {
+            let expired_at = self.expires_at(value);
+            stale
+                .lock()
+                .unwrap()
+                .keep(key, value.value.clone(), expired_at, now);
+        }
+    }
+
+    fn discard_stale(&self, key: &str) {
+        if let Some(stale) = &self.stale {
+            stale.lock().unwrap().discard(key);
+        }
+    }
+
+    /// The value of `key` as it was when it expired, if that was within the stale grace
+    /// window. Always `None` without one.
+    pub fn get_stale(&self, key: &str) -> Option<Bytes> {
+        self.stale.as_ref().and_then(|stale| {
+            let now = self.time_source.now_millis();
+            stale.lock().unwrap().get(key, now)
+        })
+    }
+
+    /// Removes every entry whose key starts with `prefix`. Returns how many were removed.
+    pub fn remove_prefix(&mut self, prefix: &str) -> usize {
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            let keys: Vec<String> = shard
+                .read()
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            for key in keys {
+                self.bury(&key);
+                self.discard_stale(&key);
+                if self.remove_from(shard, &key) {
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Changes the value of a live entry with `f`, keeping its TTL. The buffer is changed in
+    /// place when no reader holds a clone of the value and copied first otherwise, so values
+    /// returned by `get` never change under their readers. Returns false if the key is
+    /// missing or expired.
+    pub fn update(&mut self, key: &str, f: impl FnOnce(&mut BytesMut)) -> bool {
+        let now = self.time_source.now_millis();
+        let shard = self.shard(key);
+        let mut entries = shard.write();
+        let Some(cached_value) = entries
+            .get_mut(key)
+            .filter(|value| !self.is_expired(value, now))
+        else {
+            return false;
+        };
+        let mut value = mem::take(&mut cached_value.value)
+            .try_into_mut()
+            .unwrap_or_else(|shared| BytesMut::from(&shared[..]));
+        f(&mut value);
+        cached_value.value = value.freeze();
+        let weight = self.weigh(key, &cached_value.value);
+        self.weight.fetch_add(weight, Ordering::Relaxed);
+        self.weight.fetch_sub(
+            mem::replace(&mut cached_value.weight, weight),
+            Ordering::Relaxed,
+        );
+        drop(entries);
+        if let Some(max_weight) = self.max_weight() {
+            while self.weight() > max_weight && self.evict_one() {}
+        }
+        true
+    }
+
+    /// Adds `delta` to the decimal integer stored under `key` and returns the result. A
+    /// missing counter starts at 0 and lives for `ttl`; existing ones keep their expiry.
+    pub fn increment(
+        &mut self,
+        key: &str,
+        delta: i64,
+        ttl: Duration,
+    ) -> Result<i64, InMemoryCacheError> {
+        // The counter can be created or removed between the two steps, so each is tried
+        // again once.
+        for _ in 0..2 {
+            let mut count = Err(InMemoryCacheError::NotACounter);
+            if self.update(key, |value| count = add_to_counter(value, delta)) {
+                return count;
+            }
+            let created = self.set_if_absent(SetPayload {
+                key,
+                value: delta.to_string().as_bytes(),
+                ttl,
+                tier_hint: None,
+            })?;
+            if created {
+                return Ok(delta);
+            }
+        }
+        // Too heavy to keep.
+        Ok(delta)
+    }
+
+    /// Re-jitters the remaining TTL of every live entry by up to `factor`, e.g. after a bulk
+    /// import left them expiring together. Returns how many entries were changed.
+    pub fn rebalance_ttls(&self, factor: f64) -> usize {
+        let now = self.time_source.now_millis();
+        let mut rebalanced = 0;
+        for shard in self.shards.iter() {
+            for (key, value) in shard.write().iter_mut() {
+                if value.pinned || expiry_millis(value.ttl).is_none() {
+                    continue;
+                }
+                let expires_at = expiry(value.timestamp, value.ttl);
+                let Some(remaining) = expires_at.checked_sub(now) else {
+                    continue;
+                };
+                if remaining == 0 {
+                    continue;
+                }
+                value.ttl = Duration::from_millis(now - value.timestamp)
+                    + jitter_ttl(Duration::from_millis(remaining), factor);
+                self.schedule_expiry(key, value);
+                rebalanced += 1;
+            }
+        }
+        rebalanced
     }
-}
 
-pub struct InMemoryCache<T: TimeSource = SystemTimeSource> {
-    values: Arc<Mutex<HashMap<String, CacheValue>>>,
-    #[cfg(test)]
-    time_source: T,
-    #[cfg(not(test))]
-    time_source: SystemTimeSource,
-    _marker: PhantomData<T>,
-    hits: Arc<Mutex<u64>>,
-}
+    /// Calls `f` with the key, value and stored TTL of every live entry, one shard at a time.
+    pub fn for_each_live(&self, mut f: impl FnMut(&str, &Bytes, Duration)) {
+        let now = self.time_source.now_millis();
+        for shard in self.shards.iter() {
+            for (key, value) in shard.read().iter() {
+                if !self.is_expired(value, now) {
+                    f(key, &value.value, value.ttl);
+                }
+            }
+        }
+    }
 
-impl<T: TimeSource> InMemoryCache<T> {
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        let values = self.values.lock().unwrap();
-        values.get(key).map(|value| value.value.to_owned())
+    /// Live entries with their timestamps and TTLs, as saved by `save_snapshot`.
+    pub fn snapshot(&self) -> Vec<SnapshotEntry> {
+        let now = self.time_source.now_millis();
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            for (key, value) in shard.read().iter() {
+                if !self.is_expired(value, now) {
+                    entries.push(SnapshotEntry {
+                        key: key.clone(),
+                        value: value.value.clone(),
+                        stored_at: value.timestamp,
+                        ttl: value.ttl,
+                        delta: value.delta,
+                        created: value.created,
+                        pinned: value.pinned,
+                    });
+                }
+            }
+        }
+        entries
     }
 
-    pub fn set(&mut self, payload: SetPayload) -> Result<String, InMemoryCacheError> {
-        if payload.key.is_empty() {
-            return Err(InMemoryCacheError::EmptyKey);
+    /// Saves the live entries to `path`, replacing the file only once the snapshot is
+    /// complete. Returns how many were saved.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<usize> {
+        let partial = path.with_extension("partial");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        let saved = snapshot::write_snapshot(&mut writer, &self.snapshot())?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(partial, path)?;
+        Ok(saved)
+    }
+
+    /// Puts back the entries of a snapshot saved by `save_snapshot` that are still live,
+    /// keeping when they were stored and their TTLs, so they expire as if never unloaded.
+    /// Existing entries under the same keys are replaced. Returns how many were loaded.
+    pub fn load_snapshot(&mut self, path: &Path) -> io::Result<usize> {
+        let entries = snapshot::read_snapshot(&mut BufReader::new(File::open(path)?))?;
+        let now = self.time_source.now_millis();
+        let mut loaded = 0;
+        for entry in entries {
+            if entry.key.is_empty() {
+                continue;
+            }
+            let weight = self.weigh(&entry.key, &entry.value);
+            let mut value = CacheValue {
+                value: entry.value,
+                weight,
+                timestamp: entry.stored_at,
+                ttl: entry.ttl,
+                delta: entry.delta,
+                created: entry.created,
+                reads: AtomicU64::new(0),
+                last_read: AtomicU64::new(0),
+                pinned: entry.pinned,
+                protected: AtomicBool::new(false),
+                frequency: AtomicU32::new(1),
+                frequency_epoch: AtomicU64::new(self.frequency_epoch(now)),
+                slot: None,
+            };
+            if self.is_expired(&value, now) || self.too_heavy(weight) {
+                continue;
+            }
+            self.make_room(&entry.key, weight);
+            let shard = self.shard(&entry.key);
+            let mut entries = shard.write();
+            self.added(shard, &entry.key, &mut value, self.entry_segment());
+            if let Some(previous) = entries.insert(entry.key, value) {
+                self.removed(shard, &previous);
+            }
+            drop(entries);
+            self.shrink_window();
+            loaded += 1;
         }
+        Ok(loaded)
+    }
+
+    pub fn meta(&self, key: &str) -> Option<EntryMeta> {
+        let shard = self.shard(key).read();
+        shard.get(key).map(|value| EntryMeta {
+            created: value.created,
+            expires_at: self.expires_at(value),
+            ttl: value.ttl,
+            delta: value.delta,
+            pinned: value.pinned,
+            size: value.value.len(),
+            reads: value.reads.load(Ordering::Relaxed),
+            last_read: Some(value.last_read.load(Ordering::Relaxed)).filter(|&at| at != 0),
+        })
+    }
+
+    /// Exempts the live entry under `key` from expiry and eviction until `unpin`. The pin
+    /// survives `replace` but not `remove`; while every entry is pinned, inserts go past the
+    /// capacity. Returns false if the key is missing or expired.
+    pub fn pin(&self, key: &str) -> bool {
+        self.set_pinned(key, true)
+    }
 
-        let mut hits = self.hits.lock().unwrap();
-        let mut values = self.values.lock().unwrap();
-        *hits += 1;
-        let now = self.time_source.now();
+    /// Lets the entry expire and be evicted again. Its TTL counts from when it was stored, so
+    /// an entry pinned past its expiry is dropped on the next access.
+    pub fn unpin(&self, key: &str) -> bool {
+        self.set_pinned(key, false)
+    }
 
-        if *hits % 50000 == 0 {
-            values.retain(|_, value| now < value.timestamp + value.ttl);
-        } else if let Some(cached_value) = values.get(payload.key) {
-            println!("{:?}", now >= cached_value.timestamp + cached_value.ttl);
-            if now >= cached_value.timestamp + cached_value.ttl {
-                values.remove(payload.key);
+    fn set_pinned(&self, key: &str, pinned: bool) -> bool {
+        let now = self.time_source.now_millis();
+        let shard = self.shard(key);
+        let mut entries = shard.write();
+        match entries.get_mut(key) {
+            Some(value) if !self.is_expired(value, now) => {
+                if pinned && !value.pinned {
+                    self.dequeue(shard, value);
+                    value.slot = None;
+                }
+                let unpinned = value.pinned && !pinned;
+                value.pinned = pinned;
+                if unpinned {
+                    let segment = if value.is_protected() {
+                        Segment::Protected
+                    } else {
+                        Segment::Probation
+                    };
+                    self.enqueue(shard, key, value, segment);
+                }
+                if !pinned {
+                    self.schedule_expiry(key, value);
+                }
+                true
             }
+            _ => false,
         }
+    }
 
-        Ok(values
-            .entry(payload.key.to_owned())
-            .or_insert(CacheValue {
-                value: payload.value.to_owned(),
-                timestamp: now,
-                ttl: payload.ttl,
-            })
-            .value
-            .to_owned())
+    /// Removes every pinned entry. Returns how many were removed.
+    pub fn flush_pinned(&self) -> usize {
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.write();
+            let before = shard.len();
+            let mut weight = 0;
+            let mut protected = 0;
+            shard.retain(|_, value| {
+                if value.pinned {
+                    weight += value.weight;
+                    protected += value.is_protected() as usize;
+                }
+                !value.pinned
+            });
+            removed += before - shard.len();
+            self.weight.fetch_sub(weight, Ordering::Relaxed);
+            self.protected_len.fetch_sub(protected, Ordering::Relaxed);
+        }
+        self.len.fetch_sub(removed, Ordering::Relaxed);
+        removed
     }
 }
 
 impl InMemoryCache<SystemTimeSource> {
     pub fn new() -> InMemoryCache<SystemTimeSource> {
         InMemoryCache {
-            values: Arc::new(Mutex::new(HashMap::new())),
+            shards: new_shards(),
+            len: Arc::new(AtomicUsize::new(0)),
+            time_source: SystemTimeSource,
+            _marker: PhantomData,
+            wheel: Arc::new(Mutex::new(TimingWheel::new())),
+            reclaimed_until: Arc::new(AtomicU64::new(0)),
+            clock: Arc::new(AtomicU64::new(0)),
+            evictions: Arc::new(AtomicU64::new(0)),
+            expired_removals: Arc::new(AtomicU64::new(0)),
+            capacity: Arc::new(AtomicUsize::new(UNBOUNDED)),
+            max_weight: Arc::new(AtomicU64::new(0)),
+            weight: Arc::new(AtomicU64::new(0)),
+            weigher: Arc::new(value_len),
+            eviction_policy: EvictionPolicy::default(),
+            sketch: None,
+            window_len: Arc::new(AtomicUsize::new(0)),
+            admission_candidate: Arc::default(),
+            protected_len: Arc::new(AtomicUsize::new(0)),
+            protected_share: DEFAULT_SLRU_PROTECTED_SHARE,
+            track_last_read: false,
+            tombstones: None,
+            stale: None,
+            max_age: None,
+            lfu_decay: None,
+            denied_admissions: None,
+            listeners: Listeners::default(),
+        }
+    }
+
+    /// Holds at most `capacity` entries, evicting according to `eviction_policy`.
+    pub fn with_capacity(
+        capacity: usize,
+        eviction_policy: EvictionPolicy,
+    ) -> InMemoryCache<SystemTimeSource> {
+        let mut cache = InMemoryCache {
+            capacity: Arc::new(AtomicUsize::new(capacity)),
+            ..InMemoryCache::new()
+        };
+        cache.set_eviction_policy(eviction_policy);
+        cache
+    }
+}
+
+/// Clones share the same underlying entries, so a clone can be handed to a background thread.
+impl Clone for InMemoryCache<SystemTimeSource> {
+    fn clone(&self) -> Self {
+        InMemoryCache {
+            shards: Arc::clone(&self.shards),
+            len: Arc::clone(&self.len),
             time_source: SystemTimeSource,
             _marker: PhantomData,
-            hits: Arc::new(Mutex::new(0)),
+            wheel: Arc::clone(&self.wheel),
+            reclaimed_until: Arc::clone(&self.reclaimed_until),
+            clock: Arc::clone(&self.clock),
+            evictions: Arc::clone(&self.evictions),
+            expired_removals: Arc::clone(&self.expired_removals),
+            capacity: Arc::clone(&self.capacity),
+            max_weight: Arc::clone(&self.max_weight),
+            weight: Arc::clone(&self.weight),
+            weigher: Arc::clone(&self.weigher),
+            eviction_policy: self.eviction_policy,
+            sketch: self.sketch.clone(),
+            window_len: Arc::clone(&self.window_len),
+            admission_candidate: Arc::clone(&self.admission_candidate),
+            protected_len: Arc::clone(&self.protected_len),
+            protected_share: self.protected_share,
+            track_last_read: self.track_last_read,
+            tombstones: self.tombstones.clone(),
+            stale: self.stale.clone(),
+            max_age: self.max_age,
+            lfu_decay: self.lfu_decay,
+            denied_admissions: self.denied_admissions.clone(),
+            listeners: self.listeners.clone(),
         }
     }
 }
 
+impl Default for InMemoryCache<SystemTimeSource> {
+    fn default() -> Self {
+        InMemoryCache::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,49 +1542,70 @@ mod tests {
         fn new_with_time_source(time_source: T) -> InMemoryCache<T> {
             InMemoryCache {
                 time_source,
-                values: Arc::new(Mutex::new(HashMap::new())),
+                shards: new_shards(),
+                len: Arc::new(AtomicUsize::new(0)),
                 _marker: PhantomData,
-                hits: Arc::new(Mutex::new(0)),
+                wheel: Arc::new(Mutex::new(TimingWheel::new())),
+                reclaimed_until: Arc::new(AtomicU64::new(0)),
+                clock: Arc::new(AtomicU64::new(0)),
+                evictions: Arc::new(AtomicU64::new(0)),
+                expired_removals: Arc::new(AtomicU64::new(0)),
+                capacity: Arc::new(AtomicUsize::new(UNBOUNDED)),
+                max_weight: Arc::new(AtomicU64::new(0)),
+                weight: Arc::new(AtomicU64::new(0)),
+                weigher: Arc::new(value_len),
+                eviction_policy: EvictionPolicy::default(),
+                sketch: None,
+                window_len: Arc::new(AtomicUsize::new(0)),
+                admission_candidate: Arc::default(),
+                protected_len: Arc::new(AtomicUsize::new(0)),
+                protected_share: DEFAULT_SLRU_PROTECTED_SHARE,
+                track_last_read: false,
+                tombstones: None,
+                stale: None,
+                max_age: None,
+                lfu_decay: None,
+                denied_admissions: None,
+                listeners: Listeners::default(),
             }
         }
 
-        fn set_hits(&mut self, hits: u64) {
-            let mut hits_val = self.hits.lock().unwrap();
-            *hits_val = hits;
-        }
-
         fn get_values_length(&self) -> usize {
-            self.values.lock().unwrap().len()
+            self.shards.iter().map(|shard| shard.read().len()).sum()
         }
 
-        fn get_value(&self, key: &str) -> String {
-            self.values
-                .lock()
-                .unwrap()
-                .get(key)
-                .unwrap()
-                .value
-                .to_owned()
+        fn get_value(&self, key: &str) -> Bytes {
+            self.shard(key).read().get(key).unwrap().value.clone()
         }
     }
 
     struct MockTimeSource {
-        now: u64,
+        now_millis: u64,
     }
 
     impl MockTimeSource {
         fn new(now: u64) -> Self {
-            MockTimeSource { now }
+            MockTimeSource {
+                now_millis: now * 1000,
+            }
         }
 
         fn advance(&mut self, secs: u64) {
-            self.now += secs;
+            self.advance_millis(secs * 1000);
+        }
+
+        fn advance_millis(&mut self, millis: u64) {
+            self.now_millis += millis;
         }
     }
 
     impl TimeSource for MockTimeSource {
         fn now(&self) -> u64 {
-            self.now
+            self.now_millis / 1000
+        }
+
+        fn now_millis(&self) -> u64 {
+            self.now_millis
         }
     }
 
@@ -162,8 +1621,9 @@ mod tests {
         let result = cache
             .set(SetPayload {
                 key: "key",
-                value: "value",
-                ttl: 1,
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         assert_eq!(result, "value");
@@ -176,8 +1636,9 @@ mod tests {
         cache
             .set(SetPayload {
                 key: "key",
-                value: "value",
-                ttl: 1,
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         assert_eq!(cache.get_values_length(), 1);
@@ -191,33 +1652,86 @@ mod tests {
         cache
             .set(SetPayload {
                 key: "key",
-                value: "value",
-                ttl: 1,
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         let cached = cache.set(SetPayload {
             key: "key",
-            value: "value123",
-            ttl: 1,
+            value: b"value123",
+            ttl: Duration::from_secs(1),
+            tier_hint: None,
         });
         assert_eq!(cached.unwrap(), "value");
     }
 
+    #[test]
+    fn it_should_expire_sub_second_ttls_and_max_age() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache.set_max_age(Duration::from_millis(600));
+        for (key, ttl) in [("short", 250), ("long", 10_000)] {
+            cache
+                .set(SetPayload {
+                    key,
+                    value: b"value",
+                    ttl: Duration::from_millis(ttl),
+                    tier_hint: None,
+                })
+                .expect("Should not fail");
+        }
+        cache.time_source.advance_millis(200);
+        assert!(cache.get("short").is_some());
+        cache.time_source.advance_millis(100);
+        assert!(cache.get("short").is_none());
+        assert!(cache.get("long").is_some());
+        cache.time_source.advance_millis(300);
+        assert!(cache.get("long").is_none());
+    }
+
+    #[test]
+    fn it_should_rebalance_ttls_within_factor() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            cache
+                .set(SetPayload {
+                    key,
+                    value: b"value",
+                    ttl: Duration::from_secs(1000),
+                    tier_hint: None,
+                })
+                .expect("Should not fail");
+        }
+
+        assert_eq!(cache.rebalance_ttls(0.5), 100);
+        let expiries: Vec<u64> = keys
+            .iter()
+            .map(|key| cache.meta(key).unwrap().expires_at)
+            .collect();
+        assert!(expiries
+            .iter()
+            .all(|&at| (500_000..=1_500_000).contains(&at)));
+        assert!(expiries.iter().any(|&at| at != expiries[0]));
+    }
+
     #[test]
     fn it_should_change_value_on_expiry() {
         let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
         cache
             .set(SetPayload {
                 key: "key",
-                value: "value",
-                ttl: 1,
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         cache.time_source.advance(2);
         let cached = cache.set(SetPayload {
             key: "key",
-            value: "value123",
-            ttl: 1,
+            value: b"value123",
+            ttl: Duration::from_secs(1),
+            tier_hint: None,
         });
         assert_eq!(cached.unwrap(), "value123");
     }
@@ -231,15 +1745,17 @@ mod tests {
             cache
                 .set(SetPayload {
                     key: &format!("key{}", i),
-                    value: &format!("value{}", i),
-                    ttl: 100,
+                    value: format!("value{}", i).as_bytes(),
+                    ttl: Duration::from_secs(100),
+                    tier_hint: None,
                 })
                 .expect("Should not fail");
         }
         let result = cache.set(SetPayload {
             key: "key30",
-            value: "value",
-            ttl: 100,
+            value: b"value",
+            ttl: Duration::from_secs(100),
+            tier_hint: None,
         });
         let elapsed = now.elapsed().unwrap().as_millis();
         assert_eq!(&result.unwrap(), "value30");
@@ -251,36 +1767,537 @@ mod tests {
     }
 
     #[test]
-    fn it_should_get_rid_off_all_expired_keys_periodically() {
+    fn it_should_reclaim_expired_keys_on_next_write() {
         let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
         cache
             .set(SetPayload {
-                key: "key49999",
-                value: "value49999",
-                ttl: 1,
+                key: "old",
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
-        cache.set_hits(49999);
         cache.time_source.advance(2);
         cache
             .set(SetPayload {
-                key: "key50000",
-                value: "value50000",
-                ttl: 1,
+                key: "new",
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
 
         assert_eq!(cache.get_values_length(), 1);
     }
 
+    #[test]
+    fn it_should_not_get_expired_value() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        assert_eq!(cache.get("key").unwrap(), "value");
+        cache.time_source.advance(1);
+        assert!(cache.get("key").is_none());
+        assert_eq!(cache.get_values_length(), 0);
+    }
+
+    #[test]
+    fn it_should_compute_over_expired_value() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        let payload = |value| SetPayload {
+            key: "key",
+            value,
+            ttl: Duration::from_secs(1),
+            tier_hint: None,
+        };
+        cache.set_computed(payload(b"old"), 0.0).unwrap();
+        assert_eq!(cache.set_computed(payload(b"new"), 0.0).unwrap(), "old");
+        cache.time_source.advance(1);
+        assert_eq!(cache.set_computed(payload(b"new"), 0.0).unwrap(), "new");
+        assert_eq!(cache.get_values_length(), 1);
+    }
+
+    #[test]
+    fn it_should_drop_entry_after_max_age_despite_replacements() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache.set_max_age(Duration::from_secs(10));
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(8),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        cache.time_source.advance(6);
+        cache
+            .replace(
+                SetPayload {
+                    key: "key",
+                    value: b"value123",
+                    ttl: Duration::from_secs(8),
+                    tier_hint: None,
+                },
+                0.0,
+            )
+            .expect("Should not fail");
+        assert_eq!(cache.meta("key").unwrap().expires_at, 10_000);
+        cache.time_source.advance(4);
+        assert!(cache.get("key").is_none());
+    }
+
+    #[test]
+    fn it_should_track_last_read_when_enabled() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(10));
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(5),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        cache.get("key");
+        assert_eq!(cache.meta("key").unwrap().last_read, None);
+
+        cache.set_track_last_read(true);
+        cache.time_source.advance(2);
+        cache.get("key");
+        let meta = cache.meta("key").unwrap();
+        assert_eq!((meta.reads, meta.last_read), (2, Some(12_000)));
+    }
+
+    #[test]
+    fn it_should_replace_live_value() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(10));
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(5),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        cache
+            .replace(
+                SetPayload {
+                    key: "key",
+                    value: b"value123",
+                    ttl: Duration::from_secs(5),
+                    tier_hint: None,
+                },
+                0.5,
+            )
+            .expect("Should not fail");
+        assert_eq!(cache.get_value("key"), "value123");
+        assert_eq!(
+            cache.meta("key"),
+            Some(EntryMeta {
+                created: 10_000,
+                expires_at: 15_000,
+                ttl: Duration::from_secs(5),
+                delta: 0.5,
+                pinned: false,
+                size: 8,
+                reads: 0,
+                last_read: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_should_update_values_without_changing_them_under_readers() {
+        let mut cache = InMemoryCache::new();
+        set_key(&mut cache, "key");
+        let before = cache.get("key").unwrap();
+
+        assert!(cache.update("key", |value| value.extend_from_slice(b"s")));
+        assert_eq!(before, "value");
+        assert_eq!(cache.get_value("key"), "values");
+
+        drop(before);
+        let buffer = cache.get_value("key").as_ptr();
+        assert!(cache.update("key", |value| value[0] = b'V'));
+        assert_eq!(cache.get_value("key"), "Values");
+        assert_eq!(cache.get_value("key").as_ptr(), buffer);
+        assert!(!cache.update("missing", |_| {}));
+    }
+
+    #[test]
+    fn it_should_remove_value() {
+        let mut cache = InMemoryCache::new();
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        assert!(cache.remove("key"));
+        assert!(!cache.remove("key"));
+        assert_eq!(cache.get_values_length(), 0);
+    }
+
+    #[test]
+    fn it_should_handle_concurrent_writers() {
+        let cache = InMemoryCache::new();
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let mut cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = format!("key{}-{}", writer, i);
+                        cache
+                            .set(SetPayload {
+                                key: &key,
+                                value: b"value",
+                                ttl: Duration::from_secs(10),
+                                tier_hint: None,
+                            })
+                            .expect("Should not fail");
+                        assert_eq!(cache.get(&key).unwrap(), "value");
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(cache.len(), 800);
+        assert_eq!(cache.get_values_length(), 800);
+    }
+
+    #[test]
+    fn it_should_read_while_others_are_reading() {
+        let mut cache = InMemoryCache::new();
+        set_key(&mut cache, "key");
+        let mut reader = cache.clone();
+        let _other_reader = cache.shard("key").read();
+        let value = std::thread::spawn(move || reader.get("key"))
+            .join()
+            .unwrap();
+        assert_eq!(value.unwrap(), "value");
+    }
+
+    #[test]
+    fn it_should_share_entries_between_clones() {
+        let mut cache = InMemoryCache::new();
+        let mut clone = cache.clone();
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        assert_eq!(clone.get("key").unwrap(), "value");
+    }
+
+    #[test]
+    fn it_should_restart_ttl_on_touch() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        cache.time_source.advance(8);
+        assert!(cache.touch("key"));
+        assert_eq!(cache.meta("key").unwrap().expires_at, 18_000);
+        cache.time_source.advance(10);
+        assert!(!cache.touch("key"));
+        assert!(!cache.touch("missing"));
+    }
+
+    fn set_key(cache: &mut InMemoryCache, key: &str) {
+        cache
+            .set(SetPayload {
+                key,
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+    }
+
+    #[test]
+    fn it_should_evict_least_recently_used_at_capacity() {
+        let mut cache = InMemoryCache::with_capacity(2, EvictionPolicy::Lru);
+        set_key(&mut cache, "a");
+        set_key(&mut cache, "b");
+        cache.get("a");
+        set_key(&mut cache, "c");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.evictions(), 1);
+        cache.reset_stats();
+        assert_eq!(cache.evictions(), 0);
+    }
+
+    #[test]
+    fn it_should_evict_by_weight_and_skip_values_heavier_than_max() {
+        let mut cache = InMemoryCache::new();
+        cache.set_max_weight(Some(12));
+        set_key(&mut cache, "a");
+        set_key(&mut cache, "b");
+        cache.get("a");
+        set_key(&mut cache, "c");
+        assert_eq!(cache.weight(), 10);
+        assert!(cache.get("b").is_none());
+
+        let heavy = SetPayload {
+            key: "a",
+            value: b"far too heavy",
+            ttl: Duration::from_secs(10),
+            tier_hint: None,
+        };
+        cache.replace(heavy, 0.0).expect("Should not fail");
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.weight(), 5);
+        assert_eq!(cache.evictions(), 2);
+
+        cache.set_weigher(Arc::new(|key, _| key.len() as u64));
+        set_key(&mut cache, "abcdefg");
+        assert_eq!((cache.len(), cache.weight()), (2, 12));
+    }
+
+    #[test]
+    fn it_should_keep_frequent_entries_over_one_hit_wonders() {
+        let mut cache = InMemoryCache::with_capacity(3, EvictionPolicy::TinyLfu);
+        set_key(&mut cache, "hot");
+        for _ in 0..5 {
+            cache.get("hot");
+        }
+        for i in 0..10 {
+            set_key(&mut cache, &format!("once-{}", i));
+        }
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get("hot").is_some());
+        assert!(cache.get("once-9").is_some());
+        assert_eq!(cache.evictions(), 8);
+    }
+
+    #[test]
+    fn it_should_keep_entries_read_twice_through_a_scan() {
+        let mut cache = InMemoryCache::with_capacity(4, EvictionPolicy::Slru);
+        cache.set_slru_protected_share(0.5);
+        for key in ["a", "b", "c"] {
+            set_key(&mut cache, key);
+            cache.get(key);
+        }
+        for i in 0..10 {
+            set_key(&mut cache, &format!("scan-{}", i));
+        }
+        assert_eq!(cache.len(), 4);
+        assert!(cache.get("b").is_some());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn it_should_keep_pinned_entries_past_expiry_and_capacity() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache.set_capacity(Some(1));
+        cache
+            .set(SetPayload {
+                key: "pinned",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        assert!(cache.pin("pinned"));
+        cache
+            .set(SetPayload {
+                key: "other",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        assert_eq!(cache.len(), 2);
+
+        cache.time_source.advance(20);
+        cache.remove_expired(20);
+        assert_eq!(cache.get("pinned").unwrap(), "value");
+        assert!(cache.get("other").is_none());
+
+        assert!(cache.unpin("pinned"));
+        assert!(cache.get("pinned").is_none());
+        assert!(!cache.pin("pinned"));
+    }
+
+    #[test]
+    fn it_should_flush_pinned_entries() {
+        let mut cache = InMemoryCache::new();
+        set_key(&mut cache, "a");
+        set_key(&mut cache, "b");
+        cache.pin("a");
+        assert_eq!(cache.flush_pinned(), 1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn it_should_report_events_to_listeners() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache.set_capacity(Some(1));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        cache.on_event(move |event| {
+            assert_eq!(event.tier, Tier::Memory);
+            recorded
+                .lock()
+                .unwrap()
+                .push((event.key.to_string(), event.kind));
+        });
+        let payload = |key| SetPayload {
+            key,
+            value: b"value",
+            ttl: Duration::from_secs(10),
+            tier_hint: None,
+        };
+        cache.set(payload("a")).unwrap();
+        cache.get("a");
+        cache.get("b");
+        cache.time_source.advance(10);
+        cache.get("a");
+        cache.set(payload("a")).unwrap();
+        cache.set(payload("b")).unwrap();
+
+        let kinds: Vec<CacheEventKind> = events.lock().unwrap().iter().map(|e| e.1).collect();
+        let keys: String = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.0.as_str())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                CacheEventKind::Insert,
+                CacheEventKind::Hit,
+                CacheEventKind::Miss,
+                CacheEventKind::Expiration,
+                CacheEventKind::Miss,
+                CacheEventKind::Insert,
+                CacheEventKind::Eviction,
+                CacheEventKind::Insert,
+            ]
+        );
+        assert_eq!(keys, "aabaaaab");
+    }
+
+    #[test]
+    fn it_should_shrink_to_new_capacity() {
+        let mut cache = InMemoryCache::new();
+        let clone = cache.clone();
+        for key in ["a", "b", "c"] {
+            set_key(&mut cache, key);
+        }
+        clone.set_capacity(Some(1));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("c").is_some());
+        set_key(&mut cache, "d");
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn it_should_evict_oldest_inserted_at_capacity() {
+        let mut cache = InMemoryCache::with_capacity(2, EvictionPolicy::Fifo);
+        set_key(&mut cache, "a");
+        set_key(&mut cache, "b");
+        cache.get("a");
+        set_key(&mut cache, "c");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+    }
+
+    #[test]
+    fn it_should_evict_least_frequently_used_with_decay() {
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(0));
+        cache.eviction_policy = EvictionPolicy::Lfu;
+        cache.set_capacity(Some(2));
+        cache.set_lfu_decay(Duration::from_secs(10));
+        for (key, reads) in [("old", 8), ("new", 0)] {
+            cache
+                .set(SetPayload {
+                    key,
+                    value: b"value",
+                    ttl: Duration::from_secs(100),
+                    tier_hint: None,
+                })
+                .unwrap();
+            for _ in 0..reads {
+                cache.get(key);
+            }
+        }
+        cache.time_source.advance(40);
+        cache.get("new");
+        cache.get("new");
+
+        cache
+            .set(SetPayload {
+                key: "next",
+                value: b"value",
+                ttl: Duration::from_secs(100),
+                tier_hint: None,
+            })
+            .unwrap();
+        assert!(cache.get("old").is_none());
+        assert!(cache.get("new").is_some());
+    }
+
     #[test]
     fn it_should_return_error_when_key_is_empty() {
         let mut cache = InMemoryCache::new();
         let result = cache.set(SetPayload {
             key: "",
-            value: "value",
-            ttl: 1,
+            value: b"value",
+            ttl: Duration::from_secs(1),
+            tier_hint: None,
         });
         assert!(matches!(result, Err(InMemoryCacheError::EmptyKey)));
     }
+
+    #[test]
+    fn it_should_warm_start_from_snapshot_with_remaining_ttl() {
+        let path = std::env::temp_dir().join(format!("rcache-memory-{}", std::process::id()));
+        let mut cache = InMemoryCache::new_with_time_source(MockTimeSource::new(1000));
+        for (key, ttl) in [("long", 100), ("short", 5)] {
+            cache
+                .set(SetPayload {
+                    key,
+                    value: b"value",
+                    ttl: Duration::from_secs(ttl),
+                    tier_hint: None,
+                })
+                .unwrap();
+        }
+        assert_eq!(cache.save_snapshot(&path).unwrap(), 2);
+
+        let mut restarted = InMemoryCache::new_with_time_source(MockTimeSource::new(1010));
+        assert_eq!(restarted.load_snapshot(&path).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(restarted.get("short").is_none());
+        let meta = restarted.meta("long").unwrap();
+        assert_eq!(
+            (meta.expires_at, meta.ttl),
+            (1_100_000, Duration::from_secs(100))
+        );
+        assert_eq!(restarted.len(), 1);
+    }
 }
@@ -0,0 +1,57 @@
+/// Environment variables `from_env` takes the instance ID from, first set wins. `POD_NAME` is
+/// the usual name for the pod name passed in through the Kubernetes downward API, and
+/// Kubernetes sets `HOSTNAME` to the pod name as well.
+pub const ENV_VARS: [&str; 2] = ["POD_NAME", "HOSTNAME"];
+
+/// The ID of this instance as its environment names it, if one of `ENV_VARS` holds a valid
+/// ID.
+pub fn from_env() -> Option<String> {
+    from_vars(|var| std::env::var(var).ok())
+}
+
+/// Same as `from_env`, reading variables with `var`.
+pub fn from_vars<F>(var: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    ENV_VARS
+        .iter()
+        .filter_map(|name| var(name))
+        .find(|id| is_valid(id))
+}
+
+/// A random ID for instances that were not given one, different on every start.
+pub fn generate() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// IDs are 1 to 255 bytes without whitespace, so they fit in invalidation messages and
+/// envelope headers.
+pub fn is_valid(id: &str) -> bool {
+    (1..=255).contains(&id.len()) && !id.contains(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_prefer_pod_name() {
+        let env = |pod: Option<&str>, host: Option<&str>| {
+            let (pod, host) = (pod.map(str::to_string), host.map(str::to_string));
+            from_vars(move |var| match var {
+                "POD_NAME" => pod.clone(),
+                "HOSTNAME" => host.clone(),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            env(Some("web-7f9c"), Some("node")).as_deref(),
+            Some("web-7f9c")
+        );
+        assert_eq!(env(Some(""), Some("web-1")).as_deref(), Some("web-1"));
+        assert_eq!(env(None, None), None);
+        assert!(is_valid(&generate()));
+        assert!(!is_valid("web 1"));
+    }
+}
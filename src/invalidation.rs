@@ -0,0 +1,159 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use redis::Connection;
+
+use crate::in_memory_cache::InMemoryCache;
+use crate::kv_cache::KvError;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidationKind {
+    Delete,
+    Update,
+    /// Every key starting with the given prefix was deleted.
+    DeletePrefix,
+}
+
+impl InvalidationKind {
+    fn prefix(&self) -> &'static str {
+        match self {
+            InvalidationKind::Delete => "del:",
+            InvalidationKind::Update => "set:",
+            InvalidationKind::DeletePrefix => "delprefix:",
+        }
+    }
+}
+
+/// `@<origin> del:<key>`, where `origin` is the ID of the publishing instance and must be
+/// valid per `instance::is_valid`.
+pub fn encode_message(kind: InvalidationKind, key: &str, origin: &str) -> String {
+    format!("@{} {}{}", origin, kind.prefix(), key)
+}
+
+/// The origin, kind and key of a message. Messages of instances predating origins have none.
+pub fn decode_message(message: &str) -> Option<(Option<&str>, InvalidationKind, &str)> {
+    let (origin, rest) = match message.strip_prefix('@') {
+        Some(tagged) => {
+            let (origin, rest) = tagged.split_once(' ')?;
+            (Some(origin), rest)
+        }
+        None => (None, message),
+    };
+    [
+        InvalidationKind::Delete,
+        InvalidationKind::Update,
+        InvalidationKind::DeletePrefix,
+    ]
+    .into_iter()
+    .find_map(|kind| {
+        rest.strip_prefix(kind.prefix())
+            .map(|key| (origin, kind, key))
+    })
+}
+
+/// Subscribes to a Redis pub/sub channel on a background thread and evicts memory-tier
+/// entries that peers report as deleted or updated. Messages published by the instance
+/// `origin` are skipped, since it already updated its own memory tier.
+pub struct InvalidationBus {
+    channel: String,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl InvalidationBus {
+    /// Takes over `con` as a dedicated pub/sub connection.
+    pub fn new(
+        mut con: Connection,
+        channel: &str,
+        origin: &str,
+        in_memory_cache: InMemoryCache,
+    ) -> Result<InvalidationBus, KvError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread_channel = channel.to_string();
+        let own_origin = origin.to_string();
+        let (ready_sender, ready_receiver) = mpsc::sync_channel(1);
+
+        let handle = thread::spawn(move || {
+            let mut cache = in_memory_cache;
+            let mut pubsub = con.as_pubsub();
+            let subscribed = pubsub
+                .subscribe(&thread_channel)
+                .and_then(|_| pubsub.set_read_timeout(Some(POLL_INTERVAL)));
+            let _ = ready_sender.send(subscribed.map_err(KvError::CommandFailed));
+
+            while !thread_stop.load(Ordering::Relaxed) {
+                let message = match pubsub.get_message() {
+                    Ok(message) => message,
+                    Err(err) if err.is_timeout() => continue,
+                    Err(_) => break,
+                };
+                let Ok(payload) = message.get_payload::<String>() else {
+                    continue;
+                };
+                match decode_message(&payload) {
+                    Some((Some(origin), _, _)) if origin == own_origin => {}
+                    Some((_, InvalidationKind::DeletePrefix, prefix)) => {
+                        cache.remove_prefix(prefix);
+                    }
+                    Some((_, _, key)) => {
+                        cache.remove(key);
+                    }
+                    None => {}
+                }
+            }
+        });
+
+        ready_receiver
+            .recv()
+            .map_err(|_| KvError::ConnectionNotEstablished)??;
+
+        Ok(InvalidationBus {
+            channel: channel.to_string(),
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+}
+
+impl Drop for InvalidationBus {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_round_trip_messages() {
+        let message = encode_message(InvalidationKind::Delete, "user:1 a", "web-1");
+        assert_eq!(message, "@web-1 del:user:1 a");
+        assert_eq!(
+            decode_message(&message),
+            Some((Some("web-1"), InvalidationKind::Delete, "user:1 a"))
+        );
+        assert_eq!(
+            decode_message("set:user:1"),
+            Some((None, InvalidationKind::Update, "user:1"))
+        );
+        assert_eq!(
+            decode_message("@web-1 delprefix:user:1:"),
+            Some((Some("web-1"), InvalidationKind::DeletePrefix, "user:1:"))
+        );
+        assert_eq!(decode_message("unknown"), None);
+        assert_eq!(decode_message("@web-1"), None);
+    }
+}
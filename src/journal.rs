@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use bytes::Bytes;
+use redis::ConnectionLike;
+
+use crate::in_memory_cache::millis;
+use crate::{CacheService, CacheServiceError};
+
+/// A cache operation as the caller made it, keys without the namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// `get_bytes` and what it returned.
+    Get {
+        key: String,
+        value: Option<Bytes>,
+    },
+    /// A single-key `resolve` and the value it returned, cached or resolved.
+    Resolve {
+        key: String,
+        value: Bytes,
+    },
+    Set {
+        key: String,
+        value: Bytes,
+        ttl: Duration,
+    },
+    /// `update_bytes` with the value written and the value that ended up stored.
+    Update {
+        key: String,
+        value: Bytes,
+        stored: Bytes,
+    },
+    /// `incr` or `decr`, with the count it returned.
+    Increment {
+        key: String,
+        delta: i64,
+        ttl: Duration,
+        count: i64,
+    },
+    Invalidate {
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    /// Position of the operation among all operations recorded by the journal, starting at 0.
+    pub seq: u64,
+    pub operation: Operation,
+}
+
+/// Opt-in record of the operations made through a `CacheService`, in order, for replaying
+/// them against a fresh cache with `replay` to reproduce incoherence seen in production.
+/// Operations that fail are not recorded. Kept in memory, the newest `capacity` entries are
+/// retained; written to a file, every entry is appended as a line and nothing is retained.
+pub struct Journal {
+    next_seq: u64,
+    capacity: usize,
+    entries: VecDeque<JournalEntry>,
+    file: Option<BufWriter<File>>,
+}
+
+impl Journal {
+    pub fn in_memory(capacity: usize) -> Journal {
+        Journal {
+            next_seq: 0,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            file: None,
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if needed. Read it back with `read_journal`.
+    pub fn to_file(path: &Path) -> io::Result<Journal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal {
+            file: Some(BufWriter::new(file)),
+            ..Journal::in_memory(0)
+        })
+    }
+
+    /// Failing to write to the file loses the entry rather than the operation.
+    pub fn record(&mut self, operation: Operation) {
+        let entry = JournalEntry {
+            seq: self.next_seq,
+            operation,
+        };
+        self.next_seq += 1;
+        if let Some(file) = &mut self.file {
+            let _ = writeln!(file, "{}", encode_entry(&entry)).and_then(|()| file.flush());
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &JournalEntry> {
+        self.entries.iter()
+    }
+}
+
+/// Reads the entries a file-backed `Journal` wrote to `path`.
+pub fn read_journal(path: &Path) -> io::Result<Vec<JournalEntry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let entry = decode_entry(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid journal line: {}", line),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// A read whose replayed result differs from the recorded one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub seq: u64,
+    pub key: String,
+    pub recorded: Option<Bytes>,
+    pub replayed: Option<Bytes>,
+}
+
+/// Makes the recorded operations against `cache` in order and returns every read that came
+/// out differently. Resolves return the recorded value when the resolver runs. Expiry depends
+/// on the clock, so keep TTLs in replays well above the time the replay takes.
+pub fn replay<'a, C: ConnectionLike>(
+    cache: &mut CacheService<C>,
+    entries: impl IntoIterator<Item = &'a JournalEntry>,
+) -> Result<Vec<Divergence>, CacheServiceError> {
+    let mut divergences = Vec::new();
+    for entry in entries {
+        let (key, recorded, replayed) = match &entry.operation {
+            Operation::Get { key, value } => (key, value.clone(), cache.get_bytes(key)?),
+            Operation::Resolve { key, value } => {
+                let replayed = cache.resolve_bytes(key, || value.clone())?;
+                (key, Some(value.clone()), Some(replayed))
+            }
+            Operation::Set { key, value, ttl } => {
+                cache.set_bytes(key, value, *ttl)?;
+                continue;
+            }
+            Operation::Update { key, value, stored } => {
+                let replayed = cache.update_bytes(key, value)?;
+                (key, Some(stored.clone()), Some(replayed))
+            }
+            Operation::Increment {
+                key,
+                delta,
+                ttl,
+                count,
+            } => {
+                let replayed = cache.incr(key, *delta, *ttl)?;
+                let bytes = |count: i64| Some(Bytes::from(count.to_string()));
+                (key, bytes(*count), bytes(replayed))
+            }
+            Operation::Invalidate { key } => {
+                cache.invalidate(key)?;
+                continue;
+            }
+        };
+        if recorded != replayed {
+            divergences.push(Divergence {
+                seq: entry.seq,
+                key: key.clone(),
+                recorded,
+                replayed,
+            });
+        }
+    }
+    Ok(divergences)
+}
+
+/// One line per entry: the sequence number, the operation and its arguments, separated by
+/// spaces. Keys and values are hex-encoded with an `x` prefix, a missing value is `-`, and
+/// TTLs are in milliseconds with an `ms` suffix.
+fn encode_entry(entry: &JournalEntry) -> String {
+    let mut line = entry.seq.to_string();
+    let mut push = |field: &str| {
+        line.push(' ');
+        line.push_str(field);
+    };
+    match &entry.operation {
+        Operation::Get { key, value } => {
+            push("get");
+            push(&hex(key.as_bytes()));
+            push(&value.as_deref().map_or_else(|| "-".to_string(), hex));
+        }
+        Operation::Resolve { key, value } => {
+            push("resolve");
+            push(&hex(key.as_bytes()));
+            push(&hex(value));
+        }
+        Operation::Set { key, value, ttl } => {
+            push("set");
+            push(&hex(key.as_bytes()));
+            push(&hex(value));
+            push(&encode_ttl(*ttl));
+        }
+        Operation::Update { key, value, stored } => {
+            push("update");
+            push(&hex(key.as_bytes()));
+            push(&hex(value));
+            push(&hex(stored));
+        }
+        Operation::Increment {
+            key,
+            delta,
+            ttl,
+            count,
+        } => {
+            push("incr");
+            push(&hex(key.as_bytes()));
+            push(&delta.to_string());
+            push(&encode_ttl(*ttl));
+            push(&count.to_string());
+        }
+        Operation::Invalidate { key } => {
+            push("invalidate");
+            push(&hex(key.as_bytes()));
+        }
+    }
+    line
+}
+
+fn decode_entry(line: &str) -> Option<JournalEntry> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let key = || String::from_utf8(unhex(fields.get(2)?)?).ok();
+    let bytes = |index: usize| unhex(fields.get(index)?).map(Bytes::from);
+    let operation = match (*fields.get(1)?, fields.len()) {
+        ("get", 4) => Operation::Get {
+            key: key()?,
+            value: match fields[3] {
+                "-" => None,
+                _ => Some(bytes(3)?),
+            },
+        },
+        ("resolve", 4) => Operation::Resolve {
+            key: key()?,
+            value: bytes(3)?,
+        },
+        ("set", 5) => Operation::Set {
+            key: key()?,
+            value: bytes(3)?,
+            ttl: decode_ttl(fields[4])?,
+        },
+        ("update", 5) => Operation::Update {
+            key: key()?,
+            value: bytes(3)?,
+            stored: bytes(4)?,
+        },
+        ("incr", 6) => Operation::Increment {
+            key: key()?,
+            delta: fields[3].parse().ok()?,
+            ttl: decode_ttl(fields[4])?,
+            count: fields[5].parse().ok()?,
+        },
+        ("invalidate", 3) => Operation::Invalidate { key: key()? },
+        _ => return None,
+    };
+    Some(JournalEntry {
+        seq: fields[0].parse().ok()?,
+        operation,
+    })
+}
+
+fn encode_ttl(ttl: Duration) -> String {
+    format!("{}ms", millis(ttl))
+}
+
+/// Journals written before TTLs had millisecond precision record them in plain seconds.
+fn decode_ttl(field: &str) -> Option<Duration> {
+    match field.strip_suffix("ms") {
+        Some(millis) => millis.parse().ok().map(Duration::from_millis),
+        None => field.parse().ok().map(Duration::from_secs),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(1 + bytes.len() * 2);
+    encoded.push('x');
+    for byte in bytes {
+        let _ = write!(encoded, "{:02x}", byte);
+    }
+    encoded
+}
+
+fn unhex(field: &str) -> Option<Vec<u8>> {
+    let digits = field.strip_prefix('x')?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(digits.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_read_back_journal_file() {
+        let path = std::env::temp_dir().join(format!("rcache-{}.journal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let operations = [
+            Operation::Set {
+                key: "a key".into(),
+                value: Bytes::new(),
+                ttl: Duration::from_millis(10_250),
+            },
+            Operation::Get {
+                key: "a key".into(),
+                value: Some(Bytes::new()),
+            },
+            Operation::Get {
+                key: "b".into(),
+                value: None,
+            },
+            Operation::Update {
+                key: "b".into(),
+                value: Bytes::from_static(b"\n2"),
+                stored: Bytes::from_static(b"3"),
+            },
+            Operation::Increment {
+                key: "c".into(),
+                delta: -3,
+                ttl: Duration::from_secs(10),
+                count: -2,
+            },
+            Operation::Invalidate { key: "b".into() },
+        ];
+        let mut journal = Journal::to_file(&path).unwrap();
+        for operation in operations.clone() {
+            journal.record(operation);
+        }
+        drop(journal);
+
+        let entries = read_journal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let read: Vec<Operation> = entries
+            .iter()
+            .map(|entry| entry.operation.clone())
+            .collect();
+        assert_eq!(read, operations);
+        assert_eq!(entries[5].seq, 5);
+        assert!(decode_entry("0 get x6 -").is_none());
+        let legacy = decode_entry("7 set x61 x62 10").unwrap();
+        assert!(matches!(legacy.operation, Operation::Set { ttl, .. } if ttl.as_secs() == 10));
+    }
+}
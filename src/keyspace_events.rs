@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use redis::{Connection, ConnectionLike};
+
+use crate::in_memory_cache::InMemoryCache;
+use crate::kv_cache::KvError;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keyspace events after which a key is gone from Redis. UNLINK is reported as `del`.
+const EVENTS: [&str; 3] = ["del", "expired", "evicted"];
+
+/// Subscribes to the Redis keyspace events of deleted, expired and evicted keys on a
+/// background thread and evicts the memory-tier copies of those keys, so keys removed by
+/// writers other than rcache are not served from memory until their memory TTL runs out.
+/// Redis only sends the events if `notify-keyspace-events` includes `Egxe`, or `EA`.
+pub struct KeyspaceListener {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl KeyspaceListener {
+    /// Takes over `con` as a dedicated pub/sub connection, listening to the events of the
+    /// database it is connected to.
+    pub fn new(
+        mut con: Connection,
+        in_memory_cache: InMemoryCache,
+    ) -> Result<KeyspaceListener, KvError> {
+        let channels: Vec<String> = EVENTS
+            .iter()
+            .map(|event| format!("__keyevent@{}__:{}", con.get_db(), event))
+            .collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let (ready_sender, ready_receiver) = mpsc::sync_channel(1);
+
+        let handle = thread::spawn(move || {
+            let mut cache = in_memory_cache;
+            let mut pubsub = con.as_pubsub();
+            let subscribed = pubsub
+                .subscribe(&channels)
+                .and_then(|_| pubsub.set_read_timeout(Some(POLL_INTERVAL)));
+            let _ = ready_sender.send(subscribed.map_err(KvError::CommandFailed));
+
+            while !thread_stop.load(Ordering::Relaxed) {
+                let message = match pubsub.get_message() {
+                    Ok(message) => message,
+                    Err(err) if err.is_timeout() => continue,
+                    Err(_) => break,
+                };
+                if let Ok(key) = message.get_payload::<String>() {
+                    cache.remove(&key);
+                }
+            }
+        });
+
+        ready_receiver
+            .recv()
+            .map_err(|_| KvError::ConnectionNotEstablished)??;
+
+        Ok(KeyspaceListener {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for KeyspaceListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,64 +1,1960 @@
-use redis::{Client, Commands, Connection, RedisError};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+use redis::sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType};
+use redis::{
+    Client, Commands, Connection, ConnectionLike, ErrorKind, ExistenceCheck, IntoConnectionInfo,
+    Pipeline, RedisConnectionInfo, RedisError, RedisResult, Script, SetExpiry, SetOptions,
+};
+#[cfg(feature = "tls")]
+use redis::{ClientTlsConfig, TlsCertificates};
+
+use crate::conflict::ConflictPolicy;
+use crate::encryption::{self, Encryption};
+use crate::envelope::{
+    Compression, Decoded, Envelope, ImportStats, LegacyValuePolicy, MigrationCounters,
+    MigrationStats, Provenance,
+};
+use crate::failover::{FailoverConnection, FailoverObserver};
+use crate::in_memory_cache::{
+    expiry_millis, jitter_ttl, millis, shorter_ttl, SystemTimeSource, TimeSource,
+};
+use crate::memcached::{self, MemcachedConnection};
+use crate::memory_only::{self, MemoryOnlyConnection};
+use crate::retry::{is_transient, reconnecting, ReconnectPolicy, RetryPolicy, WhileReconnecting};
+#[cfg(feature = "sled")]
+use crate::sled_store::{self, SledConnection};
 use crate::SetPayload;
 
-pub struct KvCache {
-    con: Connection,
+/// Sets `KEYS[1]` only if it still holds the value read before (`ARGV[1]` is "0" when the key
+/// was absent), so a merge computed client-side is never written over a concurrent change.
+/// The TTL is in milliseconds; "keep" leaves the current expiry untouched and "none" stores
+/// the value without one.
+const COMPARE_AND_SET: &str = r#"
+-- compare-and-set
+local current = redis.call('GET', KEYS[1])
+if (ARGV[1] == '0' and not current) or (ARGV[1] == '1' and current == ARGV[2]) then
+    if ARGV[4] == 'keep' then
+        redis.call('SET', KEYS[1], ARGV[3], 'KEEPTTL')
+    elseif ARGV[4] == 'none' then
+        redis.call('SET', KEYS[1], ARGV[3])
+    else
+        redis.call('SET', KEYS[1], ARGV[3], 'PX', ARGV[4])
+    end
+    return 1
+end
+return 0
+"#;
+
+/// Deletes `KEYS[1]` only if it still holds `ARGV[1]`.
+const COMPARE_AND_DELETE: &str = r#"
+-- compare-and-delete
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// Adds `ARGV[1]` to the counter `KEYS[1]` and gives it a TTL of `ARGV[2]` milliseconds,
+/// "none" for no expiry, unless it already has one. Returns the count and the remaining TTL
+/// in milliseconds, -1 for none. Checking PTTL instead of `PEXPIRE .. NX` works before
+/// Redis 7.
+const INCREMENT: &str = r#"
+-- increment
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+if ARGV[2] ~= 'none' and redis.call('PTTL', KEYS[1]) == -1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return {count, redis.call('PTTL', KEYS[1])}
+"#;
+
+const SCAN_BATCH_SIZE: usize = 100;
+
+const MAX_MERGE_ATTEMPTS: usize = 16;
+
+/// How long reconnecting to a lost Redis may take before the attempt counts as failed.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A value together with its remaining TTL (`None` when it never expires).
+pub type ValueWithTtl = (Bytes, Option<Duration>);
+
+/// What a key held when it was read, for `set_if_version`. Opaque: two versions are equal
+/// when the stored values are the same byte for byte, creation time and writer included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version(Bytes);
+
+/// Opens a replacement connection after a failover.
+type Reconnect<C> = Box<dyn FnMut() -> RedisResult<C> + Send>;
+
+/// The connection behind a `KvCache` opened from a URL: Redis, memcached for `memcache://`
+/// URLs, an embedded sled database for `sled://` URLs, or no KV tier at all for `memory://`.
+pub enum KvConnection {
+    Redis(Connection),
+    /// Redis over an ordered list of URLs.
+    Failover(FailoverConnection),
+    Memcached(MemcachedConnection),
+    #[cfg(feature = "sled")]
+    Sled(SledConnection),
+    MemoryOnly(MemoryOnlyConnection),
+    /// Not connected yet, for a `KvCache` opened with `lazy`.
+    Pending,
+}
+
+impl KvConnection {
+    /// The Redis connection, or `None` for memcached. A failover connection gives up the
+    /// connection to its current URL.
+    pub fn into_redis(self) -> Option<Connection> {
+        match self {
+            KvConnection::Redis(con) => Some(con),
+            KvConnection::Failover(con) => Some(con.into_current()),
+            _ => None,
+        }
+    }
+}
+
+impl ConnectionLike for KvConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<redis::Value> {
+        match self {
+            KvConnection::Redis(con) => con.req_packed_command(cmd),
+            KvConnection::Failover(con) => con.req_packed_command(cmd),
+            KvConnection::Memcached(con) => con.req_packed_command(cmd),
+            #[cfg(feature = "sled")]
+            KvConnection::Sled(con) => con.req_packed_command(cmd),
+            KvConnection::MemoryOnly(con) => con.req_packed_command(cmd),
+            KvConnection::Pending => Err(reconnecting()),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<redis::Value>> {
+        match self {
+            KvConnection::Redis(con) => con.req_packed_commands(cmd, offset, count),
+            KvConnection::Failover(con) => con.req_packed_commands(cmd, offset, count),
+            KvConnection::Memcached(con) => con.req_packed_commands(cmd, offset, count),
+            #[cfg(feature = "sled")]
+            KvConnection::Sled(con) => con.req_packed_commands(cmd, offset, count),
+            KvConnection::MemoryOnly(con) => con.req_packed_commands(cmd, offset, count),
+            KvConnection::Pending => Err(reconnecting()),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            KvConnection::Redis(con) => con.get_db(),
+            KvConnection::Failover(con) => con.get_db(),
+            KvConnection::Memcached(con) => con.get_db(),
+            #[cfg(feature = "sled")]
+            KvConnection::Sled(con) => con.get_db(),
+            KvConnection::MemoryOnly(con) => con.get_db(),
+            KvConnection::Pending => 0,
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            KvConnection::Redis(con) => con.check_connection(),
+            KvConnection::Failover(con) => con.check_connection(),
+            KvConnection::Memcached(con) => con.check_connection(),
+            #[cfg(feature = "sled")]
+            KvConnection::Sled(con) => con.check_connection(),
+            KvConnection::MemoryOnly(con) => con.check_connection(),
+            KvConnection::Pending => false,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            KvConnection::Redis(con) => con.is_open(),
+            KvConnection::Failover(con) => con.is_open(),
+            KvConnection::Memcached(con) => con.is_open(),
+            #[cfg(feature = "sled")]
+            KvConnection::Sled(con) => con.is_open(),
+            KvConnection::MemoryOnly(con) => con.is_open(),
+            KvConnection::Pending => false,
+        }
+    }
+}
+
+/// The KV tier. Works over any `ConnectionLike`, so tests and custom transports can supply
+/// their own connection through `from_connection`.
+pub struct KvCache<C: ConnectionLike = KvConnection> {
+    con: C,
+    reconnect: Option<Reconnect<C>>,
+    max_age: Option<u64>,
+    compression: Option<Compression>,
+    encryption: Option<Encryption>,
+    read_unencrypted: bool,
+    writer: Option<Provenance>,
+    legacy_policy: LegacyValuePolicy,
+    migration: Arc<MigrationCounters>,
+    directory: Option<String>,
+    retry: Option<RetryPolicy>,
+    reconnect_policy: ReconnectPolicy,
+    outage: Option<Outage>,
+    /// Client ID invalidation messages go to and the key prefix tracked, turned on again
+    /// on every new connection.
+    tracking: Option<(i64, Option<String>)>,
+}
+
+/// A lost connection that could not be restored yet.
+#[derive(Debug, Clone, Copy)]
+struct Outage {
+    failures: u32,
+    next_attempt: Instant,
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum KvError {
     CommandFailed(RedisError),
     ConnectionNotEstablished,
+    MergeConflict,
+    /// Redis did not accept or answer a command within `KvTimeouts`. The connection is
+    /// replaced before the next command, as a late answer would be read as the next one's.
+    Timeout(RedisError),
+    /// Redis rejected the username or password, or requires one and none was given.
+    AuthenticationFailed(RedisError),
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::CommandFailed(_) => f.write_str("a command to the KV store failed"),
+            KvError::ConnectionNotEstablished => f.write_str("cannot connect to the KV store"),
+            KvError::MergeConflict => {
+                f.write_str("the value kept changing while it was being merged or incremented")
+            }
+            KvError::Timeout(_) => f.write_str("the KV store did not answer in time"),
+            KvError::AuthenticationFailed(_) => {
+                f.write_str("Redis rejected the credentials; check `RedisAuth` or the URL")
+            }
+        }
+    }
+}
+
+impl Error for KvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvError::CommandFailed(err)
+            | KvError::Timeout(err)
+            | KvError::AuthenticationFailed(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<RedisError> for KvError {
     fn from(err: RedisError) -> Self {
-        KvError::CommandFailed(err)
+        if err.is_timeout() {
+            KvError::Timeout(err)
+        } else if is_auth_failure(&err) {
+            KvError::AuthenticationFailed(err)
+        } else {
+            KvError::CommandFailed(err)
+        }
+    }
+}
+
+fn is_auth_failure(err: &RedisError) -> bool {
+    err.kind() == ErrorKind::AuthenticationFailed || err.code() == Some("NOAUTH")
+}
+
+/// Why a connection could not be opened: rejected credentials are told apart from an
+/// unreachable server.
+fn not_established(err: RedisError) -> KvError {
+    if is_auth_failure(&err) {
+        KvError::AuthenticationFailed(err)
+    } else {
+        KvError::ConnectionNotEstablished
+    }
+}
+
+/// Returned when a counter would leave the range of `i64`, as by Redis.
+pub(crate) fn counter_overflow() -> RedisError {
+    RedisError::from((
+        ErrorKind::ResponseError,
+        "increment or decrement would overflow",
+    ))
+}
+
+/// Returned when incrementing a value that is not a counter, as by Redis.
+fn not_a_counter() -> RedisError {
+    RedisError::from((
+        ErrorKind::ResponseError,
+        "value is not an integer or out of range",
+    ))
+}
+
+/// The count in a counter's payload, `None` if it is not one.
+fn count(payload: &[u8]) -> Option<i64> {
+    std::str::from_utf8(payload).ok()?.parse().ok()
+}
+
+/// Whether a plain value is a counter written by `KvCache::increment` without encryption,
+/// which stays out of envelopes.
+fn is_counter(payload: &[u8]) -> bool {
+    count(payload).is_some()
+}
+
+/// How long a Redis connection may take to open, and a command to be sent or answered.
+/// `None` keeps the defaults: reconnecting gives up after a second, the first connection
+/// and commands wait indefinitely. A timed out command fails with `KvError::Timeout`.
+/// Memcached and sled ignore these.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KvTimeouts {
+    /// Not applied to Sentinel deployments, whose client takes no connect timeout.
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+impl KvTimeouts {
+    /// Opens a connection with the connect timeout and sets the read and write timeouts on
+    /// it.
+    fn open(
+        &self,
+        client: &Client,
+        connect: Duration,
+        auth: &RedisAuth,
+    ) -> RedisResult<Connection> {
+        let mut con = client.get_connection_with_timeout(self.connect.unwrap_or(connect))?;
+        self.apply(&con)?;
+        auth.name(&mut con)?;
+        Ok(con)
+    }
+
+    pub(crate) fn apply(&self, con: &Connection) -> RedisResult<()> {
+        con.set_read_timeout(self.read)?;
+        con.set_write_timeout(self.write)
+    }
+}
+
+/// How to log in to Redis and which database to use, given explicitly instead of encoded in
+/// the URL. Set fields override what the URL says. A wrong username or password fails
+/// connecting with `KvError::AuthenticationFailed`. Memcached and sled ignore these.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RedisAuth {
+    /// ACL user; needs `password`. Without one Redis logs in as `default`.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<i64>,
+    /// Set with `CLIENT SETNAME`, so the connections show up by name in `CLIENT LIST`.
+    pub client_name: Option<String>,
+    /// Certificates for `rediss://` URLs. Those connect over TLS without this too, trusting
+    /// the system's CA certificates.
+    #[cfg(feature = "tls")]
+    pub tls: Option<RedisTls>,
+}
+
+/// Certificates for TLS connections to Redis, all PEM encoded. Sentinel deployments only
+/// connect to the primary over TLS with the system's CA certificates, so these are rejected
+/// for them.
+#[cfg(feature = "tls")]
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RedisTls {
+    /// CA certificates to trust instead of the system's, e.g. the one of a managed Redis.
+    pub ca_cert: Option<Vec<u8>>,
+    /// Certificate chain presented to servers requiring mutual TLS; needs `client_key`.
+    pub client_cert: Option<Vec<u8>>,
+    pub client_key: Option<Vec<u8>>,
+}
+
+/// Leaves the password out, so the config can be logged.
+impl fmt::Debug for RedisAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("RedisAuth");
+        debug
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("db", &self.db)
+            .field("client_name", &self.client_name);
+        #[cfg(feature = "tls")]
+        debug.field("tls", &self.tls);
+        debug.finish()
+    }
+}
+
+/// Leaves the client key out, for the same reason.
+#[cfg(feature = "tls")]
+impl fmt::Debug for RedisTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisTls")
+            .field("ca_cert", &self.ca_cert)
+            .field("client_cert", &self.client_cert)
+            .field(
+                "client_key",
+                &self.client_key.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl RedisTls {
+    /// Whether the certificates parse and a client certificate comes with its key.
+    pub fn is_valid(&self) -> bool {
+        self.client_cert.is_some() == self.client_key.is_some()
+            && Client::build_with_tls("rediss://localhost", self.certificates()).is_ok()
+    }
+
+    pub fn has_certificates(&self) -> bool {
+        self.ca_cert.is_some() || self.client_cert.is_some()
+    }
+
+    fn certificates(&self) -> TlsCertificates {
+        TlsCertificates {
+            client_tls: self.client_cert.clone().zip(self.client_key.clone()).map(
+                |(client_cert, client_key)| ClientTlsConfig {
+                    client_cert,
+                    client_key,
+                },
+            ),
+            root_cert: self.ca_cert.clone(),
+        }
+    }
+}
+
+impl RedisAuth {
+    /// Whether Redis can accept these: a username comes with a password, the database index
+    /// is not negative and the client name is not empty and has no spaces.
+    pub fn is_valid(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if !self.tls.as_ref().is_none_or(RedisTls::is_valid) {
+            return false;
+        }
+        (self.username.is_none() || self.password.is_some())
+            && self.db.is_none_or(|db| db >= 0)
+            && self
+                .client_name
+                .as_deref()
+                .is_none_or(|name| !name.is_empty() && !name.contains(' '))
+    }
+
+    fn override_info(&self, info: &mut RedisConnectionInfo) {
+        if let Some(username) = &self.username {
+            info.username = Some(username.clone());
+        }
+        if let Some(password) = &self.password {
+            info.password = Some(password.clone());
+        }
+        if let Some(db) = self.db {
+            info.db = db;
+        }
+    }
+
+    pub(crate) fn client(&self, url: &str) -> RedisResult<Client> {
+        let mut info = url.into_connection_info()?;
+        self.override_info(&mut info.redis);
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_ref().filter(|tls| tls.has_certificates()) {
+            return Client::build_with_tls(info, tls.certificates());
+        }
+        Client::open(info)
+    }
+
+    /// What a Sentinel client logs in to the primary with, `None` to use no credentials.
+    fn sentinel_info(&self) -> Option<SentinelNodeConnectionInfo> {
+        #[cfg(feature = "tls")]
+        let tls_mode = self.tls.as_ref().map(|_| redis::TlsMode::Secure);
+        #[cfg(not(feature = "tls"))]
+        let tls_mode = None;
+        if self.username.is_none() && self.password.is_none() && self.db.is_none() {
+            return tls_mode.map(|tls_mode| SentinelNodeConnectionInfo {
+                tls_mode: Some(tls_mode),
+                redis_connection_info: None,
+            });
+        }
+        let mut info = RedisConnectionInfo::default();
+        self.override_info(&mut info);
+        Some(SentinelNodeConnectionInfo {
+            tls_mode,
+            redis_connection_info: Some(info),
+        })
+    }
+
+    pub(crate) fn name(&self, con: &mut Connection) -> RedisResult<()> {
+        if let Some(name) = &self.client_name {
+            redis::cmd("CLIENT")
+                .arg("SETNAME")
+                .arg(name)
+                .query::<()>(con)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where to find the KV store. Cloned and reused whenever a component needs its own connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionOptions {
+    Url(String),
+    Sentinel {
+        sentinels: Vec<String>,
+        master_name: String,
+    },
+    /// Redis servers in order of preference; see `FailoverConnection`.
+    Failover {
+        urls: Vec<String>,
+        probe_interval: Duration,
+    },
+}
+
+impl ConnectionOptions {
+    pub fn is_memcached(&self) -> bool {
+        matches!(self, ConnectionOptions::Url(url) if url.starts_with(memcached::URL_SCHEME))
+    }
+
+    /// Whether this stands in for a KV tier that keeps nothing, see `MemoryOnlyConnection`.
+    pub fn is_memory_only(&self) -> bool {
+        matches!(self, ConnectionOptions::Url(url) if url.starts_with(memory_only::URL_SCHEME))
+    }
+
+    /// Whether this points at Redis, the only backend with pub/sub.
+    pub fn is_redis(&self) -> bool {
+        match self {
+            ConnectionOptions::Url(url) => {
+                !url.starts_with(memcached::URL_SCHEME)
+                    && !url.starts_with("sled://")
+                    && !url.starts_with(memory_only::URL_SCHEME)
+            }
+            ConnectionOptions::Sentinel { .. } => true,
+            ConnectionOptions::Failover { urls, .. } => urls
+                .iter()
+                .all(|url| ConnectionOptions::Url(url.clone()).is_redis()),
+        }
+    }
+
+    /// Whether every Redis connection this opens uses TLS, from `rediss://` URLs.
+    pub fn is_tls(&self) -> bool {
+        match self {
+            ConnectionOptions::Url(url) => url.starts_with("rediss://"),
+            ConnectionOptions::Sentinel { .. } => false,
+            ConnectionOptions::Failover { urls, .. } => {
+                !urls.is_empty() && urls.iter().all(|url| url.starts_with("rediss://"))
+            }
+        }
+    }
+
+    pub fn connect(&self) -> Result<KvCache, KvError> {
+        self.connect_with_timeouts(KvTimeouts::default())
+    }
+
+    /// Same as `connect`, with `timeouts` on every Redis connection opened.
+    pub fn connect_with_timeouts(&self, timeouts: KvTimeouts) -> Result<KvCache, KvError> {
+        self.connect_with(timeouts, &RedisAuth::default())
+    }
+
+    /// Same as `connect`, with `timeouts` and `auth` on every Redis connection opened.
+    pub fn connect_with(&self, timeouts: KvTimeouts, auth: &RedisAuth) -> Result<KvCache, KvError> {
+        match self {
+            ConnectionOptions::Url(url) => KvCache::open_url(url, timeouts, auth),
+            ConnectionOptions::Sentinel {
+                sentinels,
+                master_name,
+            } => {
+                let sentinels: Vec<&str> = sentinels.iter().map(String::as_str).collect();
+                KvCache::open_sentinel(&sentinels, master_name, timeouts, auth)
+            }
+            ConnectionOptions::Failover {
+                urls,
+                probe_interval,
+            } => {
+                let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+                let con =
+                    FailoverConnection::open_with(&urls, *probe_interval, timeouts, auth.clone())
+                        .map_err(not_established)?;
+                Ok(KvCache::from_connection(KvConnection::Failover(con)))
+            }
+        }
+    }
+
+    /// Same as `connect`, but a Redis URL or Sentinel deployment is only connected to on the
+    /// first command, so an unreachable server fails commands instead of this call. Failover
+    /// lists, memcached and sled still connect at once.
+    pub fn connect_lazily(&self) -> Result<KvCache, KvError> {
+        self.connect_lazily_with_timeouts(KvTimeouts::default())
+    }
+
+    /// Same as `connect_lazily`, with `timeouts` on every Redis connection opened.
+    pub fn connect_lazily_with_timeouts(&self, timeouts: KvTimeouts) -> Result<KvCache, KvError> {
+        self.connect_lazily_with(timeouts, &RedisAuth::default())
+    }
+
+    /// Same as `connect_lazily`, with `timeouts` and `auth` on every Redis connection opened.
+    pub fn connect_lazily_with(
+        &self,
+        timeouts: KvTimeouts,
+        auth: &RedisAuth,
+    ) -> Result<KvCache, KvError> {
+        match self {
+            ConnectionOptions::Url(url) if self.is_redis() => {
+                let client = auth
+                    .client(url)
+                    .map_err(|_| KvError::ConnectionNotEstablished)?;
+                let auth = auth.clone();
+                Ok(KvCache::lazy(Box::new(move || {
+                    timeouts
+                        .open(&client, RECONNECT_TIMEOUT, &auth)
+                        .map(KvConnection::Redis)
+                })))
+            }
+            ConnectionOptions::Sentinel {
+                sentinels,
+                master_name,
+            } => {
+                let mut sentinel = SentinelClient::build(
+                    sentinels.clone(),
+                    master_name.clone(),
+                    auth.sentinel_info(),
+                    SentinelServerType::Master,
+                )
+                .map_err(|_| KvError::ConnectionNotEstablished)?;
+                let auth = auth.clone();
+                Ok(KvCache::lazy(Box::new(move || {
+                    let mut con = sentinel.get_connection()?;
+                    timeouts.apply(&con)?;
+                    auth.name(&mut con)?;
+                    Ok(KvConnection::Redis(con))
+                })))
+            }
+            _ => self.connect_with(timeouts, auth),
+        }
     }
 }
 
 impl KvCache {
+    /// Connects to Redis, to memcached for a `memcache://host:port` URL, or opens the sled
+    /// database at `sled://<path>` with the `sled` feature. Neither serves scripts or pub/sub;
+    /// see `MemcachedConnection` and `SledConnection`. A lost Redis connection is reopened
+    /// according to the reconnect policy.
     pub fn new(url: &str) -> Result<KvCache, KvError> {
-        let client = Client::open(url).map_err(|_| KvError::ConnectionNotEstablished)?;
-        let con = client
+        KvCache::open_url(url, KvTimeouts::default(), &RedisAuth::default())
+    }
+
+    fn open_url(url: &str, timeouts: KvTimeouts, auth: &RedisAuth) -> Result<KvCache, KvError> {
+        if url.starts_with(memory_only::URL_SCHEME) {
+            return Ok(KvCache::from_connection(KvConnection::MemoryOnly(
+                MemoryOnlyConnection,
+            )));
+        }
+        if url.starts_with(memcached::URL_SCHEME) {
+            let con =
+                MemcachedConnection::open(url).map_err(|_| KvError::ConnectionNotEstablished)?;
+            return Ok(KvCache::from_connection(KvConnection::Memcached(con)));
+        }
+        #[cfg(feature = "sled")]
+        if url.starts_with(sled_store::URL_SCHEME) {
+            let con = SledConnection::open(url).map_err(|_| KvError::ConnectionNotEstablished)?;
+            return Ok(KvCache::from_connection(KvConnection::Sled(con)));
+        }
+        let client = auth
+            .client(url)
+            .map_err(|_| KvError::ConnectionNotEstablished)?;
+        let con = match timeouts.connect {
+            Some(_) => timeouts.open(&client, RECONNECT_TIMEOUT, auth),
+            None => client.get_connection().and_then(|mut con| {
+                timeouts.apply(&con)?;
+                auth.name(&mut con)?;
+                Ok(con)
+            }),
+        }
+        .map_err(not_established)?;
+        let auth = auth.clone();
+        Ok(KvCache {
+            reconnect: Some(Box::new(move || {
+                timeouts
+                    .open(&client, RECONNECT_TIMEOUT, &auth)
+                    .map(KvConnection::Redis)
+            })),
+            ..KvCache::from_connection(KvConnection::Redis(con))
+        })
+    }
+
+    /// Connects to the current primary of `master_name` as reported by the given Sentinels.
+    /// When the primary goes away or turns into a replica, the next command asks the
+    /// Sentinels again and retries once against the newly promoted primary.
+    pub fn from_sentinel(sentinels: &[&str], master_name: &str) -> Result<KvCache, KvError> {
+        KvCache::open_sentinel(
+            sentinels,
+            master_name,
+            KvTimeouts::default(),
+            &RedisAuth::default(),
+        )
+    }
+
+    fn open_sentinel(
+        sentinels: &[&str],
+        master_name: &str,
+        timeouts: KvTimeouts,
+        auth: &RedisAuth,
+    ) -> Result<KvCache, KvError> {
+        let mut sentinel = SentinelClient::build(
+            sentinels.to_vec(),
+            master_name.to_string(),
+            auth.sentinel_info(),
+            SentinelServerType::Master,
+        )
+        .map_err(|_| KvError::ConnectionNotEstablished)?;
+        let con = sentinel
             .get_connection()
+            .and_then(|mut con| {
+                timeouts.apply(&con)?;
+                auth.name(&mut con)?;
+                Ok(con)
+            })
+            .map_err(not_established)?;
+        let auth = auth.clone();
+        Ok(KvCache {
+            reconnect: Some(Box::new(move || {
+                let mut con = sentinel.get_connection()?;
+                timeouts.apply(&con)?;
+                auth.name(&mut con)?;
+                Ok(KvConnection::Redis(con))
+            })),
+            ..KvCache::from_connection(KvConnection::Redis(con))
+        })
+    }
+
+    /// Connects to the first reachable of `urls` and moves between them as described on
+    /// `FailoverConnection`.
+    pub fn from_failover(urls: &[&str], probe_interval: Duration) -> Result<KvCache, KvError> {
+        let con = FailoverConnection::open(urls, probe_interval)
             .map_err(|_| KvError::ConnectionNotEstablished)?;
-        Ok(KvCache { con })
+        Ok(KvCache::from_connection(KvConnection::Failover(con)))
     }
 
-    pub fn set(&mut self, payload: SetPayload) -> Result<String, KvError> {
-        let res: String = self.con.get(payload.key).unwrap_or_else(|_| "".to_string());
-        if res.is_empty() {
-            self.con
-                .set_ex(payload.key, payload.value, payload.ttl)
-                .map_err(KvError::CommandFailed)?;
-            return Ok(payload.value.to_string());
+    /// Connects with `connect` on the first command, retrying according to the reconnect
+    /// policy if that fails.
+    fn lazy(connect: Reconnect<KvConnection>) -> KvCache {
+        KvCache {
+            reconnect: Some(connect),
+            outage: Some(Outage {
+                failures: 0,
+                next_attempt: Instant::now(),
+            }),
+            ..KvCache::from_connection(KvConnection::Pending)
+        }
+    }
+
+    /// Calls `observer` whenever a failover connection switches URLs. No effect on other
+    /// connections.
+    pub fn set_failover_observer(&mut self, observer: FailoverObserver) {
+        if let KvConnection::Failover(con) = &mut self.con {
+            con.set_observer(observer);
+        }
+    }
+}
+
+impl<C: ConnectionLike> KvCache<C> {
+    pub fn from_connection(con: C) -> KvCache<C> {
+        KvCache {
+            con,
+            reconnect: None,
+            max_age: None,
+            compression: None,
+            encryption: None,
+            read_unencrypted: false,
+            writer: None,
+            legacy_policy: LegacyValuePolicy::default(),
+            migration: Arc::default(),
+            directory: None,
+            retry: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            outage: None,
+            tracking: None,
         }
-        Ok(res)
     }
 
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        let res: String = self.con.get(key).unwrap_or_else(|_| "".to_string());
-        if res.is_empty() {
+    /// Gives up the cache and returns its connection, e.g. to turn it into a pub/sub listener.
+    pub fn into_connection(self) -> C {
+        self.con
+    }
+
+    /// Runs `command`, reconnecting once if the connection was lost and retrying transient
+    /// errors under the retry policy.
+    fn run<R, F>(&mut self, command: F) -> RedisResult<R>
+    where
+        F: Fn(&mut C) -> RedisResult<R>,
+    {
+        self.run_resending(command, is_transient)
+    }
+
+    /// Runs a command that must not be applied twice, like INCRBY. A timeout or a dropped
+    /// connection may have lost the reply to a command that ran, so those fail at once; only
+    /// errors telling that it did not run are retried.
+    fn run_unrepeated<R, F>(&mut self, command: F) -> RedisResult<R>
+    where
+        F: Fn(&mut C) -> RedisResult<R>,
+    {
+        self.run_resending(command, was_rejected)
+    }
+
+    /// Runs `command`, sending it again after the errors `resend` accepts.
+    fn run_resending<R, F>(&mut self, command: F, resend: fn(&RedisError) -> bool) -> RedisResult<R>
+    where
+        F: Fn(&mut C) -> RedisResult<R>,
+    {
+        let mut attempt = 1;
+        loop {
+            let res = self.run_once(&command, resend);
+            match (self.retry, &res) {
+                (Some(retry), Err(err)) if attempt < retry.max_attempts && resend(err) => {
+                    thread::sleep(retry.delay(attempt));
+                    attempt += 1;
+                }
+                _ => return res,
+            }
+        }
+    }
+
+    fn run_once<R, F>(&mut self, command: &F, resend: fn(&RedisError) -> bool) -> RedisResult<R>
+    where
+        F: Fn(&mut C) -> RedisResult<R>,
+    {
+        if self.outage.is_some() {
+            self.restore()?;
+        }
+        match command(&mut self.con) {
+            Err(err) if self.reconnect.is_some() && is_failover_error(&err) => {
+                self.reconnect_now()?;
+                if !resend(&err) {
+                    return Err(err);
+                }
+                command(&mut self.con)
+            }
+            Err(err) if self.reconnect.is_some() && err.is_timeout() => {
+                // The answer may still arrive, so the next command gets a new connection.
+                self.outage = Some(Outage {
+                    failures: 0,
+                    next_attempt: Instant::now(),
+                });
+                Err(err)
+            }
+            res => res,
+        }
+    }
+
+    /// Tries to reconnect after an outage once the next attempt is due, waiting for it or
+    /// failing at once according to the reconnect policy.
+    fn restore(&mut self) -> RedisResult<()> {
+        let Some(outage) = &self.outage else {
+            return Ok(());
+        };
+        let wait = outage
+            .next_attempt
+            .saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            match self.reconnect_policy.while_reconnecting {
+                WhileReconnecting::Wait(max_wait) if wait <= max_wait => thread::sleep(wait),
+                _ => return Err(reconnecting()),
+            }
+        }
+        self.reconnect_now()
+    }
+
+    fn reconnect_now(&mut self) -> RedisResult<()> {
+        let Some(reconnect) = self.reconnect.as_mut() else {
+            return Ok(());
+        };
+        let tracking = &self.tracking;
+        let reconnected = reconnect().and_then(|mut con| {
+            if let Some((redirect, prefix)) = tracking {
+                tracking_command(*redirect, prefix.as_deref()).query::<()>(&mut con)?;
+            }
+            Ok(con)
+        });
+        match reconnected {
+            Ok(con) => {
+                self.con = con;
+                self.outage = None;
+                Ok(())
+            }
+            Err(err) => {
+                let failures = self.outage.map_or(0, |outage| outage.failures) + 1;
+                self.outage = Some(Outage {
+                    failures,
+                    next_attempt: Instant::now() + self.reconnect_policy.delay(failures),
+                });
+                Err(err)
+            }
+        }
+    }
+
+    /// Turns on Redis client tracking in broadcasting mode for the keys starting with
+    /// `prefix`, or all keys, and sends the invalidation messages to the connection with the
+    /// client ID `redirect`. Writes through this connection are not reported. Tracking is
+    /// turned on again whenever the connection is replaced.
+    pub fn set_client_tracking(
+        &mut self,
+        redirect: i64,
+        prefix: Option<&str>,
+    ) -> Result<(), KvError> {
+        self.tracking = Some((redirect, prefix.map(str::to_string)));
+        let command = tracking_command(redirect, prefix);
+        self.run(|con| command.query(con)).map_err(KvError::from)
+    }
+
+    /// Retries commands that fail with a transient error according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = Some(policy);
+    }
+
+    /// Spaces out attempts to get a lost connection back according to `policy`.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Values older than `max_age` since they were first stored read as missing, and their
+    /// Redis TTL never reaches past that age, even when merges keep rewriting them. Envelopes
+    /// record when a value was created to the second, so `max_age` is rounded up to whole
+    /// seconds.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age.as_secs() + u64::from(max_age.subsec_nanos() > 0));
+    }
+
+    /// Values written from now on are compressed according to `compression`. Reads handle
+    /// any codec regardless.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Values written from now on record `writer` in their envelope. `writer` must be valid.
+    pub fn set_writer(&mut self, writer: Provenance) {
+        self.writer = Some(writer);
+    }
+
+    /// Values written from now on are encrypted with the current key of `encryption`, after
+    /// compression, bound to the key they are stored under. Reads decrypt with whichever key
+    /// a value names. Encrypted values that cannot be decrypted, and values that are not
+    /// encrypted unless `set_read_unencrypted` allows them, read as missing and are left
+    /// alone. Counters are encrypted too, see `increment`.
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = Some(encryption);
+    }
+
+    /// Reads values that are not encrypted while encryption is set, e.g. ones written before
+    /// it was turned on, and converts them like other legacy values. Off by default, so
+    /// whoever can write to Redis can't plant values that are read as the service's own.
+    pub fn set_read_unencrypted(&mut self, allowed: bool) {
+        self.read_unencrypted = allowed;
+    }
+
+    fn seal(&self, key: &str, created: u64, payload: &[u8]) -> Vec<u8> {
+        let sealed = Envelope::encode_by(created, payload, self.compression, self.writer.as_ref());
+        self.encrypt(key, sealed)
+    }
+
+    fn encrypt(&self, key: &str, sealed: Vec<u8>) -> Vec<u8> {
+        match &self.encryption {
+            Some(encryption) => encryption.encrypt(key.as_bytes(), &sealed),
+            None => sealed,
+        }
+    }
+
+    /// The envelope inside `raw` as stored under `key`, or `None` for an encrypted value
+    /// without a key to it and for a plain one encryption doesn't let through.
+    fn decrypt(&self, key: &str, raw: Bytes) -> Option<Bytes> {
+        if encryption::is_encrypted(&raw) {
+            return self.encryption.as_ref()?.decrypt(key.as_bytes(), &raw);
+        }
+        if self.encryption.is_none() || self.read_unencrypted {
+            return Some(raw);
+        }
+        None
+    }
+
+    /// Decodes a raw value as read under `key`, e.g. by `sample_raw`, decrypting it first.
+    pub(crate) fn decode(&self, key: &str, raw: Bytes) -> Decoded {
+        match self.decrypt(key, raw) {
+            Some(raw) => Envelope::decode(raw),
+            None => Decoded::Unreadable,
+        }
+    }
+
+    /// What happens to plain values written before envelopes existed when they are read.
+    pub fn set_legacy_policy(&mut self, policy: LegacyValuePolicy) {
+        self.legacy_policy = policy;
+    }
+
+    /// Makes this connection count migrated entries into `counters`.
+    pub fn share_migration_counters(&mut self, counters: Arc<MigrationCounters>) {
+        self.migration = counters;
+    }
+
+    pub fn migration_stats(&self) -> MigrationStats {
+        self.migration.stats()
+    }
+
+    /// Lists every key written from now on in the Redis set `directory`, in the same
+    /// transaction as the write, and unlists deleted keys the same way.
+    pub fn set_directory(&mut self, directory: &str) {
+        self.directory = Some(directory.to_string());
+    }
+
+    /// The members of the directory set, empty without a directory.
+    pub fn directory_members(&mut self) -> Result<HashSet<String>, KvError> {
+        let Some(directory) = self.directory.clone() else {
+            return Ok(HashSet::new());
+        };
+        self.run(|con| con.smembers(&directory))
+            .map_err(KvError::from)
+    }
+
+    /// Adds `keys` to the directory, for writes that cannot share a transaction with it.
+    fn list(&mut self, keys: &[&str]) -> Result<(), KvError> {
+        let Some(directory) = self.directory.clone() else {
+            return Ok(());
+        };
+        self.run(|con| con.sadd::<_, _, ()>(&directory, keys))
+            .map_err(KvError::from)
+    }
+
+    /// Removes `key` from the directory unless it exists after all, e.g. once it expired.
+    /// Watching the key keeps a concurrent write from being unlisted.
+    pub fn unlist_missing(&mut self, key: &str) -> Result<(), KvError> {
+        let Some(directory) = self.directory.clone() else {
+            return Ok(());
+        };
+        self.run(|con| {
+            redis::transaction(con, &[key], |con, pipe| {
+                if con.exists(key)? {
+                    return Ok(Some(()));
+                }
+                pipe.srem(&directory, key).ignore().query(con)
+            })
+        })
+        .map_err(KvError::from)
+    }
+
+    /// Unwraps a raw value read from `key`, migrating it first if it is in an old format.
+    fn open(&mut self, key: &str, raw: Option<Vec<u8>>, now: u64) -> Option<Envelope> {
+        let raw = Bytes::from(raw?);
+        if raw.is_empty() {
+            return None;
+        }
+        let envelope = match Envelope::decode(self.decrypt(key, raw.clone())?) {
+            Decoded::Current(envelope) => envelope,
+            Decoded::Legacy(payload) if is_counter(&payload) => Envelope {
+                created: now,
+                writer: None,
+                payload,
+            },
+            Decoded::Legacy(payload) => self.migrate(key, &raw, Some(payload), now).ok()??,
+            Decoded::Unreadable => self.migrate(key, &raw, None, now).ok()??,
+        };
+        let too_old = self
+            .max_age
+            .is_some_and(|max_age| now >= envelope.created + max_age);
+        if envelope.payload.is_empty() || too_old {
             return None;
         }
-        Some(res)
+        Some(envelope)
+    }
+
+    /// Converts or discards a value stored in an old format, unless it changed since it was
+    /// read. Returns the converted envelope.
+    fn migrate(
+        &mut self,
+        key: &str,
+        raw: &[u8],
+        legacy: Option<Bytes>,
+        now: u64,
+    ) -> Result<Option<Envelope>, KvError> {
+        if let (LegacyValuePolicy::Convert, Some(payload)) = (self.legacy_policy, legacy) {
+            let converted = self.seal(key, now, &payload);
+            if self.compare_and_set(key, Some(raw), &converted, None)? {
+                self.migration.record(MigrationStats {
+                    converted: 1,
+                    discarded: 0,
+                });
+            }
+            return Ok(Some(Envelope {
+                created: now,
+                writer: self.writer.clone(),
+                payload,
+            }));
+        }
+        let script = Script::new(COMPARE_AND_DELETE);
+        let deleted: i64 = self
+            .run(|con| script.key(key).arg(raw).invoke(con))
+            .map_err(KvError::from)?;
+        if deleted == 1 {
+            self.migration.record(MigrationStats {
+                converted: 0,
+                discarded: 1,
+            });
+        }
+        Ok(None)
+    }
+
+    /// Walks the keys matching `pattern` and migrates every value still in an old format.
+    /// Returns what this scan converted and discarded.
+    pub fn scan_legacy(&mut self, pattern: &str) -> Result<MigrationStats, KvError> {
+        let keys = self.scan_keys(pattern)?;
+        let mut stats = MigrationStats::default();
+        for chunk in keys.chunks(SCAN_BATCH_SIZE) {
+            // Keys holding other types read as nil.
+            let values: Vec<Option<Vec<u8>>> = self
+                .run(|con| redis::cmd("MGET").arg(chunk).query(con))
+                .map_err(KvError::from)?;
+            let now = SystemTimeSource.now();
+            for (key, raw) in chunk.iter().zip(values) {
+                let Some(raw) = raw.filter(|raw| !raw.is_empty()) else {
+                    continue;
+                };
+                let Some(decrypted) = self.decrypt(key, Bytes::from(raw.clone())) else {
+                    continue;
+                };
+                let legacy = match Envelope::decode(decrypted) {
+                    Decoded::Current(_) => continue,
+                    Decoded::Legacy(payload) if is_counter(&payload) => continue,
+                    Decoded::Legacy(payload) => Some(payload),
+                    Decoded::Unreadable => None,
+                };
+                match self.migrate(key, &raw, legacy, now)? {
+                    Some(_) => stats.converted += 1,
+                    None => stats.discarded += 1,
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Copies the string values of the keys matching `pattern` to the keys `rename` gives for
+    /// them, wrapped in envelopes, for the rest of their TTL or `default_ttl` if they
+    /// have none. Values that already are envelopes are copied as they are, and target keys
+    /// that exist are left alone. The source keys stay. `progress` is called after every batch.
+    pub fn import(
+        &mut self,
+        pattern: &str,
+        rename: impl Fn(&str) -> String,
+        default_ttl: Duration,
+        mut progress: impl FnMut(&ImportStats),
+    ) -> Result<ImportStats, KvError> {
+        let keys = self.scan_keys(pattern)?;
+        let mut stats = ImportStats::default();
+        for chunk in keys.chunks(SCAN_BATCH_SIZE) {
+            let mut pipe = redis::pipe();
+            // Keys holding other types read as nil.
+            pipe.cmd("MGET").arg(chunk);
+            for key in chunk {
+                pipe.pttl(key);
+            }
+            let (values, ttls): (Vec<Option<Vec<u8>>>, Vec<i64>) = self
+                .run(|con| {
+                    let mut res: Vec<redis::Value> = pipe.query(con)?;
+                    let ttls = res.split_off(1);
+                    let values = redis::from_redis_value(&res[0])?;
+                    let ttls = ttls
+                        .iter()
+                        .map(redis::from_redis_value)
+                        .collect::<RedisResult<Vec<i64>>>()?;
+                    Ok((values, ttls))
+                })
+                .map_err(KvError::from)?;
+
+            let now = SystemTimeSource.now();
+            let mut pipe = redis::pipe();
+            let mut copied = 0;
+            for ((key, raw), ttl) in chunk.iter().zip(values).zip(ttls) {
+                let ttl = match ttl {
+                    -1 => Some(expiry_millis(default_ttl)),
+                    ttl => u64::try_from(ttl).ok().filter(|&ttl| ttl > 0).map(Some),
+                };
+                let (Some(raw), Some(ttl)) = (raw, ttl) else {
+                    stats.skipped += 1;
+                    continue;
+                };
+                let raw = Bytes::from(raw);
+                let target = rename(key);
+                // Sources are imported whether or not they are encrypted, and encrypted ones
+                // are bound to their key, so they are encrypted again for the target.
+                let plain = if encryption::is_encrypted(&raw) {
+                    self.decrypt(key, raw.clone())
+                } else {
+                    Some(raw.clone())
+                };
+                let value = match plain.map(|plain| (Envelope::decode(plain.clone()), plain)) {
+                    Some((Decoded::Legacy(payload), _)) => {
+                        Bytes::from(self.seal(&target, now, &payload))
+                    }
+                    Some((_, plain)) if self.encryption.is_some() => {
+                        Bytes::from(self.encrypt(&target, plain.to_vec()))
+                    }
+                    _ => raw,
+                };
+                pipe.cmd("SET").arg(&target).arg(&value[..]).arg("NX");
+                if let Some(ttl) = ttl {
+                    pipe.arg("PX").arg(ttl);
+                }
+                if let Some(directory) = &self.directory {
+                    pipe.sadd(directory, &target).ignore();
+                }
+                copied += 1;
+            }
+            let written: Vec<Option<String>> = if copied > 0 {
+                self.run(|con| pipe.query(con)).map_err(KvError::from)?
+            } else {
+                Vec::new()
+            };
+            let imported = written.iter().filter(|res| res.is_some()).count() as u64;
+            stats.scanned += chunk.len() as u64;
+            stats.imported += imported;
+            stats.existing += copied - imported;
+            progress(&stats);
+        }
+        Ok(stats)
+    }
+
+    /// Every key matching `pattern`, listed with SCAN.
+    pub fn scan_keys(&mut self, pattern: &str) -> Result<Vec<String>, KvError> {
+        self.run(|con| Ok(con.scan_match::<_, String>(pattern)?.collect()))
+            .map_err(KvError::from)
+    }
+
+    /// Raw values of up to `count` keys matching `pattern`, scanned from a random point of the
+    /// key space and wrapping around to the start if that runs out. Values are not decoded or
+    /// migrated.
+    pub fn sample_raw(
+        &mut self,
+        pattern: &str,
+        count: usize,
+    ) -> Result<Vec<(String, Bytes)>, KvError> {
+        let mut keys = Vec::with_capacity(count);
+        for start in [rand::random::<u32>().into(), 0] {
+            let mut cursor: u64 = start;
+            loop {
+                let (next, batch): (u64, Vec<String>) = self
+                    .run(|con| {
+                        redis::cmd("SCAN")
+                            .arg(cursor)
+                            .arg("MATCH")
+                            .arg(pattern)
+                            .arg("COUNT")
+                            .arg(count.max(10))
+                            .query(con)
+                    })
+                    .map_err(KvError::from)?;
+                keys.extend(batch);
+                cursor = next;
+                if keys.len() >= count || cursor == 0 {
+                    break;
+                }
+            }
+            keys.sort_unstable();
+            keys.dedup();
+            if keys.len() >= count {
+                break;
+            }
+        }
+        keys.truncate(count);
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Keys holding other types read as nil.
+        let values: Vec<Option<Vec<u8>>> = self
+            .run(|con| redis::cmd("MGET").arg(&keys).query(con))
+            .map_err(KvError::from)?;
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, raw)| Some((key, Bytes::from(raw?))))
+            .collect())
+    }
+
+    /// Resets the expiry of every existing key in `keys` to `ttl` from now.
+    pub fn touch_many(&mut self, keys: &[&str], ttl: Duration) -> Result<(), KvError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys {
+            expire_in(&mut pipe, key, ttl).ignore();
+        }
+        self.run(|con| pipe.query::<()>(con)).map_err(KvError::from)
+    }
+
+    /// Time until `key` expires, `Duration::MAX` if it never does. `None` if it does not
+    /// exist.
+    pub fn remaining_ttl(&mut self, key: &str) -> Result<Option<Duration>, KvError> {
+        let ttl: i64 = self.run(|con| con.pttl(key)).map_err(KvError::from)?;
+        Ok(match ttl {
+            -1 => Some(Duration::MAX),
+            ttl => u64::try_from(ttl).ok().map(Duration::from_millis),
+        })
+    }
+
+    /// Makes `key` expire `ttl` from now. Returns false if it does not exist.
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let mut pipe = redis::pipe();
+        expire_in(&mut pipe, key, ttl).ignore().exists(key);
+        let (exists,): (bool,) = self.run(|con| pipe.query(con)).map_err(KvError::from)?;
+        Ok(exists)
+    }
+
+    /// How many of `keys` exist in Redis.
+    pub fn count_existing(&mut self, keys: &[&str]) -> Result<u64, KvError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.run(|con| con.exists(keys)).map_err(KvError::from)
+    }
+
+    /// Number of keys matching `pattern`, counted with SCAN.
+    pub fn count_keys(&mut self, pattern: &str) -> Result<u64, KvError> {
+        Ok(self.scan_keys(pattern)?.len() as u64)
+    }
+
+    /// Up to `limit` keys matching `pattern` with their values and remaining TTLs, in SCAN
+    /// order. Keys that expire or turn out unreadable between the scan and the read are left
+    /// out.
+    pub fn scan_with_ttl(
+        &mut self,
+        pattern: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, ValueWithTtl)>, KvError> {
+        let keys: Vec<String> = self
+            .run(|con| Ok(con.scan_match::<_, String>(pattern)?.take(limit).collect()))
+            .map_err(KvError::from)?;
+        let mut found = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(SCAN_BATCH_SIZE) {
+            let chunk: Vec<&str> = chunk.iter().map(String::as_str).collect();
+            let values = self.get_many_with_ttl(&chunk)?;
+            for (key, value) in chunk.into_iter().zip(values) {
+                if let Some(value) = value {
+                    found.push((key.to_string(), value));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Deletes up to `count` keys matching `pattern`, soonest to expire first and keys without
+    /// an expiry last. With uniform TTLs that removes the oldest writes. Returns how many keys
+    /// were deleted.
+    pub fn evict_soonest_expiring(&mut self, pattern: &str, count: u64) -> Result<u64, KvError> {
+        let keys = self.scan_keys(pattern)?;
+        let mut by_expiry = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(SCAN_BATCH_SIZE) {
+            let mut pipe = redis::pipe();
+            for key in chunk {
+                pipe.ttl(key);
+            }
+            let ttls: Vec<i64> = self.run(|con| pipe.query(con)).map_err(KvError::from)?;
+            for (key, ttl) in chunk.iter().zip(ttls) {
+                by_expiry.push((u64::try_from(ttl).unwrap_or(u64::MAX), key));
+            }
+        }
+        by_expiry.sort_unstable();
+
+        let victims: Vec<&String> = by_expiry
+            .into_iter()
+            .take(count as usize)
+            .map(|(_, key)| key)
+            .collect();
+        if victims.is_empty() {
+            return Ok(0);
+        }
+        self.run(|con| con.del::<_, u64>(&victims))
+            .map_err(KvError::from)
+    }
+
+    /// Re-jitters the TTL of every key matching `pattern` by up to `factor`. Keys without an
+    /// expiry are left alone. Returns how many keys were changed.
+    pub fn rebalance_ttls(&mut self, pattern: &str, factor: f64) -> Result<u64, KvError> {
+        let keys = self.scan_keys(pattern)?;
+        let mut rebalanced = 0;
+        for chunk in keys.chunks(SCAN_BATCH_SIZE) {
+            let mut pipe = redis::pipe();
+            for key in chunk {
+                pipe.pttl(key);
+            }
+            let ttls: Vec<i64> = self.run(|con| pipe.query(con)).map_err(KvError::from)?;
+
+            let mut pipe = redis::pipe();
+            for (key, ttl) in chunk.iter().zip(ttls) {
+                if let Some(ttl) = u64::try_from(ttl).ok().filter(|&ttl| ttl > 0) {
+                    let ttl = jitter_ttl(Duration::from_millis(ttl), factor);
+                    pipe.pexpire(key, millis(ttl) as i64).ignore();
+                    rebalanced += 1;
+                }
+            }
+            self.run(|con| pipe.query::<()>(con))
+                .map_err(KvError::from)?;
+        }
+        Ok(rebalanced)
+    }
+
+    fn capped_ttl(&self, created: u64, ttl: Duration, now: u64) -> Duration {
+        match self.max_age {
+            Some(max_age) => {
+                let left = Duration::from_secs((created + max_age).saturating_sub(now));
+                shorter_ttl(ttl, left.max(Duration::from_millis(1)))
+            }
+            None => ttl,
+        }
+    }
+
+    pub fn set(&mut self, payload: SetPayload) -> Result<Bytes, KvError> {
+        if let Some(res) = self.get(payload.key) {
+            return Ok(res);
+        }
+        self.overwrite(payload)?;
+        Ok(Bytes::copy_from_slice(payload.value))
+    }
+
+    pub fn set_nx(&mut self, payload: SetPayload) -> Result<bool, KvError> {
+        let now = SystemTimeSource.now();
+        let mut options = SetOptions::default().conditional_set(ExistenceCheck::NX);
+        if let Some(ttl) = expiry_millis(self.capped_ttl(now, payload.ttl, now)) {
+            options = options.with_expiration(SetExpiry::PX(ttl as usize));
+        }
+        let value = self.seal(payload.key, now, payload.value);
+        let res: Option<String> = self
+            .run(|con| con.set_options(payload.key, &value, options))
+            .map_err(KvError::from)?;
+        Ok(res.is_some())
+    }
+
+    /// Takes the lock `key` for `ttl` with SET NX PX unless someone holds it. The value is a
+    /// random token in an envelope, so the legacy scan leaves it alone. Returns the raw value,
+    /// which `unlock` needs.
+    pub fn lock(&mut self, key: &str, ttl: Duration) -> Result<Option<Bytes>, KvError> {
+        let token = format!("{:032x}", rand::random::<u128>());
+        let value = Bytes::from(self.seal(key, SystemTimeSource.now(), token.as_bytes()));
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::PX(millis(ttl).max(1) as usize));
+        let res: Option<String> = self
+            .run(|con| con.set_options(key, &value[..], options))
+            .map_err(KvError::from)?;
+        Ok(res.map(|_| value))
+    }
+
+    /// Releases the lock `key` if it still holds `value`, as returned by `lock`. Returns false
+    /// if the lock expired in the meantime.
+    pub fn unlock(&mut self, key: &str, value: &[u8]) -> Result<bool, KvError> {
+        let script = Script::new(COMPARE_AND_DELETE);
+        let deleted: i64 = self
+            .run(|con| script.key(key).arg(value).invoke(con))
+            .map_err(KvError::from)?;
+        Ok(deleted == 1)
+    }
+
+    pub fn overwrite(&mut self, payload: SetPayload) -> Result<(), KvError> {
+        let now = SystemTimeSource.now();
+        let ttl = self.capped_ttl(now, payload.ttl, now);
+        let value = self.seal(payload.key, now, payload.value);
+        let mut pipe = redis::pipe();
+        set_in(&mut pipe, payload.key, &value, ttl).ignore();
+        if let Some(directory) = &self.directory {
+            pipe.atomic().sadd(directory, payload.key).ignore();
+        }
+        self.run(|con| pipe.query::<()>(con))
+            .map_err(KvError::from)?;
+        Ok(())
+    }
+
+    /// Writes `value` over the current raw value only if the key still holds `expected`.
+    fn compare_and_set(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<bool, KvError> {
+        self.compare_and_set_by(key, expected, value, ttl, is_transient)
+    }
+
+    /// `compare_and_set`, sending the script again only after the errors `resend` accepts.
+    fn compare_and_set_by(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: &[u8],
+        ttl: Option<Duration>,
+        resend: fn(&RedisError) -> bool,
+    ) -> Result<bool, KvError> {
+        let script = Script::new(COMPARE_AND_SET);
+        let swapped: i64 = self
+            .run_resending(
+                |con| {
+                    script
+                        .key(key)
+                        .arg(if expected.is_some() { "1" } else { "0" })
+                        .arg(expected.unwrap_or_default())
+                        .arg(value)
+                        .arg(match ttl.map(expiry_millis) {
+                            None => "keep".to_string(),
+                            Some(None) => "none".to_string(),
+                            Some(Some(ttl)) => ttl.to_string(),
+                        })
+                        .invoke(con)
+                },
+                resend,
+            )
+            .map_err(KvError::from)?;
+        Ok(swapped == 1)
+    }
+
+    /// Stores `payload` according to `policy` and returns the value that ended up in Redis.
+    pub fn set_with_policy(
+        &mut self,
+        payload: SetPayload,
+        policy: &ConflictPolicy,
+    ) -> Result<Bytes, KvError> {
+        match policy {
+            ConflictPolicy::KeepNewest => {
+                self.overwrite(payload)?;
+                Ok(Bytes::copy_from_slice(payload.value))
+            }
+            ConflictPolicy::KeepOldest => {
+                let stored = self.set_nx(payload)?;
+                self.list(&[payload.key])?;
+                if stored {
+                    return Ok(Bytes::copy_from_slice(payload.value));
+                }
+                Ok(self
+                    .get(payload.key)
+                    .unwrap_or_else(|| Bytes::copy_from_slice(payload.value)))
+            }
+            ConflictPolicy::Merge(_) => {
+                for _ in 0..MAX_MERGE_ATTEMPTS {
+                    let raw: Option<Vec<u8>> = self
+                        .run(|con| con.get(payload.key))
+                        .map_err(KvError::from)?;
+                    let now = SystemTimeSource.now();
+                    let existing = self.open(payload.key, raw.clone(), now);
+                    let value = policy.apply(
+                        existing.as_ref().map(|envelope| &envelope.payload[..]),
+                        payload.value,
+                    );
+                    // The merged value keeps the age of the one it was merged into.
+                    let created = existing.map_or(now, |envelope| envelope.created);
+                    let ttl = self.capped_ttl(created, payload.ttl, now);
+                    let merged = self.seal(payload.key, created, &value);
+                    if self.compare_and_set(payload.key, raw.as_deref(), &merged, Some(ttl))? {
+                        self.list(&[payload.key])?;
+                        return Ok(Bytes::from(value));
+                    }
+                }
+                Err(KvError::MergeConflict)
+            }
+        }
+    }
+
+    /// Adds `delta` to the integer stored under `key` and returns the result with the time the
+    /// key has left, `None` if it never expires. A missing counter starts at 0 and lives for
+    /// `ttl`; existing ones keep their expiry. Counters are stored as plain integers, not in
+    /// envelopes, so INCRBY updates them atomically; reads pass them through as they are.
+    /// With encryption they are sealed like other values instead, see `increment_sealed`.
+    /// Timeouts and lost connections fail instead of being retried, as the increment may have
+    /// happened.
+    pub fn increment(
+        &mut self,
+        key: &str,
+        delta: i64,
+        ttl: Duration,
+    ) -> Result<(i64, Option<Duration>), KvError> {
+        if self.encryption.is_some() {
+            return self.increment_sealed(key, delta, ttl);
+        }
+        let now = SystemTimeSource.now();
+        let ttl = match expiry_millis(self.capped_ttl(now, ttl, now)) {
+            Some(ttl) => ttl.to_string(),
+            None => "none".to_string(),
+        };
+        let script = Script::new(INCREMENT);
+        let (count, remaining): (i64, i64) = self
+            .run_unrepeated(|con| script.key(key).arg(delta).arg(&ttl).invoke(con))
+            .map_err(KvError::from)?;
+        self.list(&[key])?;
+        let remaining = u64::try_from(remaining).ok().map(Duration::from_millis);
+        Ok((count, remaining))
+    }
+
+    /// Increments an encrypted counter, which Redis can't add to, by compare-and-set. A lost
+    /// race reads the counter again, so no increment is lost. A value that can't be read,
+    /// like a plain integer planted in Redis, counts as missing and is replaced.
+    fn increment_sealed(
+        &mut self,
+        key: &str,
+        delta: i64,
+        ttl: Duration,
+    ) -> Result<(i64, Option<Duration>), KvError> {
+        loop {
+            let (raw, remaining): (Option<Vec<u8>>, i64) = self
+                .run(|con| redis::pipe().get(key).pttl(key).query(con))
+                .map_err(KvError::from)?;
+            let now = SystemTimeSource.now();
+            let (counted, created, ttl, remaining) = match self.open(key, raw.clone(), now) {
+                Some(envelope) => (
+                    count(&envelope.payload)
+                        .ok_or_else(|| KvError::CommandFailed(not_a_counter()))?,
+                    envelope.created,
+                    None,
+                    u64::try_from(remaining).ok().map(Duration::from_millis),
+                ),
+                None => {
+                    let ttl = self.capped_ttl(now, ttl, now);
+                    let remaining = expiry_millis(ttl).map(Duration::from_millis);
+                    (0, now, Some(ttl), remaining)
+                }
+            };
+            let counted = counted
+                .checked_add(delta)
+                .ok_or_else(|| KvError::CommandFailed(counter_overflow()))?;
+            let sealed = self.seal(key, created, counted.to_string().as_bytes());
+            if self.compare_and_set_by(key, raw.as_deref(), &sealed, ttl, was_rejected)? {
+                self.list(&[key])?;
+                return Ok((counted, remaining));
+            }
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Bytes> {
+        let res: Option<Vec<u8>> = self.run(|con| con.get(key)).unwrap_or(None);
+        self.open(key, res, SystemTimeSource.now())
+            .map(|envelope| envelope.payload)
+    }
+
+    /// The value stored under `key` and its version. A value in an old format is converted or
+    /// discarded first, which changes its version. Counters are versioned as they are.
+    pub fn get_versioned(&mut self, key: &str) -> Result<Option<(Bytes, Version)>, KvError> {
+        let mut previous = None;
+        loop {
+            let raw: Option<Vec<u8>> = self.run(|con| con.get(key)).map_err(KvError::from)?;
+            let Some(raw) = raw else {
+                return Ok(None);
+            };
+            let version = Version(Bytes::from(raw));
+            let migrated = match self.decrypt(key, version.0.clone()).map(Envelope::decode) {
+                Some(Decoded::Legacy(payload)) => !is_counter(&payload),
+                Some(Decoded::Unreadable) => true,
+                _ => false,
+            };
+            let envelope = self.open(key, Some(version.0.to_vec()), SystemTimeSource.now());
+            // A migration that left the value alone would be read and tried again forever.
+            if !migrated || previous.as_ref() == Some(&version) {
+                return Ok(envelope.map(|envelope| (envelope.payload, version)));
+            }
+            previous = Some(version);
+        }
+    }
+
+    /// Stores `payload` only if `key` still holds the value of version `expected`, or nothing
+    /// for `None`. Returns the new version, or `None` if the key changed since it was read.
+    pub fn set_if_version(
+        &mut self,
+        payload: SetPayload,
+        expected: Option<&Version>,
+    ) -> Result<Option<Version>, KvError> {
+        let now = SystemTimeSource.now();
+        let ttl = self.capped_ttl(now, payload.ttl, now);
+        let value = self.seal(payload.key, now, payload.value);
+        let expected = expected.map(|version| &version.0[..]);
+        if !self.compare_and_set(payload.key, expected, &value, Some(ttl))? {
+            return Ok(None);
+        }
+        self.list(&[payload.key])?;
+        Ok(Some(Version(Bytes::from(value))))
+    }
+
+    /// The envelope stored under `key` with its remaining TTL, for inspecting an entry.
+    pub fn envelope_with_ttl(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<(Envelope, Option<u64>)>, KvError> {
+        let (raw, ttl): (Option<Vec<u8>>, i64) = self
+            .run(|con| redis::pipe().get(key).ttl(key).query(con))
+            .map_err(KvError::from)?;
+        let envelope = self.open(key, raw, SystemTimeSource.now());
+        Ok(envelope.map(|envelope| (envelope, u64::try_from(ttl).ok())))
+    }
+
+    pub fn get_with_ttl(&mut self, key: &str) -> Option<ValueWithTtl> {
+        self.get_many_with_ttl(&[key])
+            .ok()
+            .and_then(|mut values| values.pop())
+            .flatten()
+    }
+
+    pub fn get_many_with_ttl(
+        &mut self,
+        keys: &[&str],
+    ) -> Result<Vec<Option<ValueWithTtl>>, KvError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        pipe.cmd("MGET").arg(keys);
+        for key in keys {
+            pipe.pttl(*key);
+        }
+        let (values, ttls): (Vec<Option<Vec<u8>>>, Vec<i64>) = self
+            .run(|con| {
+                let mut res: Vec<redis::Value> = pipe.query(con)?;
+                let ttls = res.split_off(1);
+                let values = redis::from_redis_value(&res[0])?;
+                let ttls = ttls
+                    .iter()
+                    .map(redis::from_redis_value)
+                    .collect::<RedisResult<Vec<i64>>>()?;
+                Ok((values, ttls))
+            })
+            .map_err(KvError::from)?;
+        let now = SystemTimeSource.now();
+        let mut res = Vec::with_capacity(keys.len());
+        for ((key, value), ttl) in keys.iter().zip(values).zip(ttls) {
+            res.push(self.open(key, value, now).map(|envelope| {
+                let ttl = u64::try_from(ttl).ok().map(Duration::from_millis);
+                (envelope.payload, ttl)
+            }));
+        }
+        Ok(res)
+    }
+
+    pub fn get_many(&mut self, keys: &[&str]) -> Result<Vec<Option<Bytes>>, KvError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let res: Vec<Option<Vec<u8>>> = self
+            .run(|con| redis::cmd("MGET").arg(keys).query(con))
+            .map_err(KvError::from)?;
+        let now = SystemTimeSource.now();
+        Ok(keys
+            .iter()
+            .zip(res)
+            .map(|(key, value)| self.open(key, value, now).map(|envelope| envelope.payload))
+            .collect())
+    }
+
+    pub fn set_many(&mut self, payloads: &[SetPayload]) -> Result<(), KvError> {
+        if payloads.is_empty() {
+            return Ok(());
+        }
+        let now = SystemTimeSource.now();
+        let mut pipe = redis::pipe();
+        for payload in payloads {
+            let ttl = self.capped_ttl(now, payload.ttl, now);
+            let value = self.seal(payload.key, now, payload.value);
+            set_in(&mut pipe, payload.key, &value, ttl).ignore();
+        }
+        if let Some(directory) = &self.directory {
+            let keys: Vec<&str> = payloads.iter().map(|payload| payload.key).collect();
+            pipe.atomic().sadd(directory, keys).ignore();
+        }
+        self.run(|con| pipe.query::<()>(con))
+            .map_err(KvError::from)?;
+        Ok(())
+    }
+
+    pub fn zadd(
+        &mut self,
+        key: &str,
+        members: &[(&str, f64)],
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (member, score) in members {
+            pipe.zadd(key, *member, *score).ignore();
+        }
+        expire_in(&mut pipe, key, ttl).ignore();
+        self.run(|con| pipe.query::<()>(con))
+            .map_err(KvError::from)?;
+        Ok(())
+    }
+
+    pub fn zrevrange(
+        &mut self,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.run(|con| con.zrevrange_withscores(key, start, stop))
+            .map_err(KvError::from)
+    }
+
+    pub fn zscore(&mut self, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.run(|con| con.zscore(key, member))
+            .map_err(KvError::from)
+    }
+
+    pub fn zrem(&mut self, key: &str, member: &str) -> Result<(), KvError> {
+        self.run(|con| con.zrem::<_, _, ()>(key, member))
+            .map_err(KvError::from)
+    }
+
+    pub fn publish(&mut self, channel: &str, message: &str) -> Result<(), KvError> {
+        self.run(|con| con.publish::<_, _, ()>(channel, message))
+            .map_err(KvError::from)
+    }
+
+    /// Deletes up to `limit` keys matching `pattern` in batches, as a cursor SCAN finds
+    /// them, and unlists them from the directory. UNLINK frees their memory in the
+    /// background, so large values don't block Redis either. Returns how many keys were
+    /// deleted.
+    pub fn unlink_matching(&mut self, pattern: &str, limit: usize) -> Result<usize, KvError> {
+        let mut cursor: u64 = 0;
+        let mut unlinked = 0;
+        while unlinked < limit {
+            let (next, keys): (u64, Vec<String>) = self
+                .run(|con| {
+                    redis::cmd("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(pattern)
+                        .arg("COUNT")
+                        .arg(SCAN_BATCH_SIZE)
+                        .query(con)
+                })
+                .map_err(KvError::from)?;
+            let keys = &keys[..keys.len().min(limit - unlinked)];
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                pipe.unlink(keys);
+                if let Some(directory) = &self.directory {
+                    pipe.atomic().srem(directory, keys).ignore();
+                }
+                let (count,): (usize,) = self.run(|con| pipe.query(con)).map_err(KvError::from)?;
+                unlinked += count;
+            }
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        Ok(unlinked)
+    }
+
+    /// Deletes `key` like `unset` and, in the same transaction, stores the time of the
+    /// delete, `now` in Unix milliseconds, under `tombstone` for `lifetime`.
+    pub fn bury(
+        &mut self,
+        key: &str,
+        tombstone: &str,
+        now: u64,
+        lifetime: Duration,
+    ) -> Result<(), KvError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key).ignore();
+        set_in(&mut pipe, tombstone, now.to_string().as_bytes(), lifetime).ignore();
+        if let Some(directory) = &self.directory {
+            pipe.srem(directory, key).ignore();
+        }
+        self.run(|con| pipe.query::<()>(con)).map_err(KvError::from)
+    }
+
+    /// Whether the tombstone `tombstone` records a delete at or after `since`, Unix time in
+    /// milliseconds.
+    pub fn removed_since(&mut self, tombstone: &str, since: u64) -> Result<bool, KvError> {
+        let removed_at: Option<String> =
+            self.run(|con| con.get(tombstone)).map_err(KvError::from)?;
+        Ok(removed_at
+            .and_then(|removed_at| removed_at.parse::<u64>().ok())
+            .is_some_and(|removed_at| removed_at >= since))
     }
 
     pub fn unset(&mut self, key: &str) -> Result<(), KvError> {
-        self.con.del(key).map_err(KvError::CommandFailed)?;
+        let mut pipe = redis::pipe();
+        pipe.del(key).ignore();
+        if let Some(directory) = &self.directory {
+            pipe.atomic().srem(directory, key).ignore();
+        }
+        self.run(|con| pipe.query::<()>(con))
+            .map_err(KvError::from)?;
         Ok(())
     }
 }
 
+fn tracking_command(redirect: i64, prefix: Option<&str>) -> redis::Cmd {
+    let mut command = redis::cmd("CLIENT");
+    command
+        .arg("TRACKING")
+        .arg("ON")
+        .arg("REDIRECT")
+        .arg(redirect)
+        .arg("BCAST");
+    if let Some(prefix) = prefix {
+        command.arg("PREFIX").arg(prefix);
+    }
+    command.arg("NOLOOP");
+    command
+}
+
+/// `text` with the characters SCAN and KEYS patterns give a meaning escaped.
+pub(crate) fn escape_pattern(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Adds writing `value` to `key` with `ttl` to `pipe`, without an expiry if `ttl` means none.
+fn set_in<'a>(pipe: &'a mut Pipeline, key: &str, value: &[u8], ttl: Duration) -> &'a mut Pipeline {
+    match expiry_millis(ttl) {
+        Some(ttl) => pipe.pset_ex(key, value, ttl),
+        None => pipe.set(key, value),
+    }
+}
+
+/// Adds making `key` expire `ttl` from now to `pipe`, or never if `ttl` means no expiry.
+fn expire_in<'a>(pipe: &'a mut Pipeline, key: &str, ttl: Duration) -> &'a mut Pipeline {
+    match expiry_millis(ttl) {
+        Some(ttl) => pipe.pexpire(key, ttl as i64),
+        None => pipe.persist(key),
+    }
+}
+
+pub(crate) fn is_failover_error(err: &RedisError) -> bool {
+    err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || matches!(err.kind(), ErrorKind::ReadOnly | ErrorKind::MasterDown)
+}
+
+/// Whether the command certainly did not run, so sending it again can't apply it twice.
+fn was_rejected(err: &RedisError) -> bool {
+    err.is_connection_refusal()
+        || matches!(
+            err.kind(),
+            ErrorKind::ReadOnly
+                | ErrorKind::MasterDown
+                | ErrorKind::TryAgain
+                | ErrorKind::BusyLoadingError
+        )
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex;
+
+    use redis_test::{MockCmd, MockRedisConnection};
+
     use super::*;
+    use crate::envelope::Codec;
+    use crate::resp_server::RespServer;
 
     impl KvCache {
         fn set_raw(&mut self, key: &str, value: &str) -> Result<(), KvError> {
-            self.con.set(key, value).map_err(KvError::CommandFailed)?;
+            self.con
+                .set::<_, _, ()>(key, value)
+                .map_err(KvError::from)?;
             Ok(())
         }
     }
@@ -77,8 +1973,9 @@ mod tests {
         let res = cache
             .set(SetPayload {
                 key,
-                value: "",
-                ttl: 1,
+                value: b"",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         teardown(key);
@@ -94,8 +1991,9 @@ mod tests {
         let res = cache
             .set(SetPayload {
                 key,
-                value: "42",
-                ttl: 1,
+                value: b"42",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         teardown(key);
@@ -108,6 +2006,499 @@ mod tests {
         assert!(matches!(cache, Err(KvError::ConnectionNotEstablished)));
     }
 
+    #[test]
+    fn it_should_set_only_absent_key() {
+        let key = "foo4";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        let first = cache
+            .set_nx(SetPayload {
+                key,
+                value: b"1",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        let second = cache
+            .set_nx(SetPayload {
+                key,
+                value: b"2",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        let res = cache.get(key);
+        teardown(key);
+        assert!(first);
+        assert!(!second);
+        assert_eq!(res.unwrap(), "1");
+    }
+
+    #[test]
+    fn it_should_merge_with_stored_value() {
+        let key = "merge1";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache.unset(key).unwrap();
+        let policy =
+            ConflictPolicy::merge(|existing, incoming| [existing, b"+", incoming].concat());
+        let payload = |value| SetPayload {
+            key,
+            value,
+            ttl: Duration::from_secs(10),
+            tier_hint: None,
+        };
+        assert_eq!(cache.set_with_policy(payload(b"a"), &policy).unwrap(), "a");
+        assert_eq!(
+            cache.set_with_policy(payload(b"b"), &policy).unwrap(),
+            "a+b"
+        );
+        assert_eq!(cache.get(key).unwrap(), "a+b");
+        assert!(!cache
+            .compare_and_set(key, Some(b"a"), b"c", Some(Duration::from_secs(10)))
+            .unwrap());
+        assert_eq!(cache.get(key).unwrap(), "a+b");
+        teardown(key);
+    }
+
+    #[test]
+    fn it_should_enforce_max_age_across_merges() {
+        let key = "maxage1";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache.set_max_age(Duration::from_secs(10));
+        let now = SystemTimeSource.now();
+        cache
+            .con
+            .set_ex::<_, _, ()>(key, Envelope::encode(now - 8, b"a"), 100)
+            .unwrap();
+        let policy =
+            ConflictPolicy::merge(|existing, incoming| [existing, b"+", incoming].concat());
+        let payload = SetPayload {
+            key,
+            value: b"b",
+            ttl: Duration::from_secs(100),
+            tier_hint: None,
+        };
+        assert_eq!(cache.set_with_policy(payload, &policy).unwrap(), "a+b");
+        let (_, ttl) = cache.get_with_ttl(key).unwrap();
+        assert!(ttl.unwrap() <= Duration::from_secs(2));
+
+        cache
+            .con
+            .set_ex::<_, _, ()>(key, Envelope::encode(now - 10, b"a"), 100)
+            .unwrap();
+        assert!(cache.get(key).is_none());
+        teardown(key);
+    }
+
+    #[test]
+    fn it_should_convert_legacy_value_on_read() {
+        let key = "legacy1";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache.set_raw(key, "value").unwrap();
+        assert_eq!(cache.get(key).unwrap(), "value");
+        let raw: Vec<u8> = cache.con.get(key).unwrap();
+        assert!(matches!(
+            Envelope::decode(Bytes::from(raw)),
+            Decoded::Current(_)
+        ));
+        assert_eq!(cache.migration_stats().converted, 1);
+        teardown(key);
+    }
+
+    #[test]
+    fn it_should_discard_legacy_values_in_scan() {
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache.set_legacy_policy(LegacyValuePolicy::Discard);
+        cache.set_raw("legacyscan:1", "value").unwrap();
+        cache
+            .overwrite(SetPayload {
+                key: "legacyscan:2",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        let stats = cache.scan_legacy("legacyscan:*").unwrap();
+        assert_eq!(
+            stats,
+            MigrationStats {
+                converted: 0,
+                discarded: 1
+            }
+        );
+        assert!(cache.get("legacyscan:1").is_none());
+        assert_eq!(cache.get("legacyscan:2").unwrap(), "value");
+        teardown("legacyscan:2");
+    }
+
+    #[test]
+    fn it_should_compress_large_values() {
+        let key = "compressed1";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache.set_compression(Compression {
+            codec: Codec::Zstd,
+            threshold: 128,
+        });
+        let value = "{\"name\":\"value\"}".repeat(64);
+        cache
+            .overwrite(SetPayload {
+                key,
+                value: value.as_bytes(),
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        let raw: Vec<u8> = cache.con.get(key).unwrap();
+        assert!(raw.len() < value.len());
+        assert_eq!(cache.get(key).unwrap(), value.as_bytes());
+        teardown(key);
+    }
+
+    #[test]
+    fn it_should_work_over_any_connection() {
+        let stored = Envelope::encode(SystemTimeSource.now(), b"value");
+        let con = MockRedisConnection::new(vec![
+            MockCmd::new(redis::cmd("GET").arg("mock1"), Ok(stored)),
+            MockCmd::new(redis::cmd("DEL").arg("mock1"), Ok(1)),
+        ]);
+        let mut cache = KvCache::from_connection(con);
+        assert_eq!(cache.get("mock1").unwrap(), "value");
+        cache.unset("mock1").unwrap();
+    }
+
+    #[test]
+    fn it_should_retry_transient_errors() {
+        let stored = Envelope::encode(SystemTimeSource.now(), b"value");
+        let loading = || RedisError::from((ErrorKind::BusyLoadingError, "loading"));
+        let con = MockRedisConnection::new(vec![
+            MockCmd::new(
+                redis::cmd("GET").arg("retried"),
+                Err::<Vec<u8>, _>(loading()),
+            ),
+            MockCmd::new(redis::cmd("GET").arg("retried"), Ok(stored)),
+            MockCmd::new(redis::cmd("DEL").arg("retried"), Err::<i64, _>(loading())),
+            MockCmd::new(redis::cmd("DEL").arg("retried"), Err::<i64, _>(loading())),
+        ]);
+        let mut cache = KvCache::from_connection(con);
+        cache.set_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+        assert_eq!(cache.get("retried").unwrap(), "value");
+        assert!(matches!(
+            cache.unset("retried"),
+            Err(KvError::CommandFailed(err)) if err.kind() == ErrorKind::BusyLoadingError
+        ));
+    }
+
+    #[test]
+    fn it_should_not_retry_increments_that_may_have_happened() {
+        use std::io;
+
+        let script = Script::new(INCREMENT);
+        let increment = || {
+            redis::cmd("EVALSHA")
+                .arg(script.get_hash())
+                .arg(1)
+                .arg("hits")
+                .arg(1)
+                .arg("10000")
+                .clone()
+        };
+        let loading = || RedisError::from((ErrorKind::BusyLoadingError, "loading"));
+        let dropped = || RedisError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        let con = MockRedisConnection::new(vec![
+            MockCmd::new(increment(), Err::<i64, _>(loading())),
+            MockCmd::new(
+                increment(),
+                Ok(redis::Value::Bulk(vec![
+                    redis::Value::Int(1),
+                    redis::Value::Int(10000),
+                ])),
+            ),
+            MockCmd::new(increment(), Err::<i64, _>(dropped())),
+            MockCmd::new(
+                increment(),
+                Ok(redis::Value::Bulk(vec![
+                    redis::Value::Int(2),
+                    redis::Value::Int(10000),
+                ])),
+            ),
+        ]);
+        let mut cache = KvCache::from_connection(con);
+        cache.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+
+        let first = cache.increment("hits", 1, Duration::from_secs(10)).unwrap();
+        let second = cache.increment("hits", 1, Duration::from_secs(10));
+
+        assert_eq!(first, (1, Some(Duration::from_secs(10))));
+        assert!(matches!(
+            second,
+            Err(KvError::CommandFailed(err)) if err.is_connection_dropped()
+        ));
+    }
+
+    #[test]
+    fn it_should_reconnect_with_backoff() {
+        let stand_in = |addr: &str| {
+            let cache = crate::CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("reconnect")
+                .build()
+                .unwrap();
+            let server =
+                RespServer::bind(addr, Arc::new(Mutex::new(cache)), Duration::from_secs(100))
+                    .unwrap();
+            let addr = server.local_addr().unwrap();
+            let shutdown = server.shutdown_handle().unwrap();
+            thread::spawn(move || server.serve());
+            (addr, shutdown)
+        };
+        let (addr, shutdown) = stand_in("127.0.0.1:0");
+        let mut cache = KvCache::new(&format!("redis://{}", addr)).unwrap();
+        cache.set_reconnect_policy(ReconnectPolicy {
+            base_delay: Duration::from_millis(200),
+            ..ReconnectPolicy::default()
+        });
+        cache.unset("key").unwrap();
+
+        shutdown.shutdown();
+        thread::sleep(Duration::from_millis(100));
+        assert!(cache.unset("key").is_err());
+        stand_in(&addr.to_string());
+        assert!(matches!(
+            cache.unset("key"),
+            Err(KvError::CommandFailed(err)) if err.to_string().contains("reconnecting")
+        ));
+        thread::sleep(Duration::from_millis(250));
+        cache.unset("key").unwrap();
+    }
+
+    #[test]
+    fn it_should_time_out_commands_a_silent_server_does_not_answer() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                // Answers the client setup, then reads commands without answering.
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf);
+                    let _ = stream.write_all(b"+OK\r\n+OK\r\n");
+                    while matches!(stream.read(&mut buf), Ok(read) if read > 0) {}
+                });
+            }
+        });
+        let timeouts = KvTimeouts {
+            read: Some(Duration::from_millis(100)),
+            ..KvTimeouts::default()
+        };
+        let mut cache = ConnectionOptions::Url(format!("redis://{}", addr))
+            .connect_with_timeouts(timeouts)
+            .unwrap();
+
+        let started = Instant::now();
+        assert!(matches!(cache.unset("key"), Err(KvError::Timeout(_))));
+        assert!(matches!(cache.unset("key"), Err(KvError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn it_should_log_in_and_select_the_database_given_explicitly() {
+        let options = ConnectionOptions::Url("redis://127.0.0.1:6379".to_string());
+        let auth = RedisAuth {
+            db: Some(3),
+            client_name: Some("rcache-test".to_string()),
+            ..RedisAuth::default()
+        };
+        let mut cache = options.connect_with(KvTimeouts::default(), &auth).unwrap();
+        cache
+            .overwrite(SetPayload {
+                key: "auth:db",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        let mut default_db = KvCache::new("redis://127.0.0.1:6379").unwrap();
+        assert_eq!(default_db.get("auth:db"), None);
+        assert_eq!(cache.get("auth:db").unwrap(), "value");
+        cache.unset("auth:db").unwrap();
+
+        let wrong = RedisAuth {
+            username: Some("nobody".to_string()),
+            password: Some("wrong".to_string()),
+            ..RedisAuth::default()
+        };
+        assert!(matches!(
+            options.connect_with(KvTimeouts::default(), &wrong),
+            Err(KvError::AuthenticationFailed(_))
+        ));
+        assert!(!RedisAuth {
+            username: Some("nobody".to_string()),
+            ..RedisAuth::default()
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn it_should_redact_password_in_debug() {
+        let auth = RedisAuth {
+            username: Some("app".to_string()),
+            password: Some("hunter2".to_string()),
+            ..RedisAuth::default()
+        };
+        let printed = format!("{:?}", auth);
+        assert!(printed.contains("\"app\""));
+        assert!(printed.contains("<redacted>"));
+        assert!(!printed.contains("hunter2"));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn it_should_redact_client_key_in_debug() {
+        let auth = RedisAuth {
+            tls: Some(RedisTls {
+                client_cert: Some(b"cert".to_vec()),
+                client_key: Some(b"secret-key".to_vec()),
+                ..RedisTls::default()
+            }),
+            ..RedisAuth::default()
+        };
+        let printed = format!("{:?}", auth);
+        assert!(printed.contains("client_key: Some(\"<redacted>\")"));
+        assert!(!printed.contains(&format!("{:?}", b"secret-key".to_vec())));
+    }
+
+    #[test]
+    fn it_should_return_error_for_unknown_sentinel_master() {
+        let cache = KvCache::from_sentinel(&["redis://127.0.0.1:6379"], "unknown-master");
+        assert!(matches!(cache, Err(KvError::ConnectionNotEstablished)));
+    }
+
+    #[test]
+    fn it_should_get_and_set_many() {
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache
+            .set_many(&[
+                SetPayload {
+                    key: "many1",
+                    value: b"1",
+                    ttl: Duration::from_secs(10),
+                    tier_hint: None,
+                },
+                SetPayload {
+                    key: "many2",
+                    value: b"2",
+                    ttl: Duration::from_secs(10),
+                    tier_hint: None,
+                },
+            ])
+            .expect("Should not fail");
+        let res = cache
+            .get_many(&["many1", "many_missing", "many2"])
+            .expect("Should not fail");
+        teardown("many1");
+        teardown("many2");
+        assert_eq!(
+            res,
+            vec![Some(Bytes::from("1")), None, Some(Bytes::from("2"))]
+        );
+    }
+
+    #[test]
+    fn it_should_return_value_with_remaining_ttl() {
+        let key = "foo5";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache
+            .overwrite(SetPayload {
+                key,
+                value: b"42",
+                ttl: Duration::from_secs(5),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        let res = cache.get_with_ttl(key);
+        let missing = cache.get_with_ttl("foo5_missing");
+        teardown(key);
+        let (value, ttl) = res.unwrap();
+        assert_eq!(value, "42");
+        assert!(
+            ttl.is_some_and(|ttl| ttl > Duration::from_secs(4) && ttl <= Duration::from_secs(5))
+        );
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn it_should_write_millisecond_ttls_with_psetex() {
+        let packed = |ttl| {
+            let mut pipe = redis::pipe();
+            set_in(&mut pipe, "key", b"value", ttl);
+            pipe.get_packed_pipeline()
+        };
+        assert_eq!(
+            packed(Duration::from_millis(250)),
+            redis::pipe()
+                .cmd("PSETEX")
+                .arg("key")
+                .arg(250)
+                .arg(b"value")
+                .get_packed_pipeline()
+        );
+        assert_eq!(
+            packed(Duration::from_micros(10)),
+            redis::pipe()
+                .cmd("PSETEX")
+                .arg("key")
+                .arg(1)
+                .arg(b"value")
+                .get_packed_pipeline()
+        );
+        assert_eq!(
+            packed(Duration::ZERO),
+            redis::pipe().set("key", b"value").get_packed_pipeline()
+        );
+    }
+
+    #[test]
+    fn it_should_expire_sub_second_ttls() {
+        let key = "subsecond";
+        let mut cache = KvCache::new("redis://127.0.0.1:6379")
+            .expect("Should establish connection with no problem");
+        cache
+            .overwrite(SetPayload {
+                key,
+                value: b"42",
+                ttl: Duration::from_millis(300),
+                tier_hint: None,
+            })
+            .expect("Should not fail");
+        let (value, ttl) = cache.get_with_ttl(key).unwrap();
+        thread::sleep(Duration::from_millis(400));
+        let expired = cache.get(key);
+        teardown(key);
+
+        assert_eq!(value, "42");
+        assert!(ttl.is_some_and(|ttl| ttl > Duration::ZERO && ttl <= Duration::from_millis(300)));
+        assert!(expired.is_none());
+    }
+
     #[test]
     fn it_should_cache_value_for_ttl() {
         let key = "foo3";
@@ -117,8 +2508,9 @@ mod tests {
         cache
             .set(SetPayload {
                 key,
-                value: "42",
-                ttl: 1,
+                value: b"42",
+                ttl: Duration::from_secs(1),
+                tier_hint: None,
             })
             .expect("Should not fail");
         let res = cache.get(key).unwrap();
@@ -1,134 +1,4869 @@
-use crate::in_memory_cache::InMemoryCache;
-use crate::kv_cache::KvCache;
+use std::any::{type_name, Any};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+use redis::ConnectionLike;
+
+pub use cache_service_macros::cached;
+
+use crate::admission_log::{AdmissionLog, Rejection, RejectionCause, RejectionReason};
+use crate::analytics::{KeySpaceAnalytics, KeySpaceReport};
+use crate::bloom_filter::BloomFilter;
+pub use crate::builder::CacheServiceBuilder;
+use crate::client_tracking::TrackingListener;
+use crate::conflict::ConflictPolicy;
+use crate::directory::KeyDirectory;
+use crate::envelope::{ImportStats, MigrationStats, Provenance};
+use crate::events::{CacheEvent, CacheEventKind, EventListener, Listeners};
+use crate::geo_key::GeoKey;
+use crate::hot_keys::{HotKey, HotKeys};
+use crate::in_memory_cache::{shorter_ttl, InMemoryCache, SystemTimeSource, TimeSource};
+use crate::invalidation::{InvalidationBus, InvalidationKind};
+use crate::journal::{Journal, Operation};
+use crate::keyspace_events::KeyspaceListener;
+use crate::kv_cache::{KvCache, KvConnection, KvError, ValueWithTtl, Version};
+use crate::limits::{Admission, LimitExceeded, SizeLimits};
+use crate::lock::LockGuard;
+use crate::pass_through::{NamespacePassThrough, PassThrough};
+use crate::prefetch::Prefetcher;
+use crate::quota::{QuotaAlert, QuotaObserver, QuotaPolicy, QuotaTracker};
+use crate::refresh_ahead::RefreshAhead;
+use crate::shadow::{ShadowDivergence, ShadowObserver};
+use crate::stats::{CacheStats, ResolvedEntry, SizeDistribution, Source};
+use crate::time_bucket::TimeBucketKey;
+use crate::ttl::{ExpiryMode, SharedTtls, TtlConfig};
+use crate::verifier::{VerificationStats, Verifier};
+use crate::write_behind::{QueuedWrite, WriteBehind};
+use crate::xfetch::XFetch;
+
+pub mod admission_log;
+pub mod analytics;
+mod bloom_filter;
+pub mod builder;
+pub mod client_tracking;
+pub mod comparing;
+pub mod conflict;
+mod directory;
+mod emulated;
+pub mod encryption;
+pub mod envelope;
+pub mod events;
+mod eviction_queue;
+pub mod failover;
+#[cfg(feature = "proto")]
+pub mod framed_server;
+mod frequency_sketch;
+pub mod geo_key;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hot_keys;
+#[cfg(feature = "tower")]
+pub mod http_layer;
 pub mod in_memory_cache;
+pub mod instance;
+pub mod invalidation;
+pub mod journal;
+pub mod keyspace_events;
 pub mod kv_cache;
+pub mod limits;
+pub mod lock;
+pub mod memcached;
+pub mod memory_only;
+pub mod pass_through;
+pub mod prefetch;
+#[cfg(feature = "proto")]
+pub mod proto;
+pub mod quota;
+pub mod refresh_ahead;
+pub mod resp_server;
+pub mod retry;
+mod rollout;
+pub mod scoreboard_cache;
+#[cfg(feature = "server")]
+pub mod server_config;
+pub mod shadow;
+#[cfg(feature = "sled")]
+pub mod sled_store;
+pub mod snapshot;
+mod stale;
+pub mod stats;
+pub mod tiered;
+pub mod time_bucket;
+mod timing_wheel;
+mod tombstones;
+pub mod ttl;
+pub mod verifier;
+pub mod write_behind;
+pub mod xfetch;
+
+// Lets code generated by `#[cached]` refer to `::cache_service` from inside this crate.
+extern crate self as cache_service;
 
+const IDEMPOTENCY_PREFIX: &str = "idempotency:";
+const IDEMPOTENCY_PENDING: &str = "\u{0}pending";
+const LOCK_PREFIX: &str = "lock:";
+
+const TOMBSTONE_PREFIX: &str = "tombstone:";
+
+#[derive(Clone, Copy)]
 pub struct SetPayload<'a> {
     pub key: &'a str,
-    pub value: &'a str,
-    pub ttl: u64,
+    pub value: &'a [u8],
+    /// `Duration::ZERO` stores the value without expiry, as does a TTL too long for Redis to
+    /// expire, like `Duration::MAX`.
+    pub ttl: Duration,
+    /// Keeps the value in only one tier when written through `CacheService::set`, e.g.
+    /// `Tier::Kv` for big rarely read blobs or `Tier::Memory` for small hot flags. `None`
+    /// stores it in both. The tiers' own `set` methods ignore it.
+    pub tier_hint: Option<Tier>,
+}
+
+/// Settings changed by `CacheService::reconfigure`. `None` keeps the current value.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reconfiguration {
+    pub memory_ttl: Option<Duration>,
+    pub kv_ttl: Option<Duration>,
+    pub ttl_jitter: Option<f64>,
+    /// `Some(None)` removes the memory tier's limit.
+    pub capacity: Option<Option<usize>>,
+}
+
+/// A value from `CacheService::resolve_or_stale`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaybeStale<V> {
+    pub value: V,
+    /// The value expired and was served because the resolver failed.
+    pub stale: bool,
+}
+
+/// What `CacheService::entry_info` found for a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryInfo {
+    /// The live copy in this instance's memory tier, if there is one.
+    pub memory: Option<MemoryEntry>,
+    /// The copy in the KV tier, if there is one.
+    pub stored: Option<StoredEntry>,
+}
+
+impl EntryInfo {
+    /// The tiers holding the entry, memory first.
+    pub fn tiers(&self) -> Vec<Tier> {
+        let mut tiers = Vec::with_capacity(2);
+        if self.memory.is_some() {
+            tiers.push(Tier::Memory);
+        }
+        if self.stored.is_some() {
+            tiers.push(Tier::Kv);
+        }
+        tiers
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryEntry {
+    /// Unix seconds when the key was first stored in memory; replacing the value keeps it.
+    pub created: u64,
+    /// Unix seconds when the copy expires, `None` while it is pinned or if it does not.
+    pub expires_at: Option<u64>,
+    /// Seconds left, `None` while the copy is pinned or if it does not expire.
+    pub ttl: Option<u64>,
+    pub size: usize,
+    /// Reads served from this copy since `created`.
+    pub reads: u64,
+    /// Unix seconds of the last read served from this copy, `None` if there was none or
+    /// `CacheServiceBuilder::track_last_read` is off.
+    pub last_read: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEntry {
+    /// Unix seconds when the value was first stored.
+    pub created: u64,
+    /// Unix seconds when the key expires, `None` if it does not.
+    pub expires_at: Option<u64>,
+    /// Seconds left, `None` if the key does not expire.
+    pub ttl: Option<u64>,
+    /// Length of the value, uncompressed.
+    pub size: usize,
+    /// Who wrote the value, if the writing service was configured with a `provenance`.
+    pub writer: Option<Provenance>,
+}
+
+/// One `field:value` line per known field, in the format of the RESP server's `INFO`.
+impl fmt::Display for EntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tiers: Vec<&str> = self
+            .tiers()
+            .into_iter()
+            .map(|tier| match tier {
+                Tier::Memory => "memory",
+                Tier::Kv => "kv",
+            })
+            .collect();
+        write!(f, "tiers:{}\r\n", tiers.join(","))?;
+        write!(f, "in_memory:{}\r\n", u8::from(self.memory.is_some()))?;
+        if let Some(memory) = &self.memory {
+            write!(
+                f,
+                "memory_created:{}\r\nmemory_size:{}\r\nmemory_reads:{}\r\n",
+                memory.created, memory.size, memory.reads
+            )?;
+            if let Some(last_read) = memory.last_read {
+                write!(f, "memory_last_read:{}\r\n", last_read)?;
+            }
+            if let (Some(expires_at), Some(ttl)) = (memory.expires_at, memory.ttl) {
+                write!(
+                    f,
+                    "memory_expires_at:{}\r\nmemory_ttl:{}\r\n",
+                    expires_at, ttl
+                )?;
+            }
+        }
+        let Some(stored) = &self.stored else {
+            return Ok(());
+        };
+        write!(f, "created:{}\r\nsize:{}\r\n", stored.created, stored.size)?;
+        if let (Some(expires_at), Some(ttl)) = (stored.expires_at, stored.ttl) {
+            write!(f, "expires_at:{}\r\nttl:{}\r\n", expires_at, ttl)?;
+        }
+        if let Some(writer) = &stored.writer {
+            write!(f, "writer_instance:{}\r\n", writer.instance)?;
+        }
+        if let Some(label) = stored
+            .writer
+            .as_ref()
+            .and_then(|writer| writer.label.as_ref())
+        {
+            write!(f, "writer_label:{}\r\n", label)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tier {
+    Memory,
+    Kv,
 }
 
 #[allow(dead_code)]
-pub struct CacheService {
+pub struct CacheService<C: ConnectionLike = KvConnection> {
     in_memory_cache: InMemoryCache,
-    kv_cache: KvCache,
-    ttl: u64,
+    kv_cache: KvCache<C>,
+    write_behind: Option<WriteBehind>,
+    xfetch: Option<XFetch>,
+    invalidation: Option<InvalidationBus>,
+    keyspace_listener: Option<KeyspaceListener>,
+    tracking_listener: Option<TrackingListener>,
+    prefetcher: Option<Prefetcher>,
+    refresh_ahead: Option<RefreshAhead>,
+    admission_log: Option<AdmissionLog>,
+    journal: Option<Journal>,
+    quota: Option<QuotaTracker>,
+    quota_observer: Option<QuotaObserver>,
+    analytics: Option<KeySpaceAnalytics>,
+    hot_keys: Option<HotKeys>,
+    directory: Option<KeyDirectory>,
+    tombstone_lifetime: Option<Duration>,
+    shadow_sample_rate: Option<f64>,
+    shadow_observer: Option<ShadowObserver>,
+    pass_through: PassThrough,
+    namespace_pass_through: Option<NamespacePassThrough>,
+    rollout_percent: Option<u8>,
+    resolver_timeout: Option<Duration>,
+    negative_filter: Option<BloomFilter>,
+    size_limits: SizeLimits,
+    legacy_scan: Option<JoinHandle<Result<MigrationStats, KvError>>>,
+    verifier: Option<Verifier>,
+    instance_id: String,
+    /// Tags this service's invalidation messages, see `InvalidationBus`.
+    origin: String,
+    namespace: Option<String>,
+    conflict_policy: ConflictPolicy,
+    expiry_mode: ExpiryMode,
+    deserialization_policy: DeserializationPolicy,
+    ttls: Arc<SharedTtls>,
+    stats: CacheStats,
+    sizes: SizeDistribution,
+    /// Listeners for Redis events; memory events are reported by the memory tier.
+    listeners: Listeners,
+    /// Whether the KV tier is a `MemoryOnlyConnection`, so counters live in memory alone.
+    memory_only: bool,
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum CacheServiceError {
-    InMemoryCacheError(in_memory_cache::InMemoryCacheError),
-    KvCacheError(kv_cache::KvError),
+    /// The memory tier refused `operation` on the stored key `key`.
+    InMemoryCacheError {
+        operation: &'static str,
+        key: String,
+        source: in_memory_cache::InMemoryCacheError,
+    },
+    /// The Redis command `operation` failed. `key` is the stored key it was sent for, if any.
+    KvCacheError {
+        operation: &'static str,
+        key: Option<String>,
+        source: kv_cache::KvError,
+    },
+    IdempotencyInProgress,
+    BatchResolverMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    GeoKeyError(geo_key::GeoKeyError),
+    InvalidConfig(builder::ConfigError),
+    /// A cached value could not be decoded into the type it was read as, e.g. non-UTF-8 bytes
+    /// read through one of the `String` wrappers. `raw` holds the value as stored.
+    Deserialization {
+        type_name: &'static str,
+        raw: Bytes,
+    },
+    /// Storing the value in Redis would take the namespace past its key quota.
+    QuotaExceeded {
+        max_keys: u64,
+    },
+    /// The resolver of `resolve_or_stale` failed and there was no stale value to serve.
+    Resolver(Box<dyn Error + Send + Sync>),
+    /// The resolver of `resolve_with_timeout` took longer than the timeout and there was no
+    /// stale value to serve.
+    Timeout(Duration),
+    /// The resolver panicked. `message` is what it panicked with, if that was a string.
+    ResolverPanicked {
+        message: Option<String>,
+    },
+    /// The stored key, namespace included, is longer than `SizeLimits::max_key_len`.
+    KeyTooLong {
+        key: String,
+        len: usize,
+        max: usize,
+    },
+    /// The value is larger than `SizeLimits::max_value_len` under `OversizedPolicy::Error`.
+    /// `key` is the stored key.
+    ValueTooLarge {
+        key: String,
+        len: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for CacheServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheServiceError::InMemoryCacheError { operation, key, .. } => {
+                write!(f, "{} {:?} failed in the memory tier", operation, key)
+            }
+            CacheServiceError::KvCacheError {
+                operation,
+                key: Some(key),
+                ..
+            } => write!(f, "{} {:?} failed in the KV tier", operation, key),
+            CacheServiceError::KvCacheError {
+                operation,
+                key: None,
+                ..
+            } => write!(f, "{} failed in the KV tier", operation),
+            CacheServiceError::IdempotencyInProgress => {
+                f.write_str("the idempotent operation is still running elsewhere")
+            }
+            CacheServiceError::BatchResolverMismatch { expected, actual } => write!(
+                f,
+                "the batch resolver returned {} values for {} keys",
+                actual, expected
+            ),
+            CacheServiceError::GeoKeyError(_) => f.write_str("cannot derive a geo key"),
+            CacheServiceError::InvalidConfig(_) => f.write_str("invalid cache configuration"),
+            CacheServiceError::Deserialization { type_name, raw } => write!(
+                f,
+                "cannot decode the cached value of {} bytes as {}",
+                raw.len(),
+                type_name
+            ),
+            CacheServiceError::QuotaExceeded { max_keys } => {
+                write!(f, "the namespace reached its quota of {} keys", max_keys)
+            }
+            CacheServiceError::Resolver(_) => f.write_str("the resolver failed"),
+            CacheServiceError::Timeout(timeout) => {
+                write!(f, "the resolver did not finish within {:?}", timeout)
+            }
+            CacheServiceError::ResolverPanicked {
+                message: Some(message),
+            } => {
+                write!(f, "the resolver panicked: {}", message)
+            }
+            CacheServiceError::ResolverPanicked { message: None } => {
+                f.write_str("the resolver panicked")
+            }
+            CacheServiceError::KeyTooLong { key, len, max } => write!(
+                f,
+                "key {:?} is {} bytes long, over the limit of {}",
+                key, len, max
+            ),
+            CacheServiceError::ValueTooLarge { key, len, max } => write!(
+                f,
+                "the value of {:?} is {} bytes long, over the limit of {}",
+                key, len, max
+            ),
+        }
+    }
+}
+
+impl Error for CacheServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CacheServiceError::InMemoryCacheError { source, .. } => Some(source),
+            CacheServiceError::KvCacheError { source, .. } => Some(source),
+            CacheServiceError::GeoKeyError(err) => Some(err),
+            CacheServiceError::InvalidConfig(err) => Some(err),
+            CacheServiceError::Resolver(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl CacheServiceError {
+    /// Wraps the error of the memory tier's `operation` on the stored key `key`, for
+    /// `map_err`.
+    pub(crate) fn memory<'a>(
+        operation: &'static str,
+        key: &'a str,
+    ) -> impl FnOnce(in_memory_cache::InMemoryCacheError) -> CacheServiceError + 'a {
+        move |source| CacheServiceError::InMemoryCacheError {
+            operation,
+            key: key.to_owned(),
+            source,
+        }
+    }
+
+    /// Wraps the error of the Redis command `operation` sent for the stored key `key`, for
+    /// `map_err`.
+    pub(crate) fn kv<'a>(
+        operation: &'static str,
+        key: &'a str,
+    ) -> impl FnOnce(KvError) -> CacheServiceError + 'a {
+        move |source| CacheServiceError::KvCacheError {
+            operation,
+            key: Some(key.to_owned()),
+            source,
+        }
+    }
+
+    /// Same as `kv`, for commands that are not about one key.
+    pub(crate) fn kv_unkeyed(operation: &'static str) -> impl FnOnce(KvError) -> CacheServiceError {
+        move |source| CacheServiceError::KvCacheError {
+            operation,
+            key: None,
+            source,
+        }
+    }
+}
+
+impl From<geo_key::GeoKeyError> for CacheServiceError {
+    fn from(err: geo_key::GeoKeyError) -> Self {
+        CacheServiceError::GeoKeyError(err)
+    }
+}
+
+impl From<builder::ConfigError> for CacheServiceError {
+    fn from(err: builder::ConfigError) -> Self {
+        CacheServiceError::InvalidConfig(err)
+    }
+}
+
+/// `err` followed by its sources, separated by colons, for messages that leave the process.
+pub(crate) fn error_chain(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+/// What the single-key `resolve` variants do with a cached value that can not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeserializationPolicy {
+    /// Return `CacheServiceError::Deserialization`.
+    #[default]
+    Error,
+    /// Drop the value from both tiers and call the resolver as on a miss.
+    Miss,
+}
+
+fn into_string(value: Bytes) -> Result<String, CacheServiceError> {
+    String::from_utf8(value.into()).map_err(|err| CacheServiceError::Deserialization {
+        type_name: type_name::<String>(),
+        raw: Bytes::from(err.into_bytes()),
+    })
+}
+
+fn resolver_error<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> CacheServiceError {
+    CacheServiceError::Resolver(err.into())
+}
+
+/// Runs `resolver` on a thread of its own and stops waiting for it after `timeout`.
+fn bounded<T, E>(resolver: T, timeout: Duration) -> Result<Bytes, CacheServiceError>
+where
+    T: FnOnce() -> Result<Bytes, E> + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let _ = sender.send(guarded(|| resolver().map_err(resolver_error)));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(CacheServiceError::Timeout(timeout)),
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(CacheServiceError::ResolverPanicked { message: None })
+        }
+    }
+}
+
+/// Calls `resolver`, returning a panic as `CacheServiceError::ResolverPanicked`. Resolvers
+/// run while no lock of the service is held, so unwinding out of them leaves nothing half
+/// done.
+fn guarded<R, T>(resolver: T) -> Result<R, CacheServiceError>
+where
+    T: FnOnce() -> Result<R, CacheServiceError>,
+{
+    panic::catch_unwind(AssertUnwindSafe(resolver)).unwrap_or_else(|payload| {
+        Err(CacheServiceError::ResolverPanicked {
+            message: panic_message(payload),
+        })
+    })
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(payload: Box<dyn Any + Send>) -> Option<String> {
+    match payload.downcast::<String>() {
+        Ok(message) => Some(*message),
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string()),
+    }
+}
+
+fn parse<T: FromStr>(value: Bytes) -> Result<T, CacheServiceError> {
+    let parsed = std::str::from_utf8(&value)
+        .ok()
+        .and_then(|value| value.parse().ok());
+    match parsed {
+        Some(parsed) => Ok(parsed),
+        None => Err(CacheServiceError::Deserialization {
+            type_name: type_name::<T>(),
+            raw: value,
+        }),
+    }
 }
 
 impl CacheService {
-    pub fn new(ttl: u64, redis_url: &str) -> CacheService {
-        CacheService {
-            in_memory_cache: InMemoryCache::new(),
-            kv_cache: KvCache::new(redis_url).expect("KvCache creation failed"),
+    /// Connects to `redis_url` with both tiers keeping values for `ttl`. Use `builder`
+    /// for anything else, e.g. to connect lazily.
+    pub fn new(ttl: Duration, redis_url: &str) -> Result<CacheService, CacheServiceError> {
+        CacheService::builder(redis_url).ttl(ttl).build()
+    }
+
+    pub fn builder(redis_url: &str) -> CacheServiceBuilder {
+        CacheServiceBuilder::new(redis_url)
+    }
+
+    /// A single-tier cache keeping values in memory for `ttl`, without Redis. Use
+    /// `CacheServiceBuilder::memory_only` for anything else.
+    pub fn memory_only(ttl: Duration) -> Result<CacheService, CacheServiceError> {
+        CacheServiceBuilder::memory_only().ttl(ttl).build()
+    }
+}
+
+impl<C: ConnectionLike> CacheService<C> {
+    fn without_namespace<'a>(&self, stored_key: &'a str) -> &'a str {
+        self.namespace
+            .as_deref()
+            .and_then(|namespace| stored_key.strip_prefix(namespace)?.strip_prefix(':'))
+            .unwrap_or(stored_key)
+    }
+
+    fn namespaced<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.namespace {
+            Some(namespace) => Cow::Owned(format!("{}:{}", namespace, key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Restarts the TTL of hit `keys` in both tiers under sliding expiry. Returns the TTL their
+    /// Redis keys now have, or `None` with absolute expiry.
+    fn slide(&mut self, keys: &[&str]) -> Option<Duration> {
+        if self.expiry_mode != ExpiryMode::Sliding {
+            return None;
+        }
+        for key in keys {
+            self.in_memory_cache.touch(key);
+        }
+        let kv_ttl = self.ttls.kv();
+        // A failed touch only shortens the Redis copy's life; the hit itself stands.
+        let _ = self.kv_cache.touch_many(keys, kv_ttl);
+        Some(kv_ttl)
+    }
+
+    fn promote(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        kv_remaining_ttl: Option<Duration>,
+    ) -> Result<(), CacheServiceError> {
+        let memory_ttl = self.ttls.memory();
+        let ttl = match kv_remaining_ttl {
+            Some(remaining) if remaining.is_zero() => return Ok(()),
+            Some(remaining) => shorter_ttl(remaining, memory_ttl),
+            None => memory_ttl,
+        };
+        let stored = self.in_memory_cache.set(SetPayload {
+            key,
+            value,
             ttl,
+            tier_hint: None,
+        });
+        self.admitted(key, Tier::Memory, stored)
+            .map_err(CacheServiceError::memory("set", key))?;
+        Ok(())
+    }
+
+    /// Calls `listener` for every insert, hit, miss, expiration and eviction, with the tier it
+    /// happened in, including those of background refreshes and prefetches. Redis expires and
+    /// evicts keys on its own, so only its inserts, hits and misses are reported.
+    pub fn on_event<F>(&mut self, listener: F)
+    where
+        F: Fn(&CacheEvent) + Send + Sync + 'static,
+    {
+        let listener: EventListener = Arc::new(listener);
+        let memory_listener = Arc::clone(&listener);
+        self.in_memory_cache
+            .on_event(move |event| memory_listener(event));
+        self.listeners.add(listener);
+    }
+
+    fn kv_event(&self, stored_key: &str, kind: CacheEventKind) {
+        self.listeners.emit(stored_key, kind, Tier::Kv);
+    }
+
+    pub fn admission_log(&self) -> Option<&AdmissionLog> {
+        self.admission_log.as_ref()
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Records the operation in the journal, if there is one and it succeeded.
+    fn journaled<R>(
+        &mut self,
+        result: &Result<R, CacheServiceError>,
+        operation: impl FnOnce(&R) -> Operation,
+    ) {
+        if let (Some(journal), Ok(value)) = (self.journal.as_mut(), result) {
+            journal.record(operation(value));
         }
     }
 
-    pub fn resolve<T>(&mut self, key: &str, resolver: T) -> Result<String, CacheServiceError>
+    fn admitted<R, E>(&mut self, key: &str, tier: Tier, result: Result<R, E>) -> Result<R, E>
     where
-        T: FnOnce() -> String,
+        E: RejectionCause,
     {
-        let memory_value = self.in_memory_cache.get(key);
+        if let Err(err) = &result {
+            self.reject(key, tier, err.rejection_reason());
+        }
+        if tier == Tier::Memory {
+            for denied in self.in_memory_cache.take_denied_admissions() {
+                self.reject(&denied, Tier::Memory, RejectionReason::AdmissionDenied);
+            }
+        }
+        result
+    }
 
-        if let Some(value) = memory_value {
-            return Ok(value);
+    /// Fails with `ConfigError::UnsupportedMemoryOnly` for the operations that only work
+    /// against a KV tier.
+    fn needs_kv_tier(&self) -> Result<(), CacheServiceError> {
+        if self.memory_only {
+            return Err(CacheServiceError::InvalidConfig(
+                builder::ConfigError::UnsupportedMemoryOnly,
+            ));
         }
+        Ok(())
+    }
 
-        let kv_value = self.kv_cache.get(key);
+    /// Logs that `key`, relative to the namespace, was resolved without either tier.
+    fn reject_both(&mut self, key: &str, reason: RejectionReason) {
+        if self.admission_log.is_none() {
+            return;
+        }
+        let stored_key = self.namespaced(key).into_owned();
+        self.reject(&stored_key, Tier::Memory, reason);
+        self.reject(&stored_key, Tier::Kv, reason);
+    }
 
-        if let Some(value) = kv_value {
-            return Ok(value);
+    fn reject(&mut self, key: &str, tier: Tier, reason: RejectionReason) {
+        if let Some(admission_log) = self.admission_log.as_mut() {
+            admission_log.record(Rejection {
+                key: key.to_string(),
+                tier,
+                reason,
+                timestamp: SystemTimeSource.now(),
+            });
         }
-        let value = resolver();
+    }
 
-        self.kv_cache
-            .set(SetPayload {
-                key,
-                value: &value,
-                ttl: self.ttl,
-            })
-            .map_err(CacheServiceError::KvCacheError)?;
+    /// Old-format values converted or discarded so far, lazily on read and by the legacy scan.
+    pub fn migration_stats(&self) -> MigrationStats {
+        self.kv_cache.migration_stats()
+    }
 
-        self.in_memory_cache
-            .set(SetPayload {
-                key,
-                value: &value,
-                ttl: self.ttl,
-            })
-            .map_err(CacheServiceError::InMemoryCacheError)?;
+    /// The ID naming this instance, configured with `instance_id` or derived from the
+    /// environment.
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
 
-        Ok(value)
+    /// What the payload verifier found so far, if `verify_payloads` is configured.
+    pub fn verification_stats(&self) -> Option<VerificationStats> {
+        self.verifier.as_ref().map(Verifier::stats)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Hit, miss, resolver and error counts since the service was built or last reset.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            evictions: self.in_memory_cache.evictions(),
+            expired_removals: self.in_memory_cache.expired_removals(),
+            entries: self.in_memory_cache.len(),
+            ..self.stats
+        }
+    }
 
-    #[test]
-    fn it_should_resolve_value() {
-        let mut cache = CacheService::new(10, "redis://127.0.0.1:6379");
-        let value = cache.resolve("key", || "value".to_string()).unwrap();
-        assert_eq!(value, "value");
+    /// Distributions of the key and value sizes written since the service was built or last
+    /// reset, e.g. to pick size limits from real data.
+    pub fn size_distribution(&self) -> &SizeDistribution {
+        &self.sizes
     }
 
-    #[test]
-    fn it_should_resolve_value_from_memory() {
-        let mut cache = CacheService::new(10, "redis://127.0.0.1:6379");
-        cache
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+        self.sizes = SizeDistribution::default();
+        self.in_memory_cache.reset_stats();
+        if let Some(analytics) = &mut self.analytics {
+            analytics.reset();
+        }
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.reset();
+        }
+    }
+
+    /// Entry counts, bytes, hit ratios and average TTLs grouped by key prefix, to find key
+    /// families that take up memory without being read. `None` unless enabled with
+    /// `CacheServiceBuilder::key_space_analytics`. Walks the whole memory tier.
+    pub fn key_space_report(&self) -> Option<KeySpaceReport> {
+        let analytics = self.analytics.as_ref()?;
+        let mut entries = Vec::new();
+        self.in_memory_cache.for_each_live(|key, value, ttl| {
+            let key = self.without_namespace(key);
+            entries.push((key.to_string(), value.len(), ttl));
+        });
+        Some(
+            analytics.report(
+                entries
+                    .iter()
+                    .map(|(key, bytes, ttl)| (key.as_str(), *bytes, *ttl)),
+            ),
+        )
+    }
+
+    /// The `count` most looked up keys since the service was built or its stats were last
+    /// reset, with how many of their lookups each tier answered. `None` unless enabled with
+    /// `CacheServiceBuilder::hot_keys`.
+    pub fn top_keys(&self, count: usize) -> Option<Vec<HotKey>> {
+        self.hot_keys.as_ref().map(|hot_keys| hot_keys.top(count))
+    }
+
+    fn record_lookup(&mut self, stored_key: &str, served: Option<Tier>) {
+        if self.analytics.is_none() && self.hot_keys.is_none() {
+            return;
+        }
+        let key = self.without_namespace(stored_key).to_string();
+        if let Some(analytics) = &mut self.analytics {
+            analytics.record(&key, served.is_some());
+        }
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.record(&key, served);
+        }
+    }
+
+    /// Whether the key directory or the negative lookup filter rules out that `stored_key`
+    /// is in Redis.
+    fn ruled_out(&mut self, stored_key: &str) -> bool {
+        if self.unlisted(stored_key) {
+            self.stats.directory_skips += 1;
+            return true;
+        }
+        if self.filtered_out(stored_key) {
+            self.stats.filter_skips += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Whether the key directory rules out `stored_key`. The local copy is reloaded first if
+    /// due; while Redis cannot be asked, nothing is ruled out.
+    fn unlisted(&mut self, stored_key: &str) -> bool {
+        let Some(directory) = &mut self.directory else {
+            return false;
+        };
+        let now = Instant::now();
+        if directory.needs_reload(now) {
+            match self.kv_cache.directory_members() {
+                Ok(members) => directory.load(members, now),
+                Err(_) => return false,
+            }
+        }
+        !directory.may_contain(stored_key)
+    }
+
+    /// Whether the negative lookup filter rules out `stored_key`, rebuilding it first if due
+    /// like the key directory.
+    fn filtered_out(&mut self, stored_key: &str) -> bool {
+        let now = Instant::now();
+        let needs_rebuild = match &self.negative_filter {
+            Some(filter) => filter.needs_rebuild(now),
+            None => return false,
+        };
+        if needs_rebuild {
+            let pattern = self.key_pattern();
+            let Ok(keys) = self.kv_cache.scan_keys(&pattern) else {
+                return false;
+            };
+            if let Some(filter) = &mut self.negative_filter {
+                filter.rebuild(&keys, now);
+            }
+        }
+        self.negative_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.may_contain(stored_key))
+    }
+
+    /// Looks `stored_key` up in Redis unless the key directory rules it out. A listed key
+    /// that turns out to be gone, e.g. because it expired, is unlisted.
+    fn get_listed(&mut self, stored_key: &str) -> Option<ValueWithTtl> {
+        if self.ruled_out(stored_key) {
+            return None;
+        }
+        let value = self.kv_cache.get_with_ttl(stored_key);
+        if value.is_none() {
+            self.unlist(stored_key);
+        }
+        value
+    }
+
+    fn unlist(&mut self, stored_key: &str) {
+        let Some(directory) = &mut self.directory else {
+            return;
+        };
+        // A key that stays listed only costs the lookups the directory would have saved.
+        if self.kv_cache.unlist_missing(stored_key).is_ok() {
+            directory.remove(stored_key);
+        }
+    }
+
+    /// Adds keys just written to Redis to the local copy of the key directory and to the
+    /// negative lookup filter.
+    fn listed(&mut self, stored_keys: &[&str]) {
+        for key in stored_keys {
+            self.kv_event(key, CacheEventKind::Insert);
+        }
+        if let Some(directory) = &mut self.directory {
+            for key in stored_keys {
+                directory.insert(key);
+            }
+        }
+        if let Some(filter) = &mut self.negative_filter {
+            for key in stored_keys {
+                filter.insert(key);
+            }
+        }
+    }
+
+    fn counted<R>(&mut self, result: Result<R, CacheServiceError>) -> Result<R, CacheServiceError> {
+        if result.is_err() {
+            self.stats.errors += 1;
+        }
+        result
+    }
+
+    /// Blocks until the background legacy scan finishes and returns what it migrated. `None`
+    /// if no scan was configured or its result was already taken.
+    pub fn wait_for_legacy_scan(&mut self) -> Option<Result<MigrationStats, KvError>> {
+        self.legacy_scan
+            .take()
+            .map(|scan| scan.join().expect("Legacy scan panicked"))
+    }
+
+    /// Whether `key` is cached in either tier. Not counted as a lookup.
+    pub fn contains(&mut self, key: &str) -> Result<bool, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        if self
             .in_memory_cache
-            .set(SetPayload {
-                key: "key",
-                value: "value",
-                ttl: 10,
-            })
-            .expect("All should be ok");
-        let value = cache.resolve("key", || "never_see".to_string()).unwrap();
+            .meta(key)
+            .is_some_and(|meta| meta.pinned || meta.expires_at > SystemTimeSource.now_millis())
+        {
+            return Ok(true);
+        }
+        let result = self
+            .kv_cache
+            .count_existing(&[key])
+            .map(|count| count > 0)
+            .map_err(CacheServiceError::kv("EXISTS", key));
+        self.counted(result)
+    }
 
-        assert_eq!(value, "value");
+    /// How long `key` stays cached, the longer of what its copies in memory and Redis have
+    /// left, `Duration::MAX` for a copy that is pinned or does not expire. `None` if neither
+    /// tier has it. Not counted as a lookup.
+    pub fn ttl(&mut self, key: &str) -> Result<Option<Duration>, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        let now = SystemTimeSource.now_millis();
+        let memory = self
+            .in_memory_cache
+            .meta(key)
+            .filter(|meta| meta.pinned || meta.expires_at > now)
+            .map(|meta| {
+                if meta.pinned || meta.expires_at == u64::MAX {
+                    Duration::MAX
+                } else {
+                    Duration::from_millis(meta.expires_at - now)
+                }
+            });
+        let result = self
+            .kv_cache
+            .remaining_ttl(key)
+            .map_err(CacheServiceError::kv("PTTL", key));
+        let kv = self.counted(result)?;
+        Ok(memory.max(kv))
     }
 
-    #[test]
-    fn it_should_resolve_value_from_kv() {
-        let mut cache = CacheService::new(10, "redis://127.0.0.1:6379");
-        cache
+    /// Makes `key` expire `ttl` from now in both tiers without reading or resolving
+    /// it, e.g. to keep a value that is still valid. `max_age` still applies. Returns false if
+    /// neither tier has the key.
+    pub fn touch(&mut self, key: &str, ttl: Duration) -> Result<bool, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        let in_memory = self.in_memory_cache.touch_with_ttl(key, ttl);
+        let result = self
             .kv_cache
-            .set(SetPayload {
-                key: "key",
-                value: "value",
-                ttl: 10,
-            })
-            .expect("All should be ok");
-        let value = cache.resolve("key", || "never_see".to_string()).unwrap();
+            .expire(key, ttl)
+            .map_err(CacheServiceError::kv("PEXPIRE", key));
+        Ok(self.counted(result)? || in_memory)
+    }
 
-        assert_eq!(value, "value");
+    /// Which tiers hold `key`, with the age, expiry and size of each copy, the reads served
+    /// from memory and who wrote the copy in Redis. `None` if neither tier has it. Does not
+    /// count as a read.
+    pub fn entry_info(&mut self, key: &str) -> Result<Option<EntryInfo>, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        let now_millis = SystemTimeSource.now_millis();
+        let memory = self
+            .in_memory_cache
+            .meta(key)
+            .filter(|meta| meta.pinned || meta.expires_at > now_millis)
+            .map(|meta| {
+                let expires = !meta.pinned && meta.expires_at != u64::MAX;
+                MemoryEntry {
+                    created: meta.created / 1000,
+                    expires_at: expires.then_some(meta.expires_at / 1000),
+                    ttl: expires.then(|| (meta.expires_at - now_millis) / 1000),
+                    size: meta.size,
+                    reads: meta.reads,
+                    last_read: meta.last_read.map(|at| at / 1000),
+                }
+            });
+        let result = self
+            .kv_cache
+            .envelope_with_ttl(key)
+            .map_err(CacheServiceError::kv("GET", key));
+        let stored = self.counted(result)?.map(|(envelope, ttl)| StoredEntry {
+            created: envelope.created,
+            expires_at: ttl.map(|ttl| now_millis / 1000 + ttl),
+            ttl,
+            size: envelope.payload.len(),
+            writer: envelope.writer,
+        });
+        Ok((memory.is_some() || stored.is_some()).then_some(EntryInfo { memory, stored }))
     }
 
-    #[test]
-    fn should_set_value_to_memory_cache() {
-        let mut cache = CacheService::new(10, "redis://127.0.0.1:6379");
-        cache.resolve("memkey", || "value".to_string()).unwrap();
-        cache.kv_cache.unset("memkey").unwrap();
-        let in_memory_value = cache.in_memory_cache.get("memkey").unwrap();
+    /// Takes the lock named `key` in Redis for `ttl` unless another holder has it, so services
+    /// sharing the cache can coordinate without a Redis client of their own. Does not wait:
+    /// `None` means the lock is held. The lock expires after `ttl` even if the guard is kept,
+    /// so keep it well above the work it protects.
+    pub fn lock(
+        &mut self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<LockGuard<'_, C>>, CacheServiceError> {
+        self.needs_kv_tier()?;
+        let lock_key = self
+            .namespaced(&format!("{}{}", LOCK_PREFIX, key))
+            .into_owned();
+        let result = self
+            .kv_cache
+            .lock(&lock_key, ttl)
+            .map_err(CacheServiceError::kv("SET", &lock_key));
+        Ok(self
+            .counted(result)?
+            .map(|value| LockGuard::new(self, lock_key, value)))
+    }
 
-        assert_eq!(in_memory_value, "value");
+    /// Stores `value` under `key` for `ttl` only if no one holds the key yet, with
+    /// Redis SET NX EX, e.g. for leader election or to run a job once. Returns whether this
+    /// call stored it. A live memory copy answers false without asking Redis, which only
+    /// holds as long as the key is not deleted behind the cache's back; other instances drop
+    /// their copy on `invalidate` when they share an invalidation channel. Bypasses
+    /// write-behind.
+    pub fn set_if_absent(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<bool, CacheServiceError> {
+        let result = self.try_set_if_absent(key, value, ttl);
+        if let (Some(journal), Ok(true)) = (&mut self.journal, &result) {
+            journal.record(Operation::Set {
+                key: key.to_string(),
+                value: Bytes::copy_from_slice(value),
+                ttl,
+            });
+        }
+        self.counted(result)
     }
 
-    #[test]
-    fn should_set_value_to_kv_cache() {
-        let mut cache = CacheService::new(10, "redis://127.0.0.1:6379");
-        cache.resolve("kvkey", || "kvval".to_string()).unwrap();
+    fn try_set_if_absent(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<bool, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        if self
+            .in_memory_cache
+            .meta(key)
+            .is_some_and(|meta| meta.pinned || meta.expires_at > SystemTimeSource.now_millis())
+        {
+            return Ok(false);
+        }
+        if self.admit_size(key, value)? == Admission::Nowhere {
+            return Err(CacheServiceError::ValueTooLarge {
+                key: key.to_string(),
+                len: value.len(),
+                max: self.size_limits.max_value_len.unwrap_or_default(),
+            });
+        }
+        self.admit_to_quota(&[key])?;
+        let payload = SetPayload {
+            key,
+            value,
+            ttl,
+            tier_hint: None,
+        };
+        let result = self.kv_cache.set_nx(payload);
+        if !self
+            .admitted(key, Tier::Kv, result)
+            .map_err(CacheServiceError::kv("SET", key))?
+        {
+            return Ok(false);
+        }
+        self.listed(&[key]);
+        self.publish_invalidation(InvalidationKind::Update, key)?;
+        let memory_ttl = shorter_ttl(self.ttls.memory(), ttl);
+        let stored = self.in_memory_cache.set_if_absent(SetPayload {
+            ttl: memory_ttl,
+            ..payload
+        });
+        self.admitted(key, Tier::Memory, stored)
+            .map_err(CacheServiceError::memory("set", key))?;
+        Ok(true)
+    }
 
-        let kv_cache = cache.kv_cache.get("kvkey").unwrap();
+    /// Reads `key` from Redis with its version, for a later `set_if_version`. The memory tier
+    /// is skipped, since its copy may be older than Redis. Counted as a lookup.
+    pub fn get_versioned(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<(Bytes, Version)>, CacheServiceError> {
+        self.needs_kv_tier()?;
+        let stored_key = &*self.namespaced(key);
+        let result = self
+            .kv_cache
+            .get_versioned(stored_key)
+            .map_err(CacheServiceError::kv("GET", stored_key));
+        let found = self.counted(result)?;
+        if found.is_some() {
+            self.stats.kv_hits += 1;
+            self.kv_event(stored_key, CacheEventKind::Hit);
+        } else {
+            self.stats.kv_misses += 1;
+            self.kv_event(stored_key, CacheEventKind::Miss);
+        }
+        self.record_lookup(stored_key, found.as_ref().map(|_| Tier::Kv));
+        Ok(found)
+    }
 
-        assert_eq!(kv_cache, "kvval");
+    /// Stores `value` under `key` with the configured TTLs, but only if Redis still holds the
+    /// version `expected` of the key, or nothing for `None`, so concurrent writers can update
+    /// optimistically without overwriting each other. Returns the new version, or `None` if
+    /// another write got in first; the memory copy is then dropped, and the caller should
+    /// read again and retry. Values over the size limits fail whatever the oversized policy,
+    /// and write-behind is bypassed. Successful writes are journaled as plain sets.
+    pub fn set_if_version(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        expected: Option<&Version>,
+    ) -> Result<Option<Version>, CacheServiceError> {
+        self.needs_kv_tier()?;
+        let result = self.try_set_if_version(key, value, expected);
+        if let (Some(journal), Ok(Some(_))) = (&mut self.journal, &result) {
+            journal.record(Operation::Set {
+                key: key.to_string(),
+                value: Bytes::copy_from_slice(value),
+                ttl: self.ttls.kv(),
+            });
+        }
+        self.counted(result)
+    }
+
+    fn try_set_if_version(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        expected: Option<&Version>,
+    ) -> Result<Option<Version>, CacheServiceError> {
+        let stored_key = &*self.namespaced(key);
+        let admission = self.admit_size(stored_key, value)?;
+        if admission == Admission::Nowhere {
+            return Err(CacheServiceError::ValueTooLarge {
+                key: stored_key.to_string(),
+                len: value.len(),
+                max: self.size_limits.max_value_len.unwrap_or_default(),
+            });
+        }
+        self.admit_to_quota(&[stored_key])?;
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        let payload = SetPayload {
+            key: stored_key,
+            value,
+            ttl: self.ttls.jittered(kv_ttl),
+            tier_hint: None,
+        };
+        let result = self.kv_cache.set_if_version(payload, expected);
+        let Some(version) = self
+            .admitted(stored_key, Tier::Kv, result)
+            .map_err(CacheServiceError::kv("EVALSHA", stored_key))?
+        else {
+            self.in_memory_cache.remove(stored_key);
+            return Ok(None);
+        };
+        self.listed(&[stored_key]);
+        self.publish_invalidation(InvalidationKind::Update, stored_key)?;
+
+        if admission == Admission::KvOnly {
+            self.in_memory_cache.remove(stored_key);
+            return Ok(Some(version));
+        }
+        let stored = self.in_memory_cache.replace(
+            SetPayload {
+                ttl: self.ttls.jittered(memory_ttl),
+                ..payload
+            },
+            0.0,
+        );
+        self.admitted(stored_key, Tier::Memory, stored)
+            .map_err(CacheServiceError::memory("replace", stored_key))?;
+        Ok(Some(version))
+    }
+
+    /// Adds `delta` to the counter under `key` and returns the new count. A missing counter
+    /// starts at 0 and lives for `ttl` in Redis; counting does not extend it. The
+    /// count is mirrored in the memory tier as its decimal string, which `get` returns like
+    /// any other value, and other instances are told to drop their copy. Fails if the key
+    /// holds something else. Bypasses write-behind.
+    pub fn incr(&mut self, key: &str, delta: i64, ttl: Duration) -> Result<i64, CacheServiceError> {
+        let result = self.try_incr(key, delta, ttl);
+        self.journaled(&result, |count| Operation::Increment {
+            key: key.to_string(),
+            delta,
+            ttl,
+            count: *count,
+        });
+        self.counted(result)
+    }
+
+    /// Subtracts `delta` from the counter under `key`, see `incr`.
+    pub fn decr(&mut self, key: &str, delta: i64, ttl: Duration) -> Result<i64, CacheServiceError> {
+        match delta.checked_neg() {
+            Some(delta) => self.incr(key, delta, ttl),
+            None => {
+                let result = Err(CacheServiceError::KvCacheError {
+                    operation: "INCRBY",
+                    key: Some(self.namespaced(key).into_owned()),
+                    source: KvError::CommandFailed(kv_cache::counter_overflow()),
+                });
+                self.counted(result)
+            }
+        }
+    }
+
+    fn try_incr(&mut self, key: &str, delta: i64, ttl: Duration) -> Result<i64, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        if self.memory_only {
+            let counted = self.in_memory_cache.increment(key, delta, ttl);
+            return self
+                .admitted(key, Tier::Memory, counted)
+                .map_err(CacheServiceError::memory("increment", key));
+        }
+        self.admit_to_quota(&[key])?;
+        let result = self.kv_cache.increment(key, delta, ttl);
+        let (count, remaining) = self
+            .admitted(key, Tier::Kv, result)
+            .map_err(CacheServiceError::kv("INCRBY", key))?;
+        self.listed(&[key]);
+        self.publish_invalidation(InvalidationKind::Update, key)?;
+
+        let memory_ttl = self.ttls.jittered(self.ttls.memory());
+        let stored = self.in_memory_cache.replace(
+            SetPayload {
+                key,
+                value: count.to_string().as_bytes(),
+                ttl: remaining.map_or(memory_ttl, |remaining| shorter_ttl(remaining, memory_ttl)),
+                tier_hint: None,
+            },
+            0.0,
+        );
+        self.admitted(key, Tier::Memory, stored)
+            .map_err(CacheServiceError::memory("replace", key))?;
+        Ok(count)
+    }
+
+    /// Removes the key from both tiers and tells other instances to drop their memory copy.
+    pub fn invalidate(&mut self, key: &str) -> Result<(), CacheServiceError> {
+        let result = self.try_invalidate(key);
+        self.counted(result)
+    }
+
+    fn try_invalidate(&mut self, key: &str) -> Result<(), CacheServiceError> {
+        let stored_key = &*self.namespaced(key);
+        let result = self.remove_stored(stored_key);
+        self.journaled(&result, |()| Operation::Invalidate {
+            key: key.to_string(),
+        });
+        result
+    }
+
+    /// Removes every key starting with `prefix`, relative to the namespace, e.g. `user:42:`
+    /// for all data cached about one user, from both tiers and the memory tier of other
+    /// instances. Redis keys are found with a cursor SCAN and unlinked in batches, at most
+    /// `limit` of them, so a prefix broader than intended cannot empty the whole namespace.
+    /// Returns how many Redis keys were removed; if that is `limit`, some may be left.
+    pub fn invalidate_prefix(
+        &mut self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<usize, CacheServiceError> {
+        let result = self.try_invalidate_prefix(prefix, limit);
+        self.counted(result)
+    }
+
+    fn try_invalidate_prefix(
+        &mut self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<usize, CacheServiceError> {
+        let stored_prefix = self.namespaced(prefix).into_owned();
+        self.in_memory_cache.remove_prefix(&stored_prefix);
+        let pattern = format!("{}*", kv_cache::escape_pattern(&stored_prefix));
+        let removed = self
+            .kv_cache
+            .unlink_matching(&pattern, limit)
+            .map_err(CacheServiceError::kv("UNLINK", &pattern))?;
+        if let Some(directory) = &mut self.directory {
+            directory.remove_prefix(&stored_prefix);
+        }
+        self.publish_invalidation(InvalidationKind::DeletePrefix, &stored_prefix)?;
+        Ok(removed)
+    }
+
+    fn remove_stored(&mut self, key: &str) -> Result<(), CacheServiceError> {
+        self.in_memory_cache.remove(key);
+        let removed = match self.tombstone_lifetime {
+            Some(lifetime) => {
+                let tombstone = self.tombstone_key(key);
+                let now = SystemTimeSource.now_millis();
+                self.kv_cache.bury(key, &tombstone, now, lifetime)
+            }
+            None => self.kv_cache.unset(key),
+        };
+        removed.map_err(CacheServiceError::kv("DEL", key))?;
+        if let Some(directory) = &mut self.directory {
+            directory.remove(key);
+        }
+        self.publish_invalidation(InvalidationKind::Delete, key)
+    }
+
+    /// Keeps the memory copy of `key` past its TTL and out of capacity eviction until `unpin`.
+    /// The Redis copy still expires. Returns false if `key` is not in memory.
+    pub fn pin(&self, key: &str) -> bool {
+        self.in_memory_cache.pin(&self.namespaced(key))
+    }
+
+    pub fn unpin(&self, key: &str) -> bool {
+        self.in_memory_cache.unpin(&self.namespaced(key))
+    }
+
+    /// Drops every pinned entry from memory, e.g. when pinned keys have grown stale. Returns how
+    /// many were dropped.
+    pub fn flush_pinned(&self) -> usize {
+        self.in_memory_cache.flush_pinned()
+    }
+
+    /// The kill switch of this service. While it is on, `resolve` and its variants call the
+    /// resolver directly, neither reading nor writing either tier, until it is turned off.
+    pub fn pass_through(&self) -> PassThrough {
+        self.pass_through.clone()
+    }
+
+    /// Whether `key`, relative to the namespace, is cached under a gradual rollout.
+    fn in_rollout(&self, key: &str) -> bool {
+        self.rollout_percent
+            .is_none_or(|percent| rollout::in_rollout(key, percent))
+    }
+
+    /// Whether resolving bypasses the cache, by this service's switch or its namespace's.
+    fn passing_through(&self) -> bool {
+        self.pass_through.is_on()
+            || self
+                .namespace_pass_through
+                .as_ref()
+                .zip(self.namespace.as_deref())
+                .is_some_and(|(switches, namespace)| switches.is_on(namespace))
+    }
+
+    /// Redis key of the tombstone `invalidate` leaves for `stored_key`.
+    fn tombstone_key(&self, stored_key: &str) -> String {
+        let key = self.without_namespace(stored_key);
+        self.namespaced(&format!("{}{}", TOMBSTONE_PREFIX, key))
+            .into_owned()
+    }
+
+    /// Whether a value of `stored_key` read or computed from `since` on, in Unix
+    /// milliseconds, may be outdated because the key was invalidated in the meantime. Redis
+    /// tombstones, left by other instances, take a round trip and are only checked if
+    /// `check_kv`.
+    fn invalidated_since(&mut self, stored_key: &str, since: u64, check_kv: bool) -> bool {
+        let mut invalidated = self.in_memory_cache.removed_since(stored_key, since);
+        if !invalidated && check_kv && self.tombstone_lifetime.is_some() {
+            let tombstone = self.tombstone_key(stored_key);
+            // Without an answer the value is cached, as it would be without tombstones.
+            invalidated = self
+                .kv_cache
+                .removed_since(&tombstone, since)
+                .unwrap_or(false);
+        }
+        if invalidated {
+            self.stats.dropped_fills += 1;
+        }
+        invalidated
+    }
+
+    fn publish_invalidation(
+        &mut self,
+        kind: InvalidationKind,
+        key: &str,
+    ) -> Result<(), CacheServiceError> {
+        let Some(invalidation) = &self.invalidation else {
+            return Ok(());
+        };
+        self.kv_cache
+            .publish(
+                invalidation.channel(),
+                &invalidation::encode_message(kind, key, &self.origin),
+            )
+            .map_err(CacheServiceError::kv("PUBLISH", invalidation.channel()))
+    }
+
+    /// Spreads out the expiry of existing entries in both tiers, e.g. after a bulk import left
+    /// them expiring at once. Every remaining TTL is scaled by a random factor within
+    /// `1 - factor..=1 + factor`, with `factor` clamped to `0..=1`. Without a namespace, every
+    /// key in the Redis database is affected. Returns the number of entries changed, counting
+    /// each tier separately.
+    pub fn rebalance_ttls(&mut self, factor: f64) -> Result<u64, CacheServiceError> {
+        let factor = factor.clamp(0.0, 1.0);
+        let pattern = self.key_pattern();
+        let memory = self.in_memory_cache.rebalance_ttls(factor) as u64;
+        let kv = self.kv_cache.rebalance_ttls(&pattern, factor);
+        let kv = self.counted(kv.map_err(CacheServiceError::kv("PEXPIRE", &pattern)))?;
+        Ok(memory + kv)
+    }
+
+    /// Preloads up to `limit` Redis keys matching `pattern`, relative to the namespace, into
+    /// memory for the rest of their Redis TTL, capped at the memory TTL. Meant to run before a
+    /// fresh instance takes traffic, so its first lookups don't all miss memory at once. Live
+    /// memory entries are kept. Returns how many keys were found in Redis.
+    pub fn warm_up(&mut self, pattern: &str, limit: usize) -> Result<usize, CacheServiceError> {
+        let pattern = self.namespaced(pattern).into_owned();
+        let found = self.kv_cache.scan_with_ttl(&pattern, limit);
+        let found = self.counted(found.map_err(CacheServiceError::kv("SCAN", &pattern)))?;
+        for (key, (value, remaining_ttl)) in &found {
+            self.promote(key, value, *remaining_ttl)?;
+        }
+        Ok(found.len())
+    }
+
+    /// Copies plain Redis values under ad-hoc names into this service's namespace, for
+    /// adopting keys written without rcache. Keys matching `pattern` are stored under their
+    /// name with `strip_prefix` removed, wrapped in envelopes and keeping their TTL; keys
+    /// without one get the Redis TTL. Existing keys in the namespace win, and the source keys
+    /// are kept. `progress` is called with the running totals after every batch.
+    pub fn import_keys(
+        &mut self,
+        pattern: &str,
+        strip_prefix: &str,
+        progress: impl FnMut(&ImportStats),
+    ) -> Result<ImportStats, CacheServiceError> {
+        let namespace = self.namespace.clone();
+        let rename = |key: &str| {
+            let key = key.strip_prefix(strip_prefix).unwrap_or(key);
+            match &namespace {
+                Some(namespace) => format!("{}:{}", namespace, key),
+                None => key.to_string(),
+            }
+        };
+        let imported = self
+            .kv_cache
+            .import(pattern, rename, self.ttls.kv(), progress);
+        self.counted(imported.map_err(CacheServiceError::kv("SCAN", pattern)))
+    }
+
+    /// Tiers `value` may be stored in under the size limits. Entries kept out of a tier are
+    /// logged as rejections.
+    fn admit_size(&mut self, key: &str, value: &[u8]) -> Result<Admission, CacheServiceError> {
+        self.sizes.keys.record(key.len());
+        self.sizes.values.record(value.len());
+        let admission = self.size_limits.admit(key, value);
+        if admission != Ok(Admission::Both) {
+            self.reject(key, Tier::Memory, RejectionReason::TooLarge);
+        }
+        if admission != Ok(Admission::Both) && admission != Ok(Admission::KvOnly) {
+            self.reject(key, Tier::Kv, RejectionReason::TooLarge);
+        }
+        admission.map_err(|exceeded| match exceeded {
+            LimitExceeded::Key { len, max } => CacheServiceError::KeyTooLong {
+                key: key.to_string(),
+                len,
+                max,
+            },
+            LimitExceeded::Value { len, max } => CacheServiceError::ValueTooLarge {
+                key: key.to_string(),
+                len,
+                max,
+            },
+        })
+    }
+
+    /// Matches every Redis key of this service.
+    fn key_pattern(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}:*", namespace),
+            None => "*".to_string(),
+        }
+    }
+
+    /// Checks that writing `keys` to Redis keeps the namespace within its key quota, evicting
+    /// other keys first under `QuotaPolicy::EvictOldest`. Admitted keys count as written.
+    fn admit_to_quota(&mut self, keys: &[&str]) -> Result<(), CacheServiceError> {
+        if self.quota.is_none() {
+            return Ok(());
+        }
+        let pattern = self.key_pattern();
+        let result = self.try_admit_to_quota(keys, &pattern);
+        if let Err(CacheServiceError::QuotaExceeded { .. }) = &result {
+            for key in keys {
+                self.reject(key, Tier::Kv, RejectionReason::QuotaExceeded);
+            }
+        }
+        result
+    }
+
+    fn try_admit_to_quota(
+        &mut self,
+        keys: &[&str],
+        pattern: &str,
+    ) -> Result<(), CacheServiceError> {
+        let Some(quota) = self.quota.as_mut() else {
+            return Ok(());
+        };
+        let mut incoming = keys.len() as u64;
+        if quota.needs_count(incoming) {
+            let count = self
+                .kv_cache
+                .count_keys(pattern)
+                .map_err(CacheServiceError::kv("SCAN", pattern))?;
+            quota.set_count(count);
+        }
+        if quota.excess(incoming) > 0 {
+            // Overwrites do not add keys.
+            incoming -= self
+                .kv_cache
+                .count_existing(keys)
+                .map_err(CacheServiceError::kv_unkeyed("EXISTS"))?;
+        }
+
+        let alert = quota.take_alert(incoming);
+        let excess = quota.excess(incoming);
+        let result = match quota.quota().policy {
+            _ if excess == 0 => Ok(()),
+            QuotaPolicy::Reject => {
+                quota.record_rejection();
+                Err(CacheServiceError::QuotaExceeded {
+                    max_keys: quota.quota().max_keys,
+                })
+            }
+            QuotaPolicy::EvictOldest => {
+                let evicted = self
+                    .kv_cache
+                    .evict_soonest_expiring(pattern, excess)
+                    .map_err(CacheServiceError::kv("UNLINK", pattern))?;
+                quota.record_removals(evicted);
+                Ok(())
+            }
+        };
+        if result.is_ok() {
+            quota.record_writes(incoming);
+        }
+        if let Some(keys) = alert {
+            let alert = QuotaAlert {
+                namespace: self.namespace.clone().unwrap_or_default(),
+                keys,
+                max_keys: quota.quota().max_keys,
+            };
+            self.stats.quota_alerts += 1;
+            if let Some(observer) = &self.quota_observer {
+                observer(&alert);
+            }
+        }
+        result
+    }
+
+    /// Applies new defaults without dropping cached data. New TTLs apply to writes from now on,
+    /// including background prefetches and refreshes; a smaller capacity evicts right away.
+    /// Nothing changes if any setting is invalid.
+    pub fn reconfigure(&mut self, config: Reconfiguration) -> Result<(), CacheServiceError> {
+        if config
+            .ttl_jitter
+            .is_some_and(|factor| !(0.0..1.0).contains(&factor))
+        {
+            return Err(CacheServiceError::InvalidConfig(
+                builder::ConfigError::InvalidTtlJitter,
+            ));
+        }
+        if config.capacity == Some(Some(0)) {
+            return Err(CacheServiceError::InvalidConfig(
+                builder::ConfigError::ZeroCapacity,
+            ));
+        }
+        let ttls = self.ttls.load();
+        self.ttls.store(TtlConfig {
+            memory: config.memory_ttl.unwrap_or(ttls.memory),
+            kv: config.kv_ttl.unwrap_or(ttls.kv),
+            jitter: config.ttl_jitter.unwrap_or(ttls.jitter),
+        });
+        if let Some(capacity) = config.capacity {
+            self.in_memory_cache.set_capacity(capacity);
+        }
+        Ok(())
+    }
+
+    /// Blocks until all queued write-behind writes have reached Redis.
+    pub fn flush(&self) {
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.flush();
+        }
+    }
+
+    /// Flushes queued writes and stops the write-behind flusher.
+    pub fn shutdown(&mut self) {
+        if let Some(write_behind) = self.write_behind.as_mut() {
+            write_behind.shutdown();
+        }
+        self.write_behind = None;
+    }
+
+    /// Saves the live memory entries to `path`, replacing the file only once the snapshot is
+    /// complete. Returns how many were saved.
+    pub fn save_memory_snapshot(&self, path: &Path) -> io::Result<usize> {
+        self.in_memory_cache.save_snapshot(path)
+    }
+
+    /// Puts the entries of a snapshot saved by `save_memory_snapshot` back into memory for
+    /// the rest of their TTL, e.g. to start warm after a restart. Expired entries are skipped
+    /// and Redis is not touched. Returns how many were loaded.
+    pub fn load_memory_snapshot(&mut self, path: &Path) -> io::Result<usize> {
+        self.in_memory_cache.load_snapshot(path)
+    }
+
+    fn write_kv(&mut self, payloads: &[SetPayload]) -> Result<(), CacheServiceError> {
+        let Some(write_behind) = &self.write_behind else {
+            let stored = self.kv_cache.set_many(payloads);
+            if let Err(err) = &stored {
+                let reason = err.rejection_reason();
+                for payload in payloads {
+                    self.reject(payload.key, Tier::Kv, reason);
+                }
+            }
+            return stored.map_err(CacheServiceError::kv_unkeyed("SET"));
+        };
+
+        let mut overflow = Vec::new();
+        for payload in payloads {
+            let write = QueuedWrite {
+                key: payload.key.to_string(),
+                value: Bytes::copy_from_slice(payload.value),
+                ttl: payload.ttl,
+            };
+            if let Err(write) = write_behind.enqueue(write) {
+                overflow.push(write);
+            }
+        }
+        let overflow_payloads: Vec<SetPayload> = overflow
+            .iter()
+            .map(|write| SetPayload {
+                key: &write.key,
+                value: &write.value,
+                ttl: write.ttl,
+                tier_hint: None,
+            })
+            .collect();
+        self.kv_cache
+            .set_many(&overflow_payloads)
+            .map_err(CacheServiceError::kv_unkeyed("SET"))
+    }
+
+    pub fn resolve<T>(&mut self, key: &str, resolver: T) -> Result<String, CacheServiceError>
+    where
+        T: FnOnce() -> String,
+    {
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        let resolver = || Bytes::from(resolver());
+        let result = self.resolve_decoded(key, memory_ttl, kv_ttl, resolver, into_string);
+        self.counted(result)
+    }
+
+    /// Same as `resolve` for binary values, which are stored in both tiers as-is.
+    pub fn resolve_bytes<T>(&mut self, key: &str, resolver: T) -> Result<Bytes, CacheServiceError>
+    where
+        T: FnOnce() -> Bytes,
+    {
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        let result = self.try_resolve_bytes(key, memory_ttl, kv_ttl, || Ok(resolver()));
+        self.counted(result)
+    }
+
+    /// Same as `resolve` for a resolver that can fail. If it does, the value the key had
+    /// when it expired, within the grace window of `CacheServiceBuilder::serve_stale`, is
+    /// returned instead of the error and marked as stale.
+    pub fn resolve_or_stale<T, E>(
+        &mut self,
+        key: &str,
+        resolver: T,
+    ) -> Result<MaybeStale<String>, CacheServiceError>
+    where
+        T: FnOnce() -> Result<String, E>,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let result = self
+            .try_resolve_or_stale(key, || resolver().map(Bytes::from).map_err(resolver_error))
+            .and_then(|resolved| {
+                Ok(MaybeStale {
+                    value: into_string(resolved.value)?,
+                    stale: resolved.stale,
+                })
+            });
+        self.counted(result)
+    }
+
+    /// Same as `resolve_or_stale` for binary values.
+    pub fn resolve_bytes_or_stale<T, E>(
+        &mut self,
+        key: &str,
+        resolver: T,
+    ) -> Result<MaybeStale<Bytes>, CacheServiceError>
+    where
+        T: FnOnce() -> Result<Bytes, E>,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let result = self.try_resolve_or_stale(key, || resolver().map_err(resolver_error));
+        self.counted(result)
+    }
+
+    /// Same as `resolve_or_stale`, but gives up on the resolver after `timeout`, or the
+    /// `CacheServiceBuilder::resolver_timeout` of the service if `None`, and returns
+    /// `CacheServiceError::Timeout` unless a stale value can be served. The resolver runs on
+    /// a thread of its own, which is left to finish in the background after a timeout; its
+    /// value is then not cached. Without either timeout, this waits like `resolve_or_stale`.
+    pub fn resolve_with_timeout<T, E>(
+        &mut self,
+        key: &str,
+        timeout: Option<Duration>,
+        resolver: T,
+    ) -> Result<MaybeStale<String>, CacheServiceError>
+    where
+        T: FnOnce() -> Result<String, E> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+    {
+        let resolver = || resolver().map(Bytes::from);
+        let result = match timeout.or(self.resolver_timeout) {
+            Some(timeout) => self.try_resolve_or_stale(key, || bounded(resolver, timeout)),
+            None => self.try_resolve_or_stale(key, || resolver().map_err(resolver_error)),
+        };
+        let result = result.and_then(|resolved| {
+            Ok(MaybeStale {
+                value: into_string(resolved.value)?,
+                stale: resolved.stale,
+            })
+        });
+        self.counted(result)
+    }
+
+    fn try_resolve_or_stale<T>(
+        &mut self,
+        key: &str,
+        resolver: T,
+    ) -> Result<MaybeStale<Bytes>, CacheServiceError>
+    where
+        T: FnOnce() -> Result<Bytes, CacheServiceError>,
+    {
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        let result = self.try_resolve_bytes(key, memory_ttl, kv_ttl, resolver);
+        if matches!(result, Err(CacheServiceError::Timeout(_))) {
+            self.stats.resolver_timeouts += 1;
+        }
+        match result {
+            Ok(value) => Ok(MaybeStale {
+                value,
+                stale: false,
+            }),
+            Err(err @ (CacheServiceError::Resolver(_) | CacheServiceError::Timeout(_))) => {
+                match self.in_memory_cache.get_stale(&self.namespaced(key)) {
+                    Some(value) => {
+                        self.stats.stale_served += 1;
+                        Ok(MaybeStale { value, stale: true })
+                    }
+                    None => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Same as `resolve`, but a newly resolved value is kept for `ttl` in both tiers
+    /// instead of the configured TTLs.
+    pub fn resolve_with_ttl<T>(
+        &mut self,
+        key: &str,
+        ttl: Duration,
+        resolver: T,
+    ) -> Result<String, CacheServiceError>
+    where
+        T: FnOnce() -> String,
+    {
+        let resolver = || Bytes::from(resolver());
+        let result = self.resolve_decoded(key, ttl, ttl, resolver, into_string);
+        self.counted(result)
+    }
+
+    /// Same as `resolve` for values stored in their string form, such as numbers. A newly
+    /// resolved value is kept for `ttl` in both tiers if given.
+    pub fn resolve_parsed<V, T>(
+        &mut self,
+        key: &str,
+        ttl: Option<Duration>,
+        resolver: T,
+    ) -> Result<V, CacheServiceError>
+    where
+        V: FromStr + ToString,
+        T: FnOnce() -> V,
+    {
+        let (memory_ttl, kv_ttl) = match ttl {
+            Some(ttl) => (ttl, ttl),
+            None => self.ttls.both(),
+        };
+        let resolver = || Bytes::from(resolver().to_string());
+        let result = self.resolve_decoded(key, memory_ttl, kv_ttl, resolver, parse);
+        self.counted(result)
+    }
+
+    /// Resolves `key` and decodes the value. Under `DeserializationPolicy::Miss`, a cached value
+    /// that fails to decode is dropped and the key resolved again.
+    fn resolve_decoded<V, T, D>(
+        &mut self,
+        key: &str,
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+        resolver: T,
+        decode: D,
+    ) -> Result<V, CacheServiceError>
+    where
+        T: FnOnce() -> Bytes,
+        D: Fn(Bytes) -> Result<V, CacheServiceError>,
+    {
+        let mut resolver = Some(resolver);
+        let value = self.try_resolve_bytes(key, memory_ttl, kv_ttl, || {
+            Ok(resolver
+                .take()
+                .map(|resolver| resolver())
+                .unwrap_or_default())
+        })?;
+        let decoded = decode(value);
+        let retry = matches!(decoded, Err(CacheServiceError::Deserialization { .. }))
+            && self.deserialization_policy == DeserializationPolicy::Miss;
+        // The resolver is left only if the value came from the cache; its own output is final.
+        match resolver {
+            Some(resolver) if retry => {
+                self.try_invalidate(key)?;
+                let value = self.try_resolve_bytes(key, memory_ttl, kv_ttl, || Ok(resolver()))?;
+                decode(value)
+            }
+            _ => decoded,
+        }
+    }
+
+    fn try_resolve_bytes<T>(
+        &mut self,
+        key: &str,
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+        resolver: T,
+    ) -> Result<Bytes, CacheServiceError>
+    where
+        T: FnOnce() -> Result<Bytes, CacheServiceError>,
+    {
+        let result = self.lookup_or_resolve(key, memory_ttl, kv_ttl, || guarded(resolver));
+        self.journaled(&result, |value| Operation::Resolve {
+            key: key.to_string(),
+            value: value.clone(),
+        });
+        result
+    }
+
+    fn lookup_or_resolve<T>(
+        &mut self,
+        key: &str,
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+        resolver: T,
+    ) -> Result<Bytes, CacheServiceError>
+    where
+        T: FnOnce() -> Result<Bytes, CacheServiceError>,
+    {
+        if self.passing_through() {
+            self.stats.pass_throughs += 1;
+            self.stats.resolver_calls += 1;
+            self.reject_both(key, RejectionReason::PassThrough);
+            return resolver();
+        }
+        if !self.in_rollout(key) {
+            self.stats.outside_rollout += 1;
+            self.reject_both(key, RejectionReason::OutsideRollout);
+            self.stats.resolver_calls += 1;
+            return resolver();
+        }
+        self.prefetch_related(key);
+        let stored_key = &*self.namespaced(key);
+        let memory_value = self.in_memory_cache.get(stored_key);
+
+        if let Some(value) = memory_value {
+            self.stats.memory_hits += 1;
+            self.record_lookup(stored_key, Some(Tier::Memory));
+            if !self.is_due_for_early_refresh(stored_key) {
+                self.refresh_ahead_if_due(key, stored_key);
+                self.slide(&[stored_key]);
+                self.shadow(stored_key, &value, Tier::Memory, resolver);
+                return Ok(value);
+            }
+            // The entry is still live, so a failed early refresh serves it.
+            return match self.recompute(stored_key, memory_ttl, kv_ttl, resolver) {
+                Err(CacheServiceError::Resolver(_)) => Ok(value),
+                result => result,
+            };
+        }
+        self.stats.memory_misses += 1;
+        let read_at = SystemTimeSource.now_millis();
+
+        let kv_value = self.get_listed(stored_key);
+
+        if let Some((value, remaining_ttl)) = kv_value {
+            self.stats.kv_hits += 1;
+            self.kv_event(stored_key, CacheEventKind::Hit);
+            self.record_lookup(stored_key, Some(Tier::Kv));
+            let remaining_ttl = self.slide(&[stored_key]).or(remaining_ttl);
+            if !self.invalidated_since(stored_key, read_at, false) {
+                self.promote(stored_key, &value, remaining_ttl)?;
+            }
+            self.shadow(stored_key, &value, Tier::Kv, resolver);
+            return Ok(value);
+        }
+        self.stats.kv_misses += 1;
+        self.kv_event(stored_key, CacheEventKind::Miss);
+        self.record_lookup(stored_key, None);
+        self.stats.resolver_calls += 1;
+        let started = Instant::now();
+        let value = resolver()?;
+        let delta = started.elapsed().as_secs_f64();
+        if self.invalidated_since(stored_key, read_at, true) {
+            return Ok(value);
+        }
+        self.insert(
+            SetPayload {
+                key: stored_key,
+                value: &value,
+                ttl: kv_ttl,
+                tier_hint: None,
+            },
+            memory_ttl,
+            delta,
+        )?;
+        Ok(value)
+    }
+
+    /// Under shadow mode, runs `resolver` for the sampled share of hits and reports whether
+    /// the `cached` value served from `tier` differs from the resolved one.
+    fn shadow<T>(&mut self, stored_key: &str, cached: &Bytes, tier: Tier, resolver: T)
+    where
+        T: FnOnce() -> Result<Bytes, CacheServiceError>,
+    {
+        let Some(sample_rate) = self.shadow_sample_rate else {
+            return;
+        };
+        if rand::random::<f64>() >= sample_rate {
+            return;
+        }
+        // A failed check tells nothing about the cached value.
+        let Ok(resolved) = resolver() else {
+            return;
+        };
+        self.stats.shadow_checks += 1;
+        if resolved == *cached {
+            return;
+        }
+        self.stats.shadow_divergences += 1;
+        if let Some(observer) = &self.shadow_observer {
+            observer(&ShadowDivergence {
+                key: self.without_namespace(stored_key).to_string(),
+                cached: cached.clone(),
+                resolved,
+                tier,
+            });
+        }
+    }
+
+    /// Looks `key` up in both tiers without resolving it on a miss. A Redis hit is promoted to
+    /// memory.
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<Bytes>, CacheServiceError> {
+        let result = self.try_get_bytes(key);
+        self.journaled(&result, |value| Operation::Get {
+            key: key.to_string(),
+            value: value.clone(),
+        });
+        self.counted(result)
+    }
+
+    fn try_get_bytes(&mut self, key: &str) -> Result<Option<Bytes>, CacheServiceError> {
+        let stored_key = &*self.namespaced(key);
+        if let Some(value) = self.in_memory_cache.get(stored_key) {
+            self.stats.memory_hits += 1;
+            self.record_lookup(stored_key, Some(Tier::Memory));
+            self.refresh_ahead_if_due(key, stored_key);
+            self.slide(&[stored_key]);
+            return Ok(Some(value));
+        }
+        self.stats.memory_misses += 1;
+        let read_at = SystemTimeSource.now_millis();
+
+        let Some((value, remaining_ttl)) = self.get_listed(stored_key) else {
+            self.stats.kv_misses += 1;
+            self.kv_event(stored_key, CacheEventKind::Miss);
+            self.record_lookup(stored_key, None);
+            return Ok(None);
+        };
+        self.stats.kv_hits += 1;
+        self.kv_event(stored_key, CacheEventKind::Hit);
+        self.record_lookup(stored_key, Some(Tier::Kv));
+        let remaining_ttl = self.slide(&[stored_key]).or(remaining_ttl);
+        if !self.invalidated_since(stored_key, read_at, false) {
+            self.promote(stored_key, &value, remaining_ttl)?;
+        }
+        Ok(Some(value))
+    }
+
+    /// Stores `value` under `key` in both tiers for `ttl`, the way `resolve` stores a
+    /// value it had to compute. Use it when the value is produced outside of a resolver.
+    pub fn set_bytes(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), CacheServiceError> {
+        self.set(SetPayload {
+            key,
+            value,
+            ttl,
+            tier_hint: None,
+        })
+    }
+
+    /// Same as `set_bytes`, but honours `payload.tier_hint`. A value kept in Redis only is
+    /// still promoted to memory when read.
+    pub fn set(&mut self, payload: SetPayload) -> Result<(), CacheServiceError> {
+        let key = &*self.namespaced(payload.key);
+        let result = self.insert(SetPayload { key, ..payload }, payload.ttl, 0.0);
+        self.journaled(&result, |()| Operation::Set {
+            key: payload.key.to_string(),
+            value: Bytes::copy_from_slice(payload.value),
+            ttl: payload.ttl,
+        });
+        self.counted(result)
+    }
+
+    /// Stores `payload` in the tiers allowed by its hint, using `payload.ttl` for Redis. `delta`
+    /// is how long the value took to compute, used by early expiration. Both TTLs are jittered.
+    fn insert(
+        &mut self,
+        payload: SetPayload,
+        memory_ttl: Duration,
+        delta: f64,
+    ) -> Result<(), CacheServiceError> {
+        let key = payload.key;
+        let admission = self.admit_size(key, payload.value)?;
+        let payload = SetPayload {
+            ttl: self.ttls.jittered(payload.ttl),
+            ..payload
+        };
+        if payload.tier_hint != Some(Tier::Memory) && admission != Admission::Nowhere {
+            self.admit_to_quota(&[key])?;
+            if self.write_behind.is_some() {
+                self.write_kv(&[payload])?;
+            } else {
+                let stored = self.kv_cache.set(payload);
+                self.admitted(key, Tier::Kv, stored)
+                    .map_err(CacheServiceError::kv("SET", key))?;
+            }
+            self.listed(&[key]);
+        }
+
+        if payload.tier_hint != Some(Tier::Kv) && admission == Admission::Both {
+            let stored = self.in_memory_cache.set_computed(
+                SetPayload {
+                    ttl: self.ttls.jittered(memory_ttl),
+                    ..payload
+                },
+                delta,
+            );
+            self.admitted(key, Tier::Memory, stored)
+                .map_err(CacheServiceError::memory("set", key))?;
+        }
+        Ok(())
+    }
+
+    fn refresh_ahead_if_due(&self, key: &str, stored_key: &str) {
+        let Some(refresh_ahead) = &self.refresh_ahead else {
+            return;
+        };
+        let now = SystemTimeSource.now_millis();
+        if self
+            .in_memory_cache
+            .meta(stored_key)
+            .is_some_and(|meta| refresh_ahead.is_due(&meta, now))
+        {
+            refresh_ahead.schedule(key, stored_key);
+        }
+    }
+
+    /// Hands the keys related to `key` that are not in memory yet to the prefetcher, which
+    /// warms them while `key` itself is being resolved.
+    fn prefetch_related(&self, key: &str) {
+        let Some(prefetcher) = &self.prefetcher else {
+            return;
+        };
+        let related = prefetcher
+            .related(key)
+            .iter()
+            .map(|related| self.namespaced(related).into_owned())
+            .filter(|related| self.in_memory_cache.meta(related).is_none())
+            .collect();
+        prefetcher.warm(related);
+    }
+
+    fn is_due_for_early_refresh(&self, key: &str) -> bool {
+        let Some(xfetch) = self.xfetch else {
+            return false;
+        };
+        self.in_memory_cache.meta(key).is_some_and(|meta| {
+            !meta.pinned
+                && xfetch.should_recompute(
+                    SystemTimeSource.now_millis(),
+                    meta.expires_at,
+                    meta.delta,
+                )
+        })
+    }
+
+    fn recompute<T>(
+        &mut self,
+        key: &str,
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+        resolver: T,
+    ) -> Result<Bytes, CacheServiceError>
+    where
+        T: FnOnce() -> Result<Bytes, CacheServiceError>,
+    {
+        self.stats.resolver_calls += 1;
+        let started = Instant::now();
+        let value = resolver()?;
+        let delta = started.elapsed().as_secs_f64();
+        self.store(key, &value, delta, memory_ttl, kv_ttl)
+    }
+
+    /// Writes `value` over whatever is cached under `key`, resolving the conflict with the
+    /// configured `ConflictPolicy`. Returns the value that ended up stored.
+    pub fn update(&mut self, key: &str, value: &str) -> Result<String, CacheServiceError> {
+        let value = self.update_bytes(key, value.as_bytes())?;
+        self.counted(into_string(value))
+    }
+
+    pub fn update_bytes(&mut self, key: &str, value: &[u8]) -> Result<Bytes, CacheServiceError> {
+        let result = self.try_update_bytes(key, value);
+        self.journaled(&result, |stored| Operation::Update {
+            key: key.to_string(),
+            value: Bytes::copy_from_slice(value),
+            stored: stored.clone(),
+        });
+        self.counted(result)
+    }
+
+    fn try_update_bytes(&mut self, key: &str, value: &[u8]) -> Result<Bytes, CacheServiceError> {
+        let key = &*self.namespaced(key);
+        let delta = self
+            .in_memory_cache
+            .meta(key)
+            .map_or(0.0, |meta| meta.delta);
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        self.store(key, value, delta, memory_ttl, kv_ttl)
+    }
+
+    fn store(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        delta: f64,
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+    ) -> Result<Bytes, CacheServiceError> {
+        let admission = self.admit_size(key, value)?;
+        if admission == Admission::Nowhere {
+            // The previous value would be stale.
+            self.remove_stored(key)?;
+            return Ok(Bytes::copy_from_slice(value));
+        }
+        let payload = SetPayload {
+            key,
+            value,
+            ttl: self.ttls.jittered(kv_ttl),
+            tier_hint: None,
+        };
+        self.admit_to_quota(&[key])?;
+
+        let value = match self.conflict_policy {
+            // Queued writes cannot be compared against the stored value, so only last-write-wins
+            // goes through write-behind.
+            ConflictPolicy::KeepNewest if self.write_behind.is_some() => {
+                self.write_kv(&[payload])?;
+                Bytes::copy_from_slice(value)
+            }
+            _ => {
+                let stored = self
+                    .kv_cache
+                    .set_with_policy(payload, &self.conflict_policy);
+                self.admitted(key, Tier::Kv, stored)
+                    .map_err(CacheServiceError::kv("SET", key))?
+            }
+        };
+        self.listed(&[key]);
+
+        self.publish_invalidation(InvalidationKind::Update, key)?;
+        if admission == Admission::KvOnly {
+            self.in_memory_cache.remove(key);
+            return Ok(value);
+        }
+
+        let stored = self.in_memory_cache.replace(
+            SetPayload {
+                key,
+                value: &value,
+                ttl: self.ttls.jittered(memory_ttl),
+                tier_hint: None,
+            },
+            delta,
+        );
+        self.admitted(key, Tier::Memory, stored)
+            .map_err(CacheServiceError::memory("replace", key))?;
+
+        Ok(value)
+    }
+
+    /// Resolves several keys at once. The memory tier is checked first, the remaining keys are
+    /// fetched from Redis with a single MGET, and `batch_resolver` is called once with only the
+    /// keys still missing. It must return their values in the same order.
+    pub fn resolve_many<T>(
+        &mut self,
+        keys: &[&str],
+        batch_resolver: T,
+    ) -> Result<Vec<String>, CacheServiceError>
+    where
+        T: FnOnce(&[&str]) -> Vec<String>,
+    {
+        let values = self.resolve_many_bytes(keys, |missing| {
+            batch_resolver(missing)
+                .into_iter()
+                .map(Bytes::from)
+                .collect()
+        })?;
+        let values = values.into_iter().map(into_string).collect();
+        self.counted(values)
+    }
+
+    /// Same as `resolve_many` for binary values.
+    pub fn resolve_many_bytes<T>(
+        &mut self,
+        keys: &[&str],
+        batch_resolver: T,
+    ) -> Result<Vec<Bytes>, CacheServiceError>
+    where
+        T: FnOnce(&[&str]) -> Vec<Bytes>,
+    {
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        self.resolve_many_with_ttl(keys, memory_ttl, kv_ttl, |missing| {
+            let missing_keys: Vec<&str> = missing.iter().map(|&i| keys[i]).collect();
+            batch_resolver(&missing_keys)
+        })
+    }
+
+    /// Same as `resolve_many_bytes`, but also reports which tier served each key and how long
+    /// that took. Keys looked up in Redis share the time of the MGET, and resolved keys that of
+    /// the batch resolver call and the writes. The outcomes also count towards `stats`.
+    pub fn resolve_many_with_info<T>(
+        &mut self,
+        keys: &[&str],
+        batch_resolver: T,
+    ) -> Result<Vec<ResolvedEntry>, CacheServiceError>
+    where
+        T: FnOnce(&[&str]) -> Vec<Bytes>,
+    {
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        let result = self.try_resolve_many_with_ttl(keys, memory_ttl, kv_ttl, |missing| {
+            let missing_keys: Vec<&str> = missing.iter().map(|&i| keys[i]).collect();
+            batch_resolver(&missing_keys)
+        });
+        self.counted(result)
+    }
+
+    /// Shared by the batch APIs. `batch_resolver` receives the indexes into `keys` of the
+    /// entries missing from both tiers.
+    fn resolve_many_with_ttl<T>(
+        &mut self,
+        keys: &[&str],
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+        batch_resolver: T,
+    ) -> Result<Vec<Bytes>, CacheServiceError>
+    where
+        T: FnOnce(&[usize]) -> Vec<Bytes>,
+    {
+        let result = self.try_resolve_many_with_ttl(keys, memory_ttl, kv_ttl, batch_resolver);
+        let entries = self.counted(result)?;
+        Ok(entries.into_iter().map(|entry| entry.value).collect())
+    }
+
+    fn try_resolve_many_with_ttl<T>(
+        &mut self,
+        keys: &[&str],
+        memory_ttl: Duration,
+        kv_ttl: Duration,
+        batch_resolver: T,
+    ) -> Result<Vec<ResolvedEntry>, CacheServiceError>
+    where
+        T: FnOnce(&[usize]) -> Vec<Bytes>,
+    {
+        let batch_resolver = |indexes: &[usize]| guarded(|| Ok(batch_resolver(indexes)));
+        if self.passing_through() {
+            for key in keys {
+                self.reject_both(key, RejectionReason::PassThrough);
+            }
+            return self.resolve_many_directly(keys.len(), batch_resolver);
+        }
+        // Keys outside the rollout skip both tiers and are resolved along with the misses.
+        let excluded: Vec<bool> = keys.iter().map(|key| !self.in_rollout(key)).collect();
+        for (key, _) in keys.iter().zip(&excluded).filter(|(_, &excluded)| excluded) {
+            self.reject_both(key, RejectionReason::OutsideRollout);
+        }
+        let excluded_count = excluded.iter().filter(|&&excluded| excluded).count();
+        self.stats.outside_rollout += excluded_count as u64;
+        let namespaced: Vec<Cow<str>> = keys.iter().map(|key| self.namespaced(key)).collect();
+        let keys: Vec<&str> = namespaced.iter().map(|key| &**key).collect();
+        let mut sources = vec![Source::Memory; keys.len()];
+        let mut elapsed = vec![Duration::ZERO; keys.len()];
+        let mut values: Vec<Option<Bytes>> = keys
+            .iter()
+            .zip(&mut elapsed)
+            .zip(&excluded)
+            .map(|((key, elapsed), &excluded)| {
+                if excluded {
+                    return None;
+                }
+                let started = Instant::now();
+                let value = self.in_memory_cache.get(key);
+                *elapsed = started.elapsed();
+                value
+            })
+            .collect();
+
+        let kv_indexes: Vec<usize> = (0..keys.len())
+            .filter(|&i| values[i].is_none() && !excluded[i])
+            .collect();
+        self.stats.memory_hits += (keys.len() - excluded_count - kv_indexes.len()) as u64;
+        self.stats.memory_misses += kv_indexes.len() as u64;
+        let started = Instant::now();
+        let looked_up: Vec<bool> = kv_indexes
+            .iter()
+            .map(|&i| !self.ruled_out(keys[i]))
+            .collect();
+        let kv_keys: Vec<&str> = kv_indexes
+            .iter()
+            .zip(&looked_up)
+            .filter(|(_, &looked_up)| looked_up)
+            .map(|(&i, _)| keys[i])
+            .collect();
+        let mut found = self
+            .kv_cache
+            .get_many_with_ttl(&kv_keys)
+            .map_err(CacheServiceError::kv_unkeyed("GET"))?
+            .into_iter();
+        for (key, value) in kv_keys.iter().zip(found.as_slice()) {
+            if value.is_none() {
+                self.unlist(key);
+            }
+        }
+        let kv_values: Vec<Option<ValueWithTtl>> = looked_up
+            .iter()
+            .map(|&looked_up| {
+                if looked_up {
+                    found.next().flatten()
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let hits: Vec<&str> = (0..keys.len())
+            .filter(|&i| values[i].is_some())
+            .chain(
+                kv_indexes
+                    .iter()
+                    .zip(&kv_values)
+                    .filter(|(_, kv_value)| kv_value.is_some())
+                    .map(|(&i, _)| i),
+            )
+            .map(|i| keys[i])
+            .collect();
+        let slid_ttl = self.slide(&hits);
+        for (&i, kv_value) in kv_indexes.iter().zip(kv_values) {
+            if let Some((value, remaining_ttl)) = kv_value {
+                self.promote(keys[i], &value, slid_ttl.or(remaining_ttl))?;
+                values[i] = Some(value);
+            }
+            sources[i] = Source::Kv;
+        }
+        let kv_elapsed = started.elapsed();
+        for &i in &kv_indexes {
+            elapsed[i] += kv_elapsed;
+        }
+
+        let missing_indexes: Vec<usize> =
+            (0..keys.len()).filter(|&i| values[i].is_none()).collect();
+        self.stats.kv_hits += (kv_indexes.len() + excluded_count - missing_indexes.len()) as u64;
+        self.stats.kv_misses += (missing_indexes.len() - excluded_count) as u64;
+        for &i in &kv_indexes {
+            let kind = if values[i].is_some() {
+                CacheEventKind::Hit
+            } else {
+                CacheEventKind::Miss
+            };
+            self.kv_event(keys[i], kind);
+        }
+        for i in (0..keys.len()).filter(|&i| !excluded[i]) {
+            let served = values[i].as_ref().map(|_| match sources[i] {
+                Source::Memory => Tier::Memory,
+                _ => Tier::Kv,
+            });
+            self.record_lookup(keys[i], served);
+        }
+        if !missing_indexes.is_empty() {
+            self.stats.resolver_calls += 1;
+            let started = Instant::now();
+            let missing_keys: Vec<&str> = missing_indexes.iter().map(|&i| keys[i]).collect();
+            let resolved = batch_resolver(&missing_indexes)?;
+            if resolved.len() != missing_keys.len() {
+                return Err(CacheServiceError::BatchResolverMismatch {
+                    expected: missing_keys.len(),
+                    actual: resolved.len(),
+                });
+            }
+
+            let cached: Vec<(&str, &Bytes)> = missing_indexes
+                .iter()
+                .zip(&resolved)
+                .filter(|(&i, _)| !excluded[i])
+                .map(|(&i, value)| (keys[i], value))
+                .collect();
+            let admissions = cached
+                .iter()
+                .map(|(key, value)| self.admit_size(key, value))
+                .collect::<Result<Vec<Admission>, CacheServiceError>>()?;
+            // Values too large for memory are marked as Redis-only.
+            let payloads: Vec<SetPayload> = cached
+                .into_iter()
+                .zip(admissions)
+                .filter(|(_, admission)| *admission != Admission::Nowhere)
+                .map(|((key, value), admission)| SetPayload {
+                    key,
+                    value,
+                    ttl: self.ttls.jittered(kv_ttl),
+                    tier_hint: (admission == Admission::KvOnly).then_some(Tier::Kv),
+                })
+                .collect();
+            let kv_keys: Vec<&str> = payloads.iter().map(|payload| payload.key).collect();
+            self.admit_to_quota(&kv_keys)?;
+            self.write_kv(&payloads)?;
+            self.listed(&kv_keys);
+            for payload in payloads {
+                if payload.tier_hint == Some(Tier::Kv) {
+                    continue;
+                }
+                let key = payload.key;
+                let stored = self.in_memory_cache.set(SetPayload {
+                    ttl: self.ttls.jittered(memory_ttl),
+                    ..payload
+                });
+                self.admitted(key, Tier::Memory, stored)
+                    .map_err(CacheServiceError::memory("set", key))?;
+            }
+
+            let resolver_elapsed = started.elapsed();
+            for (i, value) in missing_indexes.into_iter().zip(resolved) {
+                values[i] = Some(value);
+                sources[i] = Source::Resolver;
+                elapsed[i] += resolver_elapsed;
+            }
+        }
+
+        let entries = values
+            .into_iter()
+            .zip(sources)
+            .zip(elapsed)
+            .map(|((value, source), elapsed)| ResolvedEntry {
+                value: value.unwrap_or_default(),
+                source,
+                elapsed,
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// Resolves all of a batch of `len` keys with `batch_resolver` under pass-through.
+    fn resolve_many_directly<T>(
+        &mut self,
+        len: usize,
+        batch_resolver: T,
+    ) -> Result<Vec<ResolvedEntry>, CacheServiceError>
+    where
+        T: FnOnce(&[usize]) -> Result<Vec<Bytes>, CacheServiceError>,
+    {
+        self.stats.pass_throughs += len as u64;
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        self.stats.resolver_calls += 1;
+        let started = Instant::now();
+        let indexes: Vec<usize> = (0..len).collect();
+        let resolved = batch_resolver(&indexes)?;
+        if resolved.len() != len {
+            return Err(CacheServiceError::BatchResolverMismatch {
+                expected: len,
+                actual: resolved.len(),
+            });
+        }
+        let elapsed = started.elapsed();
+        Ok(resolved
+            .into_iter()
+            .map(|value| ResolvedEntry {
+                value,
+                source: Source::Resolver,
+                elapsed,
+            })
+            .collect())
+    }
+
+    /// Resolves the tile containing the coordinate together with its neighbouring tiles.
+    /// `batch_resolver` receives only the geohashes of tiles missing from both tiers and
+    /// returns tile values in the same order. The result pairs each geohash with its value.
+    pub fn resolve_neighborhood<T>(
+        &mut self,
+        geo_key: &GeoKey,
+        lat: f64,
+        lon: f64,
+        batch_resolver: T,
+    ) -> Result<Vec<(String, String)>, CacheServiceError>
+    where
+        T: FnOnce(&[&str]) -> Vec<String>,
+    {
+        let tiles = geo_key
+            .neighborhood(lat, lon)
+            .map_err(CacheServiceError::GeoKeyError)?;
+        let keys: Vec<String> = tiles
+            .iter()
+            .map(|tile| geo_key.key_for_tile(tile))
+            .collect();
+        let key_refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+        let (memory_ttl, kv_ttl) = self.ttls.both();
+        let values = self.resolve_many_with_ttl(&key_refs, memory_ttl, kv_ttl, |missing| {
+            let missing_tiles: Vec<&str> = missing.iter().map(|&i| tiles[i].as_str()).collect();
+            batch_resolver(&missing_tiles)
+                .into_iter()
+                .map(Bytes::from)
+                .collect()
+        })?;
+
+        let values = tiles
+            .into_iter()
+            .zip(values)
+            .map(|(tile, value)| Ok((tile, into_string(value)?)))
+            .collect();
+        self.counted(values)
+    }
+
+    /// Resolves the `count` most recent time buckets, oldest first. `batch_resolver` receives
+    /// the start timestamps of buckets missing from both tiers, and resolved buckets are cached
+    /// for the bucket length plus grace.
+    pub fn resolve_window<T>(
+        &mut self,
+        bucket_key: &TimeBucketKey,
+        count: usize,
+        batch_resolver: T,
+    ) -> Result<Vec<(u64, String)>, CacheServiceError>
+    where
+        T: FnOnce(&[u64]) -> Vec<String>,
+    {
+        let starts = bucket_key.window(SystemTimeSource.now(), count);
+        let keys: Vec<String> = starts.iter().map(|&start| bucket_key.key(start)).collect();
+        let key_refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+        let ttl = bucket_key.ttl();
+        let values = self.resolve_many_with_ttl(&key_refs, ttl, ttl, |missing| {
+            let missing_starts: Vec<u64> = missing.iter().map(|&i| starts[i]).collect();
+            batch_resolver(&missing_starts)
+                .into_iter()
+                .map(Bytes::from)
+                .collect()
+        })?;
+
+        let values = starts
+            .into_iter()
+            .zip(values)
+            .map(|(start, value)| Ok((start, into_string(value)?)))
+            .collect();
+        self.counted(values)
+    }
+
+    /// Runs `op` at most once per `key` within `ttl`. The idempotency token is recorded
+    /// in Redis with SET NX before `op` runs, so concurrent duplicates across instances get
+    /// `IdempotencyInProgress` and later duplicates get the stored result of the first call.
+    /// If `op` panics or its result can't be stored, the token is removed again so a retry
+    /// can run it.
+    pub fn idempotent<T>(
+        &mut self,
+        key: &str,
+        ttl: Duration,
+        op: T,
+    ) -> Result<String, CacheServiceError>
+    where
+        T: FnOnce() -> String,
+    {
+        let result = self.try_idempotent(key, ttl, op);
+        self.counted(result)
+    }
+
+    fn try_idempotent<T>(
+        &mut self,
+        key: &str,
+        ttl: Duration,
+        op: T,
+    ) -> Result<String, CacheServiceError>
+    where
+        T: FnOnce() -> String,
+    {
+        let token_key = format!("{}{}", IDEMPOTENCY_PREFIX, key);
+        let token_key = self.namespaced(&token_key).into_owned();
+
+        if let Some(value) = self.in_memory_cache.get(&token_key) {
+            return into_string(value);
+        }
+
+        let acquired = self
+            .kv_cache
+            .set_nx(SetPayload {
+                key: &token_key,
+                value: IDEMPOTENCY_PENDING.as_bytes(),
+                ttl,
+                tier_hint: None,
+            })
+            .map_err(CacheServiceError::kv("SET", &token_key))?;
+
+        if !acquired {
+            return match self.kv_cache.get(&token_key) {
+                Some(value) if value != IDEMPOTENCY_PENDING => into_string(value),
+                _ => Err(CacheServiceError::IdempotencyInProgress),
+            };
+        }
+
+        let stored = guarded(|| Ok(op())).and_then(|value| {
+            self.kv_cache
+                .overwrite(SetPayload {
+                    key: &token_key,
+                    value: value.as_bytes(),
+                    ttl,
+                    tier_hint: None,
+                })
+                .map_err(CacheServiceError::kv("SET", &token_key))?;
+            Ok(value)
+        });
+        let value = match stored {
+            Ok(value) => value,
+            Err(err) => {
+                let _ = self.kv_cache.unset(&token_key);
+                return Err(err);
+            }
+        };
+
+        self.in_memory_cache
+            .set(SetPayload {
+                key: &token_key,
+                value: value.as_bytes(),
+                ttl,
+                tier_hint: None,
+            })
+            .map_err(CacheServiceError::memory("set", &token_key))?;
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    use super::*;
+    use crate::encryption::StaticKeys;
+    use crate::envelope::Envelope;
+    use crate::in_memory_cache::EvictionPolicy;
+    use crate::journal::{Divergence, JournalEntry};
+    use crate::limits::OversizedPolicy;
+    use crate::quota::KeyQuota;
+    use crate::write_behind::WriteBehindConfig;
+
+    #[test]
+    fn it_should_resolve_value() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        let value = cache.resolve("key", || "value".to_string()).unwrap();
+        assert_eq!(value, "value");
+    }
+
+    #[test]
+    fn it_should_resolve_value_from_memory() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache
+            .in_memory_cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("All should be ok");
+        let value = cache.resolve("key", || "never_see".to_string()).unwrap();
+
+        assert_eq!(value, "value");
+    }
+
+    #[test]
+    fn it_should_resolve_value_from_kv() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache
+            .kv_cache
+            .set(SetPayload {
+                key: "key",
+                value: b"value",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .expect("All should be ok");
+        let value = cache.resolve("key", || "never_see".to_string()).unwrap();
+
+        assert_eq!(value, "value");
+    }
+
+    #[test]
+    fn it_should_resolve_binary_value() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("binkey").unwrap();
+        let blob = Bytes::from_static(&[0, 159, 146, 150, 255]);
+        let value = cache.resolve_bytes("binkey", || blob.clone()).unwrap();
+        assert_eq!(value, blob);
+        assert_eq!(cache.kv_cache.get("binkey").unwrap(), blob);
+        assert!(matches!(
+            cache.resolve("binkey", || "never_see".to_string()),
+            Err(CacheServiceError::Deserialization { type_name, raw })
+                if type_name == "alloc::string::String" && raw == blob
+        ));
+        cache.kv_cache.unset("binkey").unwrap();
+    }
+
+    #[test]
+    fn it_should_resolve_undecodable_value_again_on_miss_policy() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("undecodable")
+            .on_deserialization_failure(DeserializationPolicy::Miss)
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("text", &[0, 159], Duration::from_secs(10))
+            .unwrap();
+        cache
+            .set_bytes("number", b"many", Duration::from_secs(10))
+            .unwrap();
+
+        let value = cache.resolve("text", || "fresh".to_string()).unwrap();
+        assert_eq!(value, "fresh");
+        assert_eq!(cache.kv_cache.get("undecodable:text").unwrap(), "fresh");
+        let value: u32 = cache.resolve_parsed("number", None, || 7).unwrap();
+        assert_eq!(value, 7);
+
+        cache.invalidate("number").unwrap();
+        cache
+            .set_bytes("number", b"many", Duration::from_secs(10))
+            .unwrap();
+        cache.deserialization_policy = DeserializationPolicy::Error;
+        assert!(matches!(
+            cache.resolve_parsed::<u32, _>("number", None, || 7),
+            Err(CacheServiceError::Deserialization { type_name: "u32", raw }) if raw == "many"
+        ));
+        cache.invalidate("text").unwrap();
+        cache.invalidate("number").unwrap();
+    }
+
+    #[test]
+    fn should_set_value_to_memory_cache() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.resolve("memkey", || "value".to_string()).unwrap();
+        cache.kv_cache.unset("memkey").unwrap();
+        let in_memory_value = cache.in_memory_cache.get("memkey").unwrap();
+
+        assert_eq!(in_memory_value, "value");
+    }
+
+    #[test]
+    fn should_set_value_to_kv_cache() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.resolve("kvkey", || "kvval".to_string()).unwrap();
+
+        let kv_cache = cache.kv_cache.get("kvkey").unwrap();
+
+        assert_eq!(kv_cache, "kvval");
+    }
+
+    #[test]
+    fn it_should_resolve_many_from_all_tiers() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("batch_missing").unwrap();
+        cache
+            .in_memory_cache
+            .set(SetPayload {
+                key: "batch_mem",
+                value: b"mem",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        cache
+            .kv_cache
+            .overwrite(SetPayload {
+                key: "batch_kv",
+                value: b"kv",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        let mut requested = Vec::new();
+        let values = cache
+            .resolve_many(&["batch_mem", "batch_kv", "batch_missing"], |keys| {
+                requested = keys.iter().map(|key| key.to_string()).collect();
+                vec!["resolved".to_string()]
+            })
+            .unwrap();
+        let stored = cache.kv_cache.get("batch_missing");
+        cache.kv_cache.unset("batch_kv").unwrap();
+        cache.kv_cache.unset("batch_missing").unwrap();
+
+        assert_eq!(values, vec!["mem", "kv", "resolved"]);
+        assert_eq!(requested, vec!["batch_missing"]);
+        assert_eq!(stored.unwrap(), "resolved");
+    }
+
+    #[test]
+    fn it_should_reject_batch_resolver_with_wrong_length() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        let result = cache.resolve_many(&["batch_wrong1", "batch_wrong2"], |_| vec![]);
+
+        assert!(matches!(
+            result,
+            Err(CacheServiceError::BatchResolverMismatch {
+                expected: 2,
+                actual: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn it_should_count_hits_misses_and_errors() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("stats_key").unwrap();
+
+        cache.resolve("stats_key", || "value".to_string()).unwrap();
+        cache
+            .resolve("stats_key", || "never_see".to_string())
+            .unwrap();
+        cache.in_memory_cache.remove("stats_key");
+        cache
+            .resolve("stats_key", || "never_see".to_string())
+            .unwrap();
+        let _ = cache.resolve_many(&["stats_wrong"], |_| vec![]);
+
+        let stats = cache.stats();
+        assert_eq!(stats.memory_hits, 1);
+        assert_eq!(stats.memory_misses, 3);
+        assert_eq!(stats.kv_hits, 1);
+        assert_eq!(stats.kv_misses, 2);
+        assert_eq!(stats.resolver_calls, 2);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.entries, 1);
+
+        cache.reset_stats();
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 1,
+                ..CacheStats::default()
+            }
+        );
+    }
+
+    static CACHED_SQUARE_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    #[crate::cached(ttl = 10)]
+    fn cached_square(cache: &mut CacheService, n: u64) -> u64 {
+        CACHED_SQUARE_CALLS.fetch_add(1, Ordering::Relaxed);
+        n * n
+    }
+
+    #[test]
+    fn it_should_memoize_cached_function() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        let key = format!("{}::cached_square(7)", module_path!());
+        cache.kv_cache.unset(&key).unwrap();
+
+        assert_eq!(cached_square(&mut cache, 7).unwrap(), 49);
+        assert_eq!(cached_square(&mut cache, 7).unwrap(), 49);
+        assert_eq!(CACHED_SQUARE_CALLS.load(Ordering::Relaxed), 1);
+        assert!(cache.kv_cache.get(&key).is_some());
+    }
+
+    #[test]
+    fn it_should_resolve_neighborhood_tiles() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        let geo_key = GeoKey::new("places", 7).unwrap();
+        let tiles = geo_key.neighborhood(57.64911, 10.40744).unwrap();
+        for tile in &tiles {
+            cache.kv_cache.unset(&geo_key.key_for_tile(tile)).unwrap();
+        }
+        let values = cache
+            .resolve_neighborhood(&geo_key, 57.64911, 10.40744, |missing| {
+                missing
+                    .iter()
+                    .map(|tile| format!("near {}", tile))
+                    .collect()
+            })
+            .unwrap();
+        for tile in &tiles {
+            cache.kv_cache.unset(&geo_key.key_for_tile(tile)).unwrap();
+        }
+
+        assert_eq!(values.len(), 9);
+        assert_eq!(
+            values[0],
+            ("u4pruyd".to_string(), "near u4pruyd".to_string())
+        );
+    }
+
+    #[test]
+    fn it_should_resolve_window_buckets() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        let bucket_key = TimeBucketKey::new("views", time_bucket::BucketSize::Day, 60);
+        let starts = bucket_key.window(SystemTimeSource.now(), 2);
+        for start in &starts {
+            cache.kv_cache.unset(&bucket_key.key(*start)).unwrap();
+        }
+        cache
+            .kv_cache
+            .overwrite(SetPayload {
+                key: &bucket_key.key(starts[0]),
+                value: b"cached",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        let values = cache
+            .resolve_window(&bucket_key, 2, |missing| {
+                missing.iter().map(|start| start.to_string()).collect()
+            })
+            .unwrap();
+        for start in &starts {
+            cache.kv_cache.unset(&bucket_key.key(*start)).unwrap();
+        }
+
+        assert_eq!(
+            values,
+            vec![
+                (starts[0], "cached".to_string()),
+                (starts[1], starts[1].to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_write_behind_to_kv_cache() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .write_behind(WriteBehindConfig::default())
+            .build()
+            .unwrap();
+        cache.kv_cache.unset("wbkey").unwrap();
+        cache.resolve("wbkey", || "wbval".to_string()).unwrap();
+        let in_memory_value = cache.in_memory_cache.get("wbkey");
+        cache.flush();
+        let kv_value = cache.kv_cache.get("wbkey");
+        cache.kv_cache.unset("wbkey").unwrap();
+
+        assert_eq!(in_memory_value.unwrap(), "wbval");
+        assert_eq!(kv_value.unwrap(), "wbval");
+    }
+
+    #[test]
+    fn it_should_recompute_expensive_value_early() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .xfetch(XFetch::new(1e9))
+            .build()
+            .unwrap();
+        cache
+            .in_memory_cache
+            .replace(
+                SetPayload {
+                    key: "xfetchkey",
+                    value: b"old",
+                    ttl: Duration::from_secs(10),
+                    tier_hint: None,
+                },
+                1.0,
+            )
+            .unwrap();
+        let value = cache.resolve("xfetchkey", || "new".to_string()).unwrap();
+        cache.kv_cache.unset("xfetchkey").unwrap();
+
+        assert_eq!(value, "new");
+    }
+
+    #[test]
+    fn it_should_record_computation_time_on_miss() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("xfetch_delta").unwrap();
+        cache
+            .resolve("xfetch_delta", || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                "value".to_string()
+            })
+            .unwrap();
+        let delta = cache.in_memory_cache.meta("xfetch_delta").unwrap().delta;
+        cache.kv_cache.unset("xfetch_delta").unwrap();
+
+        assert!(delta >= 0.02);
+    }
+
+    #[test]
+    fn it_should_keep_fresh_value_without_xfetch() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache
+            .in_memory_cache
+            .replace(
+                SetPayload {
+                    key: "noxfetchkey",
+                    value: b"old",
+                    ttl: Duration::from_secs(10),
+                    tier_hint: None,
+                },
+                1.0,
+            )
+            .unwrap();
+        let value = cache.resolve("noxfetchkey", || "new".to_string()).unwrap();
+
+        assert_eq!(value, "old");
+    }
+
+    #[test]
+    fn it_should_apply_conflict_policy_on_update() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .conflict_policy(ConflictPolicy::merge(|existing, incoming| {
+                [existing, b",", incoming].concat()
+            }))
+            .build()
+            .unwrap();
+        cache.kv_cache.unset("conflict").unwrap();
+        cache.resolve("conflict", || "first".to_string()).unwrap();
+        let value = cache.update("conflict", "second").unwrap();
+        assert_eq!(value, "first,second");
+        assert_eq!(
+            cache.in_memory_cache.get("conflict").unwrap(),
+            "first,second"
+        );
+        assert_eq!(cache.kv_cache.get("conflict").unwrap(), "first,second");
+        cache.kv_cache.unset("conflict").unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_oldest_value_on_update() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .conflict_policy(ConflictPolicy::KeepOldest)
+            .build()
+            .unwrap();
+        cache.kv_cache.unset("oldest").unwrap();
+        cache.resolve("oldest", || "first".to_string()).unwrap();
+        assert_eq!(cache.update("oldest", "second").unwrap(), "first");
+        assert_eq!(cache.kv_cache.get("oldest").unwrap(), "first");
+        cache.kv_cache.unset("oldest").unwrap();
+    }
+
+    #[test]
+    fn it_should_migrate_legacy_values_in_background() {
+        let mut kv_cache = KvCache::new("redis://127.0.0.1:6379").unwrap();
+        let mut con = KvCache::new("redis://127.0.0.1:6379")
+            .unwrap()
+            .into_connection();
+        redis::cmd("SET")
+            .arg("bgscan:1")
+            .arg("value")
+            .query::<()>(&mut con)
+            .unwrap();
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .legacy_scan("bgscan:*")
+            .build()
+            .unwrap();
+        let stats = cache.wait_for_legacy_scan().unwrap().unwrap();
+        assert_eq!(stats.converted, 1);
+        assert_eq!(cache.migration_stats().converted, 1);
+        assert_eq!(kv_cache.get("bgscan:1").unwrap(), "value");
+        kv_cache.unset("bgscan:1").unwrap();
+    }
+
+    #[test]
+    fn it_should_resolve_over_custom_connection() {
+        let stored = Envelope::encode(SystemTimeSource.now(), b"value");
+        let mut pipe = redis::pipe();
+        pipe.cmd("MGET").arg(&["mockkey"]).pttl("mockkey");
+        let con = MockRedisConnection::new(vec![MockCmd::with_values(
+            pipe,
+            Ok(vec![
+                redis::Value::Bulk(vec![redis::Value::Data(stored)]),
+                redis::Value::Int(30_000),
+            ]),
+        )]);
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .build_with_connection(con)
+            .unwrap();
+        let value = cache
+            .resolve("mockkey", || "never_see".to_string())
+            .unwrap();
+        assert_eq!(value, "value");
+        assert_eq!(cache.in_memory_cache.get("mockkey").unwrap(), "value");
+    }
+
+    #[test]
+    fn it_should_warm_up_memory_from_redis() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("warmup")
+                .build()
+                .unwrap()
+        };
+        let mut deployed = build();
+        for key in ["item:1", "item:2", "item:3", "other"] {
+            deployed
+                .set_bytes(key, b"value", Duration::from_secs(50))
+                .unwrap();
+        }
+
+        let mut fresh = build();
+        assert_eq!(fresh.warm_up("item:*", 2).unwrap(), 2);
+        let warmed: Vec<&str> = ["item:1", "item:2", "item:3", "other"]
+            .into_iter()
+            .filter(|key| {
+                fresh
+                    .in_memory_cache
+                    .meta(&format!("warmup:{}", key))
+                    .is_some()
+            })
+            .collect();
+        assert_eq!(warmed.len(), 2);
+        assert!(warmed.iter().all(|key| key.starts_with("item:")));
+        let meta = fresh.in_memory_cache.meta(&format!("warmup:{}", warmed[0]));
+        assert!(meta.unwrap().ttl <= Duration::from_secs(50));
+        assert_eq!(fresh.warm_up("*", 10).unwrap(), 4);
+        for key in ["item:1", "item:2", "item:3", "other"] {
+            deployed.invalidate(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_should_import_ad_hoc_keys_into_namespace() {
+        let mut con = redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = redis::pipe()
+            .set_ex("adhoc:user:1", "one", 40)
+            .set("adhoc:user:2", "two")
+            .sadd("adhoc:tags", "tag")
+            .query(&mut con)
+            .unwrap();
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("imported")
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("user:2", b"newer", Duration::from_secs(100))
+            .unwrap();
+
+        let mut reports = Vec::new();
+        let stats = cache
+            .import_keys("adhoc:*", "adhoc:", |stats| reports.push(*stats))
+            .unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                scanned: 3,
+                imported: 1,
+                existing: 1,
+                skipped: 1,
+            }
+        );
+        assert_eq!(reports, [stats]);
+        let (value, ttl) = cache.kv_cache.get_with_ttl("imported:user:1").unwrap();
+        assert_eq!(value, "one");
+        assert!(ttl.is_some_and(|ttl| ttl <= Duration::from_secs(40)));
+        assert_eq!(cache.get_bytes("user:2").unwrap().unwrap(), "newer");
+
+        let _: () = redis::cmd("DEL")
+            .arg(&["adhoc:user:1", "adhoc:user:2", "adhoc:tags"])
+            .query(&mut con)
+            .unwrap();
+        for key in ["user:1", "user:2"] {
+            cache.invalidate(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_should_prefix_keys_with_namespace() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("ns")
+            .build()
+            .unwrap();
+        cache.kv_cache.unset("ns:nskey").unwrap();
+        cache.resolve("nskey", || "value".to_string()).unwrap();
+        assert_eq!(cache.kv_cache.get("ns:nskey").unwrap(), "value");
+        assert!(cache.in_memory_cache.get("ns:nskey").is_some());
+        cache.kv_cache.unset("ns:nskey").unwrap();
+    }
+
+    #[test]
+    fn it_should_prefetch_related_keys() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("pf")
+            .prefetch("user:", |key| vec![key.replace(":profile", ":prefs")])
+            .build()
+            .unwrap();
+        cache
+            .kv_cache
+            .set(SetPayload {
+                key: "pf:user:42:prefs",
+                value: b"dark",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+
+        cache
+            .resolve("user:42:profile", || "alice".to_string())
+            .unwrap();
+        cache.prefetcher.as_ref().unwrap().flush();
+
+        assert_eq!(
+            cache.in_memory_cache.get("pf:user:42:prefs").unwrap(),
+            "dark"
+        );
+        cache.kv_cache.unset("pf:user:42:prefs").unwrap();
+        cache.kv_cache.unset("pf:user:42:profile").unwrap();
+    }
+
+    #[test]
+    fn it_should_rebalance_ttls_in_both_tiers() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(1000))
+            .namespace("rebalance")
+            .build()
+            .unwrap();
+        cache.resolve("a", || "value".to_string()).unwrap();
+        cache.resolve("b", || "value".to_string()).unwrap();
+
+        assert_eq!(cache.rebalance_ttls(0.5).unwrap(), 4);
+        let (_, ttl) = cache.kv_cache.get_with_ttl("rebalance:a").unwrap();
+        assert!((Duration::from_secs(499)..=Duration::from_secs(1500)).contains(&ttl.unwrap()));
+        cache.kv_cache.unset("rebalance:a").unwrap();
+        cache.kv_cache.unset("rebalance:b").unwrap();
+    }
+
+    #[test]
+    fn it_should_jitter_ttls_of_stored_entries() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(1000))
+            .ttl_jitter(0.5)
+            .namespace("jitter")
+            .build()
+            .unwrap();
+        let keys: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        cache
+            .resolve_many(&keys, |missing| vec!["value".to_string(); missing.len()])
+            .unwrap();
+
+        let mut kv_ttls = HashSet::new();
+        for key in &keys {
+            let stored_key = format!("jitter:{}", key);
+            let (_, ttl) = cache.kv_cache.get_with_ttl(&stored_key).unwrap();
+            let ttl = ttl.unwrap();
+            assert!((Duration::from_secs(499)..=Duration::from_secs(1500)).contains(&ttl));
+            kv_ttls.insert(ttl);
+            let meta = cache.in_memory_cache.meta(&stored_key).unwrap();
+            assert!((Duration::from_secs(500)..=Duration::from_secs(1500)).contains(&meta.ttl));
+            cache.invalidate(key).unwrap();
+        }
+        assert!(kv_ttls.len() > 1);
+    }
+
+    fn quota_cache(namespace: &str, policy: QuotaPolicy) -> CacheService {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace(namespace)
+            .key_quota(KeyQuota::new(2, policy))
+            .admission_log(10)
+            .build()
+            .unwrap();
+        for key in ["a", "b", "c"] {
+            cache
+                .kv_cache
+                .unset(&format!("{}:{}", namespace, key))
+                .unwrap();
+        }
+        cache
+    }
+
+    #[test]
+    fn it_should_reject_keys_beyond_quota() {
+        let mut cache = quota_cache("quota_reject", QuotaPolicy::Reject);
+        cache
+            .set_bytes("a", b"value", Duration::from_secs(100))
+            .unwrap();
+        cache
+            .set_bytes("b", b"value", Duration::from_secs(100))
+            .unwrap();
+
+        let result = cache.set_bytes("c", b"value", Duration::from_secs(100));
+        assert!(matches!(
+            result,
+            Err(CacheServiceError::QuotaExceeded { max_keys: 2 })
+        ));
+        assert_eq!(
+            cache
+                .admission_log()
+                .unwrap()
+                .count(RejectionReason::QuotaExceeded),
+            1
+        );
+        assert_eq!(cache.update("a", "newer").unwrap(), "newer");
+        cache.invalidate("a").unwrap();
+        cache.invalidate("b").unwrap();
+    }
+
+    #[test]
+    fn it_should_evict_oldest_keys_beyond_quota() {
+        let mut cache = quota_cache("quota_evict", QuotaPolicy::EvictOldest);
+        cache
+            .set_bytes("a", b"value", Duration::from_secs(50))
+            .unwrap();
+        cache
+            .set_bytes("b", b"value", Duration::from_secs(100))
+            .unwrap();
+        cache
+            .set_bytes("c", b"value", Duration::from_secs(100))
+            .unwrap();
+
+        assert!(cache.kv_cache.get("quota_evict:a").is_none());
+        assert!(cache.kv_cache.get("quota_evict:b").is_some());
+        assert!(cache.kv_cache.get("quota_evict:c").is_some());
+        cache.invalidate("b").unwrap();
+        cache.invalidate("c").unwrap();
+    }
+
+    #[test]
+    fn it_should_alert_observer_near_quota() {
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&alerts);
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("quota_alert")
+            .key_quota(KeyQuota::new(2, QuotaPolicy::Reject))
+            .on_quota_alert(move |alert| observed.lock().unwrap().push(alert.clone()))
+            .build()
+            .unwrap();
+        cache.kv_cache.unset("quota_alert:a").unwrap();
+        cache.kv_cache.unset("quota_alert:b").unwrap();
+
+        cache
+            .set_bytes("a", b"value", Duration::from_secs(100))
+            .unwrap();
+        assert!(alerts.lock().unwrap().is_empty());
+        cache
+            .set_bytes("b", b"value", Duration::from_secs(100))
+            .unwrap();
+        assert_eq!(
+            *alerts.lock().unwrap(),
+            vec![QuotaAlert {
+                namespace: "quota_alert".to_string(),
+                keys: 2,
+                max_keys: 2,
+            }]
+        );
+        assert_eq!(cache.stats().quota_alerts, 1);
+        cache.invalidate("a").unwrap();
+        cache.invalidate("b").unwrap();
+    }
+
+    #[test]
+    fn it_should_report_key_space_by_prefix() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("analytics")
+            .key_space_analytics(1)
+            .build()
+            .unwrap();
+        cache.invalidate("user:2").unwrap();
+        cache
+            .set_bytes("user:1", b"value", Duration::from_secs(100))
+            .unwrap();
+        cache.get_bytes("user:1").unwrap();
+        cache.get_bytes("user:2").unwrap();
+
+        let report = cache.key_space_report().unwrap();
+        let user = &report.prefixes["user"];
+        assert_eq!((user.entries, user.bytes), (1, 5));
+        assert_eq!(user.hit_ratio(), Some(0.5));
+        assert_eq!(user.average_ttl(), Some(Duration::from_secs(100)));
+        cache.invalidate("user:1").unwrap();
+    }
+
+    #[test]
+    fn it_should_report_hot_keys_with_the_tier_that_served_them() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("hot_keys")
+            .hot_keys(8)
+            .build()
+            .unwrap();
+        cache.invalidate("hot").unwrap();
+        cache.invalidate("cold").unwrap();
+        cache
+            .set_bytes("hot", b"value", Duration::from_secs(100))
+            .unwrap();
+        cache.get_bytes("hot").unwrap();
+        cache.get_bytes("hot").unwrap();
+        cache.get_bytes("cold").unwrap();
+
+        let top = cache.top_keys(1).unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(
+            (top[0].key.as_str(), top[0].lookups, top[0].memory_hits),
+            ("hot", 2, 2)
+        );
+        cache.reset_stats();
+        assert!(cache.top_keys(1).unwrap().is_empty());
+        cache.invalidate("hot").unwrap();
+    }
+
+    #[test]
+    fn it_should_invalidate_keys_by_prefix_up_to_the_limit() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("prefix")
+            .build()
+            .unwrap();
+        for key in ["user:42:name", "user:42:email", "user:42:phone", "user:420"] {
+            cache
+                .set_bytes(key, b"value", Duration::from_secs(100))
+                .unwrap();
+        }
+
+        assert_eq!(cache.invalidate_prefix("user:42:", 2).unwrap(), 2);
+        assert_eq!(cache.get_bytes("user:42:name").unwrap(), None);
+        assert_eq!(cache.invalidate_prefix("user:42:", 100).unwrap(), 1);
+        assert_eq!(cache.get_bytes("user:42:phone").unwrap(), None);
+        assert!(cache.get_bytes("user:420").unwrap().is_some());
+        cache.invalidate("user:420").unwrap();
+    }
+
+    #[test]
+    fn it_should_restart_ttl_on_hit_with_sliding_expiry() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .memory_ttl(Duration::from_secs(100))
+            .kv_ttl(Duration::from_secs(600))
+            .expiry_mode(ExpiryMode::Sliding)
+            .namespace("sliding")
+            .build()
+            .unwrap();
+        cache.invalidate("key").unwrap();
+        cache
+            .set_bytes("key", b"value", Duration::from_secs(10))
+            .unwrap();
+
+        assert_eq!(cache.get_bytes("key").unwrap().unwrap(), "value");
+        let (_, ttl) = cache.kv_cache.get_with_ttl("sliding:key").unwrap();
+        assert!(ttl
+            .is_some_and(|ttl| ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600)));
+
+        cache.in_memory_cache.remove("sliding:key");
+        cache
+            .kv_cache
+            .touch_many(&["sliding:key"], Duration::from_secs(10))
+            .unwrap();
+        let values = cache
+            .resolve_many(&["key"], |_| vec!["never_see".to_string()])
+            .unwrap();
+        assert_eq!(values, vec!["value"]);
+        let (_, ttl) = cache.kv_cache.get_with_ttl("sliding:key").unwrap();
+        assert!(ttl
+            .is_some_and(|ttl| ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600)));
+        let meta = cache.in_memory_cache.meta("sliding:key").unwrap();
+        assert_eq!(meta.ttl, Duration::from_secs(100));
+        cache.invalidate("key").unwrap();
+    }
+
+    #[test]
+    fn it_should_record_key_and_value_sizes() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("sizes")
+            .build()
+            .unwrap();
+        cache.set_bytes("a", b"", Duration::from_secs(10)).unwrap();
+        cache
+            .set_bytes("bb", &[0; 100], Duration::from_secs(10))
+            .unwrap();
+        cache
+            .set_bytes("ccc", &[0; 1000], Duration::from_secs(10))
+            .unwrap();
+
+        let sizes = cache.size_distribution();
+        assert_eq!(sizes.keys.count(), 3);
+        assert_eq!(sizes.keys.max(), "sizes:ccc".len());
+        assert_eq!(sizes.values.mean(), Some(1100.0 / 3.0));
+        assert_eq!(sizes.values.percentile(0.5), Some(127));
+        assert_eq!(sizes.values.percentile(1.0), Some(1000));
+        assert_eq!(sizes.values.buckets().nth(7), Some((127, 1)));
+
+        cache.reset_stats();
+        assert_eq!(cache.size_distribution().values.percentile(0.5), None);
+    }
+
+    #[test]
+    fn it_should_apply_size_limits() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("limits")
+            .size_limits(SizeLimits {
+                max_key_len: Some(16),
+                max_value_len: Some(4),
+                oversized: OversizedPolicy::SkipMemory,
+            })
+            .build()
+            .unwrap();
+        for key in ["small", "large", "many"] {
+            cache.invalidate(key).unwrap();
+        }
+
+        assert!(matches!(
+            cache.set_bytes("much_too_long_key", b"value", Duration::from_secs(10)),
+            Err(CacheServiceError::KeyTooLong { key, len: 24, max: 16 })
+                if key == "limits:much_too_long_key"
+        ));
+        cache.resolve("small", || "tiny".to_string()).unwrap();
+        cache.resolve("large", || "value".to_string()).unwrap();
+        assert!(cache.in_memory_cache.get("limits:small").is_some());
+        assert!(cache.in_memory_cache.get("limits:large").is_none());
+        assert_eq!(cache.kv_cache.get("limits:large").unwrap(), "value");
+
+        cache.size_limits.oversized = OversizedPolicy::SkipCache;
+        cache.update("small", "larger").unwrap();
+        assert!(cache.in_memory_cache.get("limits:small").is_none());
+        assert!(cache.kv_cache.get("limits:small").is_none());
+        let values = cache
+            .resolve_many(&["many"], |_| vec!["value".to_string()])
+            .unwrap();
+        assert_eq!(values, vec!["value"]);
+        assert!(cache.kv_cache.get("limits:many").is_none());
+
+        cache.size_limits.oversized = OversizedPolicy::Error;
+        assert!(matches!(
+            cache.resolve("many", || "value".to_string()),
+            Err(CacheServiceError::ValueTooLarge { key, len: 5, max: 4 }) if key == "limits:many"
+        ));
+        cache.invalidate("large").unwrap();
+    }
+
+    #[test]
+    fn it_should_hold_lock_until_released() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("locking")
+                .build()
+                .unwrap()
+        };
+        let (mut first, mut second) = (build(), build());
+        let mut guard = first.lock("job", Duration::from_secs(10)).unwrap().unwrap();
+        guard
+            .set_bytes("progress", b"1", Duration::from_secs(10))
+            .unwrap();
+        assert!(second
+            .lock("job", Duration::from_secs(10))
+            .unwrap()
+            .is_none());
+        assert!(guard.release().unwrap());
+
+        let guard = second.lock("job", Duration::from_millis(50)).unwrap();
+        assert!(guard.is_some());
+        std::thread::sleep(Duration::from_millis(100));
+        let taken_over = first.lock("job", Duration::from_secs(10)).unwrap().unwrap();
+        assert!(!guard.unwrap().release().unwrap());
+        drop(taken_over);
+        assert!(second
+            .lock("job", Duration::from_secs(10))
+            .unwrap()
+            .is_some());
+        first.invalidate("progress").unwrap();
+    }
+
+    #[test]
+    fn it_should_elect_one_leader() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("election")
+                .build()
+                .unwrap()
+        };
+        let (mut first, mut second) = (build(), build());
+        first.invalidate("leader").unwrap();
+        let elected = [
+            first
+                .set_if_absent("leader", b"first", Duration::from_secs(10))
+                .unwrap(),
+            second
+                .set_if_absent("leader", b"second", Duration::from_secs(10))
+                .unwrap(),
+            first
+                .set_if_absent("leader", b"first", Duration::from_secs(10))
+                .unwrap(),
+        ];
+        let leader = second.get_bytes("leader").unwrap();
+        let memory_ttl = first.in_memory_cache.meta("election:leader").unwrap().ttl;
+        first.invalidate("leader").unwrap();
+
+        assert_eq!(elected, [true, false, false]);
+        assert_eq!(leader.as_deref(), Some(&b"first"[..]));
+        assert_eq!(memory_ttl, Duration::from_secs(10));
+        assert!(first
+            .set_if_absent("leader", b"first", Duration::from_secs(10))
+            .unwrap());
+        first.invalidate("leader").unwrap();
+    }
+
+    #[test]
+    fn it_should_reject_write_of_stale_version() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("cas")
+                .build()
+                .unwrap()
+        };
+        let (mut first, mut second) = (build(), build());
+        first.invalidate("cart").unwrap();
+        let created = first.set_if_version("cart", b"1", None).unwrap().unwrap();
+        assert_eq!(second.set_if_version("cart", b"x", None).unwrap(), None);
+        let (value, read) = second.get_versioned("cart").unwrap().unwrap();
+        assert_eq!((&value[..], &read), (&b"1"[..], &created));
+
+        let updated = first
+            .set_if_version("cart", b"1,2", Some(&created))
+            .unwrap();
+        let stale = second.set_if_version("cart", b"1,3", Some(&read)).unwrap();
+        let value = second.get_bytes("cart").unwrap();
+        first.invalidate("cart").unwrap();
+
+        assert!(updated.is_some_and(|version| version != created));
+        assert_eq!(stale, None);
+        assert_eq!(value.as_deref(), Some(&b"1,2"[..]));
+    }
+
+    #[test]
+    fn it_should_version_counters() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("versioned_counter")
+            .build()
+            .unwrap();
+        cache.invalidate("hits").unwrap();
+        cache.incr("hits", 5, Duration::from_secs(10)).unwrap();
+        let (value, read) = cache.get_versioned("hits").unwrap().unwrap();
+        let updated = cache.set_if_version("hits", b"reset", Some(&read)).unwrap();
+        let value_after = cache.get_bytes("hits").unwrap();
+        cache.invalidate("hits").unwrap();
+
+        assert_eq!(&value[..], b"5");
+        assert!(updated.is_some());
+        assert_eq!(value_after.as_deref(), Some(&b"reset"[..]));
+    }
+
+    #[test]
+    fn it_should_count_in_both_tiers() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("counter")
+            .build()
+            .unwrap();
+        cache.invalidate("hits").unwrap();
+        assert_eq!(cache.incr("hits", 5, Duration::from_secs(30)).unwrap(), 5);
+        assert_eq!(cache.decr("hits", 2, Duration::from_secs(300)).unwrap(), 3);
+        let memory = cache.in_memory_cache.get("counter:hits");
+        let kv = cache.kv_cache.get("counter:hits");
+        let ttl = cache.ttl("hits").unwrap().unwrap();
+        cache
+            .set_bytes("name", b"alice", Duration::from_secs(100))
+            .unwrap();
+        let not_a_counter = cache.incr("name", 1, Duration::from_secs(100));
+        cache.invalidate("hits").unwrap();
+        cache.invalidate("name").unwrap();
+
+        assert_eq!(memory.as_deref(), Some(&b"3"[..]));
+        assert_eq!(kv.as_deref(), Some(&b"3"[..]));
+        assert!(ttl <= Duration::from_secs(30));
+        assert!(matches!(
+            not_a_counter,
+            Err(CacheServiceError::KvCacheError {
+                operation: "INCRBY",
+                key: Some(key),
+                source: KvError::CommandFailed(_),
+            }) if key == "counter:name"
+        ));
+        assert!(cache
+            .decr("hits", i64::MIN, Duration::from_secs(100))
+            .is_err());
+    }
+
+    #[test]
+    fn it_should_not_lose_concurrent_increments() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("concurrent_counter")
+                .build()
+                .unwrap()
+        };
+        let mut cache = build();
+        cache.invalidate("hits").unwrap();
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let mut cache = build();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        cache.incr("hits", 1, Duration::from_secs(30)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let count = cache.incr("hits", 0, Duration::from_secs(300)).unwrap();
+        let ttl = cache.ttl("hits").unwrap().unwrap();
+        cache.invalidate("hits").unwrap();
+
+        assert_eq!(count, 1600);
+        assert!(ttl <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn it_should_extend_ttl_in_both_tiers() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("touch")
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("session", b"token", Duration::from_secs(10))
+            .unwrap();
+        let before = cache.ttl("session").unwrap().unwrap();
+        assert!(cache.touch("session", Duration::from_secs(300)).unwrap());
+        let after = cache.ttl("session").unwrap().unwrap();
+        let memory_ttl = cache.in_memory_cache.meta("touch:session").unwrap().ttl;
+        cache.invalidate("session").unwrap();
+
+        assert!(before <= Duration::from_secs(10));
+        assert!(after > Duration::from_secs(290) && after <= Duration::from_secs(300));
+        assert_eq!(memory_ttl, Duration::from_secs(300));
+        assert_eq!(cache.ttl("session").unwrap(), None);
+        assert!(!cache.touch("session", Duration::from_secs(300)).unwrap());
+        assert!(!cache.contains("session").unwrap());
+    }
+
+    #[test]
+    fn it_should_report_events_from_both_tiers() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("events")
+            .build()
+            .unwrap();
+        cache.invalidate("a").unwrap();
+        cache.invalidate("missing").unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        cache.on_event(move |event| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((event.key.to_string(), event.tier, event.kind));
+        });
+        cache
+            .set_bytes("a", b"value", Duration::from_secs(10))
+            .unwrap();
+        cache.in_memory_cache.remove("events:a");
+        cache.get_bytes("a").unwrap();
+        cache.get_bytes("missing").unwrap();
+        cache.invalidate("a").unwrap();
+
+        let events = events.lock().unwrap();
+        let event = |key: &str, tier, kind| (format!("events:{}", key), tier, kind);
+        assert_eq!(
+            *events,
+            [
+                event("a", Tier::Kv, CacheEventKind::Insert),
+                event("a", Tier::Memory, CacheEventKind::Insert),
+                event("a", Tier::Memory, CacheEventKind::Miss),
+                event("a", Tier::Kv, CacheEventKind::Hit),
+                event("a", Tier::Memory, CacheEventKind::Insert),
+                event("missing", Tier::Memory, CacheEventKind::Miss),
+                event("missing", Tier::Kv, CacheEventKind::Miss),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_keep_entries_without_ttl() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::ZERO)
+            .namespace("no-expiry")
+            .build()
+            .unwrap();
+        cache.invalidate("resolved").unwrap();
+        cache.resolve("resolved", || "value".to_string()).unwrap();
+        cache.set_bytes("forever", b"value", Duration::MAX).unwrap();
+        cache
+            .set_bytes("touched", b"value", Duration::from_secs(10))
+            .unwrap();
+        assert!(cache.touch("touched", Duration::ZERO).unwrap());
+        let ttls: Vec<_> = ["resolved", "forever", "touched"]
+            .iter()
+            .map(|key| {
+                let stored_key = format!("no-expiry:{}", key);
+                let kv_ttl = cache.kv_cache.remaining_ttl(&stored_key).unwrap();
+                let memory = cache.in_memory_cache.meta(&stored_key).unwrap();
+                cache.invalidate(key).unwrap();
+                (kv_ttl, memory.expires_at)
+            })
+            .collect();
+
+        assert!(ttls
+            .iter()
+            .all(|&ttl| ttl == (Some(Duration::MAX), u64::MAX)));
+    }
+
+    #[test]
+    fn it_should_describe_entry_in_both_tiers() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("entry-info")
+            .build()
+            .unwrap();
+        let before = SystemTimeSource.now();
+        cache
+            .set_bytes("user", b"alice", Duration::from_secs(100))
+            .unwrap();
+        cache.get_bytes("user").unwrap();
+        cache.get_bytes("user").unwrap();
+        let info = cache.entry_info("user").unwrap().unwrap();
+        cache.invalidate("user").unwrap();
+
+        assert_eq!(info.tiers(), [Tier::Memory, Tier::Kv]);
+        let memory = info.memory.as_ref().unwrap();
+        assert_eq!((memory.size, memory.reads), (5, 2));
+        assert!(memory.created >= before);
+        assert_eq!(memory.expires_at, Some(memory.created + 100));
+        assert!(memory.ttl.is_some_and(|ttl| ttl <= 100));
+        let stored = info.stored.as_ref().unwrap();
+        assert!(stored
+            .expires_at
+            .is_some_and(|at| at > before && at <= before + 101));
+        assert!(info.to_string().starts_with("tiers:memory,kv\r\n"));
+        assert!(info.to_string().contains("memory_reads:2\r\n"));
+    }
+
+    #[test]
+    fn it_should_report_writer_of_entry() {
+        let build = |instance: &str| {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace("provenance")
+                .provenance(instance, Some("checkout"))
+                .build()
+                .unwrap()
+        };
+        let mut writer = build("web-1");
+        let mut reader = build("web-2");
+        writer.invalidate("order").unwrap();
+        writer
+            .set_bytes("order", b"value", Duration::from_secs(100))
+            .unwrap();
+        let info = reader.entry_info("order").unwrap().unwrap();
+        writer.invalidate("order").unwrap();
+
+        assert_eq!(info.tiers(), [Tier::Kv]);
+        let stored = info.stored.unwrap();
+        assert_eq!(stored.size, 5);
+        assert!(stored.ttl.is_some_and(|ttl| ttl <= 100));
+        assert_eq!(
+            stored.writer,
+            Some(Provenance {
+                instance: "web-1".to_string(),
+                label: Some("checkout".to_string()),
+            })
+        );
+        assert_eq!(reader.entry_info("order").unwrap(), None);
+        assert!(matches!(
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .provenance("", None)
+                .build(),
+            Err(CacheServiceError::InvalidConfig(
+                builder::ConfigError::InvalidProvenance
+            ))
+        ));
+    }
+
+    #[test]
+    fn it_should_encrypt_values_in_redis_and_read_them_after_rotation() {
+        let build = |keys: Option<StaticKeys>| {
+            let builder = CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(10))
+                .namespace("encrypted");
+            match keys {
+                Some(keys) => builder.encryption(keys).build().unwrap(),
+                None => builder.build().unwrap(),
+            }
+        };
+        let mut writer = build(Some(StaticKeys::new("2024-01", [1; 32])));
+        let mut rotated = build(Some(
+            StaticKeys::new("2024-02", [2; 32]).with_retired("2024-01", [1; 32]),
+        ));
+        let mut keyless = build(None);
+        writer.invalidate("card").unwrap();
+        writer
+            .set_bytes("card", b"4111-1111", Duration::from_secs(10))
+            .unwrap();
+
+        let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let raw: Vec<u8> = redis::Commands::get(&mut redis, "encrypted:card").unwrap();
+        assert!(encryption::is_encrypted(&raw));
+        assert!(!raw.windows(9).any(|window| window == b"4111-1111"));
+        assert_eq!(
+            rotated.get_bytes("card").unwrap().as_deref(),
+            Some(&b"4111-1111"[..])
+        );
+        assert_eq!(keyless.get_bytes("card").unwrap(), None);
+        let kept: Vec<u8> = redis::Commands::get(&mut redis, "encrypted:card").unwrap();
+        assert_eq!(kept, raw);
+        writer.invalidate("card").unwrap();
+
+        let _: () = redis::Commands::set(&mut redis, "encrypted:moved", &raw).unwrap();
+        assert_eq!(rotated.get_bytes("moved").unwrap(), None);
+        let _: () = redis::Commands::set(&mut redis, "encrypted:plain", "planted").unwrap();
+        assert_eq!(rotated.get_bytes("plain").unwrap(), None);
+        let mut migrating = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("encrypted")
+            .encryption(StaticKeys::new("2024-01", [1; 32]))
+            .read_unencrypted(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            migrating.get_bytes("plain").unwrap().as_deref(),
+            Some(&b"planted"[..])
+        );
+        let converted: Vec<u8> = redis::Commands::get(&mut redis, "encrypted:plain").unwrap();
+        assert!(encryption::is_encrypted(&converted));
+        writer.invalidate("moved").unwrap();
+        writer.invalidate("plain").unwrap();
+
+        assert!(matches!(
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(10))
+                .encryption(StaticKeys::new("", [1; 32]))
+                .build(),
+            Err(CacheServiceError::InvalidConfig(
+                builder::ConfigError::InvalidEncryptionKeyId
+            ))
+        ));
+    }
+
+    #[test]
+    fn it_should_encrypt_counters_and_ignore_planted_integers() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(10))
+                .namespace("encrypted_counter")
+                .encryption(StaticKeys::new("2024-01", [1; 32]))
+                .build()
+                .unwrap()
+        };
+        let (mut counter, mut reader) = (build(), build());
+        counter.invalidate("visits").unwrap();
+        counter.invalidate("balance").unwrap();
+        let counted = [
+            counter.incr("visits", 2, Duration::from_secs(10)).unwrap(),
+            counter.incr("visits", 3, Duration::from_secs(10)).unwrap(),
+        ];
+
+        let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let raw: Vec<u8> = redis::Commands::get(&mut redis, "encrypted_counter:visits").unwrap();
+        let read = reader.get_bytes("visits").unwrap();
+        let _: () = redis::Commands::set(&mut redis, "encrypted_counter:balance", "1000").unwrap();
+        let planted = reader.get_bytes("balance").unwrap();
+        let recounted = reader.incr("balance", 1, Duration::from_secs(10)).unwrap();
+        counter.invalidate("visits").unwrap();
+        counter.invalidate("balance").unwrap();
+
+        assert_eq!(counted, [2, 5]);
+        assert!(encryption::is_encrypted(&raw));
+        assert_eq!(read.as_deref(), Some(&b"5"[..]));
+        assert_eq!(planted, None);
+        assert_eq!(recounted, 1);
+    }
+
+    #[test]
+    fn it_should_chain_error_sources() {
+        let err = CacheServiceError::kv("GET", "ns:key")(KvError::CommandFailed(
+            redis::RedisError::from((redis::ErrorKind::IoError, "broken pipe")),
+        ));
+        let kv_error = err.source().unwrap();
+
+        assert!(kv_error.downcast_ref::<KvError>().is_some());
+        assert!(kv_error.source().is_some());
+        assert_eq!(
+            error_chain(&err),
+            "GET \"ns:key\" failed in the KV tier: a command to the KV store failed: broken pipe- IoError"
+        );
+    }
+
+    #[test]
+    fn it_should_report_source_of_each_batch_value() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("batch_info")
+            .build()
+            .unwrap();
+        for key in ["memory", "kv", "new"] {
+            cache.invalidate(key).unwrap();
+        }
+        cache
+            .set_bytes("memory", b"m", Duration::from_secs(10))
+            .unwrap();
+        cache
+            .set_bytes("kv", b"k", Duration::from_secs(10))
+            .unwrap();
+        cache.in_memory_cache.remove("batch_info:kv");
+        cache.reset_stats();
+
+        let entries = cache
+            .resolve_many_with_info(&["memory", "kv", "new"], |missing| {
+                assert_eq!(missing, ["new"]);
+                std::thread::sleep(Duration::from_millis(20));
+                vec![Bytes::from("n")]
+            })
+            .unwrap();
+        let sources: Vec<Source> = entries.iter().map(|entry| entry.source).collect();
+        assert_eq!(sources, [Source::Memory, Source::Kv, Source::Resolver]);
+        assert_eq!(entries[2].value, "n");
+        assert!(entries[2].elapsed >= Duration::from_millis(20));
+        assert!(entries[0].elapsed < entries[2].elapsed);
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.memory_hits, stats.kv_hits, stats.kv_misses),
+            (1, 1, 1)
+        );
+        for key in ["memory", "kv", "new"] {
+            cache.invalidate(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_should_reconfigure_without_dropping_data() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("reconfigure")
+            .build()
+            .unwrap();
+        cache.invalidate("kept").unwrap();
+        cache.invalidate("new").unwrap();
+        cache.resolve("kept", || "value".to_string()).unwrap();
+
+        let invalid = cache.reconfigure(Reconfiguration {
+            kv_ttl: Some(Duration::from_secs(600)),
+            ttl_jitter: Some(1.5),
+            ..Reconfiguration::default()
+        });
+        assert!(matches!(
+            invalid,
+            Err(CacheServiceError::InvalidConfig(
+                builder::ConfigError::InvalidTtlJitter
+            ))
+        ));
+        cache
+            .reconfigure(Reconfiguration {
+                kv_ttl: Some(Duration::from_secs(600)),
+                capacity: Some(Some(2)),
+                ..Reconfiguration::default()
+            })
+            .unwrap();
+
+        let value = cache.resolve("kept", || "never_see".to_string()).unwrap();
+        assert_eq!(value, "value");
+        cache.resolve("new", || "value".to_string()).unwrap();
+        let (_, ttl) = cache.kv_cache.get_with_ttl("reconfigure:new").unwrap();
+        assert!(ttl
+            .is_some_and(|ttl| ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600)));
+        cache.invalidate("kept").unwrap();
+        cache.invalidate("new").unwrap();
+    }
+
+    #[test]
+    fn it_should_refresh_hot_key_ahead_of_expiry() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("refresh_ahead")
+            .refresh_ahead(1.0, |key| Some(Bytes::from(format!("fresh {}", key))))
+            .build()
+            .unwrap();
+        cache.invalidate("hot").unwrap();
+
+        cache.resolve("hot", || "stale".to_string()).unwrap();
+        let value = cache.resolve("hot", || "never_see".to_string()).unwrap();
+        assert_eq!(value, "stale");
+        cache.refresh_ahead.as_ref().unwrap().flush();
+
+        let value = cache.resolve("hot", || "never_see".to_string()).unwrap();
+        assert_eq!(value, "fresh hot");
+        assert_eq!(
+            cache.kv_cache.get("refresh_ahead:hot").unwrap(),
+            "fresh hot"
+        );
+        cache.invalidate("hot").unwrap();
+    }
+
+    #[test]
+    fn it_should_route_values_by_tier_hint() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("routed_blob").unwrap();
+        cache.kv_cache.unset("routed_flag").unwrap();
+
+        cache
+            .set(SetPayload {
+                key: "routed_blob",
+                value: b"blob",
+                ttl: Duration::from_secs(10),
+                tier_hint: Some(Tier::Kv),
+            })
+            .unwrap();
+        cache
+            .set(SetPayload {
+                key: "routed_flag",
+                value: b"on",
+                ttl: Duration::from_secs(10),
+                tier_hint: Some(Tier::Memory),
+            })
+            .unwrap();
+
+        assert!(cache.in_memory_cache.get("routed_blob").is_none());
+        assert_eq!(cache.kv_cache.get("routed_blob").unwrap(), "blob");
+        assert_eq!(cache.in_memory_cache.get("routed_flag").unwrap(), "on");
+        assert!(cache.kv_cache.get("routed_flag").is_none());
+        cache.kv_cache.unset("routed_blob").unwrap();
+    }
+
+    #[test]
+    fn it_should_invalidate_key_in_both_tiers() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.resolve("invkey", || "value".to_string()).unwrap();
+        cache.invalidate("invkey").unwrap();
+
+        assert!(cache.in_memory_cache.get("invkey").is_none());
+        assert!(cache.kv_cache.get("invkey").is_none());
+    }
+
+    #[test]
+    fn it_should_skip_redis_for_keys_missing_from_directory() {
+        let directory_cache = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(10))
+                .namespace("directory")
+                .key_directory("test-directory", Duration::from_secs(60))
+                .build()
+                .unwrap()
+        };
+        let mut writer = directory_cache();
+        writer.invalidate("listed").unwrap();
+        writer
+            .set_bytes("listed", b"value", Duration::from_secs(10))
+            .unwrap();
+        let mut reader = directory_cache();
+
+        assert_eq!(
+            reader.get_bytes("listed").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        assert_eq!(reader.get_bytes("unlisted").unwrap(), None);
+        assert_eq!(reader.stats().directory_skips, 1);
+        assert_eq!(reader.stats().kv_hits, 1);
+
+        writer.invalidate("listed").unwrap();
+        let mut reader = directory_cache();
+        assert_eq!(reader.get_bytes("listed").unwrap(), None);
+        assert_eq!(reader.stats().directory_skips, 1);
+    }
+
+    #[test]
+    fn it_should_skip_redis_for_keys_the_negative_lookup_filter_rules_out() {
+        let filtered_cache = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(10))
+                .namespace("bloom")
+                .negative_lookup_filter(0.01, Duration::from_secs(60))
+                .build()
+                .unwrap()
+        };
+        let mut writer = filtered_cache();
+        writer.invalidate("written").unwrap();
+        writer.invalidate("never-written").unwrap();
+        writer
+            .set_bytes("written", b"value", Duration::from_secs(10))
+            .unwrap();
+        let mut reader = filtered_cache();
+
+        assert_eq!(
+            reader.get_bytes("written").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        assert_eq!(reader.get_bytes("never-written").unwrap(), None);
+        assert_eq!(reader.stats().filter_skips, 1);
+        assert_eq!(reader.stats().kv_hits, 1);
+        writer.invalidate("written").unwrap();
+    }
+
+    #[test]
+    fn it_should_restore_memory_tier_from_snapshot() {
+        let path = std::env::temp_dir().join(format!("rcache-{}.snapshot", std::process::id()));
+        let mut cache =
+            CacheService::new(Duration::from_secs(100), "redis://127.0.0.1:6379").unwrap();
+        cache
+            .in_memory_cache
+            .set(SetPayload {
+                key: "snapshot",
+                value: b"value",
+                ttl: Duration::from_secs(100),
+                tier_hint: None,
+            })
+            .unwrap();
+        assert_eq!(cache.save_memory_snapshot(&path).unwrap(), 1);
+
+        let mut restarted =
+            CacheService::new(Duration::from_secs(100), "redis://127.0.0.1:6379").unwrap();
+        assert_eq!(restarted.load_memory_snapshot(&path).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            restarted.in_memory_cache.get("snapshot").as_deref(),
+            Some(&b"value"[..])
+        );
+        assert!(
+            restarted.in_memory_cache.meta("snapshot").unwrap().ttl <= Duration::from_secs(100)
+        );
+    }
+
+    #[test]
+    fn it_should_evict_memory_entry_invalidated_by_peer() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .invalidation_channel("test-invalidation")
+            .build()
+            .unwrap();
+        let mut peer = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .invalidation_channel("test-invalidation")
+            .build()
+            .unwrap();
+        cache
+            .in_memory_cache
+            .set(SetPayload {
+                key: "peerkey",
+                value: b"stale",
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        peer.invalidate("peerkey").unwrap();
+        peer.set_bytes("own", b"fresh", Duration::from_secs(10))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let own = peer.in_memory_cache.get("own");
+        peer.invalidate("own").unwrap();
+
+        assert!(cache.in_memory_cache.get("peerkey").is_none());
+        assert_eq!(own.as_deref(), Some(&b"fresh"[..]));
+    }
+
+    #[test]
+    fn it_should_evict_memory_entry_deleted_in_redis_by_another_writer() {
+        let mut con = redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("notify-keyspace-events")
+            .arg("Egxe")
+            .query(&mut con)
+            .unwrap();
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("keyspace")
+            .keyspace_notifications()
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("external", b"value", Duration::from_secs(10))
+            .unwrap();
+        let _: () = redis::cmd("DEL")
+            .arg("keyspace:external")
+            .query(&mut con)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+
+        assert!(cache.in_memory_cache.get("keyspace:external").is_none());
+    }
+
+    #[test]
+    fn it_should_evict_memory_entry_on_client_tracking_invalidation() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("tracking")
+            .client_tracking()
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("tracked", b"value", Duration::from_secs(10))
+            .unwrap();
+        let mut con = redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        // What Redis sends the redirect target when another client changes the key.
+        let _: () = redis::cmd("PUBLISH")
+            .arg(client_tracking::INVALIDATE_CHANNEL)
+            .arg("tracking:tracked")
+            .query(&mut con)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+
+        assert!(cache.in_memory_cache.get("tracking:tracked").is_none());
+        cache.invalidate("tracked").unwrap();
+    }
+
+    #[test]
+    fn it_should_not_cache_a_value_resolved_while_the_key_was_invalidated() {
+        let build = || {
+            CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(10))
+                .namespace("tombstones")
+                .tombstones(Duration::from_secs(10))
+                .build()
+                .unwrap()
+        };
+        let mut cache = build();
+        let mut peer = build();
+        cache.invalidate("key").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let value = cache
+            .resolve("key", || {
+                peer.invalidate("key").unwrap();
+                "stale".to_string()
+            })
+            .unwrap();
+
+        assert_eq!(value, "stale");
+        assert_eq!(cache.stats().dropped_fills, 1);
+        assert!(cache.in_memory_cache.get("tombstones:key").is_none());
+        assert_eq!(cache.kv_cache.get("tombstones:key"), None);
+    }
+
+    #[test]
+    fn it_should_compare_sampled_hits_with_the_resolver_in_shadow_mode() {
+        let divergences = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&divergences);
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("shadow")
+            .shadow_mode(1.0)
+            .on_shadow_divergence(move |divergence| {
+                observed.lock().unwrap().push(divergence.clone())
+            })
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("key", b"cached", Duration::from_secs(10))
+            .unwrap();
+
+        assert_eq!(
+            cache.resolve("key", || "fresh".to_string()).unwrap(),
+            "cached"
+        );
+        assert_eq!(
+            cache.resolve("key", || "cached".to_string()).unwrap(),
+            "cached"
+        );
+        cache.invalidate("key").unwrap();
+
+        assert_eq!(
+            (
+                cache.stats().shadow_checks,
+                cache.stats().shadow_divergences
+            ),
+            (2, 1)
+        );
+        let divergences = divergences.lock().unwrap();
+        assert_eq!(
+            (divergences[0].key.as_str(), &divergences[0].resolved[..]),
+            ("key", &b"fresh"[..])
+        );
+        assert_eq!(divergences[0].tier, Tier::Memory);
+    }
+
+    #[test]
+    fn it_should_bypass_both_tiers_while_passing_through() {
+        let switches = NamespacePassThrough::new();
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("pass_through")
+            .namespace_pass_through(switches.clone())
+            .build()
+            .unwrap();
+        cache
+            .set_bytes("key", b"cached", Duration::from_secs(10))
+            .unwrap();
+
+        cache.pass_through().set(true);
+        assert_eq!(
+            cache.resolve("key", || "fresh".to_string()).unwrap(),
+            "fresh"
+        );
+        cache.pass_through().set(false);
+        switches.set("pass_through", true);
+        assert_eq!(
+            cache
+                .resolve_many(&["key", "other"], |keys| keys
+                    .iter()
+                    .map(|key| key.to_string())
+                    .collect())
+                .unwrap(),
+            vec!["key", "other"]
+        );
+        switches.set("pass_through", false);
+        assert_eq!(
+            cache.resolve("key", || "fresh".to_string()).unwrap(),
+            "cached"
+        );
+        cache.invalidate("key").unwrap();
+
+        assert_eq!(cache.stats().pass_throughs, 3);
+        assert_eq!(cache.stats().memory_hits, 1);
+    }
+
+    #[test]
+    fn it_should_only_cache_keys_within_the_rollout() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("rollout")
+            .rollout(50)
+            .build()
+            .unwrap();
+        let keys: Vec<String> = (0..20).map(|i| format!("key:{}", i)).collect();
+        let inside = keys
+            .iter()
+            .find(|key| rollout::in_rollout(key, 50))
+            .unwrap();
+        let outside = keys
+            .iter()
+            .find(|key| !rollout::in_rollout(key, 50))
+            .unwrap();
+
+        for _ in 0..2 {
+            cache.resolve(inside, || "value".to_string()).unwrap();
+            cache.resolve(outside, || "value".to_string()).unwrap();
+        }
+        let resolved = cache
+            .resolve_many(&[inside, outside], |keys| {
+                keys.iter().map(|key| key.to_string()).collect()
+            })
+            .unwrap();
+        assert_eq!(resolved, vec!["value", outside.as_str()]);
+        cache.invalidate(inside).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.memory_hits, stats.resolver_calls), (2, 4));
+        assert_eq!(stats.outside_rollout, 3);
+    }
+
+    #[test]
+    fn it_should_serve_expired_values_when_the_resolver_fails() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_millis(100))
+            .namespace("stale")
+            .serve_stale(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        cache.resolve("key", || "old".to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+
+        let resolved = cache
+            .resolve_or_stale("key", || Err::<String, _>("the backend is down"))
+            .unwrap();
+        assert_eq!(
+            resolved,
+            MaybeStale {
+                value: "old".to_string(),
+                stale: true
+            }
+        );
+        let resolved = cache
+            .resolve_or_stale("other", || Ok::<_, io::Error>("new".to_string()))
+            .unwrap();
+        assert!(!resolved.stale);
+        cache.invalidate("key").unwrap();
+        cache.invalidate("other").unwrap();
+        assert!(matches!(
+            cache.resolve_or_stale("key", || Err::<String, _>("the backend is down")),
+            Err(CacheServiceError::Resolver(_))
+        ));
+        assert_eq!(cache.stats().stale_served, 1);
+    }
+
+    #[test]
+    fn it_should_stop_waiting_for_slow_resolvers() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_millis(100))
+            .namespace("resolver_timeout")
+            .serve_stale(Duration::from_secs(10))
+            .resolver_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let slow = || {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok::<_, io::Error>("new".to_string())
+        };
+
+        assert!(matches!(
+            cache.resolve_with_timeout("key", None, slow),
+            Err(CacheServiceError::Timeout(timeout)) if timeout == Duration::from_millis(50)
+        ));
+        cache.resolve("key", || "old".to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        let resolved = cache
+            .resolve_with_timeout("key", Some(Duration::from_millis(20)), slow)
+            .unwrap();
+        assert_eq!((resolved.value.as_str(), resolved.stale), ("old", true));
+        cache.invalidate("key").unwrap();
+
+        assert_eq!(cache.stats().resolver_timeouts, 2);
+    }
+
+    #[test]
+    fn it_should_return_resolver_panics_as_errors() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("resolver_panic")
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            cache.resolve("key", || panic!("boom")),
+            Err(CacheServiceError::ResolverPanicked { message: Some(message) }) if message == "boom"
+        ));
+        assert!(matches!(
+            cache.resolve_many(&["key", "other"], |_| panic!("boom")),
+            Err(CacheServiceError::ResolverPanicked { .. })
+        ));
+        assert_eq!(
+            cache.resolve("key", || "value".to_string()).unwrap(),
+            "value"
+        );
+        assert_eq!(
+            cache.get_bytes("key").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        cache.invalidate("key").unwrap();
+        assert_eq!(cache.stats().errors, 2);
+    }
+
+    #[test]
+    fn it_should_log_rejected_admissions() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .admission_log(10)
+            .build()
+            .unwrap();
+        let result = cache.resolve("", || "value".to_string());
+        cache.kv_cache.unset("").unwrap();
+        let admission_log = cache.admission_log().unwrap();
+        let entries: Vec<&Rejection> = admission_log.entries().collect();
+
+        assert!(result.is_err());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tier, Tier::Memory);
+        assert_eq!(entries[0].reason, RejectionReason::EmptyKey);
+        assert_eq!(admission_log.count(RejectionReason::EmptyKey), 1);
+    }
+
+    #[test]
+    fn it_should_log_skipped_and_denied_admissions() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(10))
+            .namespace("admission")
+            .capacity(3)
+            .eviction_policy(EvictionPolicy::TinyLfu)
+            .admission_log(10)
+            .build()
+            .unwrap();
+        cache.pass_through().set(true);
+        cache.resolve("skipped", || "value".to_string()).unwrap();
+        cache.pass_through().set(false);
+        for _ in 0..5 {
+            cache.resolve("hot", || "value".to_string()).unwrap();
+        }
+        for key in ["once-1", "once-2", "once-3"] {
+            cache.resolve(key, || "value".to_string()).unwrap();
+        }
+        for key in ["hot", "once-1", "once-2", "once-3"] {
+            cache.invalidate(key).unwrap();
+        }
+        let admission_log = cache.admission_log().unwrap();
+
+        assert_eq!(admission_log.count(RejectionReason::PassThrough), 2);
+        assert!(admission_log
+            .entries()
+            .any(|entry| entry.key == "admission:skipped"));
+        assert_eq!(admission_log.count(RejectionReason::AdmissionDenied), 1);
+        assert!(admission_log.entries().any(|entry| {
+            entry.key == "admission:once-1" && entry.reason == RejectionReason::AdmissionDenied
+        }));
+    }
+
+    #[test]
+    fn it_should_replay_journal_against_fresh_cache() {
+        let build = |namespace: &str| {
+            let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+                .ttl(Duration::from_secs(100))
+                .namespace(namespace)
+                .journal(Journal::in_memory(100))
+                .build()
+                .unwrap();
+            cache.invalidate("a").unwrap();
+            cache.invalidate("b").unwrap();
+            cache
+        };
+        let mut recorded = build("journal-recorded");
+        recorded
+            .set_bytes("a", b"1", Duration::from_secs(100))
+            .unwrap();
+        recorded.get_bytes("a").unwrap();
+        recorded.resolve("b", || "2".to_string()).unwrap();
+        recorded.update("b", "3").unwrap();
+        recorded.invalidate("a").unwrap();
+        recorded.get_bytes("a").unwrap();
+        let entries: Vec<JournalEntry> = recorded.journal().unwrap().entries().cloned().collect();
+
+        let mut replayed = build("journal-replayed");
+        let clean = journal::replay(&mut replayed, &entries[2..]).unwrap();
+        let diverged = journal::replay(&mut replayed, &entries[3..4]).unwrap();
+        recorded.invalidate("b").unwrap();
+        replayed.invalidate("b").unwrap();
+
+        assert_eq!(entries.len(), 8);
+        assert_eq!(
+            entries[4].operation,
+            Operation::Resolve {
+                key: "b".to_string(),
+                value: Bytes::from("2"),
+            }
+        );
+        assert!(clean.is_empty());
+        assert_eq!(
+            diverged,
+            [Divergence {
+                seq: 3,
+                key: "a".to_string(),
+                recorded: Some(Bytes::from("1")),
+                replayed: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn it_should_use_separate_ttls_per_tier() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .memory_ttl(Duration::from_secs(30))
+            .kv_ttl(Duration::from_secs(600))
+            .build()
+            .unwrap();
+        cache.kv_cache.unset("tierttl").unwrap();
+        cache.resolve("tierttl", || "value".to_string()).unwrap();
+        let memory_expires_at = cache.in_memory_cache.meta("tierttl").unwrap().expires_at;
+        let (_, kv_ttl) = cache.kv_cache.get_with_ttl("tierttl").unwrap();
+        cache.kv_cache.unset("tierttl").unwrap();
+        let now = SystemTimeSource.now_millis();
+
+        assert!(memory_expires_at <= now + 30_000);
+        assert!(memory_expires_at >= now + 29_000);
+        assert!(kv_ttl
+            .is_some_and(|ttl| ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn it_should_respect_remaining_kv_ttl_when_promoting() {
+        let mut cache = CacheService::builder("redis://127.0.0.1:6379")
+            .memory_ttl(Duration::from_secs(30))
+            .kv_ttl(Duration::from_secs(600))
+            .build()
+            .unwrap();
+        cache
+            .kv_cache
+            .overwrite(SetPayload {
+                key: "promoted",
+                value: b"value",
+                ttl: Duration::from_secs(5),
+                tier_hint: None,
+            })
+            .unwrap();
+        let value = cache
+            .resolve("promoted", || "never_see".to_string())
+            .unwrap();
+        let memory_expires_at = cache.in_memory_cache.meta("promoted").unwrap().expires_at;
+        cache.kv_cache.unset("promoted").unwrap();
+
+        assert_eq!(value, "value");
+        assert!(memory_expires_at <= SystemTimeSource.now_millis() + 5_000);
+    }
+
+    #[test]
+    fn it_should_run_idempotent_op_once() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("idempotency:op1").unwrap();
+        let first = cache
+            .idempotent("op1", Duration::from_secs(10), || "first".to_string())
+            .unwrap();
+        let second = cache
+            .idempotent("op1", Duration::from_secs(10), || "second".to_string())
+            .unwrap();
+        cache.kv_cache.unset("idempotency:op1").unwrap();
+
+        assert_eq!(first, "first");
+        assert_eq!(second, "first");
+    }
+
+    #[test]
+    fn it_should_return_stored_result_from_other_instance() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        let mut other =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("idempotency:op2").unwrap();
+        cache
+            .idempotent("op2", Duration::from_secs(10), || "first".to_string())
+            .unwrap();
+        let second = other
+            .idempotent("op2", Duration::from_secs(10), || "second".to_string())
+            .unwrap();
+        cache.kv_cache.unset("idempotency:op2").unwrap();
+
+        assert_eq!(second, "first");
+    }
+
+    #[test]
+    fn it_should_release_idempotency_token_when_op_panics() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("idempotency:op4").unwrap();
+        let panicked = cache.idempotent("op4", Duration::from_secs(10), || panic!("op failed"));
+        let retried = cache.idempotent("op4", Duration::from_secs(10), || "retried".to_string());
+        cache.kv_cache.unset("idempotency:op4").unwrap();
+
+        assert!(matches!(
+            panicked,
+            Err(CacheServiceError::ResolverPanicked { .. })
+        ));
+        assert_eq!(retried.unwrap(), "retried");
+    }
+
+    #[test]
+    fn it_should_report_idempotent_op_in_progress() {
+        let mut cache =
+            CacheService::new(Duration::from_secs(10), "redis://127.0.0.1:6379").unwrap();
+        cache.kv_cache.unset("idempotency:op3").unwrap();
+        cache
+            .kv_cache
+            .set_nx(SetPayload {
+                key: "idempotency:op3",
+                value: IDEMPOTENCY_PENDING.as_bytes(),
+                ttl: Duration::from_secs(10),
+                tier_hint: None,
+            })
+            .unwrap();
+        let result = cache.idempotent("op3", Duration::from_secs(10), || "never_see".to_string());
+        cache.kv_cache.unset("idempotency:op3").unwrap();
+
+        assert!(matches!(
+            result,
+            Err(CacheServiceError::IdempotencyInProgress)
+        ));
     }
 }
@@ -0,0 +1,83 @@
+/// What happens to a value larger than `SizeLimits::max_value_len`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedPolicy {
+    /// Fail the write with `CacheServiceError::ValueTooLarge`.
+    #[default]
+    Error,
+    /// Keep the value in Redis only.
+    SkipMemory,
+    /// Return the value without storing it in either tier.
+    SkipCache,
+}
+
+/// Upper bounds on the size of cached entries. Keys are measured as stored, namespace included,
+/// and a key over the limit always fails the write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SizeLimits {
+    pub max_key_len: Option<usize>,
+    pub max_value_len: Option<usize>,
+    pub oversized: OversizedPolicy,
+}
+
+/// Tiers an entry may be stored in under the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Both,
+    KvOnly,
+    Nowhere,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LimitExceeded {
+    Key { len: usize, max: usize },
+    Value { len: usize, max: usize },
+}
+
+impl SizeLimits {
+    pub(crate) fn admit(&self, key: &str, value: &[u8]) -> Result<Admission, LimitExceeded> {
+        if let Some(max) = self.max_key_len.filter(|&max| key.len() > max) {
+            return Err(LimitExceeded::Key {
+                len: key.len(),
+                max,
+            });
+        }
+        let Some(max) = self.max_value_len.filter(|&max| value.len() > max) else {
+            return Ok(Admission::Both);
+        };
+        match self.oversized {
+            OversizedPolicy::Error => Err(LimitExceeded::Value {
+                len: value.len(),
+                max,
+            }),
+            OversizedPolicy::SkipMemory => Ok(Admission::KvOnly),
+            OversizedPolicy::SkipCache => Ok(Admission::Nowhere),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_apply_policy_to_oversized_values() {
+        let mut limits = SizeLimits {
+            max_key_len: Some(3),
+            max_value_len: Some(4),
+            oversized: OversizedPolicy::Error,
+        };
+        assert_eq!(limits.admit("key", b"four"), Ok(Admission::Both));
+        assert_eq!(
+            limits.admit("long", b""),
+            Err(LimitExceeded::Key { len: 4, max: 3 })
+        );
+        assert_eq!(
+            limits.admit("key", b"large"),
+            Err(LimitExceeded::Value { len: 5, max: 4 })
+        );
+        limits.oversized = OversizedPolicy::SkipMemory;
+        assert_eq!(limits.admit("key", b"large"), Ok(Admission::KvOnly));
+        limits.oversized = OversizedPolicy::SkipCache;
+        assert_eq!(limits.admit("key", b"large"), Ok(Admission::Nowhere));
+    }
+}
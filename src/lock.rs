@@ -0,0 +1,60 @@
+use std::ops::{Deref, DerefMut};
+
+use bytes::Bytes;
+use redis::ConnectionLike;
+
+use crate::kv_cache::KvConnection;
+use crate::{CacheService, CacheServiceError};
+
+/// A lock held in Redis, taken with `CacheService::lock`. The cache stays usable through the
+/// guard. Dropping it releases the lock, ignoring errors; call `release` to see them.
+pub struct LockGuard<'a, C: ConnectionLike = KvConnection> {
+    cache: &'a mut CacheService<C>,
+    /// The stored key, namespace included.
+    key: String,
+    value: Bytes,
+    released: bool,
+}
+
+impl<'a, C: ConnectionLike> LockGuard<'a, C> {
+    pub(crate) fn new(cache: &'a mut CacheService<C>, key: String, value: Bytes) -> Self {
+        LockGuard {
+            cache,
+            key,
+            value,
+            released: false,
+        }
+    }
+
+    /// Releases the lock. Returns false if it had expired, in which case someone else may
+    /// have held it meanwhile.
+    pub fn release(mut self) -> Result<bool, CacheServiceError> {
+        self.released = true;
+        self.cache
+            .kv_cache
+            .unlock(&self.key, &self.value)
+            .map_err(CacheServiceError::kv("EVALSHA", &self.key))
+    }
+}
+
+impl<C: ConnectionLike> Deref for LockGuard<'_, C> {
+    type Target = CacheService<C>;
+
+    fn deref(&self) -> &Self::Target {
+        self.cache
+    }
+}
+
+impl<C: ConnectionLike> DerefMut for LockGuard<'_, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.cache
+    }
+}
+
+impl<C: ConnectionLike> Drop for LockGuard<'_, C> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.cache.kv_cache.unlock(&self.key, &self.value);
+        }
+    }
+}
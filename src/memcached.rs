@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use redis::{ConnectionLike, ErrorKind, RedisError, RedisResult, Value};
+
+use crate::emulated::{self, malformed, number, unsupported};
+use crate::in_memory_cache::{SystemTimeSource, TimeSource};
+
+pub const URL_SCHEME: &str = "memcache://";
+
+const DEFAULT_PORT: u16 = 11211;
+
+/// Memcached reads expiry times above 30 days as Unix timestamps.
+const MAX_RELATIVE_EXPIRY: u64 = 60 * 60 * 24 * 30;
+
+/// Speaks the memcached text protocol behind `ConnectionLike`, so a `KvCache` can keep its
+/// values in memcached instead of Redis. Only the commands of the plain get, set and unset
+/// paths are understood: GET, MGET, SET with EX, PX, NX or XX, SETEX, PSETEX, DEL, EXISTS,
+/// EXPIRE, PEXPIRE, PERSIST, TTL, PTTL and PING, also inside pipelines and MULTI blocks.
+/// Everything else fails, including the scripts behind merge conflict policies and
+/// idempotency, SCAN and pub/sub. Memcached expires in whole seconds, so millisecond TTLs are rounded up.
+pub struct MemcachedConnection<S: Read + Write = TcpStream> {
+    stream: BufReader<S>,
+    open: bool,
+}
+
+impl MemcachedConnection {
+    /// Connects to `memcache://host[:port]`.
+    pub fn open(url: &str) -> RedisResult<MemcachedConnection> {
+        let address = url.strip_prefix(URL_SCHEME).ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "Not a memcached URL",
+                url.to_string(),
+            ))
+        })?;
+        let address = address.trim_end_matches('/');
+        let stream = if address.contains(':') {
+            TcpStream::connect(address)?
+        } else {
+            TcpStream::connect((address, DEFAULT_PORT))?
+        };
+        Ok(MemcachedConnection::from_stream(stream))
+    }
+}
+
+impl<S: Read + Write> MemcachedConnection<S> {
+    pub fn from_stream(stream: S) -> MemcachedConnection<S> {
+        MemcachedConnection {
+            stream: BufReader::new(stream),
+            open: true,
+        }
+    }
+
+    fn execute(&mut self, command: &[Vec<u8>]) -> RedisResult<Value> {
+        let (name, args) = command.split_first().ok_or_else(malformed)?;
+        let name = String::from_utf8_lossy(name).to_uppercase();
+        match (name.as_str(), args) {
+            ("GET", [key]) => Ok(self.get(&[key])?.pop().unwrap_or(Value::Nil)),
+            ("MGET", keys) if !keys.is_empty() => {
+                let keys: Vec<&Vec<u8>> = keys.iter().collect();
+                Ok(Value::Bulk(self.get(&keys)?))
+            }
+            ("SET", [key, value, options @ ..]) => self.set(key, value, options),
+            ("SETEX", [key, ttl, value]) => self.store("set", key, value, expiry(number(ttl)?)),
+            ("PSETEX", [key, ttl, value]) => {
+                self.store("set", key, value, expiry(number(ttl)?.div_ceil(1000)))
+            }
+            ("DEL", keys) if !keys.is_empty() => {
+                let mut deleted = 0;
+                for key in keys {
+                    self.send(&[b"delete ", &key[..], b"\r\n"])?;
+                    deleted += i64::from(self.reply()? == "DELETED");
+                }
+                Ok(Value::Int(deleted))
+            }
+            ("EXISTS", keys) if !keys.is_empty() => {
+                let keys: Vec<&Vec<u8>> = keys.iter().collect();
+                let values = self.get(&keys)?;
+                let found = values.iter().filter(|value| **value != Value::Nil).count();
+                Ok(Value::Int(found as i64))
+            }
+            ("EXPIRE", [key, ttl]) => self.touch(key, expiry(number(ttl)?)),
+            ("PEXPIRE", [key, ttl]) => self.touch(key, expiry(number(ttl)?.div_ceil(1000))),
+            ("PERSIST", [key]) => self.touch(key, 0),
+            ("TTL", [key]) => self.ttl(key),
+            ("PTTL", [key]) => match self.ttl(key)? {
+                Value::Int(ttl) if ttl >= 0 => Ok(Value::Int(ttl.saturating_mul(1000))),
+                ttl => Ok(ttl),
+            },
+            ("PING", []) => {
+                self.send(&[b"version\r\n"])?;
+                self.reply()?;
+                Ok(Value::Status("PONG".to_string()))
+            }
+            _ => Err(unsupported("Command not supported by memcached", name)),
+        }
+    }
+
+    fn touch(&mut self, key: &[u8], exptime: u64) -> RedisResult<Value> {
+        let exptime = exptime.to_string();
+        self.send(&[b"touch ", key, b" ", exptime.as_bytes(), b"\r\n"])?;
+        Ok(Value::Int(i64::from(self.reply()? == "TOUCHED")))
+    }
+
+    /// Seconds `key` has left as TTL replies them: -1 without an expiry, -2 if missing.
+    fn ttl(&mut self, key: &[u8]) -> RedisResult<Value> {
+        self.send(&[b"mg ", key, b" t\r\n"])?;
+        let reply = self.reply()?;
+        let ttl = reply
+            .strip_prefix("HD t")
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(-2);
+        Ok(Value::Int(ttl))
+    }
+
+    /// Values of `keys` in order, `Nil` for the missing ones.
+    fn get(&mut self, keys: &[&Vec<u8>]) -> RedisResult<Vec<Value>> {
+        let mut request: Vec<&[u8]> = vec![b"get"];
+        for key in keys {
+            request.extend([&b" "[..], key]);
+        }
+        request.push(b"\r\n");
+        self.send(&request)?;
+
+        let mut found = HashMap::new();
+        loop {
+            let reply = self.reply()?;
+            if reply == "END" {
+                break;
+            }
+            let mut header = reply.split(' ');
+            let (Some("VALUE"), Some(key), Some(_flags), Some(len)) =
+                (header.next(), header.next(), header.next(), header.next())
+            else {
+                return Err(unexpected(reply));
+            };
+            let len: usize = len.parse().map_err(|_| unexpected(reply.clone()))?;
+            let mut data = vec![0; len + 2];
+            self.read_exact(&mut data)?;
+            data.truncate(len);
+            found.insert(key.as_bytes().to_vec(), data);
+        }
+        Ok(keys
+            .iter()
+            .map(|key| found.get(*key).cloned().map_or(Value::Nil, Value::Data))
+            .collect())
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> RedisResult<Value> {
+        let mut verb = "set";
+        let mut exptime = 0;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                "EX" => exptime = expiry(number(options.next().ok_or_else(malformed)?)?),
+                "PX" => {
+                    let millis = number(options.next().ok_or_else(malformed)?)?;
+                    exptime = expiry(millis.div_ceil(1000));
+                }
+                "NX" => verb = "add",
+                "XX" => verb = "replace",
+                option => {
+                    return Err(unsupported(
+                        "SET option not supported by memcached",
+                        option.to_string(),
+                    ))
+                }
+            }
+        }
+        self.store(verb, key, value, exptime)
+    }
+
+    /// `Okay` once stored, `Nil` if the condition of `add` or `replace` did not hold.
+    fn store(&mut self, verb: &str, key: &[u8], value: &[u8], exptime: u64) -> RedisResult<Value> {
+        let header = format!(" 0 {} {}\r\n", exptime, value.len());
+        self.send(&[
+            verb.as_bytes(),
+            b" ",
+            key,
+            header.as_bytes(),
+            value,
+            b"\r\n",
+        ])?;
+        match self.reply()?.as_str() {
+            "STORED" => Ok(Value::Okay),
+            "NOT_STORED" => Ok(Value::Nil),
+            reply => Err(unexpected(reply.to_string())),
+        }
+    }
+
+    fn send(&mut self, parts: &[&[u8]]) -> RedisResult<()> {
+        let stream = self.stream.get_mut();
+        let sent = parts
+            .iter()
+            .try_for_each(|part| stream.write_all(part))
+            .and_then(|_| stream.flush());
+        self.open &= sent.is_ok();
+        Ok(sent?)
+    }
+
+    /// Next reply line without its line ending. Error replies become errors.
+    fn reply(&mut self) -> RedisResult<String> {
+        let mut line = String::new();
+        let read = self.stream.read_line(&mut line);
+        if matches!(read, Ok(0) | Err(_)) {
+            self.open = false;
+        }
+        if read? == 0 {
+            return Err(RedisError::from((
+                ErrorKind::IoError,
+                "Memcached closed the connection",
+            )));
+        }
+        let line = line.trim_end().to_string();
+        if line == "ERROR" || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+            return Err(RedisError::from((
+                ErrorKind::ResponseError,
+                "Memcached error",
+                line,
+            )));
+        }
+        Ok(line)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> RedisResult<()> {
+        let read = self.stream.read_exact(buf);
+        self.open &= read.is_ok();
+        Ok(read?)
+    }
+}
+
+/// Memcached expiry for a TTL of `secs` seconds.
+fn expiry(secs: u64) -> u64 {
+    if secs > MAX_RELATIVE_EXPIRY {
+        SystemTimeSource.now() + secs
+    } else {
+        secs
+    }
+}
+
+fn unexpected(reply: String) -> RedisError {
+    RedisError::from((
+        ErrorKind::ResponseError,
+        "Unexpected memcached reply",
+        reply,
+    ))
+}
+
+impl<S: Read + Write> ConnectionLike for MemcachedConnection<S> {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        emulated::run_command(cmd, |command| self.execute(command))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        emulated::run_pipeline(cmd, offset, count, |command| self.execute(command))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.execute(&[b"PING".to_vec()]).is_ok()
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Answers with canned replies and records the requests.
+    struct Scripted {
+        replies: Cursor<Vec<u8>>,
+        requests: Vec<u8>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.requests.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connection(replies: &str) -> MemcachedConnection<Scripted> {
+        MemcachedConnection::from_stream(Scripted {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            requests: Vec::new(),
+        })
+    }
+
+    fn requests(con: MemcachedConnection<Scripted>) -> String {
+        String::from_utf8(con.stream.into_inner().requests).unwrap()
+    }
+
+    #[test]
+    fn it_should_translate_commands_to_memcached() {
+        let mut con = connection("STORED\r\nNOT_STORED\r\nVALUE a 0 2\r\nv1\r\nEND\r\nDELETED\r\n");
+
+        redis::cmd("SET")
+            .arg("a")
+            .arg("v1")
+            .arg("EX")
+            .arg(10)
+            .query::<()>(&mut con)
+            .unwrap();
+        let added: Option<String> = redis::cmd("SET")
+            .arg("a")
+            .arg("v2")
+            .arg("NX")
+            .arg("PX")
+            .arg(1500)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(added, None);
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg("a")
+            .arg("b")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(values, vec![Some("v1".to_string()), None]);
+        let deleted: i64 = redis::cmd("DEL").arg("a").query(&mut con).unwrap();
+        assert_eq!(deleted, 1);
+
+        assert_eq!(
+            requests(con),
+            "set a 0 10 2\r\nv1\r\nadd a 0 2 2\r\nv2\r\nget a b\r\ndelete a\r\n"
+        );
+    }
+
+    #[test]
+    fn it_should_run_pipelines_and_reject_unknown_commands() {
+        let mut con = connection("VALUE a 0 1\r\nx\r\nEND\r\nHD t42\r\nTOUCHED\r\n");
+
+        let (value, ttl): (Option<String>, i64) = redis::pipe()
+            .cmd("MGET")
+            .arg("a")
+            .ttl("a")
+            .expire("a", 60)
+            .ignore()
+            .query::<(Vec<Option<String>>, i64)>(&mut con)
+            .map(|(mut values, ttl)| (values.pop().flatten(), ttl))
+            .unwrap();
+        assert_eq!((value.as_deref(), ttl), (Some("x"), 42));
+        let err = redis::cmd("PUBLISH")
+            .arg("channel")
+            .arg("message")
+            .query::<()>(&mut con)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+
+        assert_eq!(requests(con), "get a\r\nmg a t\r\ntouch a 60\r\n");
+    }
+}
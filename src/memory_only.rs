@@ -0,0 +1,71 @@
+use redis::{ConnectionLike, RedisResult, Value};
+
+use crate::emulated::{self, malformed, unsupported};
+
+pub const URL_SCHEME: &str = "memory://";
+
+/// Stands in for the KV tier of a memory-only `CacheService`, from
+/// `CacheService::memory_only` or `CacheServiceBuilder::memory_only`. Every key is missing and
+/// writes are accepted and dropped, so the memory tier is the only one holding values. The
+/// commands of the plain get, set and unset paths are answered, also inside pipelines and
+/// MULTI blocks; everything else fails, like it does for memcached.
+#[derive(Debug, Default)]
+pub struct MemoryOnlyConnection;
+
+impl MemoryOnlyConnection {
+    fn execute(&mut self, command: &[Vec<u8>]) -> RedisResult<Value> {
+        let (name, args) = command.split_first().ok_or_else(malformed)?;
+        let name = String::from_utf8_lossy(name).to_uppercase();
+        match (name.as_str(), args) {
+            ("GET", [_]) => Ok(Value::Nil),
+            ("MGET", keys) if !keys.is_empty() => Ok(Value::Bulk(vec![Value::Nil; keys.len()])),
+            ("SET", [_, _, options @ ..]) => {
+                let replace_only = options
+                    .iter()
+                    .any(|option| option.eq_ignore_ascii_case(b"XX"));
+                Ok(if replace_only {
+                    Value::Nil
+                } else {
+                    Value::Okay
+                })
+            }
+            ("SETEX" | "PSETEX", [_, _, _]) => Ok(Value::Okay),
+            ("DEL" | "UNLINK" | "EXISTS", keys) if !keys.is_empty() => Ok(Value::Int(0)),
+            ("EXPIRE" | "PEXPIRE", [_, _]) | ("PERSIST", [_]) => Ok(Value::Int(0)),
+            ("TTL" | "PTTL", [_]) => Ok(Value::Int(-2)),
+            ("SCAN", [_, ..]) => Ok(Value::Bulk(vec![
+                Value::Data(b"0".to_vec()),
+                Value::Bulk(Vec::new()),
+            ])),
+            ("PING", []) => Ok(Value::Status("PONG".to_string())),
+            _ => Err(unsupported("Command not supported without a KV tier", name)),
+        }
+    }
+}
+
+impl ConnectionLike for MemoryOnlyConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        emulated::run_command(cmd, |command| self.execute(command))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        emulated::run_pipeline(cmd, offset, count, |command| self.execute(command))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn check_connection(&mut self) -> bool {
+        true
+    }
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
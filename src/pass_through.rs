@@ -0,0 +1,44 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Kill switch of a single `CacheService`, from `CacheService::pass_through`. While on,
+/// resolving skips both tiers and calls the resolver directly, without storing what it
+/// returns. Clones share the switch, so it can be flipped from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct PassThrough(Arc<AtomicBool>);
+
+impl PassThrough {
+    pub fn set(&self, on: bool) {
+        self.0.store(on, Ordering::Relaxed);
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Kill switches by namespace, shared by every service built with the same switches through
+/// `CacheServiceBuilder::namespace_pass_through`. Turning a namespace on makes all of those
+/// services using it resolve as under `PassThrough`. Clones share the switches.
+#[derive(Debug, Clone, Default)]
+pub struct NamespacePassThrough(Arc<RwLock<HashSet<String>>>);
+
+impl NamespacePassThrough {
+    pub fn new() -> NamespacePassThrough {
+        NamespacePassThrough::default()
+    }
+
+    pub fn set(&self, namespace: &str, on: bool) {
+        let mut namespaces = self.0.write().unwrap();
+        if on {
+            namespaces.insert(namespace.to_string());
+        } else {
+            namespaces.remove(namespace);
+        }
+    }
+
+    pub fn is_on(&self, namespace: &str) -> bool {
+        self.0.read().unwrap().contains(namespace)
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::in_memory_cache::{shorter_ttl, InMemoryCache};
+use crate::kv_cache::KvCache;
+use crate::ttl::SharedTtls;
+use crate::SetPayload;
+
+/// Maximum number of prefetch hints waiting for the warmer. Hints beyond that are dropped.
+const QUEUE_SIZE: usize = 1000;
+
+/// Maps a resolved key to the keys likely to be read next.
+pub type RelatedKeysFn = Arc<dyn Fn(&str) -> Vec<String> + Send + Sync>;
+
+/// Related-key callbacks, each applied to keys starting with its prefix.
+#[derive(Clone, Default)]
+pub struct PrefetchRules {
+    rules: Vec<(String, RelatedKeysFn)>,
+}
+
+impl PrefetchRules {
+    pub fn add(&mut self, prefix: &str, related: RelatedKeysFn) {
+        self.rules.push((prefix.to_string(), related));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Keys related to `key` by every rule whose prefix matches it.
+    pub fn related(&self, key: &str) -> Vec<String> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .flat_map(|(_, related)| related(key))
+            .filter(|related| related != key)
+            .collect()
+    }
+}
+
+enum Command {
+    Warm(Vec<String>),
+    Flush(SyncSender<()>),
+}
+
+/// Background warmer that copies related keys from Redis into the memory tier on its own
+/// connection. Keys missing from Redis are left alone; only a resolver can produce them.
+pub struct Prefetcher {
+    rules: PrefetchRules,
+    sender: Option<SyncSender<Command>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    /// Starts the warmer, which takes ownership of `kv_cache` and fills `in_memory_cache` for
+    /// at most the current memory TTL.
+    pub fn new(
+        rules: PrefetchRules,
+        kv_cache: KvCache,
+        in_memory_cache: InMemoryCache,
+        ttls: Arc<SharedTtls>,
+    ) -> Prefetcher {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let handle = thread::spawn(move || {
+            run_warmer(kv_cache, in_memory_cache, ttls, receiver);
+        });
+
+        Prefetcher {
+            rules,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn related(&self, key: &str) -> Vec<String> {
+        self.rules.related(key)
+    }
+
+    /// Queues `keys` for warming without waiting. Dropped if the warmer is backed up.
+    pub fn warm(&self, keys: Vec<String>) {
+        if keys.is_empty() {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(Command::Warm(keys));
+        }
+    }
+
+    /// Blocks until every hint queued before this call has been handled.
+    pub fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let (done_sender, done_receiver) = mpsc::sync_channel(1);
+        if sender.send(Command::Flush(done_sender)).is_ok() {
+            let _ = done_receiver.recv();
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Closing the channel stops the warmer once it has drained the queue.
+        self.sender = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_warmer(
+    mut kv_cache: KvCache,
+    mut in_memory_cache: InMemoryCache,
+    ttls: Arc<SharedTtls>,
+    receiver: Receiver<Command>,
+) {
+    while let Ok(command) = receiver.recv() {
+        let keys = match command {
+            Command::Warm(keys) => keys,
+            Command::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let keys: Vec<&str> = keys
+            .iter()
+            .map(String::as_str)
+            .filter(|key| in_memory_cache.meta(key).is_none())
+            .collect();
+        let Ok(values) = kv_cache.get_many_with_ttl(&keys) else {
+            continue;
+        };
+        let memory_ttl = ttls.memory();
+        for (key, value) in keys.into_iter().zip(values) {
+            let Some((value, remaining_ttl)) = value else {
+                continue;
+            };
+            let ttl = match remaining_ttl {
+                Some(remaining) if remaining.is_zero() => continue,
+                Some(remaining) => shorter_ttl(remaining, memory_ttl),
+                None => memory_ttl,
+            };
+            let _ = in_memory_cache.set(SetPayload {
+                key,
+                value: &value,
+                ttl,
+                tier_hint: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_match_rules_by_prefix() {
+        let mut rules = PrefetchRules::default();
+        rules.add(
+            "user:",
+            Arc::new(|key: &str| vec![key.replace(":profile", ":prefs")]),
+        );
+        rules.add("order:", Arc::new(|_: &str| vec!["never".to_string()]));
+
+        assert_eq!(rules.related("user:42:profile"), vec!["user:42:prefs"]);
+        assert!(rules.related("user:42:settings").is_empty());
+        assert!(rules.related("session:1").is_empty());
+    }
+}
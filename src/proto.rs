@@ -0,0 +1,290 @@
+//! The messages of `proto/cache.proto`, shared by the gRPC service and the length-prefixed
+//! TCP protocol of `framed_server`. They are written to match the schema field for field, so
+//! clients generated from it in any language can talk to either.
+
+use std::time::Duration;
+
+use crate::{error_chain, CacheService, CacheServiceError};
+
+/// Version of the framed protocol this server speaks. Clients that never say hello get
+/// version 1, which has every command but `Hello` and no capabilities.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Capability compressing every frame after the `Hello` exchange with zstd.
+pub const COMPRESSION: &str = "compression";
+
+/// Capabilities this server can enable on a connection.
+pub const CAPABILITIES: &[&str] = &[COMPRESSION];
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetResponse {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+    /// Seconds; 0 for the server's default TTL.
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteResponse {
+    #[prost(bool, tag = "1")]
+    pub deleted: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResolveStreamRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub keys: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Entry {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub value: Option<Vec<u8>>,
+}
+
+/// Opens a framed connection, stating the newest version the client speaks and the
+/// capabilities it would like.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HelloRequest {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(string, repeated, tag = "2")]
+    pub capabilities: Vec<String>,
+}
+
+/// The version both sides speak and the capabilities enabled for the rest of the connection.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HelloResponse {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(string, repeated, tag = "2")]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsResponse {
+    #[prost(uint64, tag = "1")]
+    pub memory_hits: u64,
+    #[prost(uint64, tag = "2")]
+    pub memory_misses: u64,
+    #[prost(uint64, tag = "3")]
+    pub kv_hits: u64,
+    #[prost(uint64, tag = "4")]
+    pub kv_misses: u64,
+    #[prost(uint64, tag = "5")]
+    pub evictions: u64,
+    #[prost(uint64, tag = "6")]
+    pub expired_removals: u64,
+    #[prost(uint64, tag = "7")]
+    pub resolver_calls: u64,
+    #[prost(uint64, tag = "8")]
+    pub errors: u64,
+    #[prost(uint64, tag = "9")]
+    pub entries: u64,
+}
+
+/// One call over the framed protocol.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Request {
+    #[prost(oneof = "request::Command", tags = "1, 2, 3, 4, 5")]
+    pub command: Option<request::Command>,
+}
+
+pub mod request {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Command {
+        #[prost(message, tag = "1")]
+        Get(super::GetRequest),
+        #[prost(message, tag = "2")]
+        Set(super::SetRequest),
+        #[prost(message, tag = "3")]
+        Delete(super::DeleteRequest),
+        #[prost(message, tag = "4")]
+        Stats(super::StatsRequest),
+        #[prost(message, tag = "5")]
+        Hello(super::HelloRequest),
+    }
+}
+
+/// The answer to a `Request`, in the same order as the requests.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Response {
+    #[prost(oneof = "response::Result", tags = "1, 2, 3, 4, 5, 6")]
+    pub result: Option<response::Result>,
+}
+
+pub mod response {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Result {
+        #[prost(message, tag = "1")]
+        Get(super::GetResponse),
+        #[prost(message, tag = "2")]
+        Set(super::SetResponse),
+        #[prost(message, tag = "3")]
+        Delete(super::DeleteResponse),
+        #[prost(message, tag = "4")]
+        Stats(super::StatsResponse),
+        #[prost(message, tag = "5")]
+        Error(super::Error),
+        #[prost(message, tag = "6")]
+        Hello(super::HelloResponse),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Error {
+    #[prost(enumeration = "ErrorCode", tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorCode {
+    Unspecified = 0,
+    Internal = 1,
+    /// Redis could not be reached.
+    Unavailable = 2,
+    /// The key or value is too large.
+    InvalidArgument = 3,
+    /// The namespace reached its key quota.
+    ResourceExhausted = 4,
+    /// The request holds no command this server knows, e.g. one added in a later version.
+    UnknownCommand = 5,
+}
+
+impl From<&CacheServiceError> for ErrorCode {
+    fn from(err: &CacheServiceError) -> ErrorCode {
+        match err {
+            CacheServiceError::QuotaExceeded { .. } => ErrorCode::ResourceExhausted,
+            CacheServiceError::KeyTooLong { .. } | CacheServiceError::ValueTooLarge { .. } => {
+                ErrorCode::InvalidArgument
+            }
+            CacheServiceError::KvCacheError { .. } => ErrorCode::Unavailable,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<CacheServiceError> for Error {
+    fn from(err: CacheServiceError) -> Error {
+        Error {
+            code: ErrorCode::from(&err) as i32,
+            message: error_chain(&err),
+        }
+    }
+}
+
+pub(crate) fn get(
+    cache: &mut CacheService,
+    request: GetRequest,
+) -> Result<GetResponse, CacheServiceError> {
+    let value = cache.get_bytes(&request.key)?;
+    Ok(GetResponse {
+        value: value.map(|value| value.to_vec()),
+    })
+}
+
+/// A `ttl` of 0 stores the value for `default_ttl`.
+pub(crate) fn set(
+    cache: &mut CacheService,
+    request: SetRequest,
+    default_ttl: Duration,
+) -> Result<SetResponse, CacheServiceError> {
+    let ttl = if request.ttl == 0 {
+        default_ttl
+    } else {
+        Duration::from_secs(request.ttl)
+    };
+    cache.set_bytes(&request.key, &request.value, ttl)?;
+    Ok(SetResponse {})
+}
+
+pub(crate) fn delete(
+    cache: &mut CacheService,
+    request: DeleteRequest,
+) -> Result<DeleteResponse, CacheServiceError> {
+    let deleted = cache.contains(&request.key)?;
+    cache.invalidate(&request.key)?;
+    Ok(DeleteResponse { deleted })
+}
+
+pub(crate) fn stats(cache: &CacheService) -> StatsResponse {
+    let stats = cache.stats();
+    StatsResponse {
+        memory_hits: stats.memory_hits,
+        memory_misses: stats.memory_misses,
+        kv_hits: stats.kv_hits,
+        kv_misses: stats.kv_misses,
+        evictions: stats.evictions,
+        expired_removals: stats.expired_removals,
+        resolver_calls: stats.resolver_calls,
+        errors: stats.errors,
+        entries: stats.entries as u64,
+    }
+}
+
+/// Settles on the older of both versions and the requested capabilities this server has,
+/// ignoring ones it does not know.
+pub fn negotiate(hello: &HelloRequest) -> HelloResponse {
+    HelloResponse {
+        version: hello.version.clamp(1, PROTOCOL_VERSION),
+        capabilities: hello
+            .capabilities
+            .iter()
+            .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Runs `request` against `cache`. Failures and unknown commands are answered with an error.
+pub fn execute(cache: &mut CacheService, request: Request, default_ttl: Duration) -> Response {
+    use request::Command;
+    use response::Result as Reply;
+
+    let result = match request.command {
+        Some(Command::Get(request)) => get(cache, request).map(Reply::Get),
+        Some(Command::Set(request)) => set(cache, request, default_ttl).map(Reply::Set),
+        Some(Command::Delete(request)) => delete(cache, request).map(Reply::Delete),
+        Some(Command::Stats(_)) => Ok(Reply::Stats(stats(cache))),
+        Some(Command::Hello(hello)) => Ok(Reply::Hello(negotiate(&hello))),
+        None => Ok(Reply::Error(Error {
+            code: ErrorCode::UnknownCommand as i32,
+            message: "unknown command".to_string(),
+        })),
+    };
+    Response {
+        result: Some(result.unwrap_or_else(|err| Reply::Error(err.into()))),
+    }
+}
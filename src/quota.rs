@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+/// What happens to a write that would take a namespace past its key quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaPolicy {
+    /// Fail the write with `CacheServiceError::QuotaExceeded`.
+    #[default]
+    Reject,
+    /// Delete the keys closest to expiring from Redis to make room.
+    EvictOldest,
+}
+
+/// Upper bound on the number of Redis keys under the service's namespace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyQuota {
+    pub max_keys: u64,
+    pub policy: QuotaPolicy,
+    /// Writes, rejected ones included, after which the keys are counted again with SCAN.
+    pub recount_every: u64,
+    /// Fraction of `max_keys` at which a `QuotaAlert` fires, in (0, 1].
+    pub alert_threshold: f64,
+}
+
+impl KeyQuota {
+    pub fn new(max_keys: u64, policy: QuotaPolicy) -> KeyQuota {
+        KeyQuota {
+            max_keys,
+            policy,
+            recount_every: 1000,
+            alert_threshold: 0.8,
+        }
+    }
+
+    fn alert_at(&self) -> u64 {
+        (self.max_keys as f64 * self.alert_threshold).ceil() as u64
+    }
+}
+
+/// Usage of a namespace whose key count reached the alert threshold of its quota.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaAlert {
+    pub namespace: String,
+    pub keys: u64,
+    pub max_keys: u64,
+}
+
+/// Receives quota alerts. Runs on the writing thread, so it should hand off anything slow.
+pub type QuotaObserver = Arc<dyn Fn(&QuotaAlert) + Send + Sync>;
+
+/// Estimates the key count of a namespace as its last SCAN count plus the writes since.
+/// Overwrites count as new keys and expiries go unnoticed until the next count, so the estimate
+/// errs high and is only trusted while it stays below the quota.
+#[derive(Debug)]
+pub(crate) struct QuotaTracker {
+    quota: KeyQuota,
+    counted: Option<u64>,
+    writes: u64,
+    rejections: u64,
+    alerted: bool,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: KeyQuota) -> QuotaTracker {
+        QuotaTracker {
+            quota,
+            counted: None,
+            writes: 0,
+            rejections: 0,
+            alerted: false,
+        }
+    }
+
+    pub(crate) fn quota(&self) -> KeyQuota {
+        self.quota
+    }
+
+    /// Whether the estimate has to be replaced by a fresh count before admitting `incoming`
+    /// keys. A namespace found full stays full until `recount_every` further attempts.
+    pub(crate) fn needs_count(&self, incoming: u64) -> bool {
+        let Some(counted) = self.counted else {
+            return true;
+        };
+        self.writes + self.rejections >= self.quota.recount_every
+            || (self.writes > 0 && counted + self.writes + incoming > self.quota.max_keys)
+    }
+
+    pub(crate) fn set_count(&mut self, count: u64) {
+        if count < self.quota.alert_at() {
+            self.alerted = false;
+        }
+        self.counted = Some(count);
+        self.writes = 0;
+        self.rejections = 0;
+    }
+
+    /// How many keys must go before `incoming` more fit.
+    pub(crate) fn excess(&self, incoming: u64) -> u64 {
+        let estimate = self.counted.unwrap_or(0) + self.writes;
+        (estimate + incoming).saturating_sub(self.quota.max_keys)
+    }
+
+    /// The estimated key count once `incoming` more are written, if that reaches the alert
+    /// threshold for the first time since a count was last below it.
+    pub(crate) fn take_alert(&mut self, incoming: u64) -> Option<u64> {
+        let estimate = self.counted? + self.writes + incoming;
+        if self.alerted || estimate < self.quota.alert_at() {
+            return None;
+        }
+        self.alerted = true;
+        Some(estimate.min(self.quota.max_keys))
+    }
+
+    pub(crate) fn record_writes(&mut self, count: u64) {
+        self.writes += count;
+    }
+
+    pub(crate) fn record_rejection(&mut self) {
+        self.rejections += 1;
+    }
+
+    pub(crate) fn record_removals(&mut self, count: u64) {
+        self.counted = self.counted.map(|counted| counted.saturating_sub(count));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_recount_only_when_estimate_is_stale_or_full() {
+        let mut tracker = QuotaTracker::new(KeyQuota {
+            max_keys: 3,
+            policy: QuotaPolicy::Reject,
+            recount_every: 5,
+            alert_threshold: 0.8,
+        });
+        assert!(tracker.needs_count(1));
+        tracker.set_count(1);
+        assert!(!tracker.needs_count(2));
+        tracker.record_writes(2);
+        assert_eq!(tracker.excess(0), 0);
+        assert!(tracker.needs_count(1));
+
+        tracker.set_count(3);
+        assert_eq!(tracker.excess(1), 1);
+        for _ in 0..4 {
+            assert!(!tracker.needs_count(1));
+            tracker.record_rejection();
+        }
+        tracker.record_rejection();
+        assert!(tracker.needs_count(1));
+    }
+
+    #[test]
+    fn it_should_alert_once_per_crossing() {
+        let mut tracker = QuotaTracker::new(KeyQuota::new(10, QuotaPolicy::Reject));
+        tracker.set_count(6);
+        assert_eq!(tracker.take_alert(1), None);
+        tracker.record_writes(1);
+        assert_eq!(tracker.take_alert(1), Some(8));
+        tracker.record_writes(1);
+        assert_eq!(tracker.take_alert(1), None);
+
+        tracker.set_count(9);
+        assert_eq!(tracker.take_alert(1), None);
+        tracker.set_count(2);
+        assert_eq!(tracker.take_alert(20), Some(10));
+    }
+}
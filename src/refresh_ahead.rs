@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::in_memory_cache::{EntryMeta, InMemoryCache};
+use crate::kv_cache::KvCache;
+use crate::ttl::SharedTtls;
+use crate::SetPayload;
+
+/// Maximum number of refreshes waiting for the scheduler. Keys beyond that are refreshed on a
+/// later access instead.
+const QUEUE_SIZE: usize = 1000;
+
+/// Loads a fresh value for a key, or `None` to let the cached one expire.
+pub type Loader = Arc<dyn Fn(&str) -> Option<Bytes> + Send + Sync>;
+
+/// Where refreshed values are written and for how long.
+pub struct RefreshTarget {
+    pub kv_cache: KvCache,
+    pub in_memory_cache: InMemoryCache,
+    pub ttls: Arc<SharedTtls>,
+}
+
+enum Command {
+    Refresh { key: String, stored_key: String },
+    Flush(SyncSender<()>),
+}
+
+/// Reloads entries in the background when they are read during the last `window` fraction of
+/// their TTL, so hot keys are replaced before they expire instead of missing.
+pub struct RefreshAhead {
+    window: f64,
+    pending: Arc<Mutex<HashSet<String>>>,
+    sender: Option<SyncSender<Command>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RefreshAhead {
+    /// Starts the scheduler, which takes ownership of the target's Redis connection.
+    pub fn new(window: f64, loader: Loader, target: RefreshTarget) -> RefreshAhead {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let scheduler_pending = Arc::clone(&pending);
+        let handle = thread::spawn(move || {
+            run_scheduler(loader, target, receiver, scheduler_pending);
+        });
+
+        RefreshAhead {
+            window,
+            pending,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Pinned entries and entries without expiry are never due. `now` is in Unix
+    /// milliseconds, like `meta.expires_at`.
+    pub fn is_due(&self, meta: &EntryMeta, now: u64) -> bool {
+        if meta.pinned || meta.expires_at == u64::MAX {
+            return false;
+        }
+        let remaining = meta.expires_at.saturating_sub(now) as f64;
+        remaining <= meta.ttl.as_millis() as f64 * self.window
+    }
+
+    /// Queues a refresh of `key`, stored as `stored_key`, unless one is already pending.
+    pub fn schedule(&self, key: &str, stored_key: &str) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if !self.pending.lock().unwrap().insert(stored_key.to_string()) {
+            return;
+        }
+        let command = Command::Refresh {
+            key: key.to_string(),
+            stored_key: stored_key.to_string(),
+        };
+        if sender.try_send(command).is_err() {
+            self.pending.lock().unwrap().remove(stored_key);
+        }
+    }
+
+    /// Blocks until every refresh queued before this call has finished.
+    pub fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let (done_sender, done_receiver) = mpsc::sync_channel(1);
+        if sender.send(Command::Flush(done_sender)).is_ok() {
+            let _ = done_receiver.recv();
+        }
+    }
+}
+
+impl Drop for RefreshAhead {
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_scheduler(
+    loader: Loader,
+    mut target: RefreshTarget,
+    receiver: Receiver<Command>,
+    pending: Arc<Mutex<HashSet<String>>>,
+) {
+    while let Ok(command) = receiver.recv() {
+        let (key, stored_key) = match command {
+            Command::Refresh { key, stored_key } => (key, stored_key),
+            Command::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let started = Instant::now();
+        // A panicking loader only costs this refresh, not the scheduler.
+        let loaded = panic::catch_unwind(AssertUnwindSafe(|| loader(&key))).unwrap_or(None);
+        if let Some(value) = loaded {
+            let delta = started.elapsed().as_secs_f64();
+            let (memory_ttl, kv_ttl) = target.ttls.both();
+            let payload = SetPayload {
+                key: &stored_key,
+                value: &value,
+                ttl: target.ttls.jittered(kv_ttl),
+                tier_hint: None,
+            };
+            if target.kv_cache.overwrite(payload).is_ok() {
+                let _ = target.in_memory_cache.replace(
+                    SetPayload {
+                        ttl: target.ttls.jittered(memory_ttl),
+                        ..payload
+                    },
+                    delta,
+                );
+            }
+        }
+        pending.lock().unwrap().remove(&stored_key);
+    }
+}
@@ -0,0 +1,384 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::stats::SizeHistogram;
+use crate::{error_chain, CacheService, CacheServiceError};
+
+/// Largest bulk string accepted from a client, as in Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Serves a `CacheService` over the Redis protocol, so Redis clients in any language can use
+/// it as a caching proxy. Understands GET, SET with EX or PX, SETEX, PSETEX, DEL, PING, INFO,
+/// and ENTRY, which describes a key like `CacheService::entry_info`. Keys go through the
+/// service's namespace, and every connection is served on its own thread.
+pub struct RespServer {
+    listener: TcpListener,
+    cache: Arc<Mutex<CacheService>>,
+    ttl: Duration,
+    shutdown: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    stopping: AtomicBool,
+    next_id: AtomicU64,
+    /// Open connections by id, so shutting down can stop their reads.
+    connections: Mutex<HashMap<u64, TcpStream>>,
+}
+
+/// Stops a `RespServer` from another thread, e.g. a signal handler.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+    addr: SocketAddr,
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl RespServer {
+    /// Listens on `addr`. SET without EX or PX stores values for `ttl`.
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        cache: Arc<Mutex<CacheService>>,
+        ttl: Duration,
+    ) -> io::Result<RespServer> {
+        Ok(RespServer {
+            listener: TcpListener::bind(addr)?,
+            cache,
+            ttl,
+            shutdown: Arc::default(),
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle {
+            state: Arc::clone(&self.shutdown),
+            addr: self.local_addr()?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until accepting fails or `ShutdownHandle::shutdown` is called. After
+    /// a shutdown, returns once every connection has answered the commands it already sent.
+    pub fn serve(&self) -> io::Result<()> {
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            if self.shutdown.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            connections.retain(|connection| !connection.is_finished());
+            let cache = Arc::clone(&self.cache);
+            let ttl = self.ttl;
+            let shutdown = Arc::clone(&self.shutdown);
+            connections.push(thread::spawn(move || {
+                let id = shutdown.next_id.fetch_add(1, Ordering::SeqCst);
+                if let Ok(clone) = stream.try_clone() {
+                    shutdown.connections.lock().unwrap().insert(id, clone);
+                }
+                // Checked after registering, so a concurrent shutdown either sees this
+                // connection or is seen by it.
+                if !shutdown.stopping.load(Ordering::SeqCst) {
+                    let _ = serve_connection(stream, &cache, ttl);
+                }
+                shutdown.connections.lock().unwrap().remove(&id);
+            }));
+        }
+        for connection in connections {
+            let _ = connection.join();
+        }
+        Ok(())
+    }
+}
+
+impl ShutdownHandle {
+    /// Makes `serve` stop accepting connections and close each open one once its pending
+    /// commands are answered. Does not wait for that; `serve` returns when it is done.
+    pub fn shutdown(&self) {
+        if self.state.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for stream in self.state.connections.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        // Wakes up the accepting thread.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn serve_connection(
+    stream: TcpStream,
+    cache: &Mutex<CacheService>,
+    ttl: Duration,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(command) = read_command(&mut reader)? {
+        if command.is_empty() {
+            continue;
+        }
+        let reply = execute(&mut cache.lock().unwrap(), &command, ttl);
+        reply.write_to(&mut writer)?;
+        // Pipelined commands are answered together.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+/// A positive expire time of SET EX or SETEX, or of SET PX or PSETEX with `millis`.
+fn expire_time(amount: &str, millis: bool) -> Option<Duration> {
+    let amount = amount.parse().ok().filter(|&amount| amount > 0)?;
+    Some(if millis {
+        Duration::from_millis(amount)
+    } else {
+        Duration::from_secs(amount)
+    })
+}
+
+fn execute(cache: &mut CacheService, command: &[Vec<u8>], ttl: Duration) -> Reply {
+    let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+    let args = &command[1..];
+    // Values are taken from `args` as they are.
+    let text: Vec<Cow<str>> = args
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg))
+        .collect();
+    let text: Vec<&str> = text.iter().map(|arg| &**arg).collect();
+    let result = match (name.as_str(), text.as_slice()) {
+        ("PING", []) => Ok(Reply::Status("PONG")),
+        ("PING", [_]) => Ok(Reply::Bulk(Some(args[0].clone()))),
+        ("GET", [key]) => cache
+            .get_bytes(key)
+            .map(|value| Reply::Bulk(value.map(|value| value.to_vec()))),
+        ("SET", [key, _]) => cache
+            .set_bytes(key, &args[1], ttl)
+            .map(|_| Reply::Status("OK")),
+        ("SET", [key, _, option, amount])
+            if option.eq_ignore_ascii_case("EX") || option.eq_ignore_ascii_case("PX") =>
+        {
+            match expire_time(amount, option.eq_ignore_ascii_case("PX")) {
+                Some(ttl) => cache
+                    .set_bytes(key, &args[1], ttl)
+                    .map(|_| Reply::Status("OK")),
+                None => Ok(Reply::Error(
+                    "ERR invalid expire time in 'set' command".to_string(),
+                )),
+            }
+        }
+        ("SETEX" | "PSETEX", [key, amount, _]) => match expire_time(amount, name == "PSETEX") {
+            Some(ttl) => cache
+                .set_bytes(key, &args[2], ttl)
+                .map(|_| Reply::Status("OK")),
+            None => Ok(Reply::Error(format!(
+                "ERR invalid expire time in '{}' command",
+                name.to_lowercase()
+            ))),
+        },
+        ("DEL", keys) if !keys.is_empty() => delete(cache, keys).map(Reply::Integer),
+        ("INFO", [] | [_]) => Ok(Reply::Bulk(Some(info(cache).into_bytes()))),
+        ("ENTRY", [key]) => cache
+            .entry_info(key)
+            .map(|info| Reply::Bulk(info.map(|info| info.to_string().into_bytes()))),
+        ("PING" | "GET" | "SET" | "SETEX" | "PSETEX" | "DEL" | "INFO" | "ENTRY", _) => {
+            Ok(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )))
+        }
+        _ => Ok(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_lowercase()
+        ))),
+    };
+    result.unwrap_or_else(|err| Reply::Error(format!("ERR {}", error_chain(&err))))
+}
+
+fn delete(cache: &mut CacheService, keys: &[&str]) -> Result<i64, CacheServiceError> {
+    let mut deleted = 0;
+    for key in keys {
+        if cache.contains(key)? {
+            deleted += 1;
+        }
+        cache.invalidate(key)?;
+    }
+    Ok(deleted)
+}
+
+fn info(cache: &CacheService) -> String {
+    let stats = cache.stats();
+    let sizes = cache.size_distribution();
+    let percentile =
+        |histogram: &SizeHistogram, quantile| histogram.percentile(quantile).unwrap_or_default();
+    let mut info = format!(
+        "# Server\r\ninstance_id:{}\r\n\r\n# Stats\r\nmemory_hits:{}\r\nmemory_misses:{}\r\nkv_hits:{}\r\nkv_misses:{}\r\n\
+         evictions:{}\r\nexpired_removals:{}\r\nerrors:{}\r\n\r\n# Keyspace\r\nentries:{}\r\n\
+         \r\n# Sizes\r\nkey_size_p50:{}\r\nkey_size_p99:{}\r\nkey_size_max:{}\r\n\
+         value_size_p50:{}\r\nvalue_size_p99:{}\r\nvalue_size_max:{}\r\n",
+        cache.instance_id(),
+        stats.memory_hits,
+        stats.memory_misses,
+        stats.kv_hits,
+        stats.kv_misses,
+        stats.evictions,
+        stats.expired_removals,
+        stats.errors,
+        stats.entries,
+        percentile(&sizes.keys, 0.5),
+        percentile(&sizes.keys, 0.99),
+        sizes.keys.max(),
+        percentile(&sizes.values, 0.5),
+        percentile(&sizes.values, 0.99),
+        sizes.values.max(),
+    );
+    if let Some(verification) = cache.verification_stats() {
+        info.push_str(&format!(
+            "\r\n# Verification\r\nsampled:{}\r\nunreadable:{}\r\nlegacy:{}\r\nerrors:{}\r\n",
+            verification.sampled, verification.unreadable, verification.legacy, verification.errors,
+        ));
+    }
+    info
+}
+
+/// Reads the next command, either a RESP array of bulk strings or an inline command as typed
+/// into telnet. `None` once the client hung up.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let line = String::from_utf8_lossy(&line).into_owned();
+        return Ok(Some(
+            line.split_whitespace()
+                .map(|arg| arg.as_bytes().to_vec())
+                .collect(),
+        ));
+    };
+    let count = parse_len(count)?;
+    let mut command = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of command"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| protocol_error("expected a bulk string"))?;
+        let len = parse_len(len)?;
+        if len > MAX_BULK_LEN {
+            return Err(protocol_error("bulk string too long"));
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        command.push(arg);
+    }
+    Ok(Some(command))
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    while matches!(line.last(), Some(b'\n' | b'\r')) {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Reply {
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status),
+            Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                write!(writer, "${}\r\n", data.len())?;
+                writer.write_all(data)?;
+                writer.write_all(b"\r\n")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::Commands;
+
+    use super::*;
+
+    #[test]
+    fn it_should_serve_redis_clients() {
+        let cache = CacheService::builder("redis://127.0.0.1:6379")
+            .ttl(Duration::from_secs(100))
+            .namespace("resp")
+            .provenance("resp-test", None)
+            .build()
+            .unwrap();
+        let server = RespServer::bind(
+            "127.0.0.1:0",
+            Arc::new(Mutex::new(cache)),
+            Duration::from_secs(100),
+        )
+        .unwrap();
+        let url = format!("redis://{}", server.local_addr().unwrap());
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || server.serve());
+        let mut con = redis::Client::open(url).unwrap().get_connection().unwrap();
+
+        let pong: String = redis::cmd("PING").query(&mut con).unwrap();
+        assert_eq!(pong, "PONG");
+        redis::cmd("SET")
+            .arg("key")
+            .arg("value")
+            .arg("PX")
+            .arg(10_000)
+            .query::<()>(&mut con)
+            .unwrap();
+        let value: Option<String> = con.get("key").unwrap();
+        assert_eq!(value.as_deref(), Some("value"));
+        let entry: String = redis::cmd("ENTRY").arg("key").query(&mut con).unwrap();
+        assert!(entry.contains("in_memory:1\r\n"));
+        assert!(entry.contains("writer_instance:resp-test\r\n"));
+        let deleted: i64 = con.del(&["key", "missing"]).unwrap();
+        assert_eq!(deleted, 1);
+        let value: Option<String> = con.get("key").unwrap();
+        assert_eq!(value, None);
+        let info: String = redis::cmd("INFO").query(&mut con).unwrap();
+        assert!(info.contains("memory_hits:1"));
+        assert!(redis::cmd("HGET")
+            .arg("key")
+            .arg("field")
+            .query::<()>(&mut con)
+            .is_err());
+
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+        assert!(redis::cmd("PING").query::<String>(&mut con).is_err());
+    }
+}
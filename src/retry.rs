@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use redis::{ErrorKind, RedisError};
+
+use crate::kv_cache::is_failover_error;
+
+/// How a `KvCache` retries commands that failed with a transient error: a dropped, refused or
+/// timed out connection, or a server that is loading, failing over or asked to try again.
+/// Other errors are returned at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per command, the first included.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each retry after it.
+    pub base_delay: Duration,
+    /// Longest delay between two attempts, before jitter.
+    pub max_delay: Duration,
+    /// Delays are scaled by a random factor in `1 - jitter..=1 + jitter`, so clients that failed
+    /// together don't retry together.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << retry.saturating_sub(1).min(31))
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        backoff.mul_f64(1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0))
+    }
+}
+
+/// What commands do while the connection to Redis is lost and the next reconnect attempt is
+/// not due yet.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WhileReconnecting {
+    /// Fail at once, without waiting or touching the network.
+    #[default]
+    FailFast,
+    /// Wait for the next attempt if it is due within the given time, and fail if it isn't or
+    /// the attempt fails.
+    Wait(Duration),
+}
+
+/// How a `KvCache` gets a lost connection back: reconnect attempts are spaced out starting at
+/// `base_delay` and doubling up to `max_delay`, and commands in between behave according to
+/// `while_reconnecting`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub while_reconnecting: WhileReconnecting,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            while_reconnecting: WhileReconnecting::default(),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait after `failures` failed attempts in a row.
+    pub fn delay(&self, failures: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(1 << failures.saturating_sub(1).min(31))
+            .min(self.max_delay)
+    }
+}
+
+/// Returned by commands that were not sent because the connection is being restored.
+pub(crate) fn reconnecting() -> RedisError {
+    RedisError::from((ErrorKind::IoError, "reconnecting to Redis"))
+}
+
+pub(crate) fn is_transient(err: &RedisError) -> bool {
+    is_failover_error(err)
+        || err.is_timeout()
+        || matches!(
+            err.kind(),
+            ErrorKind::IoError | ErrorKind::TryAgain | ErrorKind::BusyLoadingError
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_back_off_exponentially_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+        };
+        let delays: Vec<u128> = (1..=4)
+            .map(|retry| policy.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, [10, 20, 40, 50]);
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        let delay = jittered.delay(1);
+        assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(15));
+        assert!(is_transient(&RedisError::from((
+            ErrorKind::TryAgain,
+            "slot migrating"
+        ))));
+        assert!(!is_transient(&RedisError::from((
+            ErrorKind::TypeError,
+            "wrong type"
+        ))));
+    }
+}
@@ -0,0 +1,31 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Whether `key` is among the `percent` of keys cached during a gradual rollout. Keys are
+/// picked by an FNV-1a hash rather than the standard hasher, whose output may change between
+/// Rust releases, so every instance and every restart caches the same keys.
+pub(crate) fn in_rollout(key: &str, percent: u8) -> bool {
+    let hash = key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    hash % 100 < percent as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_pick_about_the_configured_share_of_keys_consistently() {
+        let keys: Vec<String> = (0..10_000).map(|i| format!("user:{}", i)).collect();
+        let included = keys.iter().filter(|key| in_rollout(key, 25)).count();
+        assert!((2_250..=2_750).contains(&included), "{} included", included);
+
+        assert!(keys.iter().all(|key| in_rollout(key, 100)));
+        assert!(!keys.iter().any(|key| in_rollout(key, 0)));
+        assert!(keys
+            .iter()
+            .filter(|key| in_rollout(key, 10))
+            .all(|key| in_rollout(key, 25)));
+    }
+}
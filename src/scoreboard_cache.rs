@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use crate::in_memory_cache::{expiry, SystemTimeSource, TimeSource};
+use crate::kv_cache::{KvCache, KvError};
+
+struct Mirror {
+    entries: Vec<(String, f64)>,
+    /// Unix time in milliseconds.
+    timestamp: u64,
+}
+
+/// Ranking data cached in a Redis sorted set. The sorted set expires `ttl` after the last
+/// write, and the top `top_n` entries are mirrored in memory for up to `ttl`.
+pub struct ScoreboardCache<T: TimeSource = SystemTimeSource> {
+    kv_cache: KvCache,
+    key: String,
+    ttl: Duration,
+    top_n: usize,
+    mirror: Option<Mirror>,
+    time_source: T,
+}
+
+impl ScoreboardCache<SystemTimeSource> {
+    pub fn new(
+        redis_url: &str,
+        name: &str,
+        ttl: Duration,
+        top_n: usize,
+    ) -> Result<ScoreboardCache<SystemTimeSource>, KvError> {
+        Ok(ScoreboardCache {
+            kv_cache: KvCache::new(redis_url)?,
+            key: format!("scoreboard:{}", name),
+            ttl,
+            top_n,
+            mirror: None,
+            time_source: SystemTimeSource,
+        })
+    }
+}
+
+impl<T: TimeSource> ScoreboardCache<T> {
+    pub fn add(&mut self, member: &str, score: f64) -> Result<(), KvError> {
+        self.add_many(&[(member, score)])
+    }
+
+    pub fn add_many(&mut self, members: &[(&str, f64)]) -> Result<(), KvError> {
+        self.kv_cache.zadd(&self.key, members, self.ttl)?;
+        self.mirror = None;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, member: &str) -> Result<(), KvError> {
+        self.kv_cache.zrem(&self.key, member)?;
+        self.mirror = None;
+        Ok(())
+    }
+
+    pub fn score(&mut self, member: &str) -> Result<Option<f64>, KvError> {
+        self.kv_cache.zscore(&self.key, member)
+    }
+
+    /// Returns the `n` highest scored members, best first. Requests within the mirrored top-N
+    /// are served from memory while the mirror is fresh.
+    pub fn top(&mut self, n: usize) -> Result<Vec<(String, f64)>, KvError> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        if n > self.top_n {
+            return self.kv_cache.zrevrange(&self.key, 0, n as isize - 1);
+        }
+
+        let now = self.time_source.now_millis();
+        let is_fresh = matches!(
+            &self.mirror,
+            Some(mirror) if now < expiry(mirror.timestamp, self.ttl)
+        );
+        if !is_fresh {
+            let entries = self
+                .kv_cache
+                .zrevrange(&self.key, 0, self.top_n as isize - 1)?;
+            self.mirror = Some(Mirror {
+                entries,
+                timestamp: now,
+            });
+        }
+
+        Ok(self
+            .mirror
+            .as_ref()
+            .map(|mirror| mirror.entries.iter().take(n).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    pub fn clear(&mut self) -> Result<(), KvError> {
+        self.kv_cache.unset(&self.key)?;
+        self.mirror = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_return_top_members_in_order() {
+        let mut board = ScoreboardCache::new(
+            "redis://127.0.0.1:6379",
+            "board1",
+            Duration::from_secs(10),
+            2,
+        )
+        .expect("Should establish connection with no problem");
+        board.clear().expect("Should not fail");
+        board
+            .add_many(&[("alice", 10.0), ("bob", 30.0), ("carol", 20.0)])
+            .expect("Should not fail");
+        let top = board.top(2).expect("Should not fail");
+        let all = board.top(3).expect("Should not fail");
+        board.clear().expect("Should not fail");
+
+        assert_eq!(
+            top,
+            vec![("bob".to_string(), 30.0), ("carol".to_string(), 20.0)]
+        );
+        assert_eq!(all.len(), 3);
+    }
+
+    #[test]
+    fn it_should_serve_top_from_mirror() {
+        let mut board = ScoreboardCache::new(
+            "redis://127.0.0.1:6379",
+            "board2",
+            Duration::from_secs(10),
+            2,
+        )
+        .expect("Should establish connection with no problem");
+        board.clear().expect("Should not fail");
+        board.add("alice", 10.0).expect("Should not fail");
+        board.top(1).expect("Should not fail");
+        board
+            .kv_cache
+            .unset("scoreboard:board2")
+            .expect("Should not fail");
+        let top = board.top(1).expect("Should not fail");
+
+        assert_eq!(top, vec![("alice".to_string(), 10.0)]);
+    }
+
+    #[test]
+    fn it_should_refresh_mirror_after_write() {
+        let mut board = ScoreboardCache::new(
+            "redis://127.0.0.1:6379",
+            "board3",
+            Duration::from_secs(10),
+            2,
+        )
+        .expect("Should establish connection with no problem");
+        board.clear().expect("Should not fail");
+        board.add("alice", 10.0).expect("Should not fail");
+        board.top(1).expect("Should not fail");
+        board.add("bob", 20.0).expect("Should not fail");
+        let top = board.top(1).expect("Should not fail");
+        let score = board.score("alice").expect("Should not fail");
+        board.clear().expect("Should not fail");
+
+        assert_eq!(top, vec![("bob".to_string(), 20.0)]);
+        assert_eq!(score, Some(10.0));
+    }
+}
@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+
+use crate::in_memory_cache::EvictionPolicy;
+use crate::CacheServiceBuilder;
+
+/// Settings of the `rcache` binary, read from a TOML file. Every field is optional and
+/// can be overridden by an environment variable, e.g. `RCACHE_BIND` for `bind`:
+///
+/// ```toml
+/// bind = "0.0.0.0:6380"
+/// redis_url = "redis://cache-1:6379"
+/// ttl = 300
+/// capacity = 100000
+/// eviction_policy = "lfu"
+/// log_level = "debug"
+/// snapshot_path = "/var/lib/rcache/memory.snapshot"
+/// instance_id = "cache-proxy-1"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the RESP server listens on.
+    pub bind: String,
+    pub redis_url: String,
+    /// TTL in seconds of values stored without one, in both tiers.
+    pub ttl: u64,
+    /// Maximum number of memory entries, unbounded if unset.
+    pub capacity: Option<usize>,
+    /// One of `lru`, `fifo` or `lfu`; only used with a capacity.
+    pub eviction_policy: Option<EvictionPolicy>,
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: LevelFilter,
+    /// Where the memory tier is saved on shutdown and loaded from on startup. Not saved if
+    /// unset.
+    pub snapshot_path: Option<PathBuf>,
+    /// Names the server in invalidation messages and `INFO`, and is recorded as the writer of
+    /// every value it stores, labelled `rcache`, and shown by `ENTRY`. Without it, the pod
+    /// name or a random ID names the server and writers are not recorded.
+    pub instance_id: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ServerConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidOverride { var: &'static str, value: String },
+}
+
+impl fmt::Display for ServerConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerConfigError::Read(path, err) => {
+                write!(f, "cannot read {}: {}", path.display(), err)
+            }
+            ServerConfigError::Parse(path, err) => {
+                write!(f, "invalid config in {}: {}", path.display(), err)
+            }
+            ServerConfigError::InvalidOverride { var, value } => {
+                write!(f, "invalid value {:?} in {}", value, var)
+            }
+        }
+    }
+}
+
+impl Error for ServerConfigError {}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "127.0.0.1:3000".to_string(),
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            ttl: 60,
+            capacity: None,
+            eviction_policy: None,
+            log_level: LevelFilter::Info,
+            snapshot_path: None,
+            instance_id: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads `path`, or starts from the defaults without one, then applies the `RCACHE_*`
+    /// environment variables.
+    pub fn load(path: Option<&Path>) -> Result<ServerConfig, ServerConfigError> {
+        let config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|err| ServerConfigError::Read(path.to_path_buf(), err))?;
+                toml::from_str(&text)
+                    .map_err(|err| ServerConfigError::Parse(path.to_path_buf(), err))?
+            }
+            None => ServerConfig::default(),
+        };
+        config.with_overrides(|var| std::env::var(var).ok())
+    }
+
+    /// Applies the overrides `var` returns for each `RCACHE_*` variable name.
+    pub fn with_overrides<F>(mut self, var: F) -> Result<ServerConfig, ServerConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(bind) = var("RCACHE_BIND") {
+            self.bind = bind;
+        }
+        if let Some(redis_url) = var("RCACHE_REDIS_URL") {
+            self.redis_url = redis_url;
+        }
+        if let Some(ttl) = override_with(&var, "RCACHE_TTL", |value| value.parse().ok())? {
+            self.ttl = ttl;
+        }
+        if let Some(capacity) = override_with(&var, "RCACHE_CAPACITY", |value| value.parse().ok())?
+        {
+            self.capacity = Some(capacity);
+        }
+        if let Some(policy) = override_with(&var, "RCACHE_EVICTION_POLICY", |value| {
+            let value: StrDeserializer<ValueError> = value.into_deserializer();
+            EvictionPolicy::deserialize(value).ok()
+        })? {
+            self.eviction_policy = Some(policy);
+        }
+        if let Some(level) = override_with(&var, "RCACHE_LOG_LEVEL", |value| value.parse().ok())? {
+            self.log_level = level;
+        }
+        if let Some(path) = var("RCACHE_SNAPSHOT_PATH") {
+            self.snapshot_path = Some(PathBuf::from(path));
+        }
+        if let Some(instance_id) = var("RCACHE_INSTANCE_ID") {
+            self.instance_id = Some(instance_id);
+        }
+        Ok(self)
+    }
+
+    /// A builder for the cache the server fronts.
+    pub fn cache_builder(&self) -> CacheServiceBuilder {
+        let mut builder =
+            CacheServiceBuilder::new(&self.redis_url).ttl(Duration::from_secs(self.ttl));
+        if let Some(capacity) = self.capacity {
+            builder = builder.capacity(capacity);
+        }
+        if let Some(policy) = self.eviction_policy {
+            builder = builder.eviction_policy(policy);
+        }
+        if let Some(instance_id) = &self.instance_id {
+            builder = builder
+                .instance_id(instance_id)
+                .provenance(instance_id, Some("rcache"));
+        }
+        builder
+    }
+}
+
+fn override_with<T, F, P>(
+    var: &F,
+    name: &'static str,
+    parse: P,
+) -> Result<Option<T>, ServerConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+    P: Fn(&str) -> Option<T>,
+{
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+    match parse(&value) {
+        Some(parsed) => Ok(Some(parsed)),
+        None => Err(ServerConfigError::InvalidOverride { var: name, value }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn it_should_read_config_with_env_overrides() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            bind = "0.0.0.0:6380"
+            ttl = 300
+            eviction_policy = "fifo"
+            log_level = "debug"
+            "#,
+        )
+        .unwrap();
+        let env = HashMap::from([
+            ("RCACHE_TTL", "30"),
+            ("RCACHE_CAPACITY", "1000"),
+            ("RCACHE_EVICTION_POLICY", "lfu"),
+        ]);
+        let config = config
+            .with_overrides(|var| env.get(var).map(|value| value.to_string()))
+            .unwrap();
+
+        assert_eq!(config.bind, "0.0.0.0:6380");
+        assert_eq!(config.redis_url, "redis://127.0.0.1:6379");
+        assert_eq!(config.ttl, 30);
+        assert_eq!(config.capacity, Some(1000));
+        assert_eq!(config.eviction_policy, Some(EvictionPolicy::Lfu));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert!(config.cache_builder().validate().is_ok());
+
+        let bad = ServerConfig::default()
+            .with_overrides(|var| (var == "RCACHE_TTL").then(|| "soon".to_string()));
+        assert_eq!(
+            bad.unwrap_err().to_string(),
+            "invalid value \"soon\" in RCACHE_TTL"
+        );
+        assert!(toml::from_str::<ServerConfig>("port = 1").is_err());
+    }
+}
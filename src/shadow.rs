@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::Tier;
+
+/// A cached value that differed from what the resolver returned when shadow mode checked it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowDivergence {
+    /// The key without the namespace.
+    pub key: String,
+    /// The value served, from `tier`.
+    pub cached: Bytes,
+    pub resolved: Bytes,
+    pub tier: Tier,
+}
+
+/// Receives shadow mode divergences. Runs on the resolving thread, so it should hand off
+/// anything slow.
+pub type ShadowObserver = Arc<dyn Fn(&ShadowDivergence) + Send + Sync>;
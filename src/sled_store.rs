@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use redis::{ConnectionLike, ErrorKind, RedisError, RedisResult, Value};
+use sled::Db;
+
+use crate::emulated::{self, malformed, number, unsupported};
+use crate::in_memory_cache::{SystemTimeSource, TimeSource};
+
+pub const URL_SCHEME: &str = "sled://";
+
+/// How often expired entries that were never read again are removed from disk.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Each stored value is prefixed with its expiry as big-endian Unix seconds, 0 for none.
+const HEADER_LEN: usize = 8;
+
+/// Databases opened by this process, so the connections of all components share one handle;
+/// sled allows a single one per path.
+fn open_stores() -> &'static Mutex<HashMap<PathBuf, Weak<Store>>> {
+    static OPEN: OnceLock<Mutex<HashMap<PathBuf, Weak<Store>>>> = OnceLock::new();
+    OPEN.get_or_init(Mutex::default)
+}
+
+/// A database and the thread sweeping it.
+struct Store {
+    db: Db,
+    stop: Option<SyncSender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Store {
+    fn new(db: Db) -> Store {
+        let (stop, stopped) = mpsc::sync_channel(0);
+        let sweeper_db = db.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SWEEP_INTERVAL) {
+                let _ = sweep(&sweeper_db);
+            }
+        });
+        Store {
+            db,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        self.stop = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// An embedded on-disk KV tier behind `ConnectionLike`, for single-node deployments without
+/// Redis. TTLs are stored alongside the values: expired entries read as missing and are
+/// removed on access, and a background sweep removes the rest. Understands GET, MGET, SET
+/// with EX, PX, NX, XX or KEEPTTL, SETEX, PSETEX, DEL, EXISTS, EXPIRE, PEXPIRE, PERSIST, TTL,
+/// PTTL, SCAN and PING, also inside pipelines and MULTI blocks; scripts, pub/sub and sorted sets
+/// fail. Expiry is kept in whole seconds, so millisecond TTLs are rounded up.
+pub struct SledConnection {
+    store: Arc<Store>,
+}
+
+impl SledConnection {
+    /// Opens the database at `sled://<path>`, sharing it with other connections to the same
+    /// path in this process.
+    pub fn open(url: &str) -> RedisResult<SledConnection> {
+        let path = url.strip_prefix(URL_SCHEME).ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "Not a sled URL",
+                url.to_string(),
+            ))
+        })?;
+        let path = PathBuf::from(path);
+        let mut open = open_stores().lock().unwrap();
+        if let Some(store) = open.get(&path).and_then(Weak::upgrade) {
+            return Ok(SledConnection { store });
+        }
+        let store = Arc::new(Store::new(sled::open(&path).map_err(storage_error)?));
+        open.retain(|_, store| store.strong_count() > 0);
+        open.insert(path, Arc::downgrade(&store));
+        Ok(SledConnection { store })
+    }
+
+    /// Uses an already opened database, e.g. a temporary one.
+    pub fn from_db(db: Db) -> SledConnection {
+        SledConnection {
+            store: Arc::new(Store::new(db)),
+        }
+    }
+
+    /// Removes every expired entry now instead of waiting for the background sweep. Returns
+    /// how many were removed.
+    pub fn sweep(&self) -> RedisResult<usize> {
+        sweep(&self.store.db)
+    }
+
+    fn execute(&self, command: &[Vec<u8>]) -> RedisResult<Value> {
+        let (name, args) = command.split_first().ok_or_else(malformed)?;
+        let name = String::from_utf8_lossy(name).to_uppercase();
+        let now = SystemTimeSource.now();
+        match (name.as_str(), args) {
+            ("GET", [key]) => Ok(self.live_value(key, now)?.map_or(Value::Nil, Value::Data)),
+            ("MGET", keys) if !keys.is_empty() => keys
+                .iter()
+                .map(|key| Ok(self.live_value(key, now)?.map_or(Value::Nil, Value::Data)))
+                .collect::<RedisResult<_>>()
+                .map(Value::Bulk),
+            ("SET", [key, value, options @ ..]) => self.set_with_options(key, value, options, now),
+            ("SETEX", [key, ttl, value]) => self.set_expiring(key, value, now + number(ttl)?),
+            ("PSETEX", [key, ttl, value]) => {
+                self.set_expiring(key, value, now + number(ttl)?.div_ceil(1000))
+            }
+            ("DEL", keys) if !keys.is_empty() => {
+                let mut deleted = 0;
+                for key in keys {
+                    let removed = self.db().remove(key).map_err(storage_error)?;
+                    deleted += i64::from(removed.is_some_and(|raw| is_live(&raw, now)));
+                }
+                Ok(Value::Int(deleted))
+            }
+            ("EXISTS", keys) if !keys.is_empty() => {
+                let mut found = 0;
+                for key in keys {
+                    found += i64::from(self.live_value(key, now)?.is_some());
+                }
+                Ok(Value::Int(found))
+            }
+            ("EXPIRE", [key, ttl]) => self.expire(key, now + number(ttl)?, now),
+            ("PEXPIRE", [key, ttl]) => self.expire(key, now + number(ttl)?.div_ceil(1000), now),
+            ("PERSIST", [key]) => self.expire(key, 0, now),
+            ("TTL", [key]) => Ok(Value::Int(self.ttl(key, now)?)),
+            ("PTTL", [key]) => {
+                let ttl = self.ttl(key, now)?;
+                Ok(Value::Int(if ttl >= 0 { ttl * 1000 } else { ttl }))
+            }
+            ("SCAN", [_cursor, options @ ..]) => self.scan(options, now),
+            ("PING", []) => Ok(Value::Status("PONG".to_string())),
+            _ => Err(unsupported("Command not supported by sled", name)),
+        }
+    }
+
+    fn db(&self) -> &Db {
+        &self.store.db
+    }
+
+    fn set_expiring(&self, key: &[u8], value: &[u8], expires_at: u64) -> RedisResult<Value> {
+        self.db()
+            .insert(key, encode(expires_at, value))
+            .map_err(storage_error)?;
+        Ok(Value::Okay)
+    }
+
+    fn expire(&self, key: &[u8], expires_at: u64, now: u64) -> RedisResult<Value> {
+        let mut touched = false;
+        self.db()
+            .fetch_and_update(key, |current| {
+                let current = current.filter(|raw| is_live(raw, now))?;
+                touched = true;
+                Some(encode(expires_at, &current[HEADER_LEN..]))
+            })
+            .map_err(storage_error)?;
+        Ok(Value::Int(i64::from(touched)))
+    }
+
+    /// Seconds `key` has left as TTL replies them: -1 without an expiry, -2 if missing.
+    fn ttl(&self, key: &[u8], now: u64) -> RedisResult<i64> {
+        Ok(match self.db().get(key).map_err(storage_error)? {
+            Some(raw) if is_live(&raw, now) => match expires_at(&raw) {
+                0 => -1,
+                expires_at => (expires_at - now) as i64,
+            },
+            _ => -2,
+        })
+    }
+
+    /// The value of `key` unless it is missing or expired. Expired entries are removed,
+    /// unless they were overwritten in the meantime.
+    fn live_value(&self, key: &[u8], now: u64) -> RedisResult<Option<Vec<u8>>> {
+        let Some(raw) = self.db().get(key).map_err(storage_error)? else {
+            return Ok(None);
+        };
+        if is_live(&raw, now) {
+            return Ok(Some(raw[HEADER_LEN..].to_vec()));
+        }
+        let _ = self
+            .db()
+            .compare_and_swap(key, Some(raw), None::<&[u8]>)
+            .map_err(storage_error)?;
+        Ok(None)
+    }
+
+    fn set_with_options(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        options: &[Vec<u8>],
+        now: u64,
+    ) -> RedisResult<Value> {
+        let mut expiry = Some(0);
+        let mut only_if: Option<bool> = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                "EX" => expiry = Some(now + number(options.next().ok_or_else(malformed)?)?),
+                "PX" => {
+                    let millis = number(options.next().ok_or_else(malformed)?)?;
+                    expiry = Some(now + millis.div_ceil(1000));
+                }
+                "KEEPTTL" => expiry = None,
+                "NX" => only_if = Some(false),
+                "XX" => only_if = Some(true),
+                option => {
+                    return Err(unsupported(
+                        "SET option not supported by sled",
+                        option.to_string(),
+                    ))
+                }
+            }
+        }
+
+        let mut stored = false;
+        self.db()
+            .fetch_and_update(key, |current| {
+                let current = current.filter(|raw| is_live(raw, now));
+                stored = only_if.is_none_or(|exists| exists == current.is_some());
+                if !stored {
+                    return current.map(<[u8]>::to_vec);
+                }
+                let expiry = expiry.unwrap_or_else(|| current.map_or(0, expires_at));
+                Some(encode(expiry, value))
+            })
+            .map_err(storage_error)?;
+        Ok(if stored { Value::Okay } else { Value::Nil })
+    }
+
+    /// Answers every SCAN in one go, with the final cursor 0.
+    fn scan(&self, options: &[Vec<u8>], now: u64) -> RedisResult<Value> {
+        let mut pattern: &[u8] = b"*";
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let arg = options.next().ok_or_else(malformed)?;
+            match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                "MATCH" => pattern = arg,
+                "COUNT" => {}
+                option => {
+                    return Err(unsupported(
+                        "SCAN option not supported by sled",
+                        option.to_string(),
+                    ))
+                }
+            }
+        }
+        let mut keys = Vec::new();
+        for entry in self.db().iter() {
+            let (key, raw) = entry.map_err(storage_error)?;
+            if is_live(&raw, now) && glob_match(pattern, &key) {
+                keys.push(Value::Data(key.to_vec()));
+            }
+        }
+        Ok(Value::Bulk(vec![
+            Value::Data(b"0".to_vec()),
+            Value::Bulk(keys),
+        ]))
+    }
+}
+
+fn sweep(db: &Db) -> RedisResult<usize> {
+    let now = SystemTimeSource.now();
+    let mut removed = 0;
+    for entry in db.iter() {
+        let (key, raw) = entry.map_err(storage_error)?;
+        if !is_live(&raw, now) {
+            let swapped = db
+                .compare_and_swap(key, Some(raw), None::<&[u8]>)
+                .map_err(storage_error)?;
+            removed += usize::from(swapped.is_ok());
+        }
+    }
+    Ok(removed)
+}
+
+fn encode(expires_at: u64, value: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(HEADER_LEN + value.len());
+    raw.extend_from_slice(&expires_at.to_be_bytes());
+    raw.extend_from_slice(value);
+    raw
+}
+
+fn expires_at(raw: &[u8]) -> u64 {
+    raw.get(..HEADER_LEN)
+        .and_then(|header| header.try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
+
+/// Entries too short to hold a header were not written by this module and count as expired.
+fn is_live(raw: &[u8], now: u64) -> bool {
+    raw.len() >= HEADER_LEN && (expires_at(raw) == 0 || expires_at(raw) > now)
+}
+
+/// Redis glob matching with `*` and `?`.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.split_first(), key.split_first()) {
+        (None, _) => key.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, key) || (!key.is_empty() && glob_match(pattern, &key[1..]))
+        }
+        (Some((b'?', rest)), Some((_, key_rest))) => glob_match(rest, key_rest),
+        (Some((c, rest)), Some((k, key_rest))) => c == k && glob_match(rest, key_rest),
+        (Some(_), None) => false,
+    }
+}
+
+fn storage_error(err: sled::Error) -> RedisError {
+    RedisError::from((ErrorKind::IoError, "Sled error", err.to_string()))
+}
+
+impl ConnectionLike for SledConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        emulated::run_command(cmd, |command| self.execute(command))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        emulated::run_pipeline(cmd, offset, count, |command| self.execute(command))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn check_connection(&mut self) -> bool {
+        true
+    }
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::Commands;
+
+    use super::*;
+
+    fn connection() -> SledConnection {
+        SledConnection::from_db(sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    #[test]
+    fn it_should_store_values_with_ttl() {
+        let mut con = connection();
+
+        con.set_ex::<_, _, ()>("a", "1", 100).unwrap();
+        con.set::<_, _, ()>("b", "2").unwrap();
+        let set: bool = con
+            .set_options(
+                "a",
+                "3",
+                redis::SetOptions::default().conditional_set(redis::ExistenceCheck::NX),
+            )
+            .unwrap();
+        assert!(!set);
+
+        let values: Vec<Option<String>> = con.mget(&["a", "b", "c"]).unwrap();
+        assert_eq!(
+            values,
+            vec![Some("1".to_string()), Some("2".to_string()), None]
+        );
+        let ttls: (i64, i64, i64) = redis::pipe()
+            .ttl("a")
+            .ttl("b")
+            .ttl("c")
+            .query(&mut con)
+            .unwrap();
+        assert!(ttls.0 > 98 && ttls.0 <= 100);
+        assert_eq!((ttls.1, ttls.2), (-1, -2));
+        let mut keys: Vec<String> = con.scan_match("*").unwrap().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert!(con.publish::<_, _, ()>("channel", "message").is_err());
+    }
+
+    #[test]
+    fn it_should_drop_expired_entries_on_read_and_sweep() {
+        let mut con = connection();
+        let now = SystemTimeSource.now();
+        con.db().insert("read", encode(now - 1, b"x")).unwrap();
+        con.db().insert("unread", encode(now - 1, b"y")).unwrap();
+        con.db().insert("live", encode(now + 100, b"z")).unwrap();
+
+        let value: Option<String> = con.get("read").unwrap();
+        assert_eq!(value, None);
+        assert_eq!(con.db().len(), 2);
+        assert_eq!(con.sweep().unwrap(), 1);
+        let value: Option<String> = con.get("live").unwrap();
+        assert_eq!(value.as_deref(), Some("z"));
+    }
+
+    #[test]
+    fn it_should_match_glob_patterns() {
+        assert!(glob_match(b"ns:*", b"ns:key"));
+        assert!(glob_match(b"ns:?ey", b"ns:key"));
+        assert!(!glob_match(b"ns:*", b"other:key"));
+        assert!(glob_match(b"*", b""));
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{self, BufRead, Read, Write};
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::in_memory_cache::{expiry, millis};
+
+const MAGIC: &[u8] = b"rcache-snapshot-3\n";
+
+/// A memory entry as saved in a snapshot, with the key as stored, namespace included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotEntry {
+    pub key: String,
+    pub value: Bytes,
+    /// Unix time in milliseconds the current value was stored at.
+    pub stored_at: u64,
+    /// TTL the current value was stored with, saved in whole milliseconds.
+    pub ttl: Duration,
+    /// Seconds it took to compute the value, used by early expiration.
+    pub delta: f64,
+    /// Unix time in milliseconds the key was first stored at, used by the max age.
+    pub created: u64,
+    pub pinned: bool,
+}
+
+impl SnapshotEntry {
+    /// Unix time in milliseconds, `u64::MAX` if the entry never expires.
+    pub fn expires_at(&self) -> u64 {
+        expiry(self.stored_at, self.ttl)
+    }
+}
+
+/// Writes `entries` in the snapshot format: a header line, then the key and value of each
+/// entry with 4-byte big-endian lengths, followed by its big-endian `stored_at`, `ttl`,
+/// `delta` and `created`, and a byte for `pinned`.
+pub fn write_snapshot<'a>(
+    writer: &mut impl Write,
+    entries: impl IntoIterator<Item = &'a SnapshotEntry>,
+) -> io::Result<usize> {
+    writer.write_all(MAGIC)?;
+    let mut count = 0;
+    for entry in entries {
+        write_chunk(writer, entry.key.as_bytes())?;
+        write_chunk(writer, &entry.value)?;
+        writer.write_all(&entry.stored_at.to_be_bytes())?;
+        writer.write_all(&millis(entry.ttl).to_be_bytes())?;
+        writer.write_all(&entry.delta.to_be_bytes())?;
+        writer.write_all(&entry.created.to_be_bytes())?;
+        writer.write_all(&[entry.pinned as u8])?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads every entry of a snapshot written by `write_snapshot`.
+pub fn read_snapshot(reader: &mut impl BufRead) -> io::Result<Vec<SnapshotEntry>> {
+    let mut magic = vec![0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("not an rcache snapshot"));
+    }
+    let mut entries = Vec::new();
+    while !reader.fill_buf()?.is_empty() {
+        let key = String::from_utf8(read_chunk(reader)?).map_err(|_| invalid("invalid key"))?;
+        let value = Bytes::from(read_chunk(reader)?);
+        let mut fields = [0; 33];
+        reader.read_exact(&mut fields)?;
+        let word = |index: usize| fields[index * 8..(index + 1) * 8].try_into().unwrap();
+        entries.push(SnapshotEntry {
+            key,
+            value,
+            stored_at: u64::from_be_bytes(word(0)),
+            ttl: Duration::from_millis(u64::from_be_bytes(word(1))),
+            delta: f64::from_be_bytes(word(2)),
+            created: u64::from_be_bytes(word(3)),
+            pinned: fields[32] != 0,
+        });
+    }
+    Ok(entries)
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(chunk.len()).map_err(|_| invalid("entry too large"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(chunk)
+}
+
+fn read_chunk(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut chunk = Vec::new();
+    reader
+        .take(u32::from_be_bytes(len) as u64)
+        .read_to_end(&mut chunk)?;
+    if chunk.len() != u32::from_be_bytes(len) as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(chunk)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_read_back_written_snapshot() {
+        let entries = [
+            SnapshotEntry {
+                key: "a".to_string(),
+                value: Bytes::from_static(b"1"),
+                stored_at: 100_000,
+                ttl: Duration::from_millis(10_500),
+                delta: 0.0,
+                created: 90_000,
+                pinned: false,
+            },
+            SnapshotEntry {
+                key: "ns:b".to_string(),
+                value: Bytes::new(),
+                stored_at: 100_000,
+                ttl: Duration::from_millis(u64::MAX),
+                delta: 0.25,
+                created: 100_000,
+                pinned: true,
+            },
+        ];
+        let mut buf = Vec::new();
+        assert_eq!(write_snapshot(&mut buf, &entries).unwrap(), 2);
+
+        assert_eq!(read_snapshot(&mut &buf[..]).unwrap(), entries);
+        assert_eq!(entries[1].expires_at(), u64::MAX);
+        assert!(read_snapshot(&mut &buf[..buf.len() - 1]).is_err());
+        assert!(read_snapshot(&mut &b"garbage"[..]).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+/// Copies below this count are never pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Values of expired entries, kept for `grace` milliseconds past their expiry so they can
+/// be served when the resolver fails.
+#[derive(Debug)]
+pub(crate) struct StaleCopies {
+    grace: u64,
+    copies: HashMap<String, (Bytes, u64)>,
+    prune_at: usize,
+}
+
+impl StaleCopies {
+    pub(crate) fn new(grace: u64) -> StaleCopies {
+        StaleCopies {
+            grace,
+            copies: HashMap::new(),
+            prune_at: MIN_PRUNE_LEN,
+        }
+    }
+
+    pub(crate) fn keep(&mut self, key: &str, value: Bytes, expired_at: u64, now: u64) {
+        if self.copies.len() >= self.prune_at {
+            let grace = self.grace;
+            self.copies
+                .retain(|_, (_, expired_at)| now.saturating_sub(*expired_at) < grace);
+            self.prune_at = (self.copies.len() * 2).max(MIN_PRUNE_LEN);
+        }
+        self.copies.insert(key.to_string(), (value, expired_at));
+    }
+
+    /// The value `key` had when it expired, if that was less than `grace` before `now`.
+    pub(crate) fn get(&self, key: &str, now: u64) -> Option<Bytes> {
+        self.copies
+            .get(key)
+            .filter(|(_, expired_at)| now.saturating_sub(*expired_at) < self.grace)
+            .map(|(value, _)| value.clone())
+    }
+
+    pub(crate) fn discard(&mut self, key: &str) {
+        self.copies.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_keep_expired_values_for_the_grace_window() {
+        let mut copies = StaleCopies::new(1_000);
+        copies.keep("key", Bytes::from_static(b"old"), 5_000, 5_010);
+
+        assert_eq!(copies.get("key", 5_900), Some(Bytes::from_static(b"old")));
+        assert_eq!(copies.get("key", 6_000), None);
+        copies.discard("key");
+        assert_eq!(copies.get("key", 5_900), None);
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+/// Where a value returned by a batch lookup came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Memory,
+    Kv,
+    Resolver,
+}
+
+/// A value from `CacheService::resolve_many_with_info` with where it came from and how long
+/// getting it took.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedEntry {
+    pub value: Bytes,
+    pub source: Source,
+    pub elapsed: Duration,
+}
+
+/// Counters collected by a `CacheService` since it was built or since `reset_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    pub memory_hits: u64,
+    pub memory_misses: u64,
+    pub kv_hits: u64,
+    pub kv_misses: u64,
+    /// Live memory entries dropped to stay within capacity.
+    pub evictions: u64,
+    /// Expired memory entries removed on access or by a sweep.
+    pub expired_removals: u64,
+    /// Resolver invocations, counting a batch resolver call once.
+    pub resolver_calls: u64,
+    /// Operations that returned an error.
+    pub errors: u64,
+    /// Times the namespace reached the alert threshold of its key quota.
+    pub quota_alerts: u64,
+    /// Redis lookups skipped because the key directory showed the key was absent.
+    pub directory_skips: u64,
+    /// Redis lookups skipped because the negative lookup filter showed the key was never
+    /// written.
+    pub filter_skips: u64,
+    /// Values read from Redis or resolved that were not cached because the key was
+    /// invalidated while they were being fetched, as told by its tombstone.
+    pub dropped_fills: u64,
+    /// Hits shadow mode checked against the resolver.
+    pub shadow_checks: u64,
+    /// Shadow mode checks where the cached value differed from the resolved one.
+    pub shadow_divergences: u64,
+    /// Keys resolved directly, bypassing both tiers, while pass-through was on.
+    pub pass_throughs: u64,
+    /// Keys resolved directly because they fell outside the rollout percentage. The other
+    /// counters only cover keys within it, for comparison with the uncached population.
+    pub outside_rollout: u64,
+    /// Expired values served by `resolve_or_stale` because the resolver failed.
+    pub stale_served: u64,
+    /// Resolvers `resolve_with_timeout` stopped waiting for.
+    pub resolver_timeouts: u64,
+    /// Entries currently in the memory tier. Not affected by `reset_stats`.
+    pub entries: usize,
+}
+
+/// Counts of sizes in power-of-two buckets: the first holds size 0 and the `i`-th sizes in
+/// `2^(i-1)..2^i`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SizeHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    total: u64,
+    max: usize,
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, size: usize) {
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += size as u64;
+        self.max = self.max.max(size);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// `None` until a size was recorded.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total as f64 / self.count as f64)
+    }
+
+    /// Upper bound of the sizes below which `quantile` of the recorded sizes fall, e.g. 0.99
+    /// for the 99th percentile. Exact only up to the bucket, and never above `max`.
+    pub fn percentile(&self, quantile: f64) -> Option<usize> {
+        let rank = (quantile.clamp(0.0, 1.0) * self.count as f64)
+            .ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+        for (upper, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return Some(upper.min(self.max));
+            }
+        }
+        None
+    }
+
+    /// The largest size of each bucket with how many sizes fell into it, smallest first.
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(bucket, &count)| {
+            let upper = match bucket {
+                0 => 0,
+                bucket => usize::MAX >> (usize::BITS as usize - bucket),
+            };
+            (upper, count)
+        })
+    }
+}
+
+/// Sizes of the keys, namespace included, and values written through a `CacheService`,
+/// including writes rejected by its size limits.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SizeDistribution {
+    pub keys: SizeHistogram,
+    pub values: SizeHistogram,
+}
@@ -0,0 +1,340 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use redis::ConnectionLike;
+
+use crate::builder::ConfigError;
+use crate::in_memory_cache::{shorter_ttl, InMemoryCache, TimeSource};
+use crate::kv_cache::{KvCache, ValueWithTtl};
+use crate::{CacheServiceError, SetPayload};
+
+/// One level of a `TieredCache`. Implemented by `InMemoryCache` and by `KvCache` over any
+/// backend, so a local disk tier is a `KvCache` opened on a `sled://` URL.
+pub trait CacheTier: Send {
+    fn get(&mut self, key: &str) -> Option<Bytes>;
+
+    /// Same as `get`, with the time the value has left in this tier, `None` if it never
+    /// expires.
+    fn get_with_ttl(&mut self, key: &str) -> Option<ValueWithTtl>;
+
+    /// Stores `value` even over a live one. `Duration::ZERO` stores it without expiry.
+    fn set(&mut self, key: &str, value: &[u8], ttl: Duration) -> Result<(), CacheServiceError>;
+
+    fn remove(&mut self, key: &str) -> Result<(), CacheServiceError>;
+}
+
+impl<T: TimeSource + Send> CacheTier for InMemoryCache<T> {
+    fn get(&mut self, key: &str) -> Option<Bytes> {
+        InMemoryCache::get(self, key)
+    }
+
+    fn get_with_ttl(&mut self, key: &str) -> Option<ValueWithTtl> {
+        InMemoryCache::get_with_ttl(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &[u8], ttl: Duration) -> Result<(), CacheServiceError> {
+        let payload = SetPayload {
+            key,
+            value,
+            ttl,
+            tier_hint: None,
+        };
+        self.replace(payload, 0.0)
+            .map_err(CacheServiceError::memory("replace", key))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), CacheServiceError> {
+        InMemoryCache::remove(self, key);
+        Ok(())
+    }
+}
+
+impl<C: ConnectionLike + Send> CacheTier for KvCache<C> {
+    fn get(&mut self, key: &str) -> Option<Bytes> {
+        KvCache::get(self, key)
+    }
+
+    fn get_with_ttl(&mut self, key: &str) -> Option<ValueWithTtl> {
+        KvCache::get_with_ttl(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &[u8], ttl: Duration) -> Result<(), CacheServiceError> {
+        let payload = SetPayload {
+            key,
+            value,
+            ttl,
+            tier_hint: None,
+        };
+        self.overwrite(payload)
+            .map_err(CacheServiceError::kv("SET", key))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), CacheServiceError> {
+        self.unset(key).map_err(CacheServiceError::kv("DEL", key))
+    }
+}
+
+/// Whether a value found in a slower tier is copied into this one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Promotion {
+    #[default]
+    OnHit,
+    /// The tier only holds what is written to it, e.g. to keep a big rarely read value out
+    /// of memory.
+    Never,
+}
+
+/// Whether `TieredCache::set` writes to this tier or it only receives values demoted into
+/// it from the tier above.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Demotion {
+    #[default]
+    WriteThrough,
+    /// Filled by `TieredCache::demote` and by promotion from below only.
+    OnDemote,
+}
+
+/// How long one tier keeps values and how they move into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TierPolicy {
+    pub ttl: Duration,
+    pub promotion: Promotion,
+    pub demotion: Demotion,
+}
+
+impl TierPolicy {
+    pub fn new(ttl: Duration) -> TierPolicy {
+        TierPolicy {
+            ttl,
+            promotion: Promotion::default(),
+            demotion: Demotion::default(),
+        }
+    }
+
+    pub fn promotion(mut self, promotion: Promotion) -> TierPolicy {
+        self.promotion = promotion;
+        self
+    }
+
+    pub fn demotion(mut self, demotion: Demotion) -> TierPolicy {
+        self.demotion = demotion;
+        self
+    }
+}
+
+/// Collects the tiers of a `TieredCache`, fastest first.
+#[derive(Default)]
+pub struct TieredCacheBuilder {
+    tiers: Vec<(Box<dyn CacheTier>, TierPolicy)>,
+}
+
+impl TieredCacheBuilder {
+    pub fn new() -> TieredCacheBuilder {
+        TieredCacheBuilder::default()
+    }
+
+    /// Adds `tier` below the ones added so far.
+    pub fn tier(
+        mut self,
+        tier: impl CacheTier + 'static,
+        policy: TierPolicy,
+    ) -> TieredCacheBuilder {
+        self.tiers.push((Box::new(tier), policy));
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self.tiers.first() {
+            None => Err(ConfigError::NoTiers),
+            Some((_, policy)) if policy.demotion == Demotion::OnDemote => {
+                Err(ConfigError::DemotionIntoFirstTier)
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    pub fn build(self) -> Result<TieredCache, CacheServiceError> {
+        self.validate()?;
+        Ok(TieredCache { tiers: self.tiers })
+    }
+}
+
+/// Any number of cache tiers composed in order, e.g. memory, then a local disk, then Redis.
+/// Reads go down the tiers until one has the key and copy the value into the faster tiers
+/// that promote; writes go to every write-through tier, each with its own TTL. A copy moved
+/// into another tier never outlives the value it was read from. A failing slower tier is
+/// read as a miss, like `KvCache::get` does. `CacheService` keeps its own memory and Redis
+/// tiers rather than running on this.
+pub struct TieredCache {
+    tiers: Vec<(Box<dyn CacheTier>, TierPolicy)>,
+}
+
+impl TieredCache {
+    pub fn builder() -> TieredCacheBuilder {
+        TieredCacheBuilder::new()
+    }
+
+    pub fn tier_count(&self) -> usize {
+        self.tiers.len()
+    }
+
+    /// The value from the fastest tier holding `key`. Promotion is best effort: a tier that
+    /// fails to store the copy does not fail the read.
+    pub fn get(&mut self, key: &str) -> Option<Bytes> {
+        let (found_at, value, remaining) = self.find(key)?;
+        for (tier, policy) in &mut self.tiers[..found_at] {
+            if policy.promotion == Promotion::OnHit {
+                let _ = tier.set(key, &value, copy_ttl(policy, remaining));
+            }
+        }
+        Some(value)
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), CacheServiceError> {
+        for (tier, policy) in &mut self.tiers {
+            if policy.demotion == Demotion::WriteThrough {
+                tier.set(key, value, policy.ttl)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The cached value, or the one `resolver` computes, which is then stored with `set`.
+    pub fn resolve_bytes<T>(&mut self, key: &str, resolver: T) -> Result<Bytes, CacheServiceError>
+    where
+        T: FnOnce() -> Bytes,
+    {
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+        let value = resolver();
+        self.set(key, &value)?;
+        Ok(value)
+    }
+
+    /// Removes `key` from every tier.
+    pub fn remove(&mut self, key: &str) -> Result<(), CacheServiceError> {
+        for (tier, _) in &mut self.tiers {
+            tier.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Moves `key` from the fastest tier holding it into the tier below, for a key known to
+    /// have gone cold. Returns false if no tier holds it or it is only in the slowest one.
+    pub fn demote(&mut self, key: &str) -> Result<bool, CacheServiceError> {
+        let Some((found_at, value, remaining)) = self.find(key) else {
+            return Ok(false);
+        };
+        let Some((below, policy)) = self.tiers.get_mut(found_at + 1) else {
+            return Ok(false);
+        };
+        below.set(key, &value, copy_ttl(policy, remaining))?;
+        self.tiers[found_at].0.remove(key)?;
+        Ok(true)
+    }
+
+    /// The fastest tier holding `key`, with the value and the time it has left there.
+    fn find(&mut self, key: &str) -> Option<(usize, Bytes, Option<Duration>)> {
+        self.tiers
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, (tier, _))| {
+                let (value, remaining) = tier.get_with_ttl(key)?;
+                Some((index, value, remaining))
+            })
+    }
+}
+
+/// The TTL for a copy into the tier with `policy` of a value that has `remaining` left where
+/// it was found.
+fn copy_ttl(policy: &TierPolicy, remaining: Option<Duration>) -> Duration {
+    match remaining {
+        // `Duration::ZERO` would store the copy without expiry.
+        Some(remaining) => shorter_ttl(policy.ttl, remaining.max(Duration::from_millis(1))),
+        None => policy.ttl,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_promote_hits_and_demote_cold_keys() {
+        let kv = KvCache::new("redis://127.0.0.1:6379").unwrap();
+        let mut cache = TieredCache::builder()
+            .tier(
+                InMemoryCache::new(),
+                TierPolicy::new(Duration::from_secs(10)),
+            )
+            .tier(
+                InMemoryCache::new(),
+                TierPolicy::new(Duration::from_secs(10))
+                    .demotion(Demotion::OnDemote)
+                    .promotion(Promotion::Never),
+            )
+            .tier(kv, TierPolicy::new(Duration::from_secs(10)))
+            .build()
+            .unwrap();
+        cache.remove("tiered:key").unwrap();
+
+        let mut calls = 0;
+        for _ in 0..2 {
+            let value = cache.resolve_bytes("tiered:key", || {
+                calls += 1;
+                Bytes::from_static(b"value")
+            });
+            assert_eq!(value.unwrap(), Bytes::from_static(b"value"));
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(cache.tiers[1].0.get("tiered:key"), None);
+
+        assert!(cache.demote("tiered:key").unwrap());
+        assert_eq!(cache.tiers[0].0.get("tiered:key"), None);
+        assert!(cache.tiers[1].0.get("tiered:key").is_some());
+
+        cache.tiers[1].0.remove("tiered:key").unwrap();
+        assert!(cache.get("tiered:key").is_some());
+        assert!(cache.tiers[0].0.get("tiered:key").is_some());
+        assert_eq!(cache.tiers[1].0.get("tiered:key"), None);
+
+        cache.remove("tiered:key").unwrap();
+        assert_eq!(cache.get("tiered:key"), None);
+        assert!(matches!(
+            TieredCache::builder().build(),
+            Err(CacheServiceError::InvalidConfig(ConfigError::NoTiers))
+        ));
+    }
+
+    #[test]
+    fn it_should_not_let_copies_outlive_the_value() {
+        let mut cache = TieredCache::builder()
+            .tier(
+                InMemoryCache::new(),
+                TierPolicy::new(Duration::from_secs(100)),
+            )
+            .tier(
+                InMemoryCache::new(),
+                TierPolicy::new(Duration::from_secs(100)).demotion(Demotion::OnDemote),
+            )
+            .build()
+            .unwrap();
+        cache.tiers[1]
+            .0
+            .set("key", b"value", Duration::from_secs(5))
+            .unwrap();
+        assert!(cache.get("key").is_some());
+        let (_, promoted) = cache.tiers[0].0.get_with_ttl("key").unwrap();
+        assert!(promoted.unwrap() <= Duration::from_secs(5));
+
+        cache.tiers[1].0.remove("key").unwrap();
+        cache.tiers[0]
+            .0
+            .set("key", b"value", Duration::from_secs(3))
+            .unwrap();
+        assert!(cache.demote("key").unwrap());
+        let (_, demoted) = cache.tiers[1].0.get_with_ttl("key").unwrap();
+        assert!(demoted.unwrap() <= Duration::from_secs(3));
+    }
+}
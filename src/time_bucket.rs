@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BucketSize {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl BucketSize {
+    pub fn secs(&self) -> u64 {
+        match self {
+            BucketSize::Minute => 60,
+            BucketSize::Hour => 60 * 60,
+            BucketSize::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+/// Derives cache keys for fixed time windows, e.g. `pageviews:bucket:3600:1700000000` for the
+/// hour starting at that timestamp. Entries live for the bucket length plus `grace` seconds.
+pub struct TimeBucketKey {
+    prefix: String,
+    size: BucketSize,
+    grace: u64,
+}
+
+impl TimeBucketKey {
+    pub fn new(prefix: &str, size: BucketSize, grace: u64) -> TimeBucketKey {
+        TimeBucketKey {
+            prefix: prefix.to_string(),
+            size,
+            grace,
+        }
+    }
+
+    pub fn bucket_start(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.size.secs()
+    }
+
+    pub fn key(&self, timestamp: u64) -> String {
+        format!(
+            "{}:bucket:{}:{}",
+            self.prefix,
+            self.size.secs(),
+            self.bucket_start(timestamp)
+        )
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.size.secs() + self.grace)
+    }
+
+    /// Returns the starts of the `count` most recent buckets up to and including the one
+    /// containing `now`, oldest first.
+    pub fn window(&self, now: u64, count: usize) -> Vec<u64> {
+        let current = self.bucket_start(now);
+        let size = self.size.secs();
+        (0..count as u64)
+            .rev()
+            .filter_map(|i| current.checked_sub(i * size))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_align_timestamp_to_bucket_start() {
+        let bucket_key = TimeBucketKey::new("views", BucketSize::Hour, 60);
+        assert_eq!(bucket_key.bucket_start(7205), 7200);
+        assert_eq!(bucket_key.key(7205), "views:bucket:3600:7200");
+    }
+
+    #[test]
+    fn it_should_add_grace_to_ttl() {
+        let bucket_key = TimeBucketKey::new("views", BucketSize::Minute, 30);
+        assert_eq!(bucket_key.ttl(), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn it_should_return_window_oldest_first() {
+        let bucket_key = TimeBucketKey::new("views", BucketSize::Minute, 0);
+        assert_eq!(bucket_key.window(185, 3), vec![60, 120, 180]);
+        assert_eq!(bucket_key.window(61, 3), vec![0, 60]);
+    }
+}
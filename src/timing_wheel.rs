@@ -0,0 +1,139 @@
+use std::mem;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+/// Deadlines further out than this are parked in the top level and looked at again when
+/// their slot comes up, about every two years.
+const MAX_SPAN: u64 = 1 << (SLOT_BITS as usize * LEVELS);
+
+/// Hierarchical timing wheel of keys by expiry time in milliseconds. Level `n` has 64 slots
+/// of `64^n` milliseconds each, so scheduling and taking the keys due are O(1) per key, and
+/// keys far from expiry only move down a level when their slot comes up.
+///
+/// The wheel does not know about overwrites or removals: a key is handed back once per
+/// `schedule` call, and the caller checks whether it really expired.
+#[derive(Debug)]
+pub(crate) struct TimingWheel {
+    /// Time up to which due keys were handed back.
+    elapsed: u64,
+    levels: Vec<Level>,
+    /// Keys scheduled at or before `elapsed`, handed back by the next `advance`.
+    overdue: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Level {
+    /// Bit `n` is set if slot `n` holds keys.
+    occupied: u64,
+    slots: Vec<Vec<(String, u64)>>,
+}
+
+impl Level {
+    fn new() -> Level {
+        Level {
+            occupied: 0,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+        }
+    }
+}
+
+impl TimingWheel {
+    pub(crate) fn new() -> TimingWheel {
+        TimingWheel {
+            elapsed: 0,
+            levels: (0..LEVELS).map(|_| Level::new()).collect(),
+            overdue: Vec::new(),
+        }
+    }
+
+    pub(crate) fn schedule(&mut self, key: String, expires_at: u64) {
+        if expires_at <= self.elapsed {
+            self.overdue.push(key);
+            return;
+        }
+        let parked_at = expires_at.min(self.elapsed.saturating_add(MAX_SPAN - 1));
+        let level = level_for(self.elapsed, parked_at);
+        let slot = slot_for(parked_at, level);
+        self.levels[level].slots[slot].push((key, expires_at));
+        self.levels[level].occupied |= 1 << slot;
+    }
+
+    /// Moves the wheel forward to `now` and returns the keys scheduled to expire by then.
+    pub(crate) fn advance(&mut self, now: u64) -> Vec<String> {
+        let mut due = mem::take(&mut self.overdue);
+        while let Some((level, slot, deadline)) = self.next_slot() {
+            if deadline > now {
+                break;
+            }
+            self.elapsed = deadline;
+            self.levels[level].occupied &= !(1 << slot);
+            for (key, expires_at) in mem::take(&mut self.levels[level].slots[slot]) {
+                if expires_at <= deadline {
+                    due.push(key);
+                } else {
+                    self.schedule(key, expires_at);
+                }
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+        due
+    }
+
+    /// The occupied slot that starts first, with its start time.
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter(|(_, level)| level.occupied != 0)
+            .map(|(index, level)| {
+                let shift = SLOT_BITS * index as u32;
+                let current = ((self.elapsed >> shift) as usize) % SLOTS;
+                let slot = (level.occupied.rotate_right(current as u32).trailing_zeros() as usize
+                    + current)
+                    % SLOTS;
+                let level_start = self.elapsed >> (shift + SLOT_BITS) << (shift + SLOT_BITS);
+                let mut deadline = level_start + ((slot as u64) << shift);
+                // Only keys parked in the top level can sit in a slot that has come around
+                // already; theirs is the slot's next turn.
+                if deadline <= self.elapsed {
+                    deadline += 1 << (shift + SLOT_BITS);
+                }
+                (index, slot, deadline)
+            })
+            .min_by_key(|(_, _, deadline)| *deadline)
+    }
+}
+
+/// The level whose slots are the finest that still tell `elapsed` and `expires_at` apart.
+fn level_for(elapsed: u64, expires_at: u64) -> usize {
+    let masked = (elapsed ^ expires_at) | (SLOTS as u64 - 1);
+    let significant = 63 - masked.leading_zeros();
+    ((significant / SLOT_BITS) as usize).min(LEVELS - 1)
+}
+
+fn slot_for(expires_at: u64, level: usize) -> usize {
+    ((expires_at >> (SLOT_BITS * level as u32)) as usize) % SLOTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_hand_back_keys_once_due() {
+        let mut wheel = TimingWheel::new();
+        wheel.advance(1_000);
+        wheel.schedule("soon".to_string(), 1_010);
+        wheel.schedule("later".to_string(), 1_000 + 5 * 60 * 1000);
+        wheel.schedule("past".to_string(), 500);
+
+        assert_eq!(wheel.advance(1_005), ["past"]);
+        assert_eq!(wheel.advance(1_010), ["soon"]);
+        assert!(wheel.advance(1_000 + 5 * 60 * 1000 - 1).is_empty());
+        assert_eq!(wheel.advance(1_000 + 10 * 60 * 1000), ["later"]);
+
+        wheel.schedule("far".to_string(), u64::MAX - 1);
+        assert!(wheel.advance(MAX_SPAN * 3).is_empty());
+    }
+}
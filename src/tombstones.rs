@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+/// Tombstones below this count are never pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// When keys were last removed, kept for `lifetime` milliseconds, so a value read or
+/// computed before a removal can be told apart from one that is still current.
+#[derive(Debug)]
+pub(crate) struct Tombstones {
+    lifetime: u64,
+    removed_at: HashMap<String, u64>,
+    prune_at: usize,
+}
+
+impl Tombstones {
+    pub(crate) fn new(lifetime: u64) -> Tombstones {
+        Tombstones {
+            lifetime,
+            removed_at: HashMap::new(),
+            prune_at: MIN_PRUNE_LEN,
+        }
+    }
+
+    pub(crate) fn bury(&mut self, key: &str, now: u64) {
+        if self.removed_at.len() >= self.prune_at {
+            let lifetime = self.lifetime;
+            self.removed_at
+                .retain(|_, removed_at| now.saturating_sub(*removed_at) < lifetime);
+            self.prune_at = (self.removed_at.len() * 2).max(MIN_PRUNE_LEN);
+        }
+        self.removed_at.insert(key.to_string(), now);
+    }
+
+    /// Whether `key` was removed at or after `since`, judged at `now`.
+    pub(crate) fn removed_since(&self, key: &str, since: u64, now: u64) -> bool {
+        self.removed_at.get(key).is_some_and(|&removed_at| {
+            removed_at >= since && now.saturating_sub(removed_at) < self.lifetime
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_report_removals_after_a_read_until_they_fade() {
+        let mut tombstones = Tombstones::new(1_000);
+        tombstones.bury("key", 5_000);
+
+        assert!(tombstones.removed_since("key", 4_900, 5_100));
+        assert!(!tombstones.removed_since("key", 5_001, 5_100));
+        assert!(!tombstones.removed_since("key", 4_900, 6_000));
+        assert!(!tombstones.removed_since("other", 0, 5_100));
+    }
+}
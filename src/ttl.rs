@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+
+use crate::in_memory_cache::jitter_ttl;
+
+/// How reads affect the expiry of an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpiryMode {
+    /// Entries expire a fixed time after they were stored.
+    #[default]
+    Absolute,
+    /// Every hit restarts the entry's TTL, so only entries left unread expire.
+    Sliding,
+}
+
+/// Default TTLs of both tiers and the jitter applied when storing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TtlConfig {
+    pub memory: Duration,
+    pub kv: Duration,
+    /// Fraction by which stored TTLs are randomly scaled up or down.
+    pub jitter: f64,
+}
+
+/// The effective `TtlConfig`, shared with the background components so a reconfiguration
+/// reaches them without restarting anything. Reads are lock-free and always see a whole
+/// configuration, never half of an update.
+#[derive(Debug)]
+pub struct SharedTtls {
+    config: ArcSwap<TtlConfig>,
+}
+
+impl SharedTtls {
+    pub fn new(config: TtlConfig) -> SharedTtls {
+        SharedTtls {
+            config: ArcSwap::from_pointee(config),
+        }
+    }
+
+    pub fn load(&self) -> TtlConfig {
+        **self.config.load()
+    }
+
+    pub fn store(&self, config: TtlConfig) {
+        self.config.store(Arc::new(config));
+    }
+
+    pub fn memory(&self) -> Duration {
+        self.config.load().memory
+    }
+
+    pub fn kv(&self) -> Duration {
+        self.config.load().kv
+    }
+
+    /// The memory and KV TTLs from the same configuration.
+    pub fn both(&self) -> (Duration, Duration) {
+        let config = self.config.load();
+        (config.memory, config.kv)
+    }
+
+    /// `ttl` scaled by a random factor within the configured jitter, drawn anew on every call
+    /// so entries stored together expire at different times.
+    pub fn jittered(&self, ttl: Duration) -> Duration {
+        match self.config.load().jitter {
+            factor if factor > 0.0 => jitter_ttl(ttl, factor),
+            _ => ttl,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_replace_whole_config() {
+        let ttls = SharedTtls::new(TtlConfig {
+            memory: Duration::from_secs(10),
+            kv: Duration::from_secs(100),
+            jitter: 0.0,
+        });
+        let ttl = Duration::from_millis(1500);
+        assert_eq!(ttls.jittered(ttl), ttl);
+        ttls.store(TtlConfig {
+            memory: Duration::from_secs(20),
+            kv: Duration::from_secs(200),
+            jitter: 0.5,
+        });
+        assert_eq!(
+            ttls.both(),
+            (Duration::from_secs(20), Duration::from_secs(200))
+        );
+        let jittered = ttls.jittered(Duration::from_secs(100)).as_secs_f64();
+        assert!((50.0..=150.0).contains(&jittered));
+    }
+}
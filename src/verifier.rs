@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::envelope::Decoded;
+use crate::kv_cache::KvCache;
+
+/// How much of the KV tier `Verifier` reads and how often.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerifierConfig {
+    pub interval: Duration,
+    /// Entries read per round.
+    pub sample_size: usize,
+}
+
+impl Default for VerifierConfig {
+    fn default() -> Self {
+        VerifierConfig {
+            interval: Duration::from_secs(60),
+            sample_size: 10,
+        }
+    }
+}
+
+/// What the verifier found since the service was built.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VerificationStats {
+    /// Entries read and decoded.
+    pub sampled: u64,
+    /// Entries in an envelope this build cannot read, or whose payload does not decompress.
+    /// Reading them through the cache would discard them as misses.
+    pub unreadable: u64,
+    /// Plain values from before envelopes, which reads convert or discard.
+    pub legacy: u64,
+    /// Rounds that failed to read from the KV tier.
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sampled: AtomicU64,
+    unreadable: AtomicU64,
+    legacy: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> VerificationStats {
+        VerificationStats {
+            sampled: self.sampled.load(Ordering::Relaxed),
+            unreadable: self.unreadable.load(Ordering::Relaxed),
+            legacy: self.legacy.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Reads a few random entries of the KV tier every interval on its own connection and checks
+/// that they still decode, so a broken codec or a bad write shows up in the counters before
+/// users miss on it. Entries are only read: not migrated, promoted or touched.
+pub struct Verifier {
+    counters: Arc<Counters>,
+    stop: Option<SyncSender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Verifier {
+    /// Starts sampling the keys matching `pattern`, taking ownership of `kv_cache`.
+    pub fn new(mut kv_cache: KvCache, pattern: String, config: VerifierConfig) -> Verifier {
+        let counters = Arc::new(Counters::default());
+        let sampler_counters = Arc::clone(&counters);
+        let (stop, stopped) = mpsc::sync_channel(0);
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(config.interval) {
+                verify(
+                    &mut kv_cache,
+                    &pattern,
+                    config.sample_size,
+                    &sampler_counters,
+                );
+            }
+        });
+        Verifier {
+            counters,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stats(&self) -> VerificationStats {
+        self.counters.stats()
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        self.stop = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn verify(kv_cache: &mut KvCache, pattern: &str, sample_size: usize, counters: &Counters) {
+    let Ok(sample) = kv_cache.sample_raw(pattern, sample_size) else {
+        counters.errors.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    for (key, raw) in sample.into_iter().filter(|(_, raw)| !raw.is_empty()) {
+        counters.sampled.fetch_add(1, Ordering::Relaxed);
+        match kv_cache.decode(&key, raw) {
+            Decoded::Current(_) => {}
+            Decoded::Legacy(_) => {
+                counters.legacy.fetch_add(1, Ordering::Relaxed);
+            }
+            Decoded::Unreadable => {
+                counters.unreadable.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use redis::Commands;
+
+    use super::*;
+    use crate::envelope::{Codec, Compression, Envelope};
+
+    #[test]
+    fn it_should_count_unreadable_entries() {
+        let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let zstd = Compression {
+            codec: Codec::Zstd,
+            threshold: 0,
+        };
+        let good = Envelope::encode_with(1700000000, &[7; 100], Some(zstd));
+        let truncated = good[..good.len() - 3].to_vec();
+        let keys = ["verify:good", "verify:truncated", "verify:legacy"];
+        redis.set_ex::<_, _, ()>(keys[0], good, 100).unwrap();
+        redis.set_ex::<_, _, ()>(keys[1], truncated, 100).unwrap();
+        redis.set_ex::<_, _, ()>(keys[2], "plain", 100).unwrap();
+
+        let verifier = Verifier::new(
+            KvCache::new("redis://127.0.0.1:6379").unwrap(),
+            "verify:*".to_string(),
+            VerifierConfig {
+                interval: Duration::from_millis(10),
+                sample_size: 3,
+            },
+        );
+        let started = Instant::now();
+        while verifier.stats().sampled < 3 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let counters = Arc::clone(&verifier.counters);
+        drop(verifier);
+        let stats = counters.stats();
+        redis.del::<_, ()>(&keys).unwrap();
+
+        assert!(stats.sampled >= 3);
+        assert_eq!(stats.unreadable, stats.sampled / 3);
+        assert_eq!(stats.legacy, stats.sampled / 3);
+        assert_eq!(stats.errors, 0);
+    }
+}
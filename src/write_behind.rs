@@ -0,0 +1,237 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::kv_cache::KvCache;
+use crate::SetPayload;
+
+pub struct WriteBehindConfig {
+    /// Maximum number of writes waiting for the flusher. When the queue is full, writes go
+    /// to Redis synchronously instead.
+    pub queue_size: usize,
+    /// Maximum number of writes sent to Redis in one pipeline.
+    pub batch_size: usize,
+    /// How long the first write of a batch waits for more before a partial batch is sent.
+    pub flush_interval: Duration,
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> Self {
+        WriteBehindConfig {
+            queue_size: 10000,
+            batch_size: 100,
+            flush_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+pub struct QueuedWrite {
+    pub key: String,
+    pub value: Bytes,
+    pub ttl: Duration,
+}
+
+enum Command {
+    Write(QueuedWrite),
+    Flush(SyncSender<()>),
+    Shutdown,
+}
+
+/// Background flusher that batches KV writes on its own Redis connection.
+pub struct WriteBehind {
+    sender: SyncSender<Command>,
+    handle: Option<JoinHandle<()>>,
+    failed_writes: Arc<AtomicU64>,
+}
+
+impl WriteBehind {
+    /// Starts the flusher, which takes ownership of `kv_cache` and uses it exclusively.
+    pub fn new(kv_cache: KvCache, config: WriteBehindConfig) -> WriteBehind {
+        let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+        let failed_writes = Arc::new(AtomicU64::new(0));
+        let flusher_failed_writes = Arc::clone(&failed_writes);
+        let handle = thread::spawn(move || {
+            run_flusher(kv_cache, receiver, config, flusher_failed_writes);
+        });
+
+        WriteBehind {
+            sender,
+            handle: Some(handle),
+            failed_writes,
+        }
+    }
+
+    /// Queues the write, handing it back if the queue is full or the flusher is gone.
+    pub fn enqueue(&self, write: QueuedWrite) -> Result<(), QueuedWrite> {
+        match self.sender.try_send(Command::Write(write)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(Command::Write(write)))
+            | Err(TrySendError::Disconnected(Command::Write(write))) => Err(write),
+            Err(_) => unreachable!("only writes are enqueued"),
+        }
+    }
+
+    /// Blocks until every write queued before this call has been sent to Redis.
+    pub fn flush(&self) {
+        let (done_sender, done_receiver) = mpsc::sync_channel(1);
+        if self.sender.send(Command::Flush(done_sender)).is_ok() {
+            let _ = done_receiver.recv();
+        }
+    }
+
+    pub fn failed_writes(&self) -> u64 {
+        self.failed_writes.load(Ordering::Relaxed)
+    }
+
+    /// Flushes the queue and stops the flusher thread.
+    pub fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.sender.send(Command::Shutdown);
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for WriteBehind {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_flusher(
+    mut kv_cache: KvCache,
+    receiver: Receiver<Command>,
+    config: WriteBehindConfig,
+    failed_writes: Arc<AtomicU64>,
+) {
+    let mut batch: Vec<QueuedWrite> = Vec::with_capacity(config.batch_size);
+    // When the oldest write in `batch` is due, so a steady trickle of writes can't hold
+    // back a partial batch.
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let received = match deadline {
+            Some(deadline) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let command = match received {
+            Ok(command) => Some(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Command::Shutdown),
+        };
+
+        match command {
+            Some(Command::Write(write)) => {
+                if batch.is_empty() {
+                    deadline = Some(Instant::now() + config.flush_interval);
+                }
+                batch.push(write);
+                if batch.len() >= config.batch_size {
+                    write_batch(&mut kv_cache, &mut batch, &failed_writes);
+                }
+            }
+            Some(Command::Flush(done)) => {
+                write_batch(&mut kv_cache, &mut batch, &failed_writes);
+                let _ = done.send(());
+            }
+            Some(Command::Shutdown) => {
+                write_batch(&mut kv_cache, &mut batch, &failed_writes);
+                return;
+            }
+            None => write_batch(&mut kv_cache, &mut batch, &failed_writes),
+        }
+        if batch.is_empty() {
+            deadline = None;
+        }
+    }
+}
+
+fn write_batch(kv_cache: &mut KvCache, batch: &mut Vec<QueuedWrite>, failed_writes: &AtomicU64) {
+    if batch.is_empty() {
+        return;
+    }
+    let payloads: Vec<SetPayload> = batch
+        .iter()
+        .map(|write| SetPayload {
+            key: &write.key,
+            value: &write.value,
+            ttl: write.ttl,
+            tier_hint: None,
+        })
+        .collect();
+    if kv_cache.set_many(&payloads).is_err() {
+        failed_writes.fetch_add(batch.len() as u64, Ordering::Relaxed);
+    }
+    batch.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect() -> KvCache {
+        KvCache::new("redis://127.0.0.1:6379").expect("Should establish connection with no problem")
+    }
+
+    fn queued(key: &str, value: &str) -> QueuedWrite {
+        QueuedWrite {
+            key: key.to_string(),
+            value: Bytes::copy_from_slice(value.as_bytes()),
+            ttl: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn it_should_write_queued_values_on_flush() {
+        let write_behind = WriteBehind::new(connect(), WriteBehindConfig::default());
+        let mut kv_cache = connect();
+        write_behind
+            .enqueue(queued("wb1", "value"))
+            .unwrap_or_else(|_| panic!("Should be queued"));
+        write_behind.flush();
+        let res = kv_cache.get("wb1");
+        kv_cache.unset("wb1").expect("Should not fail");
+        assert_eq!(res.unwrap(), "value");
+    }
+
+    #[test]
+    fn it_should_write_queued_values_on_shutdown() {
+        let mut write_behind = WriteBehind::new(connect(), WriteBehindConfig::default());
+        let mut kv_cache = connect();
+        write_behind
+            .enqueue(queued("wb2", "value"))
+            .unwrap_or_else(|_| panic!("Should be queued"));
+        write_behind.shutdown();
+        let res = kv_cache.get("wb2");
+        kv_cache.unset("wb2").expect("Should not fail");
+        assert_eq!(res.unwrap(), "value");
+    }
+
+    #[test]
+    fn it_should_send_partial_batch_while_writes_keep_arriving() {
+        let config = WriteBehindConfig {
+            flush_interval: Duration::from_millis(50),
+            ..WriteBehindConfig::default()
+        };
+        let write_behind = WriteBehind::new(connect(), config);
+        let mut kv_cache = connect();
+        write_behind
+            .enqueue(queued("wb3", "value"))
+            .unwrap_or_else(|_| panic!("Should be queued"));
+        for _ in 0..30 {
+            thread::sleep(Duration::from_millis(10));
+            write_behind
+                .enqueue(queued("wb3-filler", "value"))
+                .unwrap_or_else(|_| panic!("Should be queued"));
+        }
+        let res = kv_cache.get("wb3");
+        kv_cache.unset("wb3").expect("Should not fail");
+        kv_cache.unset("wb3-filler").expect("Should not fail");
+        assert_eq!(res.unwrap(), "value");
+    }
+}
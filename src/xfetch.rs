@@ -0,0 +1,56 @@
+/// Probabilistic early expiration ("XFetch", Vattani et al.). An entry is recomputed before it
+/// expires with a probability that grows as expiry approaches and with how expensive the
+/// value was to compute, so refreshes of hot keys spread out instead of piling up at expiry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct XFetch {
+    /// Values above 1.0 favour earlier recomputation, below 1.0 later.
+    pub beta: f64,
+}
+
+impl Default for XFetch {
+    fn default() -> Self {
+        XFetch { beta: 1.0 }
+    }
+}
+
+impl XFetch {
+    pub fn new(beta: f64) -> XFetch {
+        XFetch { beta }
+    }
+
+    /// `delta` is the recompute cost in seconds; `now` and `expires_at` are Unix milliseconds,
+    /// like the memory tier's `EntryMeta`.
+    pub fn should_recompute(&self, now: u64, expires_at: u64, delta: f64) -> bool {
+        self.should_recompute_with(now, expires_at, delta, 1.0 - rand::random::<f64>())
+    }
+
+    fn should_recompute_with(&self, now: u64, expires_at: u64, delta: f64, sample: f64) -> bool {
+        let gap = delta * 1000.0 * self.beta * -sample.ln();
+        now as f64 + gap >= expires_at as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_recompute_expired_entry() {
+        let xfetch = XFetch::default();
+        assert!(xfetch.should_recompute(100_000, 100_000, 0.0));
+        assert!(xfetch.should_recompute(101_000, 100_000, 5.0));
+    }
+
+    #[test]
+    fn it_should_not_recompute_cheap_fresh_entry() {
+        let xfetch = XFetch::default();
+        assert!(!xfetch.should_recompute(99_900, 100_000, 0.0));
+    }
+
+    #[test]
+    fn it_should_recompute_early_for_expensive_entry() {
+        let xfetch = XFetch::new(1.0);
+        assert!(xfetch.should_recompute_with(90_000, 100_000, 10.0, 0.1));
+        assert!(!xfetch.should_recompute_with(90_000, 100_000, 10.0, 0.9));
+    }
+}